@@ -8,6 +8,7 @@
 use std::os::fd::AsRawFd as _;
 
 use cap_std::fs::File;
+use cap_std::fs::FileTypeExt;
 use cap_std::fs::MetadataExt;
 
 use super::maybe_chmod;
@@ -48,6 +49,31 @@ pub(super) fn compare<C: Contents>(old: File, new: File) -> Result<Vec<Operation
         }
     }
 
+    // Device nodes can't be modified in place, so a different device number
+    // means it must be recreated
+    if (new_meta.file_type().is_char_device() || new_meta.file_type().is_block_device())
+        && old_meta.rdev() != new_meta.rdev()
+    {
+        ops.push(Operation::Unlink);
+        ops.push(Operation::Mknod {
+            rdev: new_meta.rdev(),
+            mode: new_meta.mode(),
+        });
+        // the new node starts out with none of the old metadata, so it all
+        // has to be set again, just like for a newly added node (the mode is
+        // implied by the Mknod)
+        ops.extend(xattr_ops(None, new)?);
+        ops.push(Operation::Chown {
+            uid: new_meta.uid(),
+            gid: new_meta.gid(),
+        });
+        ops.push(Operation::SetTimes {
+            mtime: new_meta.modified()?.into_std(),
+            atime: new_meta.accessed()?.into_std(),
+        });
+        return Ok(ops);
+    }
+
     ops.extend(xattr_ops(Some(old), new)?);
     if let Some(op) = maybe_chmod(&old_meta, &new_meta) {
         ops.push(op);
@@ -74,6 +100,21 @@ pub(super) fn add<C: Contents>(file: File) -> Result<Vec<Operation<C>>> {
     } else if ft.is_symlink() {
         let target = symlink_target(&file)?;
         ops.push(Operation::Symlink { target });
+    } else if ft.is_char_device() || ft.is_block_device() {
+        // the file type bits are kept in the mode so that the receiver can
+        // tell block and character devices apart
+        ops.push(Operation::Mknod {
+            rdev: meta.rdev(),
+            mode: meta.mode(),
+        });
+    } else if ft.is_fifo() {
+        ops.push(Operation::Mkfifo {
+            mode: sanitize_mode(meta.mode()),
+        });
+    } else if ft.is_socket() {
+        ops.push(Operation::Mksock {
+            mode: sanitize_mode(meta.mode()),
+        });
     } else {
         let path = std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()))?;
         return Err(Error::UnsupportedFileType(path, ft));
@@ -105,3 +146,164 @@ fn symlink_target(f: &File) -> std::io::Result<std::path::PathBuf> {
     let path = std::fs::read_link(format!("/proc/self/fd/{}", f.as_raw_fd()))?;
     std::fs::read_link(&path)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use cap_std::ambient_authority;
+    use cap_std::fs::Dir;
+    use nix::sys::stat::makedev;
+    use nix::sys::stat::mknod;
+    use nix::sys::stat::Mode;
+    use nix::sys::stat::SFlag;
+
+    use super::*;
+    use crate::compare::tree::path_open_opts;
+
+    fn open(dir: &Path, name: &str) -> File {
+        Dir::open_ambient_dir(dir, ambient_authority())
+            .expect("failed to open dir")
+            .open_with(name, &path_open_opts())
+            .expect("failed to open file")
+    }
+
+    /// Every operation other than timestamps, which are not deterministic
+    fn without_times(ops: Vec<Operation<Vec<u8>>>) -> Vec<Operation<Vec<u8>>> {
+        ops.into_iter()
+            .filter(|op| !matches!(op, Operation::SetTimes { .. }))
+            .collect()
+    }
+
+    fn mkfifo(path: &Path, mode: u32) {
+        nix::unistd::mkfifo(path, Mode::empty()).expect("failed to mkfifo");
+        std::fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(mode))
+            .expect("failed to chmod");
+    }
+
+    fn mkchr(path: &Path, mode: u32, minor: u64) {
+        mknod(path, SFlag::S_IFCHR, Mode::empty(), makedev(1, minor)).expect("failed to mknod");
+        std::fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(mode))
+            .expect("failed to chmod");
+    }
+
+    #[test]
+    fn add_fifo() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        mkfifo(&dir.path().join("fifo"), 0o640);
+        let meta = std::fs::symlink_metadata(dir.path().join("fifo")).expect("failed to stat");
+        assert_eq!(
+            without_times(add(open(dir.path(), "fifo")).expect("failed to add")),
+            [
+                Operation::Mkfifo { mode: 0o640 },
+                Operation::Chown {
+                    uid: std::os::unix::fs::MetadataExt::uid(&meta),
+                    gid: std::os::unix::fs::MetadataExt::gid(&meta),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn add_socket() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let _listener = std::os::unix::net::UnixListener::bind(dir.path().join("sock"))
+            .expect("failed to bind socket");
+        std::fs::set_permissions(
+            dir.path().join("sock"),
+            std::os::unix::fs::PermissionsExt::from_mode(0o600),
+        )
+        .expect("failed to chmod");
+        let meta = std::fs::symlink_metadata(dir.path().join("sock")).expect("failed to stat");
+        assert_eq!(
+            without_times(add(open(dir.path(), "sock")).expect("failed to add")),
+            [
+                Operation::Mksock { mode: 0o600 },
+                Operation::Chown {
+                    uid: std::os::unix::fs::MetadataExt::uid(&meta),
+                    gid: std::os::unix::fs::MetadataExt::gid(&meta),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn compare_fifo() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        mkfifo(&dir.path().join("old"), 0o600);
+        mkfifo(&dir.path().join("new"), 0o644);
+        assert_eq!(
+            without_times(
+                compare(open(dir.path(), "old"), open(dir.path(), "new"))
+                    .expect("failed to compare")
+            ),
+            [Operation::Chmod { mode: 0o644 }]
+        );
+    }
+
+    #[test]
+    #[ignore = "must run as root"]
+    fn add_device() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        mkchr(&dir.path().join("null"), 0o666, 3);
+        assert_eq!(
+            without_times(add(open(dir.path(), "null")).expect("failed to add")),
+            [
+                // the file type is kept so that receivers can tell char and
+                // block devices apart
+                Operation::Mknod {
+                    rdev: makedev(1, 3),
+                    mode: libc::S_IFCHR | 0o666,
+                },
+                Operation::Chown { uid: 0, gid: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    #[ignore = "must run as root"]
+    fn compare_device() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        mkchr(&dir.path().join("same-old"), 0o666, 3);
+        mkchr(&dir.path().join("same-new"), 0o600, 3);
+        mkchr(&dir.path().join("changed"), 0o666, 5);
+        xattr::set(dir.path().join("changed"), "trusted.antlir", b"hello")
+            .expect("failed to set xattr");
+        // only the metadata changed, so the node does not need to be recreated
+        assert_eq!(
+            without_times(
+                compare(open(dir.path(), "same-old"), open(dir.path(), "same-new"))
+                    .expect("failed to compare")
+            ),
+            [Operation::Chmod { mode: 0o600 }]
+        );
+        // device numbers can't be changed in place
+        assert_eq!(
+            without_times(
+                compare(open(dir.path(), "same-old"), open(dir.path(), "changed"))
+                    .expect("failed to compare")
+            ),
+            [
+                Operation::Unlink,
+                Operation::Mknod {
+                    rdev: makedev(1, 5),
+                    mode: libc::S_IFCHR | 0o666,
+                },
+                Operation::SetXattr {
+                    name: "trusted.antlir".into(),
+                    value: b"hello".to_vec(),
+                },
+                Operation::Chown { uid: 0, gid: 0 },
+            ]
+        );
+        // even if none of the metadata changed, it has to be set on the
+        // recreated node
+        let ops: Vec<Operation<Vec<u8>>> =
+            compare(open(dir.path(), "same-old"), open(dir.path(), "changed"))
+                .expect("failed to compare");
+        assert!(
+            matches!(ops.last(), Some(Operation::SetTimes { .. })),
+            "{ops:?}"
+        );
+    }
+}
@@ -23,7 +23,7 @@ use crate::Change;
 use crate::Operation;
 use crate::Result;
 
-fn path_open_opts() -> OpenOptions {
+pub(super) fn path_open_opts() -> OpenOptions {
     let mut opts = OpenOptions::new();
     opts.read(true)
         .custom_flags(libc::O_PATH | libc::O_NOFOLLOW);
@@ -185,6 +185,11 @@ pub(super) fn add<C>(prefix: &Path, dir: Dir) -> Result<Vec<Instruction<C>>> {
             gid: dir_meta.gid(),
         },
     )));
+    stack.extend(
+        xattr_ops(None, dir.as_raw_fd())?
+            .into_iter()
+            .map(|op| Instruction::Change(Change::new(prefix.to_owned(), op))),
+    );
 
     for entry in dir.entries()? {
         let entry = entry?;
@@ -213,3 +218,72 @@ pub(super) fn add<C>(prefix: &Path, dir: Dir) -> Result<Vec<Instruction<C>>> {
     )));
     Ok(stack)
 }
+
+#[cfg(test)]
+mod tests {
+    use cap_std::ambient_authority;
+
+    use super::*;
+
+    fn root_ops(instructions: Vec<Instruction<Vec<u8>>>) -> Vec<Operation<Vec<u8>>> {
+        instructions
+            .into_iter()
+            .filter_map(|i| match i {
+                Instruction::Change(change) if change.path() == Path::new("") => {
+                    Some(change.operation().clone())
+                }
+                _ => None,
+            })
+            .filter(|op| !matches!(op, Operation::SetTimes { .. }))
+            .collect()
+    }
+
+    fn open(dir: &Path) -> Dir {
+        Dir::open_ambient_dir(dir, ambient_authority()).expect("failed to open dir")
+    }
+
+    #[test]
+    fn root_xattrs() {
+        let old = tempfile::tempdir().expect("failed to create tempdir");
+        let new = tempfile::tempdir().expect("failed to create tempdir");
+        xattr::set(old.path(), "user.removed", b"old").expect("failed to set xattr");
+        xattr::set(old.path(), "user.changed", b"old").expect("failed to set xattr");
+        xattr::set(old.path(), "user.same", b"same").expect("failed to set xattr");
+        xattr::set(new.path(), "user.changed", b"new").expect("failed to set xattr");
+        xattr::set(new.path(), "user.same", b"same").expect("failed to set xattr");
+        xattr::set(new.path(), "user.added", b"new").expect("failed to set xattr");
+
+        let mut ops = root_ops(
+            compare(Path::new(""), open(old.path()), open(new.path())).expect("failed to compare"),
+        );
+        // both tempdirs are created with the same mode
+        ops.retain(|op| !matches!(op, Operation::Chmod { .. }));
+        assert_eq!(
+            ops,
+            [
+                Operation::RemoveXattr {
+                    name: "user.removed".into()
+                },
+                Operation::SetXattr {
+                    name: "user.added".into(),
+                    value: b"new".to_vec(),
+                },
+                Operation::SetXattr {
+                    name: "user.changed".into(),
+                    value: b"new".to_vec(),
+                },
+            ]
+        );
+
+        // a new tree gets all of the root directory's xattrs
+        let ops = root_ops(add(Path::new(""), open(new.path())).expect("failed to add"));
+        assert!(ops.contains(&Operation::SetXattr {
+            name: "user.same".into(),
+            value: b"same".to_vec(),
+        }));
+        assert!(ops.contains(&Operation::SetXattr {
+            name: "user.added".into(),
+            value: b"new".to_vec(),
+        }));
+    }
+}
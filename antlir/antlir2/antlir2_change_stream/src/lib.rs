@@ -52,6 +52,8 @@ pub enum Operation<C> {
     Mkdir { mode: u32 },
     /// Create a new fifo
     Mkfifo { mode: u32 },
+    /// Create a new unix socket
+    Mksock { mode: u32 },
     /// Create a new device node
     Mknod { rdev: u64, mode: u32 },
     /// Remove an empty directory
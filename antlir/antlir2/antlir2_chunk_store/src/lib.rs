@@ -0,0 +1,476 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Content-defined chunking (in the style of casync/desync) for distributing
+//! large images that mostly share bytes with previous versions.
+//!
+//! A stream is split into chunks at boundaries that depend only on the
+//! nearby content (with FastCDC), so inserting or removing bytes only changes
+//! the chunks around the edit. Chunks are stored (zstd compressed) in a
+//! [ChunkStore] under their sha256, and an [Index] records which chunks make
+//! up the original stream. Anyone that already has most of the chunks from a
+//! previous version only needs to fetch the few that changed.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+
+/// Name of the index in a self-contained package (a directory holding an
+/// index and a store with all of its chunks)
+pub const INDEX_FILENAME: &str = "index.json";
+/// Name of the chunk store in a self-contained package
+pub const STORE_DIRNAME: &str = "chunks";
+
+/// Multiplicative constants for the gear hash, generated with splitmix64 so
+/// that chunk boundaries never change between builds of this crate.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x616e_746c_6972_3263;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Bounds on the size of chunks.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChunkSizes {
+    min: usize,
+    avg: usize,
+    max: usize,
+}
+
+impl ChunkSizes {
+    /// Chunks will average out to about `avg` bytes, and are never smaller
+    /// than a quarter or bigger than four times that (the same ratios that
+    /// casync uses).
+    pub fn new(avg: usize) -> Result<Self> {
+        ensure!(
+            avg.is_power_of_two() && avg >= 256,
+            "average chunk size must be a power of two of at least 256 bytes"
+        );
+        Ok(Self {
+            min: avg / 4,
+            avg,
+            max: avg * 4,
+        })
+    }
+
+    /// Masks to test the gear hash with before and after the average chunk
+    /// size. Following FastCDC's "normalized chunking", a boundary is harder
+    /// to find before reaching the average size and easier after it, which
+    /// keeps chunk sizes closer to the average.
+    fn masks(&self) -> (u64, u64) {
+        let bits = self.avg.trailing_zeros();
+        (!0u64 << (64 - (bits + 1)), !0u64 << (64 - (bits - 1)))
+    }
+
+    /// Length of the first chunk in `data`, or None if more data is needed to
+    /// find it (and `data` is not the end of the stream)
+    fn cut(&self, data: &[u8]) -> Option<usize> {
+        if data.len() <= self.min {
+            return None;
+        }
+        let (mask_small, mask_large) = self.masks();
+        let mut hash = 0u64;
+        let normal = std::cmp::min(self.avg, data.len());
+        for (i, b) in data.iter().enumerate().take(normal).skip(self.min) {
+            hash = (hash << 1).wrapping_add(GEAR[*b as usize]);
+            if hash & mask_small == 0 {
+                return Some(i + 1);
+            }
+        }
+        let end = std::cmp::min(self.max, data.len());
+        for (i, b) in data.iter().enumerate().take(end).skip(normal) {
+            hash = (hash << 1).wrapping_add(GEAR[*b as usize]);
+            if hash & mask_large == 0 {
+                return Some(i + 1);
+            }
+        }
+        if data.len() >= self.max {
+            Some(self.max)
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for ChunkSizes {
+    fn default() -> Self {
+        Self::new(64 * 1024).expect("64K is a valid chunk size")
+    }
+}
+
+/// sha256 of the uncompressed contents of a chunk
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ChunkId(String);
+
+impl ChunkId {
+    fn of(data: &[u8]) -> Self {
+        Self(hex::encode(Sha256::digest(data)))
+    }
+}
+
+impl std::fmt::Display for ChunkId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Directory of zstd-compressed chunks, addressed by [ChunkId].
+///
+/// Chunks are spread out into subdirectories named after the first 4 hex
+/// characters of their id so that no single directory gets too big.
+#[derive(Debug, Clone)]
+pub struct ChunkStore {
+    path: PathBuf,
+    compression_level: i32,
+}
+
+impl ChunkStore {
+    /// Open an existing chunk store at `path`
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        ensure!(
+            path.is_dir(),
+            "chunk store {} does not exist",
+            path.display()
+        );
+        Ok(Self {
+            path,
+            compression_level: 3,
+        })
+    }
+
+    /// Create a chunk store at `path`, or open it if it already exists
+    pub fn create(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        std::fs::create_dir_all(&path)
+            .with_context(|| format!("while creating chunk store {}", path.display()))?;
+        Self::open(path)
+    }
+
+    pub fn compression_level(mut self, level: i32) -> Self {
+        self.compression_level = level;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn chunk_path(&self, id: &ChunkId) -> Result<PathBuf> {
+        ensure!(
+            id.0.len() == 64 && id.0.bytes().all(|b| b.is_ascii_hexdigit()),
+            "invalid chunk id '{id}'"
+        );
+        Ok(self.path.join(&id.0[..4]).join(format!("{id}.zst")))
+    }
+
+    pub fn contains(&self, id: &ChunkId) -> Result<bool> {
+        Ok(self.chunk_path(id)?.exists())
+    }
+
+    /// Add a chunk to the store (if it's not already there)
+    pub fn insert(&self, data: &[u8]) -> Result<ChunkId> {
+        let id = ChunkId::of(data);
+        let path = self.chunk_path(&id)?;
+        if path.exists() {
+            return Ok(id);
+        }
+        let dir = path.parent().expect("chunk path always has a parent");
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("while creating {}", dir.display()))?;
+        // write to a temporary file first so that a partially written chunk
+        // can never be mistaken for a complete one
+        let mut tmp = tempfile::NamedTempFile::new_in(dir)
+            .with_context(|| format!("while creating temp file in {}", dir.display()))?;
+        zstd::stream::copy_encode(data, &mut tmp, self.compression_level)
+            .with_context(|| format!("while compressing chunk {id}"))?;
+        tmp.persist(&path)
+            .with_context(|| format!("while writing chunk {}", path.display()))?;
+        Ok(id)
+    }
+
+    /// Read a chunk out of the store, making sure that it has the expected
+    /// contents
+    pub fn get(&self, id: &ChunkId) -> Result<Vec<u8>> {
+        let path = self.chunk_path(id)?;
+        let f =
+            File::open(&path).with_context(|| format!("while opening chunk {}", path.display()))?;
+        let data = zstd::stream::decode_all(BufReader::new(f))
+            .with_context(|| format!("while decompressing chunk {}", path.display()))?;
+        ensure!(
+            &ChunkId::of(&data) == id,
+            "chunk {} is corrupt: contents do not match its id",
+            path.display()
+        );
+        Ok(data)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IndexEntry {
+    pub id: ChunkId,
+    pub len: u64,
+}
+
+/// Ordered list of the chunks that make up a stream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Index {
+    /// Total size of the stream
+    pub size: u64,
+    pub chunk_sizes: ChunkSizes,
+    pub chunks: Vec<IndexEntry>,
+}
+
+impl Index {
+    pub fn read(path: &Path) -> Result<Self> {
+        let f =
+            File::open(path).with_context(|| format!("while opening index {}", path.display()))?;
+        serde_json::from_reader(BufReader::new(f))
+            .with_context(|| format!("while parsing index {}", path.display()))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut f = BufWriter::new(
+            File::create(path)
+                .with_context(|| format!("while creating index {}", path.display()))?,
+        );
+        serde_json::to_writer(&mut f, self).context("while serializing index")?;
+        f.flush()
+            .with_context(|| format!("while writing index {}", path.display()))
+    }
+
+    /// Chunks that are in this index but not in `store` (in other words,
+    /// what needs to be fetched before the stream can be reassembled)
+    pub fn missing<'a>(&'a self, store: &ChunkStore) -> Result<Vec<&'a ChunkId>> {
+        let mut seen = HashSet::new();
+        let mut missing = Vec::new();
+        for entry in &self.chunks {
+            if seen.insert(&entry.id) && !store.contains(&entry.id)? {
+                missing.push(&entry.id);
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Reassemble the original stream from chunks in `store`
+    pub fn reader(self, store: ChunkStore) -> IndexReader {
+        IndexReader {
+            chunks: self.chunks.into_iter(),
+            store,
+            current: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+/// Splits everything written to it into chunks that are added to a
+/// [ChunkStore].
+pub struct ChunkWriter {
+    store: ChunkStore,
+    sizes: ChunkSizes,
+    buf: Vec<u8>,
+    index: Index,
+}
+
+impl ChunkWriter {
+    pub fn new(store: ChunkStore, sizes: ChunkSizes) -> Self {
+        Self {
+            store,
+            sizes,
+            buf: Vec::with_capacity(sizes.max * 2),
+            index: Index {
+                size: 0,
+                chunk_sizes: sizes,
+                chunks: Vec::new(),
+            },
+        }
+    }
+
+    fn emit(&mut self, len: usize) -> Result<()> {
+        let id = self.store.insert(&self.buf[..len])?;
+        self.index.size += len as u64;
+        self.index.chunks.push(IndexEntry {
+            id,
+            len: len as u64,
+        });
+        self.buf.drain(..len);
+        Ok(())
+    }
+
+    /// Flush the last (possibly short) chunk and return the complete
+    /// [Index]
+    pub fn finish(mut self) -> Result<Index> {
+        while let Some(len) = self.sizes.cut(&self.buf) {
+            self.emit(len)?;
+        }
+        if !self.buf.is_empty() {
+            self.emit(self.buf.len())?;
+        }
+        Ok(self.index)
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        while self.buf.len() >= self.sizes.max {
+            let len = self
+                .sizes
+                .cut(&self.buf)
+                .expect("a full buffer always has a cut point");
+            self.emit(len).map_err(std::io::Error::other)?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// [Read] implementation that reassembles a stream from an [Index], one chunk
+/// at a time.
+pub struct IndexReader {
+    chunks: std::vec::IntoIter<IndexEntry>,
+    store: ChunkStore,
+    current: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for IndexReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.current.len() {
+            match self.chunks.next() {
+                Some(entry) => {
+                    let data = self.store.get(&entry.id).map_err(std::io::Error::other)?;
+                    if data.len() as u64 != entry.len {
+                        return Err(std::io::Error::other(format!(
+                            "chunk {} is {} bytes, but the index says it should be {}",
+                            entry.id,
+                            data.len(),
+                            entry.len
+                        )));
+                    }
+                    self.current = data;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.current.len() - self.pos);
+        buf[..n].copy_from_slice(&self.current[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic, incompressible-ish test data
+    fn data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn chunk(store: &ChunkStore, data: &[u8]) -> Index {
+        let mut w = ChunkWriter::new(store.clone(), ChunkSizes::new(4096).expect("valid size"));
+        // uneven writes to make sure that boundaries don't depend on them
+        for piece in data.chunks(1000) {
+            w.write_all(piece).expect("failed to write");
+        }
+        w.finish().expect("failed to finish")
+    }
+
+    #[test]
+    fn roundtrip() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let store = ChunkStore::create(dir.path()).expect("failed to create store");
+        let data = data(1024 * 1024, 1);
+        let index = chunk(&store, &data);
+        assert_eq!(data.len() as u64, index.size);
+        for entry in &index.chunks {
+            assert!(entry.len <= 16384, "{entry:?} is too big");
+        }
+        assert!(index.missing(&store).expect("failed to check").is_empty());
+        let mut out = Vec::new();
+        index
+            .reader(store)
+            .read_to_end(&mut out)
+            .expect("failed to read");
+        assert_eq!(data, out);
+    }
+
+    #[test]
+    fn edits_only_change_nearby_chunks() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let store = ChunkStore::create(dir.path()).expect("failed to create store");
+        let original = data(1024 * 1024, 2);
+        let before = chunk(&store, &original);
+        let mut edited = original.clone();
+        edited.splice(500_000..500_000, b"some inserted bytes".iter().copied());
+        let after = chunk(&store, &edited);
+        let new_chunks = after
+            .chunks
+            .iter()
+            .filter(|c| !before.chunks.contains(c))
+            .count();
+        assert!(
+            new_chunks <= 2,
+            "{new_chunks} of {} chunks changed",
+            after.chunks.len()
+        );
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let store = ChunkStore::create(dir.path()).expect("failed to create store");
+        let id = store.insert(b"hello").expect("failed to insert");
+        let path = store.chunk_path(&id).expect("valid id");
+        std::fs::write(
+            &path,
+            zstd::stream::encode_all(&b"goodbye"[..], 3).expect("failed to compress"),
+        )
+        .expect("failed to overwrite chunk");
+        assert!(store.get(&id).is_err());
+    }
+}
@@ -9,6 +9,9 @@ use std::collections::BTreeMap;
 use std::ffi::OsString;
 use std::fs::File;
 use std::io::BufWriter;
+use std::io::Write;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -35,6 +38,13 @@ struct Args {
     out: PathBuf,
     #[clap(long)]
     rootless: bool,
+    #[clap(long, env = "SOURCE_DATE_EPOCH", default_value_t = 1075852800)]
+    /// Timestamps make things non-deterministic even if everything else is
+    /// 100% equal, so clamp all mtimes to be no later than this. To preempt
+    /// any bugs from tools that don't tolerate 0 timestamps very well, the
+    /// default is an arbitrary time of February 4 2004, the initial launch of
+    /// thefacebook.com
+    source_date_epoch: u64,
 }
 
 struct Entry {
@@ -91,6 +101,12 @@ fn main() -> Result<()> {
                 header.set_mode(mode);
                 header.set_entry_type(EntryType::Fifo);
             }
+            Operation::Mksock { .. } => {
+                bail!(
+                    "unix sockets cannot be represented in a tar: {}",
+                    path.display()
+                );
+            }
             Operation::Mknod { rdev, mode } => {
                 let header = &mut entries.entry(path).or_default().header;
                 header.set_mode(mode);
@@ -113,7 +129,8 @@ fn main() -> Result<()> {
                 header.set_gid(gid as u64);
             }
             Operation::SetTimes { atime: _, mtime: _ } => {
-                // timestamps make things very non-reproducible
+                // timestamps alone are not worth sending a whole file again
+                // for, and anything that is sent has its mtime clamped below
             }
             Operation::HardLink { target } => {
                 let entry = entries.entry(path).or_default();
@@ -175,17 +192,23 @@ fn main() -> Result<()> {
                 .iter()
                 .map(|(k, v)| (k.as_str(), v.as_slice())),
         )?;
-        // Timestamps make things non-deterministic even if everything else is
-        // 100% equal. To get around this (and to preempty any bugs from tools
-        // that don't tolerate 0 timestamps very well), choose an arbitrary time
-        // of February 4 2004, the initial launch of thefacebook.com
-        entry.header.set_mtime(1075852800);
+        let mtime = match entry.contents {
+            Contents::Whiteout => args.source_date_epoch,
+            _ => {
+                let meta = std::fs::symlink_metadata(args.child.join(&path))?;
+                std::cmp::min(
+                    u64::try_from(meta.mtime()).unwrap_or_default(),
+                    args.source_date_epoch,
+                )
+            }
+        };
+        entry.header.set_mtime(mtime);
         match entry.contents {
             Contents::Link(target) => {
                 builder.append_link(&mut entry.header, path, target)?;
             }
-            Contents::File(mut f) => {
-                builder.append_file(path, &mut f)?;
+            Contents::File(f) => {
+                append_file(&mut builder, path, f, mtime)?;
             }
             Contents::Whiteout => {
                 builder.append_data(&mut entry.header, path, std::io::empty())?;
@@ -196,8 +219,8 @@ fn main() -> Result<()> {
                 // layer.
                 let meta = std::fs::symlink_metadata(args.child.join(&path))?;
                 if meta.is_file() {
-                    let mut f = File::open(args.child.join(&path))?;
-                    builder.append_file(path, &mut f)?;
+                    let f = File::open(args.child.join(&path))?;
+                    append_file(&mut builder, path, f, mtime)?;
                 } else if meta.is_dir() {
                     entry.header.set_entry_type(EntryType::Directory);
                     builder.append_data(&mut entry.header, path, std::io::empty())?;
@@ -205,6 +228,20 @@ fn main() -> Result<()> {
                     entry.header.set_entry_type(EntryType::Symlink);
                     let target = std::fs::read_link(args.child.join(&path))?;
                     builder.append_link(&mut entry.header, path, target)?;
+                } else if meta.file_type().is_block_device() || meta.file_type().is_char_device() {
+                    entry
+                        .header
+                        .set_entry_type(if meta.file_type().is_block_device() {
+                            EntryType::Block
+                        } else {
+                            EntryType::Char
+                        });
+                    entry.header.set_device_major(major(meta.rdev()) as u32)?;
+                    entry.header.set_device_minor(minor(meta.rdev()) as u32)?;
+                    builder.append_data(&mut entry.header, path, std::io::empty())?;
+                } else if meta.file_type().is_fifo() {
+                    entry.header.set_entry_type(EntryType::Fifo);
+                    builder.append_data(&mut entry.header, path, std::io::empty())?;
                 } else {
                     bail!(
                         "not sure what to do with unset contents on filetype {:?}",
@@ -216,3 +253,17 @@ fn main() -> Result<()> {
     }
     Ok(())
 }
+
+/// Like [Builder::append_file], but with a clamped mtime
+fn append_file<W: Write>(
+    builder: &mut Builder<W>,
+    path: PathBuf,
+    f: File,
+    mtime: u64,
+) -> Result<()> {
+    let mut header = Header::new_ustar();
+    header.set_metadata(&f.metadata()?);
+    header.set_mtime(mtime);
+    builder.append_data(&mut header, path, f)?;
+    Ok(())
+}
@@ -5,6 +5,7 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::path::Path;
 use std::path::PathBuf;
@@ -16,15 +17,21 @@ use anyhow::Result;
 use serde::Deserialize;
 use tempfile::NamedTempFile;
 
+use crate::manifest::Manifest;
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Btrfs {
     btrfs_packager_path: Vec<PathBuf>,
     spec: serde_json::Value,
+    /// Manifests of the sendstreams for each subvol (by path), if they were
+    /// built with one
+    #[serde(default)]
+    subvol_manifests: BTreeMap<PathBuf, PathBuf>,
 }
 
 impl Btrfs {
-    pub fn build(&self, out: &Path) -> Result<()> {
+    pub fn build(&self, out: &Path, manifest: Option<&mut Manifest>) -> Result<()> {
         let btrfs_packager_path = self
             .btrfs_packager_path
             .first()
@@ -78,13 +85,19 @@ impl Btrfs {
                 .context("failed to render btrfs-packager stderr")?,
         );
 
-        match output.status.success() {
-            true => Ok(()),
-            false => Err(anyhow!(
+        if !output.status.success() {
+            return Err(anyhow!(
                 "failed to run command {:?}: {:?}",
                 btrfs_package_cmd,
                 output
-            )),
+            ));
+        }
+
+        if let Some(manifest) = manifest {
+            for (path, subvol_manifest) in &self.subvol_manifests {
+                manifest.nest(path, Manifest::read(subvol_manifest)?);
+            }
         }
+        Ok(())
     }
 }
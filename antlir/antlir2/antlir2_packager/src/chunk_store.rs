@@ -0,0 +1,49 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use std::path::Path;
+
+use antlir2_chunk_store::ChunkSizes;
+use antlir2_chunk_store::ChunkWriter;
+use antlir2_chunk_store::INDEX_FILENAME;
+use antlir2_chunk_store::STORE_DIRNAME;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::manifest::Manifest;
+use crate::tar::append_layer;
+use crate::PackageFormat;
+
+/// The layer as a tar stream, split into content-defined chunks so that
+/// consecutive versions of an image can share most of their bytes.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChunkStore {
+    /// Chunks are on average about this many bytes
+    avg_chunk_size: usize,
+    /// zstd level for each chunk
+    compression_level: i32,
+}
+
+impl PackageFormat for ChunkStore {
+    fn build(&self, out: &Path, layer: &Path, manifest: Option<&mut Manifest>) -> Result<()> {
+        let sizes = ChunkSizes::new(self.avg_chunk_size)?;
+        std::fs::create_dir_all(out).context("while creating output dir")?;
+        let store = antlir2_chunk_store::ChunkStore::create(out.join(STORE_DIRNAME))?
+            .compression_level(self.compression_level);
+        let mut builder = tar::Builder::new(ChunkWriter::new(store, sizes));
+        append_layer(&mut builder, layer, false, manifest)?;
+        let index = builder
+            .into_inner()
+            .context("while finishing archive")?
+            .finish()
+            .context("while writing last chunk")?;
+        index.write(&out.join(INDEX_FILENAME))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,493 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! composefs-style images: the contents of every regular file go into a
+//! content-addressed object store (that can be shared by many images), and a
+//! small erofs image holds just the metadata. Regular files in the erofs image
+//! are sparse and carry overlayfs metacopy+redirect xattrs that point at their
+//! object, so mounting the erofs image as an overlayfs lower dir with the
+//! object store as a data-only lower dir gives back the original layer.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::fs::Permissions;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use antlir2_isolate::unshare;
+use antlir2_isolate::IsolationContext;
+use anyhow::Context;
+use anyhow::Result;
+use nix::sys::stat::mknod;
+use nix::sys::stat::utimensat;
+use nix::sys::stat::Mode;
+use nix::sys::stat::SFlag;
+use nix::sys::stat::UtimensatFlags;
+use nix::sys::time::TimeSpec;
+use nix::unistd::mkfifo;
+use serde::Deserialize;
+use sha2::Digest as _;
+use sha2::Sha256;
+
+use crate::manifest::Manifest;
+use crate::reproducible;
+use crate::reproducible::Digest;
+use crate::run_cmd;
+use crate::BuildAppliance;
+use crate::PackageFormat;
+
+static OBJECTS_DIRNAME: &str = "objects";
+static IMAGE_FILENAME: &str = "image.erofs";
+
+static OVERLAY_XATTR_PREFIX: &str = "trusted.overlay.";
+static OVERLAY_METACOPY: &str = "trusted.overlay.metacopy";
+static OVERLAY_REDIRECT: &str = "trusted.overlay.redirect";
+
+/// fs-verity block size (and Merkle tree block size), which must be the page
+/// size for the kernel to be able to enable verity on the objects
+const FSVERITY_BLOCK_SIZE: usize = 4096;
+/// FS_VERITY_HASH_ALG_SHA256
+const FSVERITY_HASH_ALG_SHA256: u8 = 1;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Composefs {
+    build_appliance: BuildAppliance,
+    label: Option<String>,
+    object_digest: ObjectDigest,
+}
+
+/// How objects are named in the store
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ObjectDigest {
+    /// Plain sha256 of the file contents
+    Sha256,
+    /// fs-verity (sha256, 4k blocks, no salt) digest of the file contents,
+    /// which is also recorded in the metacopy xattr so that overlayfs can
+    /// enforce it with `verity=require`
+    Fsverity,
+}
+
+impl PackageFormat for Composefs {
+    fn build(&self, out: &Path, layer: &Path, manifest: Option<&mut Manifest>) -> Result<()> {
+        std::fs::create_dir_all(out.join(OBJECTS_DIRNAME))
+            .context("while creating object store")?;
+        // the metadata-only copy of the layer that is turned into the erofs
+        // image (regular files are all sparse, so this is cheap)
+        let shadow = tempfile::Builder::new()
+            .prefix(".shadow")
+            .tempdir_in(out)
+            .context("while creating shadow dir")?;
+
+        let mut hardlinks: HashMap<(u64, u64), PathBuf> = HashMap::new();
+        let mut dir_times: Vec<(PathBuf, TimeSpec, TimeSpec)> = Vec::new();
+        let digest = Digest::of_tree_with(layer, manifest, |relpath, meta| {
+            let src = layer.join(relpath);
+            let dst = shadow.path().join(relpath);
+            let ft = meta.file_type();
+            if ft.is_file() && meta.nlink() > 1 {
+                if let Some(first) = hardlinks.get(&(meta.dev(), meta.ino())) {
+                    return std::fs::hard_link(first, &dst)
+                        .with_context(|| format!("while hardlinking {}", dst.display()));
+                }
+                hardlinks.insert((meta.dev(), meta.ino()), dst.clone());
+            }
+            let mode = Mode::from_bits_truncate(meta.mode());
+            if relpath == Path::new("") {
+                // the root is the shadow dir itself
+            } else if ft.is_dir() {
+                std::fs::create_dir(&dst)
+                    .with_context(|| format!("while creating directory {}", dst.display()))?;
+            } else if ft.is_symlink() {
+                let target = std::fs::read_link(&src)
+                    .with_context(|| format!("while reading link {}", src.display()))?;
+                std::os::unix::fs::symlink(target, &dst)
+                    .with_context(|| format!("while creating symlink {}", dst.display()))?;
+            } else if ft.is_file() {
+                File::create(&dst)
+                    .and_then(|f| f.set_len(meta.len()))
+                    .with_context(|| format!("while creating sparse file {}", dst.display()))?;
+                // empty files are left inline in the image, there's nothing to
+                // redirect to
+                if meta.len() > 0 {
+                    let object = self
+                        .store_object(&out.join(OBJECTS_DIRNAME), &src)
+                        .with_context(|| format!("while storing {}", src.display()))?;
+                    xattr::set(&dst, OVERLAY_METACOPY, &object.metacopy())
+                        .and_then(|_| {
+                            xattr::set(&dst, OVERLAY_REDIRECT, object.redirect().as_bytes())
+                        })
+                        .with_context(|| {
+                            format!("while setting overlay xattrs on {}", dst.display())
+                        })?;
+                }
+            } else if ft.is_fifo() {
+                mkfifo(&dst, mode)
+                    .with_context(|| format!("while making fifo {}", dst.display()))?;
+            } else {
+                let kind = if ft.is_block_device() {
+                    SFlag::S_IFBLK
+                } else if ft.is_char_device() {
+                    SFlag::S_IFCHR
+                } else {
+                    SFlag::S_IFSOCK
+                };
+                mknod(&dst, kind, mode, meta.rdev())
+                    .with_context(|| format!("while making node {}", dst.display()))?;
+            }
+            for name in xattr::list(&src)
+                .with_context(|| format!("while listing xattrs on {}", src.display()))?
+            {
+                let value = xattr::get(&src, &name)
+                    .with_context(|| format!("while reading xattrs on {}", src.display()))?
+                    .unwrap_or_default();
+                xattr::set(&dst, escape_xattr(&name.to_string_lossy()), &value)
+                    .with_context(|| format!("while setting xattrs on {}", dst.display()))?;
+            }
+            // chown before chmod, since chown clears setuid/setgid
+            std::os::unix::fs::lchown(&dst, Some(meta.uid()), Some(meta.gid()))
+                .with_context(|| format!("while chowning {}", dst.display()))?;
+            if !ft.is_symlink() {
+                std::fs::set_permissions(&dst, Permissions::from_mode(meta.mode() & 0o7777))
+                    .with_context(|| format!("while chmodding {}", dst.display()))?;
+            }
+            let atime = TimeSpec::new(meta.atime(), meta.atime_nsec());
+            let mtime = TimeSpec::new(meta.mtime(), meta.mtime_nsec());
+            if ft.is_dir() {
+                // creating the children would change the times again, so
+                // directories are done after everything else
+                dir_times.push((dst, atime, mtime));
+            } else {
+                set_times(&dst, &atime, &mtime)?;
+            }
+            Ok(())
+        })
+        .context("while building object store")?;
+        // children come after their parent in the walk, so set them first
+        for (dst, atime, mtime) in dir_times.into_iter().rev() {
+            set_times(&dst, &atime, &mtime)?;
+        }
+
+        let image = out.join(IMAGE_FILENAME);
+        File::create(&image).context("failed to create image file")?;
+        let epoch = reproducible::source_date_epoch()?.to_string();
+
+        let isol_context = IsolationContext::builder(self.build_appliance.path())
+            .ephemeral(false)
+            .readonly()
+            .tmpfs(Path::new("/__antlir2__/out"))
+            .outputs(("/__antlir2__/out/erofs", image.as_path()))
+            .inputs((Path::new("/__antlir2__/root"), shadow.path()))
+            // without -T, mkfs.erofs uses SOURCE_DATE_EPOCH as the build time
+            // and clamps every file's timestamp to it (-T would instead set
+            // all of them)
+            .setenv(("SOURCE_DATE_EPOCH", epoch.as_str()))
+            .inputs((
+                PathBuf::from("/__antlir2__/working_directory"),
+                std::env::current_dir()?,
+            ))
+            .working_directory(Path::new("/__antlir2__/working_directory"))
+            .build();
+
+        let mut cmd = unshare(isol_context)?.command("mkfs.erofs")?;
+        cmd.arg("/__antlir2__/out/erofs").arg("/__antlir2__/root");
+        cmd.arg("-U").arg(digest.uuid("composefs").to_string());
+        if let Some(label) = &self.label {
+            cmd.arg("-L").arg(label);
+        }
+
+        run_cmd(&mut cmd).context("while running mkfs.erofs")?;
+
+        Ok(())
+    }
+}
+
+impl Composefs {
+    /// Copy the contents of `src` into the object store (if it's not already
+    /// there)
+    fn store_object(&self, objects: &Path, src: &Path) -> Result<Object> {
+        let mut f = File::open(src).context("while opening file")?;
+        let mut tmp =
+            tempfile::NamedTempFile::new_in(objects).context("while creating temporary object")?;
+        let mut hasher = ObjectHasher::new(self.object_digest);
+        let mut buf = vec![0; 1024 * 1024];
+        loop {
+            let len = f.read(&mut buf).context("while reading file")?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&buf[..len]);
+            tmp.write_all(&buf[..len])
+                .context("while writing temporary object")?;
+        }
+        let object = Object {
+            kind: self.object_digest,
+            digest: hasher.finalize(),
+        };
+        let path = objects.join(object.relpath());
+        if !path.exists() {
+            std::fs::create_dir_all(path.parent().expect("always has a parent"))
+                .context("while creating object dir")?;
+            tmp.as_file()
+                .set_permissions(Permissions::from_mode(0o644))
+                .context("while setting object permissions")?;
+            tmp.persist(&path)
+                .with_context(|| format!("while persisting object {}", path.display()))?;
+        }
+        Ok(object)
+    }
+}
+
+fn set_times(path: &Path, atime: &TimeSpec, mtime: &TimeSpec) -> Result<()> {
+    utimensat(None, path, atime, mtime, UtimensatFlags::NoFollowSymlink)
+        .with_context(|| format!("while setting times on {}", path.display()))
+}
+
+/// Files in the layer can have their own overlay xattrs (for example if the
+/// layer contains an overlayfs upper dir), so they must be escaped to not be
+/// interpreted by the overlay that mounts the composefs image.
+fn escape_xattr(name: &str) -> String {
+    match name.strip_prefix(OVERLAY_XATTR_PREFIX) {
+        Some(rest) => format!("{OVERLAY_XATTR_PREFIX}overlay.{rest}"),
+        None => name.to_owned(),
+    }
+}
+
+struct Object {
+    kind: ObjectDigest,
+    digest: [u8; 32],
+}
+
+impl Object {
+    /// Path of this object relative to the root of the object store
+    fn relpath(&self) -> PathBuf {
+        let hex = hex::encode(self.digest);
+        Path::new(&hex[..2]).join(&hex[2..])
+    }
+
+    /// overlayfs looks up redirects relative to the root of the data-only
+    /// lower dir
+    fn redirect(&self) -> String {
+        Path::new("/")
+            .join(self.relpath())
+            .to_str()
+            .expect("always utf8")
+            .to_owned()
+    }
+
+    /// `struct ovl_metacopy`: with an fs-verity digest, overlayfs can check
+    /// that the object is what the image expects
+    fn metacopy(&self) -> Vec<u8> {
+        match self.kind {
+            ObjectDigest::Sha256 => Vec::new(),
+            ObjectDigest::Fsverity => {
+                let mut v = vec![
+                    0, // version
+                    4 + self.digest.len() as u8,
+                    0, // flags
+                    FSVERITY_HASH_ALG_SHA256,
+                ];
+                v.extend_from_slice(&self.digest);
+                v
+            }
+        }
+    }
+}
+
+enum ObjectHasher {
+    Sha256(Sha256),
+    Fsverity(FsverityHasher),
+}
+
+impl ObjectHasher {
+    fn new(kind: ObjectDigest) -> Self {
+        match kind {
+            ObjectDigest::Sha256 => Self::Sha256(Sha256::new()),
+            ObjectDigest::Fsverity => Self::Fsverity(FsverityHasher::default()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(h) => h.update(data),
+            Self::Fsverity(h) => h.update(data),
+        }
+    }
+
+    fn finalize(self) -> [u8; 32] {
+        match self {
+            Self::Sha256(h) => h.finalize().into(),
+            Self::Fsverity(h) => h.finalize(),
+        }
+    }
+}
+
+/// Streaming computation of the fs-verity file digest, without ever holding
+/// more than one block per Merkle tree level in memory.
+#[derive(Default)]
+struct FsverityHasher {
+    size: u64,
+    block: Vec<u8>,
+    levels: Vec<MerkleLevel>,
+}
+
+#[derive(Default)]
+struct MerkleLevel {
+    /// Hashes that have not yet filled a whole block
+    block: Vec<u8>,
+    /// Total number of hashes that have been added to this level
+    count: u64,
+}
+
+fn hash_block(block: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(block);
+    hasher.update(&[0; FSVERITY_BLOCK_SIZE][block.len()..]);
+    hasher.finalize().into()
+}
+
+impl FsverityHasher {
+    fn update(&mut self, mut data: &[u8]) {
+        self.size += data.len() as u64;
+        while !data.is_empty() {
+            let take = (FSVERITY_BLOCK_SIZE - self.block.len()).min(data.len());
+            self.block.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.block.len() == FSVERITY_BLOCK_SIZE {
+                let hash = hash_block(&self.block);
+                self.block.clear();
+                self.push_hash(0, hash);
+            }
+        }
+    }
+
+    fn push_hash(&mut self, level: usize, hash: [u8; 32]) {
+        if self.levels.len() == level {
+            self.levels.push(MerkleLevel::default());
+        }
+        let l = &mut self.levels[level];
+        l.block.extend_from_slice(&hash);
+        l.count += 1;
+        if l.block.len() == FSVERITY_BLOCK_SIZE {
+            let hash = hash_block(&l.block);
+            l.block.clear();
+            self.push_hash(level + 1, hash);
+        }
+    }
+
+    fn root_hash(&mut self) -> [u8; 32] {
+        if !self.block.is_empty() {
+            let hash = hash_block(&self.block);
+            self.block.clear();
+            self.push_hash(0, hash);
+        }
+        let mut level = 0;
+        loop {
+            match self.levels.get_mut(level) {
+                // empty file
+                None => return [0; 32],
+                // the first level with a single hash is the root
+                Some(l) if l.count == 1 => {
+                    return l.block[..32].try_into().expect("exactly one hash");
+                }
+                Some(l) => {
+                    if !l.block.is_empty() {
+                        let hash = hash_block(&l.block);
+                        l.block.clear();
+                        self.push_hash(level + 1, hash);
+                    }
+                    level += 1;
+                }
+            }
+        }
+    }
+
+    /// Digest of the `struct fsverity_descriptor`
+    fn finalize(mut self) -> [u8; 32] {
+        let root_hash = self.root_hash();
+        let mut descriptor = [0u8; 256];
+        descriptor[0] = 1; // version
+        descriptor[1] = FSVERITY_HASH_ALG_SHA256;
+        descriptor[2] = FSVERITY_BLOCK_SIZE.trailing_zeros() as u8;
+        // salt_size and reserved are all 0
+        descriptor[8..16].copy_from_slice(&self.size.to_le_bytes());
+        descriptor[16..48].copy_from_slice(&root_hash);
+        // the rest of root_hash, salt and reserved are all 0
+        Sha256::digest(descriptor).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fsverity(data: &[u8]) -> String {
+        let mut hasher = FsverityHasher::default();
+        // feed it in awkward pieces to exercise the buffering
+        for chunk in data.chunks(1000) {
+            hasher.update(chunk);
+        }
+        hex::encode(hasher.finalize())
+    }
+
+    #[test]
+    fn fsverity_digest() {
+        // same as `fsverity digest` reports
+        assert_eq!(
+            "3d248ca542a24fc62d1c43b916eae5016878e2533c88238480b26128a1f1af95",
+            fsverity(b""),
+        );
+        assert_eq!(
+            "9c76eecc7b76fcb46199cb27b90cf59a660e10575bb0412128905129d5b1c2aa",
+            fsverity(b"hello\n"),
+        );
+        // multiple blocks, one level of tree
+        let data: Vec<u8> = (0..=255).cycle().take(25600).collect();
+        assert_eq!(
+            "acc2aa580879ed3fe7913c61a51217899772b0ab1cbcad458b6f4e06ab955ebd",
+            fsverity(&data),
+        );
+        // just more blocks than fit in one level of tree
+        assert_eq!(
+            "5e017d1b2874a358b38508e61f7a1bca2021923c50220632385d6ae92c11a1fe",
+            fsverity(&vec![0xab; FSVERITY_BLOCK_SIZE * 129 + 7]),
+        );
+    }
+
+    #[test]
+    fn object_xattrs() {
+        let object = Object {
+            kind: ObjectDigest::Fsverity,
+            digest: [0xab; 32],
+        };
+        assert_eq!(format!("/ab/{}", "ab".repeat(31)), object.redirect(),);
+        let metacopy = object.metacopy();
+        assert_eq!([0, 36, 0, 1], metacopy[..4]);
+        assert_eq!(object.digest, metacopy[4..]);
+        assert!(Object {
+            kind: ObjectDigest::Sha256,
+            digest: [0xab; 32],
+        }
+        .metacopy()
+        .is_empty());
+    }
+
+    #[test]
+    fn escapes_overlay_xattrs() {
+        assert_eq!("user.foo", escape_xattr("user.foo"));
+        assert_eq!(
+            "trusted.overlay.overlay.opaque",
+            escape_xattr("trusted.overlay.opaque")
+        );
+    }
+}
@@ -0,0 +1,134 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use std::io::Result;
+use std::io::Write;
+use std::num::NonZeroUsize;
+
+use flate2::write::GzEncoder;
+use flate2::GzBuilder;
+use serde::Deserialize;
+use xz2::stream::Check;
+use xz2::stream::MtStreamBuilder;
+use xz2::write::XzEncoder;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    fn default_level(self) -> u32 {
+        match self {
+            Self::None => 0,
+            Self::Gzip => 3,
+            Self::Zstd => 15,
+            Self::Xz => 6,
+        }
+    }
+
+    /// Conventional file extension for this compression algorithm
+    pub(crate) fn extension(self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Gzip => Some("gz"),
+            Self::Zstd => Some("zst"),
+            Self::Xz => Some("xz"),
+        }
+    }
+
+    /// Wrap a writer so that everything written to it is compressed.
+    ///
+    /// If `level` is unset, a reasonable default is chosen for each algorithm.
+    /// `threads` only applies to zstd and xz, and defaults to the number of
+    /// available cpus.
+    ///
+    /// The compressed stream is byte-for-byte reproducible regardless of the
+    /// number of threads used or the machine it is produced on. gzip headers
+    /// never include a filename or mtime, and zstd and xz always use their
+    /// multi-threaded encoders (even with only 1 thread), since those split
+    /// the input into fixed-size jobs/blocks that do not depend on the thread
+    /// count, unlike the single-threaded encoders.
+    pub(crate) fn compressor<W: Write>(
+        self,
+        w: W,
+        level: Option<u32>,
+        threads: Option<NonZeroUsize>,
+    ) -> Result<Compressor<W>> {
+        let level = level.unwrap_or_else(|| self.default_level());
+        let threads = threads
+            .or_else(|| std::thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get);
+        Ok(match self {
+            Compression::None => Compressor::None(w),
+            Compression::Gzip => Compressor::Gzip(
+                GzBuilder::new()
+                    .mtime(0)
+                    .write(w, flate2::Compression::new(level)),
+            ),
+            Compression::Zstd => {
+                let mut enc = zstd::Encoder::new(w, level as i32)?;
+                enc.multithread(threads as u32)?;
+                enc.include_checksum(true)?;
+                Compressor::Zstd(enc)
+            }
+            Compression::Xz => {
+                let stream = MtStreamBuilder::new()
+                    .preset(level)
+                    .threads(threads as u32)
+                    .check(Check::Crc64)
+                    .encoder()?;
+                Compressor::Xz(XzEncoder::new_stream(w, stream))
+            }
+        })
+    }
+}
+
+pub(crate) enum Compressor<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Xz(XzEncoder<W>),
+}
+
+impl<W: Write> Compressor<W> {
+    /// Write out any remaining compressed data and trailers, returning the
+    /// underlying writer.
+    pub(crate) fn finish(self) -> Result<W> {
+        match self {
+            Self::None(w) => Ok(w),
+            Self::Gzip(w) => w.finish(),
+            Self::Zstd(w) => w.finish(),
+            Self::Xz(w) => w.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Compressor<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            Self::None(w) => w.write(buf),
+            Self::Gzip(w) => w.write(buf),
+            Self::Zstd(w) => w.write(buf),
+            Self::Xz(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Self::None(w) => w.flush(),
+            Self::Gzip(w) => w.flush(),
+            Self::Zstd(w) => w.flush(),
+            Self::Xz(w) => w.flush(),
+        }
+    }
+}
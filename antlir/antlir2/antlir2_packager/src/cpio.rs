@@ -5,60 +5,504 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::fs::File;
+use std::fs::Metadata;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::path::PathBuf;
-use std::process::Stdio;
 
-use antlir2_isolate::unshare;
-use antlir2_isolate::IsolationContext;
+use anyhow::ensure;
 use anyhow::Context;
 use anyhow::Result;
+use nix::sys::stat::major;
+use nix::sys::stat::minor;
 use serde::Deserialize;
+use walkdir::WalkDir;
 
-use crate::run_cmd;
-use crate::BuildAppliance;
+use crate::compression::Compression;
+use crate::manifest::HashingReader;
+use crate::manifest::Manifest;
+use crate::reproducible;
 use crate::PackageFormat;
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Cpio {
-    build_appliance: BuildAppliance,
+    #[serde(default)]
+    compression: Compression,
+    #[serde(default)]
+    compression_level: Option<u32>,
+    #[serde(default)]
+    compression_threads: Option<NonZeroUsize>,
+    /// Microcode files (or directories of them) in the layer for each cpu
+    /// vendor, to put into an uncompressed archive ahead of the main (possibly
+    /// compressed) one, which is the only place the kernel looks for it that
+    /// early in boot
+    #[serde(default)]
+    early_microcode: BTreeMap<MicrocodeVendor, Vec<PathBuf>>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+pub enum MicrocodeVendor {
+    GenuineIntel,
+    AuthenticAMD,
+}
+
+impl MicrocodeVendor {
+    /// Path that the kernel's early microcode loader looks for
+    fn path(self) -> &'static str {
+        match self {
+            Self::GenuineIntel => "kernel/x86/microcode/GenuineIntel.bin",
+            Self::AuthenticAMD => "kernel/x86/microcode/AuthenticAMD.bin",
+        }
+    }
 }
 
 impl PackageFormat for Cpio {
-    fn build(&self, out: &Path, layer: &Path) -> Result<()> {
-        File::create(out).context("failed to create output file")?;
-
-        let isol_context = IsolationContext::builder(self.build_appliance.path())
-            .ephemeral(false)
-            .readonly()
-            .tmpfs(Path::new("/__antlir2__/out"))
-            .outputs(("/__antlir2__/out/cpio", out))
-            .inputs((Path::new("/__antlir2__/root"), layer))
-            .inputs((
-                PathBuf::from("/__antlir2__/working_directory"),
-                std::env::current_dir()?,
-            ))
-            .working_directory(Path::new("/__antlir2__/working_directory"))
-            .build();
-
-        let cpio_script = "set -ue -o pipefail; \
-            pushd /__antlir2__/root; \
-            /usr/bin/find . -mindepth 1 ! -type s | \
-            LANG=C /usr/bin/sort | \
-            LANG=C /usr/bin/cpio -o -H newc \
-            > /__antlir2__/out/cpio";
-
-        run_cmd(
-            unshare(isol_context)?
-                .command("/bin/bash")?
-                .arg("-c")
-                .arg(cpio_script)
-                .stdout(Stdio::piped()),
-        )
-        .context("Failed to build cpio archive")?;
+    fn build(&self, out: &Path, layer: &Path, mut manifest: Option<&mut Manifest>) -> Result<()> {
+        let source_date_epoch = reproducible::source_date_epoch()?;
+        let mut out = BufWriter::new(File::create(out).context("while creating output file")?);
+        if !self.early_microcode.is_empty() {
+            write_early_microcode(
+                &mut out,
+                layer,
+                &self.early_microcode,
+                source_date_epoch,
+                manifest.as_deref_mut(),
+            )
+            .context("while writing early microcode archive")?;
+        }
+        let mut archive = NewcWriter::new(
+            self.compression
+                .compressor(out, self.compression_level, self.compression_threads)
+                .context("while setting up compression")?,
+        );
+        append_layer(&mut archive, layer, source_date_epoch, manifest)?;
+        archive
+            .finish()
+            .context("while finishing archive")?
+            .finish()
+            .context("while finishing compression")?
+            .flush()
+            .context("while flushing output")?;
+        Ok(())
+    }
+}
+
+/// Size of a newc header, which is a 6 byte magic number followed by 13
+/// 8-character hex fields
+const NEWC_HEADER_LEN: u64 = 110;
+const NEWC_MAGIC: &[u8] = b"070701";
+const NEWC_TRAILER: &[u8] = b"TRAILER!!!";
+/// Archives are padded to a multiple of this (like GNU cpio does), which also
+/// keeps anything concatenated after an archive nicely aligned
+const BLOCK_SIZE: u64 = 512;
+
+/// The parts of a newc header that vary between entries (dev major/minor and
+/// the checksum are always 0)
+#[derive(Debug, Default, Clone, Copy)]
+struct Header {
+    ino: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u64,
+    mtime: u64,
+    filesize: u64,
+    rdev: u64,
+}
+
+struct NewcWriter<W: Write> {
+    inner: W,
+    offset: u64,
+}
 
+impl<W: Write> NewcWriter<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, offset: 0 }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.inner.write_all(buf)?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+
+    fn pad(&mut self, to: u64) -> std::io::Result<()> {
+        let padding = self.offset.next_multiple_of(to) - self.offset;
+        self.write(&vec![0; padding as usize])
+    }
+
+    /// Add an entry, with exactly `header.filesize` bytes from `data`
+    fn append(&mut self, name: &[u8], header: Header, data: impl Read) -> Result<()> {
+        let fields = [
+            header.ino,
+            header.mode.into(),
+            header.uid.into(),
+            header.gid.into(),
+            header.nlink,
+            header.mtime,
+            header.filesize,
+            0,
+            0,
+            major(header.rdev),
+            minor(header.rdev),
+            name.len() as u64 + 1,
+            0,
+        ];
+        let mut buf = Vec::with_capacity(NEWC_HEADER_LEN as usize + name.len() + 1);
+        buf.extend_from_slice(NEWC_MAGIC);
+        for field in fields {
+            ensure!(
+                field <= u32::MAX.into(),
+                "{field} is too large for a newc header"
+            );
+            write!(buf, "{field:08x}").expect("infallible");
+        }
+        buf.extend_from_slice(name);
+        buf.push(0);
+        self.write(&buf)?;
+        self.pad(4)?;
+        let copied = std::io::copy(&mut data.take(header.filesize), &mut self.inner)?;
+        ensure!(
+            copied == header.filesize,
+            "expected {} bytes of data but only got {copied}",
+            header.filesize
+        );
+        self.offset += copied;
+        self.pad(4)?;
         Ok(())
     }
+
+    /// Write the trailer and pad the archive out to a whole block
+    fn finish(mut self) -> Result<W> {
+        self.append(
+            NEWC_TRAILER,
+            Header {
+                nlink: 1,
+                ..Default::default()
+            },
+            std::io::empty(),
+        )?;
+        self.pad(BLOCK_SIZE)?;
+        Ok(self.inner)
+    }
+}
+
+fn header(meta: &Metadata, source_date_epoch: u64) -> Header {
+    Header {
+        ino: 0,
+        mode: meta.mode(),
+        uid: meta.uid(),
+        gid: meta.gid(),
+        nlink: 1,
+        mtime: meta.mtime().clamp(0, source_date_epoch as i64) as u64,
+        filesize: 0,
+        rdev: if meta.file_type().is_block_device() || meta.file_type().is_char_device() {
+            meta.rdev()
+        } else {
+            0
+        },
+    }
+}
+
+/// Add everything in the layer to the archive, sorted by name so that it's
+/// reproducible.
+///
+/// Hardlinks share an inode number and (like GNU cpio does) only the last
+/// link carries the file data. Sockets can't be meaningfully archived, so they
+/// are skipped.
+fn append_layer<W: Write>(
+    archive: &mut NewcWriter<W>,
+    layer: &Path,
+    source_date_epoch: u64,
+    mut manifest: Option<&mut Manifest>,
+) -> Result<()> {
+    let mut entries = Vec::new();
+    for entry in WalkDir::new(layer).min_depth(1).sort_by_file_name() {
+        let entry = entry.with_context(|| format!("while walking {}", layer.display()))?;
+        let meta = entry.metadata()?;
+        if meta.file_type().is_socket() {
+            continue;
+        }
+        entries.push((entry.into_path(), meta));
+    }
+
+    // inode numbers are assigned in archive order so that they don't leak
+    // anything about the filesystem the layer was on
+    let mut inodes: HashMap<(u64, u64), (u64, u64)> = HashMap::new();
+    for (_, meta) in &entries {
+        let next_ino = inodes.len() as u64 + 1;
+        inodes
+            .entry((meta.dev(), meta.ino()))
+            .or_insert((next_ino, 0))
+            .1 += 1;
+    }
+    let mut links_seen: HashMap<(u64, u64), u64> = HashMap::new();
+
+    for (path, meta) in &entries {
+        let relpath = path.strip_prefix(layer)?;
+        let name = relpath.as_os_str().as_bytes();
+        let (ino, nlink) = inodes[&(meta.dev(), meta.ino())];
+        let mut header = Header {
+            ino,
+            nlink,
+            ..header(meta, source_date_epoch)
+        };
+        let ft = meta.file_type();
+        if ft.is_file() {
+            let seen = links_seen.entry((meta.dev(), meta.ino())).or_default();
+            *seen += 1;
+            if *seen == nlink {
+                header.filesize = meta.len();
+                let f = File::open(path)
+                    .with_context(|| format!("while opening {}", path.display()))?;
+                let mut data = HashingReader::new(BufReader::new(f), manifest.is_some());
+                archive
+                    .append(name, header, &mut data)
+                    .with_context(|| format!("while adding {}", relpath.display()))?;
+                if let Some(manifest) = manifest.as_deref_mut() {
+                    manifest.add(layer, relpath, meta, data.into_hasher())?;
+                }
+                continue;
+            }
+        } else if ft.is_symlink() {
+            let target = std::fs::read_link(path)
+                .with_context(|| format!("while reading link {}", path.display()))?;
+            let target = target.as_os_str().as_bytes();
+            header.filesize = target.len() as u64;
+            archive
+                .append(name, header, target)
+                .with_context(|| format!("while adding {}", relpath.display()))?;
+            if let Some(manifest) = manifest.as_deref_mut() {
+                manifest.add(layer, relpath, meta, None)?;
+            }
+            continue;
+        }
+        archive
+            .append(name, header, std::io::empty())
+            .with_context(|| format!("while adding {}", relpath.display()))?;
+        if let Some(manifest) = manifest.as_deref_mut() {
+            manifest.add(layer, relpath, meta, None)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write an uncompressed archive of just the microcode for each vendor
+/// (concatenated together if there are multiple files)
+fn write_early_microcode<W: Write>(
+    out: W,
+    layer: &Path,
+    microcode: &BTreeMap<MicrocodeVendor, Vec<PathBuf>>,
+    source_date_epoch: u64,
+    mut manifest: Option<&mut Manifest>,
+) -> Result<()> {
+    let mut archive = NewcWriter::new(out);
+    let mut ino = 0;
+    let dir = |ino| Header {
+        ino,
+        mode: 0o40755,
+        nlink: 2,
+        mtime: source_date_epoch,
+        ..Default::default()
+    };
+    for name in ["kernel", "kernel/x86", "kernel/x86/microcode"] {
+        ino += 1;
+        archive.append(name.as_bytes(), dir(ino), std::io::empty())?;
+    }
+    for (vendor, sources) in microcode {
+        let mut files = Vec::new();
+        for src in sources {
+            let src = layer.join(src.strip_prefix("/").unwrap_or(src));
+            let meta = std::fs::metadata(&src)
+                .with_context(|| format!("while statting {}", src.display()))?;
+            if meta.is_dir() {
+                let mut children = std::fs::read_dir(&src)
+                    .with_context(|| format!("while listing {}", src.display()))?
+                    .map(|e| e.map(|e| e.path()))
+                    .collect::<std::io::Result<Vec<_>>>()
+                    .with_context(|| format!("while listing {}", src.display()))?;
+                children.sort();
+                files.extend(children.into_iter().filter(|p| p.is_file()));
+            } else {
+                files.push(src);
+            }
+        }
+        let mut filesize = 0;
+        let mut readers: Box<dyn Read> = Box::new(std::io::empty());
+        for file in files {
+            filesize += std::fs::metadata(&file)
+                .with_context(|| format!("while statting {}", file.display()))?
+                .len();
+            let f =
+                File::open(&file).with_context(|| format!("while opening {}", file.display()))?;
+            readers = Box::new(readers.chain(BufReader::new(f)));
+        }
+        ino += 1;
+        let mut readers = HashingReader::new(readers, manifest.is_some());
+        archive
+            .append(
+                vendor.path().as_bytes(),
+                Header {
+                    ino,
+                    mode: 0o100644,
+                    nlink: 1,
+                    mtime: source_date_epoch,
+                    filesize,
+                    ..Default::default()
+                },
+                &mut readers,
+            )
+            .with_context(|| format!("while adding {vendor:?} microcode"))?;
+        if let (Some(manifest), Some(sha256)) = (manifest.as_deref_mut(), readers.into_hasher()) {
+            manifest.add_generated(Path::new(vendor.path()), filesize, sha256);
+        }
+    }
+    archive.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    /// Read a field of a newc header, where `idx` is the index after the magic
+    fn newc_field(header: &[u8], idx: usize) -> u64 {
+        let start = NEWC_MAGIC.len() + idx * 8;
+        let field = std::str::from_utf8(&header[start..start + 8]).expect("header is not ascii");
+        u64::from_str_radix(field, 16).expect("invalid header field")
+    }
+
+    #[derive(Debug)]
+    struct Parsed {
+        name: String,
+        ino: u64,
+        mode: u64,
+        nlink: u64,
+        mtime: u64,
+        data: Vec<u8>,
+    }
+
+    /// Parse one archive, returning its entries (excluding the trailer) and
+    /// the length of the archive including padding
+    fn parse(archive: &[u8]) -> (Vec<Parsed>, usize) {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        loop {
+            let header = &archive[offset..offset + NEWC_HEADER_LEN as usize];
+            assert!(header.starts_with(NEWC_MAGIC), "bad magic at {offset}");
+            let filesize = newc_field(header, 6) as usize;
+            let namesize = newc_field(header, 11) as usize;
+            let name_start = offset + NEWC_HEADER_LEN as usize;
+            let name = std::str::from_utf8(&archive[name_start..name_start + namesize - 1])
+                .expect("name is not utf8")
+                .to_owned();
+            let data_start = (name_start + namesize).next_multiple_of(4);
+            let data = archive[data_start..data_start + filesize].to_vec();
+            offset = (data_start + filesize).next_multiple_of(4);
+            if name.as_bytes() == NEWC_TRAILER {
+                return (entries, offset.next_multiple_of(BLOCK_SIZE as usize));
+            }
+            entries.push(Parsed {
+                name,
+                ino: newc_field(header, 0),
+                mode: newc_field(header, 1),
+                nlink: newc_field(header, 4),
+                mtime: newc_field(header, 5),
+                data,
+            });
+        }
+    }
+
+    #[test]
+    fn archives_layer() {
+        let layer = tempfile::tempdir().expect("failed to create tempdir");
+        std::fs::create_dir(layer.path().join("dir")).expect("failed to create dir");
+        std::fs::write(layer.path().join("dir/a"), "hello\n").expect("failed to write file");
+        std::fs::set_permissions(
+            layer.path().join("dir/a"),
+            std::fs::Permissions::from_mode(0o4755),
+        )
+        .expect("failed to chmod");
+        std::fs::hard_link(layer.path().join("dir/a"), layer.path().join("z"))
+            .expect("failed to hardlink");
+        std::os::unix::fs::symlink("dir/a", layer.path().join("link")).expect("failed to symlink");
+
+        let mut archive = NewcWriter::new(Vec::new());
+        append_layer(&mut archive, layer.path(), 1000, None).expect("failed to archive layer");
+        let archive = archive.finish().expect("failed to finish");
+        assert_eq!(0, archive.len() % BLOCK_SIZE as usize);
+
+        let (entries, len) = parse(&archive);
+        assert_eq!(archive.len(), len);
+        let names: Vec<_> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["dir", "dir/a", "link", "z"]);
+        assert!(entries.iter().all(|e| e.mtime == 1000));
+        // hardlinks share an inode and only the last one has the data
+        let (a, z) = (&entries[1], &entries[3]);
+        assert_eq!(a.ino, z.ino);
+        assert_eq!((2, 2), (a.nlink, z.nlink));
+        assert_eq!(0o104755, a.mode);
+        assert!(a.data.is_empty());
+        assert_eq!(b"hello\n", z.data.as_slice());
+        assert_eq!(b"dir/a", entries[2].data.as_slice());
+        assert_eq!(0o120777, entries[2].mode);
+    }
+
+    #[test]
+    fn early_microcode() {
+        let layer = tempfile::tempdir().expect("failed to create tempdir");
+        std::fs::create_dir(layer.path().join("intel-ucode")).expect("failed to create dir");
+        std::fs::write(layer.path().join("intel-ucode/06-02"), "second")
+            .expect("failed to write file");
+        std::fs::write(layer.path().join("intel-ucode/06-01"), "first")
+            .expect("failed to write file");
+        std::fs::write(layer.path().join("amd.bin"), "amd").expect("failed to write file");
+
+        let mut out = Vec::new();
+        write_early_microcode(
+            &mut out,
+            layer.path(),
+            &BTreeMap::from([
+                (
+                    MicrocodeVendor::GenuineIntel,
+                    vec![PathBuf::from("/intel-ucode")],
+                ),
+                (
+                    MicrocodeVendor::AuthenticAMD,
+                    vec![PathBuf::from("amd.bin")],
+                ),
+            ]),
+            1000,
+            None,
+        )
+        .expect("failed to write microcode");
+        let (entries, len) = parse(&out);
+        assert_eq!(out.len(), len);
+        let names: Vec<_> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "kernel",
+                "kernel/x86",
+                "kernel/x86/microcode",
+                "kernel/x86/microcode/GenuineIntel.bin",
+                "kernel/x86/microcode/AuthenticAMD.bin",
+            ]
+        );
+        assert_eq!(b"firstsecond", entries[3].data.as_slice());
+        assert_eq!(b"amd", entries[4].data.as_slice());
+    }
 }
@@ -0,0 +1,403 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Seek;
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
+use itertools::Itertools;
+#[cfg(feature = "libcap")]
+use libcap::FileExt as _;
+use md5::Digest;
+use md5::Md5;
+use serde::Deserialize;
+use tar::EntryType;
+use tar::Header;
+
+use crate::compression::Compression;
+use crate::manifest::Manifest;
+use crate::PackageFormat;
+
+/// Version of the binary package format, the contents of the 'debian-binary'
+/// member
+const DEB_FORMAT_VERSION: &[u8] = b"2.0\n";
+const AR_MAGIC: &[u8] = b"!<arch>\n";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Deb {
+    #[serde(rename = "deb_name")]
+    name: String,
+    version: String,
+    arch: String,
+    maintainer: String,
+    summary: Option<String>,
+    description: Option<String>,
+    section: Option<String>,
+    priority: Option<String>,
+    homepage: Option<String>,
+    #[serde(default)]
+    depends: Vec<String>,
+    #[serde(default)]
+    pre_depends: Vec<String>,
+    #[serde(default)]
+    recommends: Vec<String>,
+    #[serde(default)]
+    suggests: Vec<String>,
+    #[serde(default)]
+    conflicts: Vec<String>,
+    #[serde(default)]
+    breaks: Vec<String>,
+    #[serde(default)]
+    replaces: Vec<String>,
+    #[serde(default)]
+    provides: Vec<String>,
+    /// Configuration files that dpkg should not overwrite if they were
+    /// modified locally
+    #[serde(default)]
+    conffiles: Vec<PathBuf>,
+    pre_install_script: Option<String>,
+    post_install_script: Option<String>,
+    pre_uninstall_script: Option<String>,
+    post_uninstall_script: Option<String>,
+    #[serde(default)]
+    compression: Compression,
+    compression_level: Option<u32>,
+    compression_threads: Option<NonZeroUsize>,
+}
+
+/// Everything about the layer contents that ends up in the control archive
+#[derive(Default)]
+struct Contents {
+    /// Installed-Size in KiB
+    installed_size: u64,
+    md5sums: String,
+    /// (path, capabilities) for files with file capabilities
+    caps: Vec<(String, String)>,
+}
+
+impl Contents {
+    fn scan(layer: &Path) -> Result<Self> {
+        let mut contents = Self::default();
+        for entry in walkdir::WalkDir::new(layer).sort_by_file_name() {
+            let entry = entry.context("while walking layer")?;
+            let relpath = entry
+                .path()
+                .strip_prefix(layer)
+                .expect("must be under layer");
+            if relpath == Path::new("") {
+                continue;
+            }
+            let relpath = relpath
+                .to_str()
+                .with_context(|| format!("{relpath:?} is not valid utf8"))?;
+            if !entry.file_type().is_file() {
+                contents.installed_size += 1;
+                continue;
+            }
+            let mut f = File::open(entry.path()).context("while opening file")?;
+            let len = f.metadata().context("while statting file")?.len();
+            contents.installed_size += len.div_ceil(1024);
+            let mut hasher = Md5::new();
+            std::io::copy(&mut f, &mut hasher).context("while hashing file")?;
+            writeln!(
+                contents.md5sums,
+                "{}  {relpath}",
+                hex::encode(hasher.finalize())
+            )?;
+            #[cfg(feature = "libcap")]
+            if let Some(caps) = f.get_capabilities()? {
+                contents.caps.push((relpath.to_owned(), caps.to_text()?));
+            }
+        }
+        Ok(contents)
+    }
+}
+
+/// dpkg runs maintainer scripts directly, so they must have an interpreter
+fn maintainer_script(body: &str) -> String {
+    if body.starts_with("#!") {
+        body.to_owned()
+    } else {
+        format!("#!/bin/sh\nset -e\n{body}\n")
+    }
+}
+
+/// Single quote a path for use in a shell script
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+impl Deb {
+    fn control(&self, contents: &Contents) -> Result<String> {
+        let mut control = String::new();
+        writeln!(control, "Package: {}", self.name)?;
+        writeln!(control, "Version: {}", self.version)?;
+        writeln!(control, "Architecture: {}", self.arch)?;
+        writeln!(control, "Maintainer: {}", self.maintainer)?;
+        writeln!(control, "Installed-Size: {}", contents.installed_size)?;
+        let mut depends = self.depends.clone();
+        if !contents.caps.is_empty() {
+            // for setcap in postinst
+            depends.push("libcap2-bin".to_owned());
+        }
+        for (field, values) in [
+            ("Pre-Depends", &self.pre_depends),
+            ("Depends", &depends),
+            ("Recommends", &self.recommends),
+            ("Suggests", &self.suggests),
+            ("Conflicts", &self.conflicts),
+            ("Breaks", &self.breaks),
+            ("Replaces", &self.replaces),
+            ("Provides", &self.provides),
+        ] {
+            if !values.is_empty() {
+                writeln!(control, "{field}: {}", values.iter().join(", "))?;
+            }
+        }
+        for (field, value) in [
+            ("Section", &self.section),
+            ("Priority", &self.priority),
+            ("Homepage", &self.homepage),
+        ] {
+            if let Some(value) = value {
+                writeln!(control, "{field}: {value}")?;
+            }
+        }
+        writeln!(
+            control,
+            "Description: {}",
+            self.summary.as_deref().unwrap_or(self.name.as_str())
+        )?;
+        // the extended description is indented by one space, with blank lines
+        // represented as a single '.'
+        for line in self.description.as_deref().unwrap_or_default().lines() {
+            match line.trim().is_empty() {
+                true => control.push_str(" .\n"),
+                false => writeln!(control, " {line}")?,
+            }
+        }
+        Ok(control)
+    }
+
+    fn post_install_script(&self, contents: &Contents) -> Option<String> {
+        if contents.caps.is_empty() {
+            return self.post_install_script.as_deref().map(maintainer_script);
+        }
+        // dpkg does not restore xattrs, so file capabilities have to be
+        // applied after the files are unpacked
+        let mut setcap = String::from("if [ \"$1\" = configure ]; then\n");
+        for (path, caps) in &contents.caps {
+            setcap.push_str(&format!(
+                "    setcap {} {}\n",
+                shell_quote(caps),
+                shell_quote(&format!("/{path}"))
+            ));
+        }
+        setcap.push_str("fi\n");
+        let script = maintainer_script(self.post_install_script.as_deref().unwrap_or_default());
+        // run before the rest of the script, right after the interpreter line
+        let (shebang, rest) = script.split_once('\n').unwrap_or((&script, ""));
+        Some(format!("{shebang}\n{setcap}{rest}"))
+    }
+
+    fn control_tar(&self, layer: &Path) -> Result<Vec<u8>> {
+        let contents = Contents::scan(layer)?;
+        for conffile in &self.conffiles {
+            ensure!(
+                conffile.is_absolute(),
+                "conffile '{}' must be an absolute path",
+                conffile.display()
+            );
+            let meta = std::fs::symlink_metadata(
+                layer.join(conffile.strip_prefix("/").expect("is absolute")),
+            )
+            .with_context(|| format!("conffile '{}' is not in the layer", conffile.display()))?;
+            ensure!(
+                meta.is_file(),
+                "conffile '{}' is not a regular file",
+                conffile.display()
+            );
+        }
+
+        let mut files: Vec<(&str, Vec<u8>, u32)> = vec![
+            ("control", self.control(&contents)?.into_bytes(), 0o644),
+            ("md5sums", contents.md5sums.clone().into_bytes(), 0o644),
+        ];
+        if !self.conffiles.is_empty() {
+            let conffiles = self
+                .conffiles
+                .iter()
+                .map(|c| format!("{}\n", c.display()))
+                .join("");
+            files.push(("conffiles", conffiles.into_bytes(), 0o644));
+        }
+        for (name, script) in [
+            (
+                "preinst",
+                self.pre_install_script.as_deref().map(maintainer_script),
+            ),
+            ("postinst", self.post_install_script(&contents)),
+            (
+                "prerm",
+                self.pre_uninstall_script.as_deref().map(maintainer_script),
+            ),
+            (
+                "postrm",
+                self.post_uninstall_script.as_deref().map(maintainer_script),
+            ),
+        ] {
+            if let Some(script) = script {
+                files.push((name, script.into_bytes(), 0o755));
+            }
+        }
+
+        let mut builder = tar::Builder::new(
+            self.compression
+                .compressor(Vec::new(), self.compression_level, self.compression_threads)
+                .context("while setting up compression")?,
+        );
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_size(0);
+        control_owner(&mut header)?;
+        builder
+            .append_data(&mut header, "./", std::io::empty())
+            .context("while adding control dir")?;
+        for (name, data, mode) in files {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Regular);
+            header.set_mode(mode);
+            header.set_size(data.len() as u64);
+            control_owner(&mut header)?;
+            builder
+                .append_data(&mut header, name, data.as_slice())
+                .with_context(|| format!("while adding {name} to control archive"))?;
+        }
+        builder
+            .into_inner()
+            .context("while finishing control archive")?
+            .finish()
+            .context("while finishing compression")
+    }
+
+    fn tar_name(&self, name: &str) -> String {
+        match self.compression.extension() {
+            Some(ext) => format!("{name}.tar.{ext}"),
+            None => format!("{name}.tar"),
+        }
+    }
+}
+
+fn control_owner(header: &mut Header) -> Result<()> {
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_username("root")?;
+    header.set_groupname("root")?;
+    header.set_mtime(0);
+    Ok(())
+}
+
+/// Write one member of an ar archive, in the common format understood by
+/// dpkg-deb
+fn append_ar_member<W: Write, R: std::io::Read>(
+    w: &mut W,
+    name: &str,
+    size: u64,
+    mut data: R,
+) -> Result<()> {
+    ensure!(name.len() <= 16, "ar member name '{name}' is too long");
+    writeln!(
+        w,
+        "{name:<16}{:<12}{:<6}{:<6}{:<8o}{size:<10}`",
+        0, 0, 0, 0o100644
+    )?;
+    let copied = std::io::copy(&mut data, w).context("while writing ar member")?;
+    ensure!(copied == size, "{name} changed size while being written");
+    if size % 2 == 1 {
+        w.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+impl PackageFormat for Deb {
+    fn build(&self, out: &Path, layer: &Path, manifest: Option<&mut Manifest>) -> Result<()> {
+        let control = self.control_tar(layer)?;
+
+        let data_tmp = tempfile::tempfile().context("while creating tempfile for data.tar")?;
+        let mut builder = tar::Builder::new(
+            self.compression
+                .compressor(
+                    BufWriter::new(data_tmp),
+                    self.compression_level,
+                    self.compression_threads,
+                )
+                .context("while setting up compression")?,
+        );
+        crate::tar::append_layer(&mut builder, layer, true, manifest)?;
+        let mut data = builder
+            .into_inner()
+            .context("while finishing data archive")?
+            .finish()
+            .context("while finishing compression")?
+            .into_inner()
+            .context("while flushing data archive")?;
+        let data_len = data.stream_position()?;
+        data.rewind()?;
+
+        let mut w = BufWriter::new(File::create(out).context("while creating output file")?);
+        w.write_all(AR_MAGIC)?;
+        append_ar_member(
+            &mut w,
+            "debian-binary",
+            DEB_FORMAT_VERSION.len() as u64,
+            DEB_FORMAT_VERSION,
+        )?;
+        append_ar_member(
+            &mut w,
+            &self.tar_name("control"),
+            control.len() as u64,
+            control.as_slice(),
+        )?;
+        append_ar_member(&mut w, &self.tar_name("data"), data_len, data)?;
+        w.flush().context("while flushing output")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ar_header() {
+        let mut buf = Vec::new();
+        append_ar_member(&mut buf, "debian-binary", 4, &b"2.0\n"[..]).expect("write member");
+        assert_eq!(
+            buf,
+            b"debian-binary   0           0     0     100644  4         `\n2.0\n"
+        );
+    }
+
+    #[test]
+    fn shebang() {
+        assert_eq!("#!/bin/sh\nset -e\necho hi\n", maintainer_script("echo hi"));
+        assert_eq!(
+            "#!/bin/bash\necho hi",
+            maintainer_script("#!/bin/bash\necho hi")
+        );
+    }
+}
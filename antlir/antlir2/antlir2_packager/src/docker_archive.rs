@@ -5,55 +5,357 @@
  * LICENSE file in the root directory of this source tree.
  */
 
-use std::ffi::OsString;
+//! `docker save`-compatible archive of an OCI image layout. The blobs are all
+//! the same, they just need to be re-laid out and referenced from a
+//! `manifest.json` instead of an OCI index.
+
 use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
 
-use antlir2_isolate::unshare;
-use antlir2_isolate::IsolationContext;
+use anyhow::bail;
+use anyhow::ensure;
 use anyhow::Context;
 use anyhow::Result;
+use flate2::read::GzDecoder;
+use oci_spec::image::Descriptor;
+use oci_spec::image::ImageIndex;
+use oci_spec::image::ImageManifest;
+use oci_spec::image::MediaType;
+use oci_spec::image::Platform;
 use serde::Deserialize;
+use serde::Serialize;
 
-use crate::run_cmd;
-use crate::BuildAppliance;
+use crate::compression::Compression;
+use crate::oci::DigestWriter;
+use crate::oci::TargetArch;
+use crate::reproducible;
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct DockerArchive {
-    build_appliance: BuildAppliance,
     oci: PathBuf,
+    /// Tags to load the image as (eg `repo/name:tag`)
+    #[serde(default)]
+    repo_tags: Vec<String>,
+    #[serde(default)]
+    layer_compression: ArchiveLayerCompression,
+    /// Platform to pick out of a multi-arch image (defaults to the host's)
+    #[serde(default)]
+    target_arch: Option<TargetArch>,
+}
+
+/// docker load understands uncompressed and gzipped layers, but not zstd
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveLayerCompression {
+    #[default]
+    Uncompressed,
+    Gzip,
+}
+
+impl ArchiveLayerCompression {
+    fn compression(self) -> Compression {
+        match self {
+            Self::Uncompressed => Compression::None,
+            Self::Gzip => Compression::Gzip,
+        }
+    }
+}
+
+/// One image in the `manifest.json` of a docker-archive
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ArchiveManifest {
+    config: String,
+    repo_tags: Vec<String>,
+    layers: Vec<String>,
+}
+
+/// docker always saves fully-qualified tags, so do the same and reject things
+/// that can't be loaded as a tag at all
+fn normalize_repo_tag(tag: &str) -> Result<String> {
+    ensure!(!tag.is_empty(), "repo tag cannot be empty");
+    ensure!(
+        !tag.contains('@'),
+        "'{tag}' is a digest reference, not a tag"
+    );
+    let name = tag.rsplit_once('/').map_or(tag, |(_, name)| name);
+    if name.contains(':') {
+        Ok(tag.to_owned())
+    } else {
+        Ok(format!("{tag}:latest"))
+    }
 }
 
 impl DockerArchive {
+    fn blob(&self, descriptor: &Descriptor) -> Result<PathBuf> {
+        let digest = descriptor.digest();
+        let hex = digest
+            .strip_prefix("sha256:")
+            .with_context(|| format!("unsupported digest '{digest}'"))?;
+        Ok(self.oci.join("blobs/sha256").join(hex))
+    }
+
+    /// Find the image manifest for `platform`, descending into nested indexes
+    /// (which is where multi-arch images keep theirs)
+    fn manifest(&self, index: &ImageIndex, platform: &Platform) -> Result<Option<ImageManifest>> {
+        for descriptor in index.manifests() {
+            if let Some(p) = descriptor.platform() {
+                if p.architecture() != platform.architecture()
+                    || p.os() != platform.os()
+                    || p.variant() != platform.variant()
+                {
+                    continue;
+                }
+            }
+            match descriptor.media_type() {
+                MediaType::ImageManifest => {
+                    return ImageManifest::from_file(self.blob(descriptor)?)
+                        .context("while reading image manifest")
+                        .map(Some);
+                }
+                MediaType::ImageIndex => {
+                    let nested = ImageIndex::from_file(self.blob(descriptor)?)
+                        .context("while reading nested index")?;
+                    if let Some(manifest) = self.manifest(&nested, platform)? {
+                        return Ok(Some(manifest));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
     pub(crate) fn build(&self, out: &Path) -> Result<()> {
-        File::create(out).context("failed to create output file")?;
-
-        let isol_context = IsolationContext::builder(self.build_appliance.path())
-            .ephemeral(false)
-            .readonly()
-            .tmpfs(Path::new("/__antlir2__/out"))
-            .outputs(("/__antlir2__/out/docker_archive", out))
-            .inputs((
-                PathBuf::from("/__antlir2__/working_directory"),
-                std::env::current_dir()?,
-            ))
-            .working_directory(Path::new("/__antlir2__/working_directory"))
-            .build();
-
-        let mut oci_arg: OsString = "oci:".into();
-        oci_arg.push(self.oci.as_os_str());
-
-        run_cmd(
-            unshare(isol_context)?
-                .command("skopeo")?
-                .arg("copy")
-                .arg(oci_arg)
-                .arg("docker-archive:/__antlir2__/out/docker_archive"),
+        let repo_tags = self
+            .repo_tags
+            .iter()
+            .map(|t| normalize_repo_tag(t))
+            .collect::<Result<Vec<_>>>()?;
+        let index = ImageIndex::from_file(self.oci.join("index.json"))
+            .context("while reading oci index")?;
+        let target_arch = match self.target_arch {
+            Some(arch) => arch,
+            None => TargetArch::host()?,
+        };
+        let manifest = self
+            .manifest(&index, &target_arch.platform()?)?
+            .with_context(|| format!("oci image has no manifest for {target_arch:?}"))?;
+
+        let mtime = reproducible::source_date_epoch()?;
+        let mut builder = tar::Builder::new(BufWriter::new(
+            File::create(out).context("failed to create output file")?,
+        ));
+
+        let config_hex = manifest
+            .config()
+            .digest()
+            .strip_prefix("sha256:")
+            .context("config digest is not sha256")?;
+        let config_name = format!("{config_hex}.json");
+        append(
+            &mut builder,
+            &config_name,
+            File::open(self.blob(manifest.config())?).context("while opening config")?,
+            mtime,
+        )
+        .context("while adding config")?;
+
+        let mut layers = Vec::new();
+        for layer in manifest.layers() {
+            let name = self
+                .append_layer(&mut builder, layer, mtime)
+                .with_context(|| format!("while adding layer {}", layer.digest()))?;
+            layers.push(name);
+        }
+
+        let archive_manifest = serde_json::to_vec(&[ArchiveManifest {
+            config: config_name,
+            repo_tags,
+            layers,
+        }])
+        .context("while serializing manifest.json")?;
+        append(
+            &mut builder,
+            "manifest.json",
+            std::io::Cursor::new(archive_manifest),
+            mtime,
         )
-        .context("Failed to convert to docker-archive")?;
+        .context("while adding manifest.json")?;
 
+        builder
+            .into_inner()
+            .context("while finishing archive")?
+            .flush()
+            .context("while flushing archive")?;
         Ok(())
     }
+
+    /// Add a layer blob to the archive (converting its compression if
+    /// necessary) and return its path in the archive
+    fn append_layer<W: Write>(
+        &self,
+        builder: &mut tar::Builder<W>,
+        layer: &Descriptor,
+        mtime: u64,
+    ) -> Result<String> {
+        let source = match layer.media_type() {
+            MediaType::ImageLayer => Compression::None,
+            MediaType::ImageLayerGzip => Compression::Gzip,
+            MediaType::ImageLayerZstd => Compression::Zstd,
+            other => bail!("unsupported layer media type '{other}'"),
+        };
+        let target = self.layer_compression.compression();
+        let blob = File::open(self.blob(layer)?).context("while opening layer blob")?;
+        // the layer is named after its own digest, which is only known before
+        // converting if the compression stays the same
+        if source == target {
+            let hex = layer
+                .digest()
+                .strip_prefix("sha256:")
+                .context("layer digest is not sha256")?;
+            let name = layer_name(hex, target);
+            append(builder, &name, blob, mtime)?;
+            return Ok(name);
+        }
+        let mut uncompressed: Box<dyn Read> = match source {
+            Compression::None => Box::new(BufReader::new(blob)),
+            Compression::Gzip => Box::new(GzDecoder::new(BufReader::new(blob))),
+            Compression::Zstd => {
+                Box::new(zstd::Decoder::new(blob).context("while setting up zstd decompression")?)
+            }
+            Compression::Xz => bail!("xz is not an oci layer compression"),
+        };
+        let mut converted = tempfile::tempfile().context("while creating temporary file")?;
+        let digest = {
+            let mut hasher = DigestWriter::new(BufWriter::new(&mut converted));
+            let mut compressor = target
+                .compressor(&mut hasher, None, None)
+                .context("while setting up compression")?;
+            std::io::copy(&mut uncompressed, &mut compressor).context("while converting layer")?;
+            compressor.finish().context("while finishing compression")?;
+            hasher.flush().context("while flushing converted layer")?;
+            hasher.hex_digest()
+        };
+        let name = layer_name(&digest, target);
+        converted
+            .rewind()
+            .context("while rewinding converted layer")?;
+        append(builder, &name, converted, mtime)?;
+        Ok(name)
+    }
+}
+
+fn layer_name(hex: &str, compression: Compression) -> String {
+    match compression.extension() {
+        Some(ext) => format!("{hex}.tar.{ext}"),
+        None => format!("{hex}.tar"),
+    }
+}
+
+/// Add a file to the archive with fixed metadata so that the archive is
+/// reproducible
+fn append<W: Write, R: Read + Seek>(
+    builder: &mut tar::Builder<W>,
+    name: &str,
+    mut contents: R,
+    mtime: u64,
+) -> Result<()> {
+    let size = contents
+        .seek(std::io::SeekFrom::End(0))
+        .context("while getting size")?;
+    contents.rewind().context("while rewinding")?;
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(mtime);
+    builder
+        .append_data(&mut header, name, contents)
+        .with_context(|| format!("while appending {name}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::oci::Oci;
+
+    #[test]
+    fn repo_tags() {
+        assert_eq!(normalize_repo_tag("foo").expect("valid"), "foo:latest");
+        assert_eq!(normalize_repo_tag("foo:bar").expect("valid"), "foo:bar");
+        assert_eq!(
+            normalize_repo_tag("registry:5000/foo").expect("valid"),
+            "registry:5000/foo:latest"
+        );
+        assert_eq!(
+            normalize_repo_tag("registry:5000/foo:1.0").expect("valid"),
+            "registry:5000/foo:1.0"
+        );
+        assert!(normalize_repo_tag("").is_err());
+        assert!(normalize_repo_tag("foo@sha256:abcd").is_err());
+    }
+
+    #[test]
+    fn selects_platform_from_multi_arch_image() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let tar = dir.path().join("layer.tar");
+        tar::Builder::new(File::create(&tar).expect("failed to create tar"))
+            .into_inner()
+            .expect("failed to write tar");
+        let oci = dir.path().join("oci");
+        serde_json::from_value::<Oci>(serde_json::json!({
+            "ref": "test",
+            "manifests": (["aarch64", "x86_64"].map(|arch| serde_json::json!({
+                "deltas": [{"tar": tar, "label": "layer"}],
+                "target_arch": arch,
+                "entrypoint": ["/bin/sh"],
+                "layer_compression": "uncompressed",
+            }))),
+        }))
+        .expect("invalid oci spec")
+        .build(&oci, None)
+        .expect("failed to build oci image");
+
+        for (target_arch, goarch) in [("aarch64", "arm64"), ("x86_64", "amd64")] {
+            let out = dir.path().join(format!("{target_arch}.tar"));
+            serde_json::from_value::<DockerArchive>(serde_json::json!({
+                "oci": oci,
+                "target_arch": target_arch,
+            }))
+            .expect("invalid docker_archive spec")
+            .build(&out)
+            .expect("failed to build docker archive");
+
+            let mut files = BTreeMap::new();
+            let mut archive = tar::Archive::new(File::open(&out).expect("failed to open archive"));
+            for entry in archive.entries().expect("failed to read archive") {
+                let mut entry = entry.expect("failed to read entry");
+                let path = entry.path().expect("bad path").into_owned();
+                let mut contents = Vec::new();
+                entry
+                    .read_to_end(&mut contents)
+                    .expect("failed to read entry");
+                files.insert(path, contents);
+            }
+            let manifest: serde_json::Value =
+                serde_json::from_slice(&files[Path::new("manifest.json")])
+                    .expect("invalid manifest.json");
+            let config_name = manifest[0]["Config"].as_str().expect("no config");
+            let config: serde_json::Value =
+                serde_json::from_slice(&files[Path::new(config_name)]).expect("invalid config");
+            assert_eq!(config["architecture"], goarch);
+        }
+    }
 }
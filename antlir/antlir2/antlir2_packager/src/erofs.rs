@@ -15,6 +15,9 @@ use anyhow::Context;
 use anyhow::Result;
 use serde::Deserialize;
 
+use crate::manifest::Manifest;
+use crate::reproducible;
+use crate::reproducible::Digest;
 use crate::run_cmd;
 use crate::BuildAppliance;
 use crate::PackageFormat;
@@ -28,8 +31,9 @@ pub struct Erofs {
 }
 
 impl PackageFormat for Erofs {
-    fn build(&self, out: &Path, layer: &Path) -> Result<()> {
+    fn build(&self, out: &Path, layer: &Path, manifest: Option<&mut Manifest>) -> Result<()> {
         File::create(out).context("failed to create output file")?;
+        let epoch = reproducible::source_date_epoch()?.to_string();
 
         let isol_context = IsolationContext::builder(self.build_appliance.path())
             .ephemeral(false)
@@ -37,6 +41,10 @@ impl PackageFormat for Erofs {
             .tmpfs(Path::new("/__antlir2__/out"))
             .outputs(("/__antlir2__/out/erofs", out))
             .inputs((Path::new("/__antlir2__/root"), layer))
+            // without -T, mkfs.erofs uses SOURCE_DATE_EPOCH as the build time
+            // and clamps every file's timestamp to it (-T would instead set
+            // all of them)
+            .setenv(("SOURCE_DATE_EPOCH", epoch.as_str()))
             .inputs((
                 PathBuf::from("/__antlir2__/working_directory"),
                 std::env::current_dir()?,
@@ -46,6 +54,8 @@ impl PackageFormat for Erofs {
 
         let mut cmd = unshare(isol_context)?.command("mkfs.erofs")?;
         cmd.arg("/__antlir2__/out/erofs").arg("/__antlir2__/root");
+        cmd.arg("-U")
+            .arg(Digest::of_tree(layer, manifest)?.uuid("erofs").to_string());
         if let Some(compression) = &self.compression {
             cmd.arg("-z").arg(compression);
         }
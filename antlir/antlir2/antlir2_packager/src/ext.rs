@@ -5,18 +5,32 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+use std::collections::BTreeSet;
+use std::collections::HashSet;
 use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
 
 use antlir2_isolate::unshare;
 use antlir2_isolate::IsolationContext;
+use anyhow::ensure;
 use anyhow::Context;
 use anyhow::Result;
 use bytesize::ByteSize;
 use serde::Deserialize;
+use tempfile::NamedTempFile;
+use uuid::Uuid;
 use walkdir::WalkDir;
 
+use crate::manifest::Manifest;
+use crate::reproducible;
+use crate::reproducible::Digest;
 use crate::run_cmd;
 use crate::BuildAppliance;
 use crate::PackageFormat;
@@ -31,22 +45,80 @@ pub struct Ext3 {
 }
 
 const MAPPED_OUTPUT: &str = "/__antlir2__/out/ext3";
+const MAPPED_CLAMP_TIMES: &str = "/__antlir2__/clamp_times";
+
+/// mke2fs copies inode timestamps from the source directory (and does not
+/// respect SOURCE_DATE_EPOCH for them until e2fsprogs 1.47.1), so write a
+/// debugfs script to clamp them after the fact.
+fn clamp_times_script(layer: &Path, source_date_epoch: u64) -> Result<NamedTempFile> {
+    let mut script = NamedTempFile::new().context("while creating debugfs script")?;
+    let mut w = BufWriter::new(script.as_file_mut());
+    for entry in WalkDir::new(layer) {
+        let entry = entry.context("while walking layer")?;
+        let meta = entry.metadata().context("while getting metadata")?;
+        let relpath = entry.path().strip_prefix(layer)?.as_os_str().as_bytes();
+        ensure!(
+            !relpath.contains(&b'\n'),
+            "debugfs cannot handle newlines in paths: {}",
+            entry.path().display()
+        );
+        // debugfs args are quoted with '"', and a literal '"' is escaped by
+        // doubling it
+        let mut quoted = b"\"/".to_vec();
+        for b in relpath {
+            if *b == b'"' {
+                quoted.push(b'"');
+            }
+            quoted.push(*b);
+        }
+        quoted.push(b'"');
+        for (field, secs) in [
+            ("atime", meta.atime()),
+            ("mtime", meta.mtime()),
+            ("ctime", meta.ctime()),
+        ] {
+            let time = match u64::try_from(secs) {
+                Ok(secs) => SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+                Err(_) => SystemTime::UNIX_EPOCH,
+            };
+            let secs = reproducible::clamp(time, source_date_epoch)
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            w.write_all(b"sif ")?;
+            w.write_all(&quoted)?;
+            writeln!(w, " {field} @{secs}")?;
+        }
+    }
+    w.flush().context("while writing debugfs script")?;
+    drop(w);
+    Ok(script)
+}
 
 impl PackageFormat for Ext3 {
-    fn build(&self, out: &Path, layer: &Path) -> Result<()> {
+    fn build(&self, out: &Path, layer: &Path, manifest: Option<&mut Manifest>) -> Result<()> {
         File::create(out).context("failed to create output file")?;
 
+        let source_date_epoch = reproducible::source_date_epoch()?;
+        let epoch = source_date_epoch.to_string();
+        let digest = Digest::of_tree(layer, manifest)?;
+        let clamp_times = clamp_times_script(layer, source_date_epoch)?;
+
         let isol_context = IsolationContext::builder(self.build_appliance.path())
             .ephemeral(false)
             .readonly()
             .tmpfs(Path::new("/__antlir2__/out"))
             .outputs((MAPPED_OUTPUT, out))
             .inputs((Path::new("/__antlir2__/root"), layer))
+            .inputs((Path::new(MAPPED_CLAMP_TIMES), clamp_times.path()))
             .inputs((
                 PathBuf::from("/__antlir2__/working_directory"),
                 std::env::current_dir()?,
             ))
             .working_directory(Path::new("/__antlir2__/working_directory"))
+            // filesystem-level timestamps (superblock, lost+found, etc)
+            .setenv(("E2FSPROGS_FAKE_TIME", epoch.as_str()))
+            .setenv(("SOURCE_DATE_EPOCH", epoch.as_str()))
             .build();
 
         let isol = unshare(isol_context)?;
@@ -54,11 +126,13 @@ impl PackageFormat for Ext3 {
         if let Some(label) = &self.label {
             cmd.arg("-L").arg(label);
         }
+        cmd.arg("-U").arg(digest.uuid("ext3").to_string());
+        cmd.arg("-E")
+            .arg(format!("hash_seed={}", digest.uuid("ext3 hash_seed")));
         cmd.arg("-d").arg("/__antlir2__/root");
         cmd.arg(MAPPED_OUTPUT);
         if let Some(size_mb) = self.size_mb {
             cmd.arg(format!("{}M", size_mb));
-            run_cmd(&mut cmd).context("failed to build ext3 archive")?;
         } else {
             let total_file_size = ByteSize::b(
                 WalkDir::new(layer)
@@ -91,8 +165,18 @@ impl PackageFormat for Ext3 {
             // It's just one kilobyte Michael, what could it cost? $10?
             let size_kb = (size.0 / 1024) + 1;
             cmd.arg(format!("{size_kb}K"));
-            run_cmd(&mut cmd).context("failed to build ext3 archive")?;
+        }
+        run_cmd(&mut cmd).context("failed to build ext3 archive")?;
+        run_cmd(
+            isol.command("debugfs")?
+                .arg("-w")
+                .arg("-f")
+                .arg(MAPPED_CLAMP_TIMES)
+                .arg(MAPPED_OUTPUT),
+        )
+        .context("while clamping timestamps")?;
 
+        if self.size_mb.is_none() {
             run_cmd(isol.command("resize2fs")?.arg("-M").arg(MAPPED_OUTPUT))
                 .context("while minimizing fs size")?;
 
@@ -110,8 +194,489 @@ impl PackageFormat for Ext3 {
                 run_cmd(isol.command("resize2fs")?.arg(MAPPED_OUTPUT))
                     .context("failed to resize ext3 archive")?;
             }
+        }
+
+        Ok(())
+    }
+}
+
+/// ext4 filesystem image, for when the details of the filesystem matter (like
+/// a VM root disk).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ext4 {
+    build_appliance: BuildAppliance,
+    label: Option<String>,
+    /// Absolute size of the image. When omitted, the image is sized to fit
+    /// exactly what the layer needs, plus `free_mb` and `reserved_blocks`.
+    size_mb: Option<u64>,
+    free_mb: u64,
+    /// Features to enable (or disable when prefixed with '^') on top of
+    /// [BASE_FEATURES]
+    features: Vec<String>,
+    block_size: u64,
+    inode_size: u64,
+    /// Total number of inodes. When omitted, the image gets as many as the
+    /// layer needs plus enough for `free_mb` at the default inode ratio.
+    inodes: Option<u64>,
+    /// Blocks that only root is allowed to allocate
+    reserved_blocks: u64,
+    /// Filesystem uuid, derived from the layer contents when omitted
+    uuid: Option<Uuid>,
+}
+
+const MAPPED_EXT4_OUTPUT: &str = "/__antlir2__/out/ext4";
+
+/// Features that every image starts with. These are passed to mke2fs
+/// explicitly (instead of relying on whatever mke2fs.conf in the build
+/// appliance says) so that images are sized correctly and don't change when
+/// e2fsprogs does.
+const BASE_FEATURES: &[&str] = &[
+    "64bit",
+    "dir_index",
+    "dir_nlink",
+    "ext_attr",
+    "extent",
+    "extra_isize",
+    "filetype",
+    "flex_bg",
+    "has_journal",
+    "huge_file",
+    "large_file",
+    "metadata_csum",
+    "resize_inode",
+    "sparse_super",
+];
+
+/// mke2fs gives every inode this many bytes of the filesystem by default
+const DEFAULT_INODE_RATIO: u64 = 16384;
+
+/// Inodes 1-10 are reserved and 11 is lost+found
+const FIRST_INODE: u64 = 11;
+
+/// Largest number of blocks that a single extent can cover
+const MAX_EXTENT_LEN: u64 = 32768;
+
+/// Space used by a layer once it is in an ext4 filesystem
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Usage {
+    inodes: u64,
+    blocks: u64,
+}
+
+impl Usage {
+    fn of_layer(layer: &Path, block_size: u64, inode_size: u64) -> Result<Self> {
+        let mut usage = Self {
+            // reserved inodes, plus one for the orphan file
+            inodes: FIRST_INODE + 1,
+            // lost+found is preallocated to 16K
+            blocks: (16 * 1024_u64).div_ceil(block_size),
+        };
+        let mut seen_hardlinks = HashSet::new();
+        for entry in WalkDir::new(layer) {
+            let entry = entry.context("while walking layer")?;
+            let meta = entry
+                .metadata()
+                .with_context(|| format!("while getting metadata of {}", entry.path().display()))?;
+            if meta.nlink() > 1
+                && !meta.is_dir()
+                && !seen_hardlinks.insert((meta.dev(), meta.ino()))
+            {
+                continue;
+            }
+            usage.inodes += 1;
+            let data_blocks = if meta.is_file() {
+                // holes are preserved, so only count allocated blocks
+                std::cmp::min(meta.len(), meta.blocks() * 512).div_ceil(block_size)
+            } else if meta.is_dir() {
+                let mut entries_len = dirent_len(1) + dirent_len(2);
+                for child in std::fs::read_dir(entry.path())
+                    .with_context(|| format!("while reading dir {}", entry.path().display()))?
+                {
+                    let child = child
+                        .with_context(|| format!("while reading dir {}", entry.path().display()))?;
+                    entries_len += dirent_len(child.file_name().len() as u64);
+                }
+                // entries can't span blocks, so the end of each block may be
+                // wasted, and the last 12 bytes are the checksum tail
+                let blocks = entries_len.div_ceil(block_size - 12 - dirent_len(255));
+                // anything bigger than a single block gets an htree index
+                if blocks > 1 {
+                    blocks + 1
+                } else {
+                    blocks
+                }
+            } else if meta.is_symlink() {
+                // short symlinks are stored directly in the inode
+                if meta.len() < 60 {
+                    0
+                } else {
+                    1
+                }
+            } else {
+                0
+            };
+            usage.blocks += data_blocks;
+            // up to 4 extents fit in the inode itself, past that they need
+            // index blocks
+            let extents = data_blocks.div_ceil(MAX_EXTENT_LEN) + 1;
+            if extents > 4 {
+                usage.blocks += extents.div_ceil((block_size - 12) / 12);
+            }
+            let mut xattrs_len = 4;
+            for name in xattr::list(entry.path())
+                .with_context(|| format!("while listing xattrs on {}", entry.path().display()))?
+            {
+                let value = xattr::get(entry.path(), &name)
+                    .with_context(|| format!("while reading xattrs on {}", entry.path().display()))?
+                    .unwrap_or_default();
+                xattrs_len += (16 + name.len() as u64).next_multiple_of(4)
+                    + (value.len() as u64).next_multiple_of(4);
+            }
+            // small xattrs fit in the inode after the fixed fields and
+            // i_extra_isize, anything else needs an extra block
+            if xattrs_len > 4 && xattrs_len > inode_size.saturating_sub(128 + 32) {
+                usage.blocks += 1;
+            }
+        }
+        Ok(usage)
+    }
+}
+
+/// Size of a directory entry with a name of `name_len` bytes
+fn dirent_len(name_len: u64) -> u64 {
+    (8 + name_len).next_multiple_of(4)
+}
+
+/// Default journal size (in blocks) that mke2fs would pick for a filesystem
+/// of `blocks` blocks
+fn journal_blocks(blocks: u64) -> u64 {
+    match blocks {
+        0..32768 => 1024,
+        32768..262144 => 4096,
+        262144..524288 => 8192,
+        524288..4194304 => 16384,
+        4194304..8388608 => 32768,
+        8388608..16777216 => 65536,
+        16777216..33554432 => 131072,
+        _ => 262144,
+    }
+}
+
+/// Default orphan file size (in blocks) that mke2fs would pick for a
+/// filesystem of `blocks` blocks
+fn orphan_file_blocks(blocks: u64) -> u64 {
+    match blocks {
+        0..131072 => 32,
+        131072..2097152 => blocks / 4096,
+        _ => 512,
+    }
+}
+
+/// Groups that have a backup of the superblock and group descriptors (with
+/// sparse_super, that's 0, 1 and powers of 3, 5 and 7)
+fn has_superblock_backup(group: u64) -> bool {
+    if group <= 1 {
+        return true;
+    }
+    [3, 5, 7].into_iter().any(|base| {
+        let mut n = base;
+        while n < group {
+            n *= base;
+        }
+        n == group
+    })
+}
+
+impl Ext4 {
+    /// [BASE_FEATURES] with the requested features added or removed
+    fn resolved_features(&self) -> BTreeSet<&str> {
+        let mut features: BTreeSet<&str> = BASE_FEATURES.iter().copied().collect();
+        for feature in &self.features {
+            match feature.strip_prefix('^') {
+                Some(disabled) => features.remove(disabled),
+                None => features.insert(feature),
+            };
+        }
+        features
+    }
+
+    fn feature_enabled(&self, feature: &str) -> bool {
+        self.resolved_features().contains(feature)
+    }
+
+    /// Number of blocks used by filesystem metadata in a filesystem of
+    /// `blocks` blocks with `inodes` inodes
+    fn overhead(&self, blocks: u64, inodes: u64) -> u64 {
+        let bs = self.block_size;
+        let blocks_per_group = bs * 8;
+        let groups = blocks.div_ceil(blocks_per_group);
+        let inodes_per_group = inodes
+            .div_ceil(groups)
+            .next_multiple_of(std::cmp::max(8, bs / self.inode_size));
+        let inode_table_blocks = (inodes_per_group * self.inode_size).div_ceil(bs);
+        // 64bit group descriptors
+        let descs_per_block = bs / 64;
+        let gdt_blocks = groups.div_ceil(descs_per_block);
+        let reserved_gdt_blocks = if self.feature_enabled("resize_inode") {
+            // enough to grow 1024x online
+            let max_groups =
+                std::cmp::min(u32::MAX as u64, blocks * 1024).div_ceil(blocks_per_group);
+            std::cmp::min(
+                max_groups
+                    .div_ceil(descs_per_block)
+                    .saturating_sub(gdt_blocks),
+                bs / 4,
+            ) + 1
+        } else {
+            0
         };
+        let backups = (0..groups).filter(|g| has_superblock_backup(*g)).count() as u64;
+
+        let mut overhead =
+            groups * (2 + inode_table_blocks) + backups * (1 + gdt_blocks + reserved_gdt_blocks);
+        if self.feature_enabled("has_journal") {
+            overhead += journal_blocks(blocks) + 1;
+        }
+        if self.feature_enabled("orphan_file") {
+            overhead += orphan_file_blocks(blocks) + 1;
+        }
+        overhead
+    }
+
+    /// Smallest filesystem (in blocks) that can hold `usage` with `inodes`
+    /// total inodes, and still have `free_mb` and `reserved_blocks` to spare.
+    fn minimal_blocks(&self, usage: Usage, inodes: u64) -> u64 {
+        let needed = usage.blocks
+            + ByteSize::mib(self.free_mb).as_u64() / self.block_size
+            + self.reserved_blocks;
+        // mke2fs won't make a journal for anything smaller than this
+        let mut blocks = std::cmp::max(needed, 2048);
+        loop {
+            // the overhead grows with the size of the filesystem, so iterate
+            // until it settles
+            let next = std::cmp::max(needed + self.overhead(blocks, inodes), blocks);
+            if next == blocks {
+                break;
+            }
+            blocks = next;
+        }
+        // mke2fs doesn't pack data perfectly, leave it a little room
+        blocks + blocks / 64 + 16
+    }
+}
+
+impl PackageFormat for Ext4 {
+    fn build(&self, out: &Path, layer: &Path, manifest: Option<&mut Manifest>) -> Result<()> {
+        ensure!(
+            self.block_size.is_power_of_two() && (1024..=65536).contains(&self.block_size),
+            "block_size must be a power of two between 1024 and 65536"
+        );
+        ensure!(
+            self.inode_size.is_power_of_two() && (128..=self.block_size).contains(&self.inode_size),
+            "inode_size must be a power of two between 128 and block_size"
+        );
+        for feature in &self.features {
+            ensure!(
+                !feature.is_empty() && !feature.contains(','),
+                "invalid feature '{feature}'"
+            );
+        }
+
+        File::create(out).context("failed to create output file")?;
+
+        let usage = Usage::of_layer(layer, self.block_size, self.inode_size)
+            .context("while measuring layer")?;
+        if let Some(inodes) = self.inodes {
+            ensure!(
+                inodes >= usage.inodes,
+                "layer needs at least {} inodes but only {inodes} were requested",
+                usage.inodes
+            );
+        }
+        let (size, inodes) = match self.size_mb {
+            Some(size_mb) => (ByteSize::mib(size_mb).as_u64(), self.inodes),
+            None => {
+                let inodes = self.inodes.unwrap_or_else(|| {
+                    usage.inodes + ByteSize::mib(self.free_mb).as_u64() / DEFAULT_INODE_RATIO
+                });
+                (
+                    self.minimal_blocks(usage, inodes) * self.block_size,
+                    Some(inodes),
+                )
+            }
+        };
+
+        let source_date_epoch = reproducible::source_date_epoch()?;
+        let epoch = source_date_epoch.to_string();
+        let digest = Digest::of_tree(layer, manifest)?;
+        let clamp_times = clamp_times_script(layer, source_date_epoch)?;
+
+        let isol_context = IsolationContext::builder(self.build_appliance.path())
+            .ephemeral(false)
+            .readonly()
+            .tmpfs(Path::new("/__antlir2__/out"))
+            .outputs((MAPPED_EXT4_OUTPUT, out))
+            .inputs((Path::new("/__antlir2__/root"), layer))
+            .inputs((Path::new(MAPPED_CLAMP_TIMES), clamp_times.path()))
+            .inputs((
+                PathBuf::from("/__antlir2__/working_directory"),
+                std::env::current_dir()?,
+            ))
+            .working_directory(Path::new("/__antlir2__/working_directory"))
+            // filesystem-level timestamps (superblock, lost+found, etc)
+            .setenv(("E2FSPROGS_FAKE_TIME", epoch.as_str()))
+            .setenv(("SOURCE_DATE_EPOCH", epoch.as_str()))
+            .build();
+
+        let isol = unshare(isol_context)?;
+        let mut cmd = isol.command("mkfs.ext4")?;
+        cmd.arg("-b").arg(self.block_size.to_string());
+        cmd.arg("-I").arg(self.inode_size.to_string());
+        if let Some(inodes) = inodes {
+            cmd.arg("-N").arg(inodes.to_string());
+        }
+        // 'none' clears the mke2fs.conf defaults first
+        cmd.arg("-O").arg(
+            std::iter::once("none")
+                .chain(self.resolved_features())
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+        if let Some(label) = &self.label {
+            cmd.arg("-L").arg(label);
+        }
+        // reserved blocks are set as an exact count afterwards
+        cmd.arg("-m").arg("0");
+        cmd.arg("-U")
+            .arg(self.uuid.unwrap_or_else(|| digest.uuid("ext4")).to_string());
+        cmd.arg("-E")
+            .arg(format!("hash_seed={}", digest.uuid("ext4 hash_seed")));
+        cmd.arg("-d").arg("/__antlir2__/root");
+        cmd.arg(MAPPED_EXT4_OUTPUT);
+        cmd.arg(format!("{}K", size / 1024));
+        run_cmd(&mut cmd).context("failed to build ext4 image")?;
+        run_cmd(
+            isol.command("debugfs")?
+                .arg("-w")
+                .arg("-f")
+                .arg(MAPPED_CLAMP_TIMES)
+                .arg(MAPPED_EXT4_OUTPUT),
+        )
+        .context("while clamping timestamps")?;
+        if self.reserved_blocks != 0 {
+            run_cmd(
+                isol.command("tune2fs")?
+                    .arg("-r")
+                    .arg(self.reserved_blocks.to_string())
+                    .arg(MAPPED_EXT4_OUTPUT),
+            )
+            .context("while setting reserved blocks")?;
+        }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamp_times_quotes_paths() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let path = dir.path().join("we\"ird name");
+        std::fs::write(&path, "hello").expect("failed to write file");
+        File::options()
+            .write(true)
+            .open(&path)
+            .expect("failed to open file")
+            .set_times(
+                std::fs::FileTimes::new()
+                    .set_accessed(SystemTime::UNIX_EPOCH + Duration::from_secs(1000))
+                    .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(3000)),
+            )
+            .expect("failed to set times");
+        let script = clamp_times_script(dir.path(), 2000).expect("failed to write script");
+        let script = std::fs::read_to_string(script.path()).expect("failed to read script");
+        let lines: Vec<_> = script.lines().collect();
+        assert_eq!(
+            &lines[3..],
+            [
+                "sif \"/we\"\"ird name\" atime @1000",
+                "sif \"/we\"\"ird name\" mtime @2000",
+                "sif \"/we\"\"ird name\" ctime @2000",
+            ]
+        );
+    }
+
+    #[test]
+    fn usage_counts_hardlinks_once() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        std::fs::write(dir.path().join("a"), vec![1u8; 10000]).expect("failed to write file");
+        let before = Usage::of_layer(dir.path(), 4096, 256).expect("failed to measure");
+        std::fs::hard_link(dir.path().join("a"), dir.path().join("b")).expect("failed to hardlink");
+        let after = Usage::of_layer(dir.path(), 4096, 256).expect("failed to measure");
+        assert_eq!(before.inodes, after.inodes);
+        assert_eq!(before.blocks, after.blocks);
+        std::fs::write(dir.path().join("c"), "hello").expect("failed to write file");
+        let after = Usage::of_layer(dir.path(), 4096, 256).expect("failed to measure");
+        assert_eq!(before.inodes + 1, after.inodes);
+        assert_eq!(before.blocks + 1, after.blocks);
+    }
+
+    #[test]
+    fn superblock_backups() {
+        assert_eq!(
+            vec![0, 1, 3, 5, 7, 9, 25, 27, 49, 81, 125],
+            (0..128)
+                .filter(|g| has_superblock_backup(*g))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    fn spec(features: &[&str]) -> Ext4 {
+        serde_json::from_value(serde_json::json!({
+            "build_appliance": "/",
+            "label": null,
+            "size_mb": null,
+            "free_mb": 100,
+            "features": features,
+            "block_size": 4096,
+            "inode_size": 256,
+            "inodes": null,
+            "reserved_blocks": 1000,
+            "uuid": null,
+        }))
+        .expect("invalid spec")
+    }
+
+    #[test]
+    fn resolved_features() {
+        let ext4 = spec(&["^has_journal", "orphan_file"]);
+        let features = ext4.resolved_features();
+        assert!(!features.contains("has_journal"));
+        assert!(features.contains("orphan_file"));
+        assert!(features.contains("metadata_csum"));
+        assert!(!ext4.feature_enabled("has_journal"));
+        assert!(ext4.feature_enabled("orphan_file"));
+        // nothing beyond the base features unless asked for
+        assert!(!spec(&[]).feature_enabled("orphan_file"));
+    }
+
+    #[test]
+    fn minimal_blocks_fits_usage_and_free_space() {
+        let ext4 = spec(&["^has_journal"]);
+        let usage = Usage {
+            inodes: 50000,
+            blocks: 130000,
+        };
+        let blocks = ext4.minimal_blocks(usage, usage.inodes);
+        let overhead = ext4.overhead(blocks, usage.inodes);
+        assert!(blocks >= usage.blocks + 25600 + 1000 + overhead);
+        // inode tables are the bulk of the overhead
+        assert!(overhead >= 50000 * 256 / 4096);
+        // but it's still pretty close to minimal
+        assert!(blocks < (usage.blocks + 25600 + 1000 + overhead) * 21 / 20);
+    }
+}
@@ -5,20 +5,28 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+use std::collections::BTreeSet;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::Seek;
 use std::io::SeekFrom;
 use std::path::Path;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
 use anyhow::Context;
 use anyhow::Result;
 use bytesize::ByteSize;
 use serde::Deserialize;
 use uuid::Uuid;
 
+use crate::manifest::Manifest;
+use crate::reproducible::Digest;
+use crate::verity::VerityParams;
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Gpt {
@@ -26,13 +34,360 @@ pub struct Gpt {
     partitions: Vec<Partition>,
     #[serde(default)]
     block_size: BlockSize,
+    #[serde(default)]
+    mbr: Mbr,
 }
 
-#[derive(Debug, Copy, Clone, Deserialize)]
+/// What to put in the legacy MBR at LBA0
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Mbr {
+    /// Leave LBA0 empty
+    None,
+    /// A single 0xEE partition covering the whole disk, so that MBR-only
+    /// tools don't think that the disk is empty
+    #[default]
+    Protective,
+    /// Protective 0xEE partition covering the GPT itself, followed by MBR
+    /// entries mirroring (up to 3) partitions marked with `hybrid_mbr`, for
+    /// firmware that only understands MBR
+    Hybrid,
+}
+
+/// Partition type GUIDs that are not specific to a cpu architecture, named
+/// the same way that systemd (`systemd-id128 show`) and the Discoverable
+/// Partitions Specification name them
+const PARTITION_TYPES: &[(&str, u128)] = &[
+    ("bios-boot", 0x21686148_6449_6E6F_744E_656564454649),
+    ("esp", 0xC12A7328_F81F_11D2_BA4B_00A0C93EC93B),
+    ("home", 0x933AC7E1_2EB4_4F13_B844_0E14E2AEF915),
+    ("linux", 0x0FC63DAF_8483_4772_8E79_3D69D8477DE4),
+    ("srv", 0x3B8F8425_20E0_4F3B_907F_1A25A76F98E8),
+    ("swap", 0x0657FD6D_A4AB_43C4_84E5_0933C84B4F4F),
+    ("tmp", 0x7EC6F557_3BC5_4ACA_B293_16EF5DF639D1),
+    ("user-home", 0x773F91EF_66D4_49B5_BD83_D683BF40AD16),
+    ("var", 0x4D21B016_B534_45C2_A9FB_5C16E091FD2D),
+    ("xbootldr", 0xBC13C2FF_59E6_4262_A352_B275FD6F7172),
+];
+
+/// Per-architecture Discoverable Partitions Specification types, in the
+/// order root, root-verity, root-verity-sig, usr, usr-verity, usr-verity-sig
+const ARCH_PARTITION_TYPES: &[(&str, [u128; 6])] = &[
+    (
+        "alpha",
+        [
+            0x6523F8AE_3EB1_4E2A_A05A_18B695AE656F,
+            0xFC56D9E9_E6E5_4C06_BE32_E74407CE09A5,
+            0xD46495B7_A053_414F_80F7_700C99921EF8,
+            0xE18CF08C_33EC_4C0D_8246_C6C6FB3DA024,
+            0x8CCE0D25_C0D0_4A44_BD87_46331BF1DF67,
+            0x5C6E1C76_076A_457A_A0FE_F3B4CD21CE6E,
+        ],
+    ),
+    (
+        "arc",
+        [
+            0xD27F46ED_2919_4CB8_BD25_9531F3C16534,
+            0x24B2D975_0F97_4521_AFA1_CD531E421B8D,
+            0x143A70BA_CBD3_4F06_919F_6C05683A78BC,
+            0x7978A683_6316_4922_BBEE_38BFF5A2FECC,
+            0xFCA0598C_D880_4591_8C16_4EDA05C7347C,
+            0x94F9A9A1_9971_427A_A400_50CB297F0F35,
+        ],
+    ),
+    (
+        "arm",
+        [
+            0x69DAD710_2CE4_4E3C_B16C_21A1D49ABED3,
+            0x7386CDF2_203C_47A9_A498_F2ECCE45A2D6,
+            0x42B0455F_EB11_491D_98D3_56145BA9D037,
+            0x7D0359A3_02B3_4F0A_865C_654403E70625,
+            0xC215D751_7BCD_4649_BE90_6627490A4C05,
+            0xD7FF812F_37D1_4902_A810_D76BA57B975A,
+        ],
+    ),
+    (
+        "arm64",
+        [
+            0xB921B045_1DF0_41C3_AF44_4C6F280D3FAE,
+            0xDF3300CE_D69F_4C92_978C_9BFB0F38D820,
+            0x6DB69DE6_29F4_4758_A7A5_962190F00CE3,
+            0xB0E01050_EE5F_4390_949A_9101B17104E9,
+            0x6E11A4E7_FBCA_4DED_B9E9_E1A512BB664E,
+            0xC23CE4FF_44BD_4B00_B2D4_B41B3419E02A,
+        ],
+    ),
+    (
+        "ia64",
+        [
+            0x993D8D3D_F80E_4225_855A_9DAF8ED7EA97,
+            0x86ED10D5_B607_45BB_8957_D350F23D0571,
+            0xE98B36EE_32BA_4882_9B12_0CE14655F46A,
+            0x4301D2A6_4E3B_4B2A_BB94_9E0B2C4225EA,
+            0x6A491E03_3BE7_4545_8E38_83320E0EA880,
+            0x8DE58BC2_2A43_460D_B14E_A76E4A17B47F,
+        ],
+    ),
+    (
+        "loongarch64",
+        [
+            0x77055800_792C_4F94_B39A_98C91B762BB6,
+            0xF3393B22_E9AF_4613_A948_9D3BFBD0C535,
+            0x5AFB67EB_ECC8_4F85_AE8E_AC1E7C50E7D0,
+            0xE611C702_575C_4CBE_9A46_434FA0BF7E3F,
+            0xF46B2C26_59AE_48F0_9106_C50ED47F673D,
+            0xB024F315_D330_444C_8461_44BBDE524E99,
+        ],
+    ),
+    (
+        "mips",
+        [
+            0xE9434544_6E2C_47CC_BAE2_12D6DEAFB44C,
+            0x7A430799_F711_4C7E_8E5B_1D685BD48607,
+            0xBBA210A2_9C5D_45EE_9E87_FF2CCBD002D0,
+            0x773B2ABC_2A99_4398_8BF5_03BAAC40D02B,
+            0x6E5A1BC8_D223_49B7_BCA8_37A5FCCEB996,
+            0x97AE158D_F216_497B_8057_F7F905770F54,
+        ],
+    ),
+    (
+        "mips64",
+        [
+            0xD113AF76_80EF_41B4_BDB6_0CFF4D3D4A25,
+            0x579536F8_6A33_4055_A95A_DF2D5E2C42A8,
+            0x43CE94D4_0F3D_4999_8250_B9DEAFD98E6E,
+            0x57E13958_7331_4365_8E6E_35EEEE17C61B,
+            0x81CF9D90_7458_4DF4_8DCF_C8A3A404F09B,
+            0x05816CE2_DD40_4AC6_A61D_37D32DC1BA7D,
+        ],
+    ),
+    (
+        "mips-le",
+        [
+            0x37C58C8A_D913_4156_A25F_48B1B64E07F0,
+            0xD7D150D2_2A04_4A33_8F12_16651205FF7B,
+            0xC919CC1F_4456_4EFF_918C_F75E94525CA5,
+            0x0F4868E9_9952_4706_979F_3ED3A473E947,
+            0x46B98D8D_B55C_4E8F_AAB3_37FCA7F80752,
+            0x3E23CA0B_A4BC_4B4E_8087_5AB6A26AA8A9,
+        ],
+    ),
+    (
+        "mips64-le",
+        [
+            0x700BDA43_7A34_4507_B179_EEB93D7A7CA3,
+            0x16B417F8_3E06_4F57_8DD2_9B5232F41AA6,
+            0x904E58EF_5C65_4A31_9C57_6AF5FC7C5DE7,
+            0xC97C1F32_BA06_40B4_9F22_236061B08AA8,
+            0x3C3D61FE_B5F3_414D_BB71_8739A694A4EF,
+            0xF2C2C7EE_ADCC_4351_B5C6_EE9816B66E16,
+        ],
+    ),
+    (
+        "parisc",
+        [
+            0x1AACDB3B_5444_4138_BD9E_E5C2239B2346,
+            0xD212A430_FBC5_49F9_A983_A7FEEF2B8D0E,
+            0x15DE6170_65D3_431C_916E_B0DCD8393F25,
+            0xDC4A4480_6917_4262_A4EC_DB9384949F25,
+            0x5843D618_EC37_48D7_9F12_CEA8E08768B2,
+            0x450DD7D1_3224_45EC_9CF2_A43A346D71EE,
+        ],
+    ),
+    (
+        "ppc",
+        [
+            0x1DE3F1EF_FA98_47B5_8DCD_4A860A654D78,
+            0x98CFE649_1588_46DC_B2F0_ADD147424925,
+            0x1B31B5AA_ADD9_463A_B2ED_BD467FC857E7,
+            0x7D14FEC5_CC71_415D_9D6C_06BF0B3C3EAF,
+            0xDF765D00_270E_49E5_BC75_F47BB2118B09,
+            0x7007891D_D371_4A80_86A4_5CB875B9302E,
+        ],
+    ),
+    (
+        "ppc64",
+        [
+            0x912ADE1D_A839_4913_8964_A10EEE08FBD2,
+            0x9225A9A3_3C19_4D89_B4F6_EEFF88F17631,
+            0xF5E2C20C_45B2_4FFA_BCE9_2A60737E1AAF,
+            0x2C9739E2_F068_46B3_9FD0_01C5A9AFBCCA,
+            0xBDB528A5_A259_475F_A87D_DA53FA736A07,
+            0x0B888863_D7F8_4D9E_9766_239FCE4D58AF,
+        ],
+    ),
+    (
+        "ppc64-le",
+        [
+            0xC31C45E6_3F39_412E_80FB_4809C4980599,
+            0x906BD944_4589_4AAE_A4E4_DD983917446A,
+            0xD4A236E7_E873_4C07_BF1D_BF6CF7F1C3C6,
+            0x15BB03AF_77E7_4D4A_B12B_C0D084F7491C,
+            0xEE2B9983_21E8_4153_86D9_B6901A54D1CE,
+            0xC8BFBD1E_268E_4521_8BBA_BF314C399557,
+        ],
+    ),
+    (
+        "riscv32",
+        [
+            0x60D5A7FE_8E7D_435C_B714_3DD8162144E1,
+            0xAE0253BE_1167_4007_AC68_43926C14C5DE,
+            0x3A112A75_8729_4380_B4CF_764D79934448,
+            0xB933FB22_5C3F_4F91_AF90_E2BB0FA50702,
+            0xCB1EE4E3_8CD0_4136_A0A4_AA61A32E8730,
+            0xC3836A13_3137_45BA_B583_B16C50FE5EB4,
+        ],
+    ),
+    (
+        "riscv64",
+        [
+            0x72EC70A6_CF74_40E6_BD49_4BDA08E8F224,
+            0xB6ED5582_440B_4209_B8DA_5FF7C419EA3D,
+            0xEFE0F087_EA8D_4469_821A_4C2A96A8386A,
+            0xBEAEC34B_8442_439B_A40B_984381ED097D,
+            0x8F1056BE_9B05_47C4_81D6_BE53128E5B54,
+            0xD2F9000A_7A18_453F_B5CD_4D32F77A7B32,
+        ],
+    ),
+    (
+        "s390",
+        [
+            0x08A7ACEA_624C_4A20_91E8_6E0FA67D23F9,
+            0x7AC63B47_B25C_463B_8DF8_B4A94E6C90E1,
+            0x3482388E_4254_435A_A241_766A065F9960,
+            0xCD0F869B_D0FB_4CA0_B141_9EA87CC78D66,
+            0xB663C618_E7BC_4D6D_90AA_11B756BB1797,
+            0x17440E4F_A8D0_467F_A46E_3912AE6EF2C5,
+        ],
+    ),
+    (
+        "s390x",
+        [
+            0x5EEAD9A9_FE09_4A1E_A1D7_520D00531306,
+            0xB325BFBE_C7BE_4AB8_8357_139E652D2F6B,
+            0xC80187A5_73A3_491A_901A_017C3FA953E9,
+            0x8A4F5770_50AA_4ED3_874A_99B710DB6FEA,
+            0x31741CC4_1A2A_4111_A581_E00B447D2D06,
+            0x3F324816_667B_46AE_86EE_9B0C0C6C11B4,
+        ],
+    ),
+    (
+        "tilegx",
+        [
+            0xC50CDD70_3862_4CC3_90E1_809A8C93EE2C,
+            0x966061EC_28E4_4B2E_B4A5_1F0A825A1D84,
+            0xB3671439_97B0_4A53_90F7_2D5A8F3AD47B,
+            0x55497029_C7C1_44CC_AA39_815ED1558630,
+            0x2FB4BF56_07FA_42DA_8132_6B139F2026AE,
+            0x4EDE75E2_6CCC_4CC8_B9C7_70334B087510,
+        ],
+    ),
+    (
+        "x86",
+        [
+            0x44479540_F297_41B2_9AF7_D131D5F0458A,
+            0xD13C5D3B_B5D1_422A_B29F_9454FDC89D76,
+            0x5996FC05_109C_48DE_808B_23FA0830B676,
+            0x75250D76_8CC6_458E_BD66_BD47CC81A812,
+            0x8F461B0D_14EE_4E81_9AA9_049B6FB97ABD,
+            0x974A71C0_DE41_43C3_BE5D_5C5CCD1AD2C0,
+        ],
+    ),
+    (
+        "x86-64",
+        [
+            0x4F68BCE3_E8CD_4DB1_96E7_FBCAF984B709,
+            0x2C7357ED_EBD2_46D9_AEC1_23D437EC2BF5,
+            0x41092B05_9FC8_4523_994F_2DEF0408B176,
+            0x8484680C_9521_48C6_9C11_B0720656F69E,
+            0x77FF5F63_E7B6_4633_ACF4_1565B864C0E6,
+            0xE7BB33FB_06CF_4E81_8273_E543B413E2E2,
+        ],
+    ),
+];
+
+const ARCH_PARTITION_KINDS: [&str; 6] = [
+    "root-{}",
+    "root-{}-verity",
+    "root-{}-verity-sig",
+    "usr-{}",
+    "usr-{}-verity",
+    "usr-{}-verity-sig",
+];
+
+/// Partition type, identified by name (see [PARTITION_TYPES] and
+/// [ARCH_PARTITION_TYPES]) or by an explicit type GUID
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+struct PartitionType {
+    name: String,
+    guid: Uuid,
+}
+
+impl TryFrom<String> for PartitionType {
+    type Error = anyhow::Error;
+
+    fn try_from(name: String) -> Result<Self> {
+        let guid = Self::lookup(&name)
+            .or_else(|| Uuid::parse_str(&name).ok())
+            .with_context(|| format!("'{name}' is not a known partition type or a guid"))?;
+        Ok(Self { name, guid })
+    }
+}
+
+impl PartitionType {
+    fn lookup(name: &str) -> Option<Uuid> {
+        if let Some((_, guid)) = PARTITION_TYPES.iter().find(|(n, _)| *n == name) {
+            return Some(Uuid::from_u128(*guid));
+        }
+        ARCH_PARTITION_TYPES.iter().find_map(|(arch, guids)| {
+            ARCH_PARTITION_KINDS
+                .iter()
+                .zip(guids)
+                .find(|(kind, _)| kind.replace("{}", arch) == name)
+                .map(|(_, guid)| Uuid::from_u128(*guid))
+        })
+    }
+
+    fn gpt_type(&self) -> Result<gpt::partition_types::Type> {
+        gpt::partition_types::Type::from_str(&self.guid.to_string())
+            .map_err(|e| anyhow!("invalid partition type guid {}: {e}", self.guid))
+    }
+
+    /// OS type byte used when this partition is mirrored in a hybrid MBR
+    fn mbr_os_type(&self) -> u8 {
+        match self.name.as_str() {
+            "esp" => 0xef,
+            "swap" => 0x82,
+            _ => 0x83,
+        }
+    }
+}
+
+/// GPT partition attribute bits
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
 #[serde(rename_all = "kebab-case")]
-enum PartitionType {
-    Esp,
-    Linux,
+enum PartitionAttribute {
+    /// BIOS bootable (like the MBR active flag)
+    LegacyBiosBootable,
+    /// Discoverable Partitions Specification: grow the filesystem to fill
+    /// the partition on first mount
+    GrowFs,
+    /// Discoverable Partitions Specification: mount read-only
+    ReadOnly,
+    /// Discoverable Partitions Specification: do not automatically mount
+    NoAuto,
+}
+
+impl PartitionAttribute {
+    fn bit(self) -> u64 {
+        match self {
+            Self::LegacyBiosBootable => 1 << 2,
+            Self::GrowFs => 1 << 59,
+            Self::ReadOnly => 1 << 60,
+            Self::NoAuto => 1 << 63,
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, Default, Deserialize)]
@@ -62,26 +417,109 @@ impl From<BlockSize> for gpt::disk::LogicalBlockSize {
     }
 }
 
-impl PartitionType {
-    fn gpt_type(&self) -> gpt::partition_types::Type {
-        match self {
-            Self::Esp => gpt::partition_types::EFI,
-            Self::Linux => gpt::partition_types::LINUX_FS,
-        }
-    }
-}
-
 #[derive(Debug, Clone, Deserialize)]
 struct Partition {
     src: PathBuf,
+    /// Manifest of the package that `src` came from, if it was built with one
+    #[serde(default)]
+    manifest: Option<PathBuf>,
     #[serde(rename = "type")]
     partition_type: PartitionType,
     name: Option<String>,
     alignment: Option<u64>,
+    verity: Option<PartitionVerity>,
+    /// Unique partition GUID (otherwise derived from the contents)
+    #[serde(default)]
+    guid: Option<Uuid>,
+    #[serde(default)]
+    attributes: BTreeSet<PartitionAttribute>,
+    /// Mirror this partition in the MBR when using [Mbr::Hybrid]
+    #[serde(default)]
+    hybrid_mbr: bool,
+}
+
+impl Partition {
+    fn flags(&self) -> u64 {
+        self.attributes.iter().fold(0, |flags, a| flags | a.bit())
+    }
+}
+
+/// Partition that is part of a dm-verity protected image
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PartitionVerity {
+    /// Params json written by the verity packager
+    params: PathBuf,
+    role: VerityRole,
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum VerityRole {
+    Data,
+    Hash,
+}
+
+impl PartitionVerity {
+    /// Following the Discoverable Partitions Specification, the data partition
+    /// UUID is the first 128 bits of the root hash and the hash partition UUID
+    /// is the last 128 bits, so that the pair can be found from just the root
+    /// hash.
+    fn partition_guid(&self) -> Result<Uuid> {
+        let params: VerityParams = serde_json::from_reader(
+            File::open(&self.params).context("while opening verity params")?,
+        )
+        .context("while parsing verity params")?;
+        let root_hash = params.root_hash_bytes()?;
+        if root_hash.len() < 32 {
+            return Err(anyhow!("root hash is too short to derive partition uuids"));
+        }
+        let bytes = match self.role {
+            VerityRole::Data => &root_hash[..16],
+            VerityRole::Hash => &root_hash[root_hash.len() - 16..],
+        };
+        Ok(Uuid::from_slice(bytes)?)
+    }
+}
+
+/// MBR partition record for an LBA range, using the conventional "too big for
+/// CHS" values for the CHS fields
+fn mbr_record(
+    os_type: u8,
+    bootable: bool,
+    first_lba: u64,
+    last_lba: u64,
+) -> Result<gpt::mbr::PartRecord> {
+    Ok(gpt::mbr::PartRecord {
+        boot_indicator: if bootable { 0x80 } else { 0 },
+        start_head: 0xfe,
+        start_sector: 0xff,
+        start_track: 0xff,
+        os_type,
+        end_head: 0xfe,
+        end_sector: 0xff,
+        end_track: 0xff,
+        lb_start: u32::try_from(first_lba)
+            .context("partition starts beyond what MBR can address")?,
+        lb_size: u32::try_from(last_lba - first_lba + 1)
+            .context("partition is too large for MBR")?,
+    })
 }
 
 impl Gpt {
-    pub(crate) fn build(&self, out: &Path) -> Result<()> {
+    pub(crate) fn build(&self, out: &Path, manifest: Option<&mut Manifest>) -> Result<()> {
+        let hybrid = self.partitions.iter().filter(|p| p.hybrid_mbr).count();
+        match self.mbr {
+            Mbr::Hybrid => ensure!(
+                (1..=3).contains(&hybrid),
+                "hybrid mbr needs between 1 and 3 partitions marked with hybrid_mbr, not {hybrid}"
+            ),
+            _ => ensure!(
+                hybrid == 0,
+                "partitions can only be marked with hybrid_mbr when using a hybrid mbr"
+            ),
+        }
+
         // 2mb of scratch space for gpt headers
         let mut total_size = ByteSize::mb(2);
         for partition in &self.partitions {
@@ -118,11 +556,18 @@ impl Gpt {
             .context("while opening second fd")?;
 
         // Create a protective MBR at LBA0
-        let mbr = gpt::mbr::ProtectiveMBR::with_lb_size(
+        let mut mbr = gpt::mbr::ProtectiveMBR::with_lb_size(
             u32::try_from((total_size.as_u64() / self.block_size.as_u64()) - 1)
                 .context("while converting size to u32")?,
         );
-        mbr.overwrite_lba0(&mut file).context("while writing mbr")?;
+        if self.mbr != Mbr::None {
+            mbr.overwrite_lba0(&mut file).context("while writing mbr")?;
+        }
+
+        // the partition table itself is full of random guids, so derive them
+        // from the partition contents instead
+        let digest = Digest::of_files(self.partitions.iter().map(|p| &p.src))
+            .context("while hashing partitions")?;
 
         let mut gdisk = gpt::GptConfig::default()
             .writable(true)
@@ -130,29 +575,54 @@ impl Gpt {
             .create_from_device(Box::new(file), None)
             .context("while creating new gpt")?;
 
-        if let Some(guid) = self.disk_guid {
-            gdisk.update_guid(Some(
-                guid.to_string().parse().context("while re-parsing uuid")?,
-            ))
-        }
+        let disk_guid = self.disk_guid.unwrap_or_else(|| digest.uuid("gpt disk"));
+        gdisk.update_guid(Some(
+            disk_guid
+                .to_string()
+                .parse()
+                .context("while re-parsing uuid")?,
+        ));
 
-        for partition in self.partitions.iter() {
+        let mut guids = Vec::new();
+        let mut hybrid_records = Vec::new();
+        for (idx, partition) in self.partitions.iter().enumerate() {
             let src_size = ByteSize::b(partition.src.metadata()?.len());
             let id = gdisk
                 .add_partition(
                     partition.name.as_deref().unwrap_or_default(),
                     src_size.as_u64(),
-                    partition.partition_type.gpt_type(),
-                    0,
+                    partition.partition_type.gpt_type()?,
+                    partition.flags(),
                     partition
                         .alignment
                         .map(|alignment| alignment / self.block_size.as_u64()),
                 )
                 .with_context(|| format!("while adding partition {partition:?}"))?;
+            guids.push((
+                id,
+                match (&partition.verity, partition.guid) {
+                    (Some(_), Some(_)) => {
+                        bail!("verity partitions cannot have an explicit guid")
+                    }
+                    (Some(verity), None) => verity.partition_guid()?,
+                    (None, Some(guid)) => guid,
+                    (None, None) => digest.uuid(&format!("gpt partition {idx}")),
+                },
+            ));
             let part = gdisk
                 .partitions()
                 .get(&id)
                 .context("while reading back partition")?;
+            if partition.hybrid_mbr {
+                hybrid_records.push(mbr_record(
+                    partition.partition_type.mbr_os_type(),
+                    partition
+                        .attributes
+                        .contains(&PartitionAttribute::LegacyBiosBootable),
+                    part.first_lba,
+                    part.last_lba,
+                )?);
+            }
             let start = part
                 .bytes_start(self.block_size.into())
                 .context("while computing bytes offset")?;
@@ -164,8 +634,161 @@ impl Gpt {
                 .context("while copying partition contents")?;
         }
 
+        let mut partitions = gdisk.take_partitions();
+        for (id, guid) in guids {
+            partitions
+                .get_mut(&id)
+                .context("partition disappeared")?
+                .part_guid = guid.to_string().parse().context("while re-parsing uuid")?;
+        }
+        let first_partition_lba = partitions
+            .values()
+            .map(|p| p.first_lba)
+            .min()
+            .context("gpt has no partitions")?;
+        gdisk
+            .update_partitions(partitions)
+            .context("while updating partition guids")?;
+
         gdisk.write().context("while writing partition table")?;
 
+        if self.mbr == Mbr::Hybrid {
+            // the 0xEE partition only covers the gpt itself, so that the
+            // hybrid partitions are the only thing that MBR-only tools see
+            mbr.set_partition(0, mbr_record(0xee, false, 1, first_partition_lba - 1)?);
+            for (idx, record) in hybrid_records.into_iter().enumerate() {
+                mbr.set_partition(idx + 1, record);
+            }
+            mbr.overwrite_lba0(&mut file_for_writing_contents)
+                .context("while writing hybrid mbr")?;
+        }
+
+        // each partition is listed under its partition number, either with
+        // everything in it (if it came with a manifest) or as a single file
+        if let Some(manifest) = manifest {
+            for (idx, partition) in self.partitions.iter().enumerate() {
+                let number = PathBuf::from((idx + 1).to_string());
+                match &partition.manifest {
+                    Some(partition_manifest) => {
+                        manifest.nest(&number, Manifest::read(partition_manifest)?)
+                    }
+                    None => manifest.add_file(&number, &partition.src)?,
+                }
+            }
+        }
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn partition_types() {
+        let guid = |name: &str| {
+            PartitionType::try_from(name.to_owned())
+                .expect("valid partition type")
+                .guid
+                .to_string()
+        };
+        assert_eq!(guid("esp"), "c12a7328-f81f-11d2-ba4b-00a0c93ec93b");
+        assert_eq!(guid("root-x86-64"), "4f68bce3-e8cd-4db1-96e7-fbcaf984b709");
+        assert_eq!(
+            guid("root-x86-64-verity"),
+            "2c7357ed-ebd2-46d9-aec1-23d437ec2bf5"
+        );
+        assert_eq!(
+            guid("usr-arm64-verity-sig"),
+            "c23ce4ff-44bd-4b00-b2d4-b41b3419e02a"
+        );
+        assert_eq!(
+            guid("root-mips64-le"),
+            "700bda43-7a34-4507-b179-eeb93d7a7ca3"
+        );
+        assert_eq!(
+            guid("01234567-89ab-cdef-0123-456789abcdef"),
+            "01234567-89ab-cdef-0123-456789abcdef"
+        );
+        assert!(PartitionType::try_from("root".to_owned()).is_err());
+        assert!(PartitionType::try_from("root-x86-64-verity-sig-foo".to_owned()).is_err());
+    }
+
+    #[test]
+    fn hybrid_mbr_and_attributes() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let esp = dir.path().join("esp");
+        std::fs::write(&esp, vec![1; 1024 * 1024]).expect("failed to write esp");
+        let root = dir.path().join("root");
+        std::fs::write(&root, vec![2; 1024 * 1024]).expect("failed to write root");
+        let spec: Gpt = serde_json::from_value(serde_json::json!({
+            "disk_guid": null,
+            "mbr": "hybrid",
+            "partitions": [
+                {
+                    "src": esp,
+                    "type": "esp",
+                    "name": "ESP",
+                    "alignment": null,
+                    "verity": null,
+                    "attributes": ["legacy-bios-bootable"],
+                    "hybrid_mbr": true,
+                },
+                {
+                    "src": root,
+                    "type": "root-x86-64",
+                    "name": "root",
+                    "alignment": null,
+                    "verity": null,
+                    "guid": "01234567-89ab-cdef-0123-456789abcdef",
+                    "attributes": ["read-only", "grow-fs"],
+                },
+            ],
+        }))
+        .expect("invalid spec");
+        let out = dir.path().join("out.gpt");
+        spec.build(&out, None).expect("failed to build");
+
+        let disk = gpt::GptConfig::default()
+            .open(&out)
+            .expect("failed to read gpt");
+        let partitions: Vec<_> = disk.partitions().values().cloned().collect();
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[0].part_type_guid, gpt::partition_types::EFI);
+        assert_eq!(partitions[0].flags, 1 << 2);
+        assert_eq!(
+            partitions[1].part_type_guid,
+            gpt::partition_types::LINUX_ROOT_X64
+        );
+        assert_eq!(partitions[1].flags, (1 << 60) | (1 << 59));
+        assert_eq!(
+            partitions[1].part_guid.to_string(),
+            "01234567-89ab-cdef-0123-456789abcdef"
+        );
+
+        let mut lba0 = [0; 512];
+        File::open(&out)
+            .expect("failed to open output")
+            .read_exact(&mut lba0)
+            .expect("failed to read lba0");
+        assert_eq!(&lba0[510..], &[0x55, 0xaa]);
+        let record = |idx: usize| &lba0[446 + idx * 16..446 + (idx + 1) * 16];
+        let lba = |r: &[u8], off: usize| u32::from_le_bytes(r[off..off + 4].try_into().unwrap());
+        // protective partition covering just the gpt
+        assert_eq!(record(0)[4], 0xee);
+        assert_eq!(lba(record(0), 8), 1);
+        assert_eq!(u64::from(lba(record(0), 12)), partitions[0].first_lba - 1);
+        // the esp, marked as active
+        assert_eq!(record(1)[0], 0x80);
+        assert_eq!(record(1)[4], 0xef);
+        assert_eq!(u64::from(lba(record(1), 8)), partitions[0].first_lba);
+        assert_eq!(
+            u64::from(lba(record(1), 12)),
+            partitions[0].last_lba - partitions[0].first_lba + 1
+        );
+        assert_eq!(record(2), &[0; 16]);
+    }
+}
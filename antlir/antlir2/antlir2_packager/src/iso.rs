@@ -0,0 +1,123 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use std::fs::File;
+use std::path::Path;
+use std::path::PathBuf;
+
+use antlir2_isolate::unshare;
+use antlir2_isolate::IsolationContext;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::manifest::Manifest;
+use crate::reproducible;
+use crate::run_cmd;
+use crate::BuildAppliance;
+use crate::PackageFormat;
+
+/// Where the EFI boot image is placed in the ISO
+const EFI_BOOT_IMAGE: &str = "efiboot.img";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Iso {
+    build_appliance: BuildAppliance,
+    volume_label: Option<String>,
+    /// Path of the El Torito boot catalog in the ISO
+    boot_catalog: String,
+    /// No-emulation BIOS boot image in the layer (like isolinux.bin)
+    bios_boot_image: Option<PathBuf>,
+    bios_boot_load_size: u16,
+    /// Patch the BIOS boot image with a boot info table (required by isolinux)
+    bios_boot_info_table: bool,
+    /// FAT filesystem image (like a vfat package) for the UEFI boot entry
+    efi_boot_image: Option<PathBuf>,
+}
+
+impl PackageFormat for Iso {
+    fn build(&self, out: &Path, layer: &Path, manifest: Option<&mut Manifest>) -> Result<()> {
+        File::create(out).context("failed to create output file")?;
+
+        let epoch = reproducible::source_date_epoch()?.to_string();
+        let mut isol_context = IsolationContext::builder(self.build_appliance.path());
+        isol_context
+            .ephemeral(false)
+            .readonly()
+            .tmpfs(Path::new("/__antlir2__/out"))
+            .outputs(("/__antlir2__/out/iso", out))
+            // xorriso uses this for the volume uuid and every file timestamp
+            .setenv(("SOURCE_DATE_EPOCH", epoch.as_str()))
+            .inputs((Path::new("/__antlir2__/root"), layer))
+            .inputs((
+                PathBuf::from("/__antlir2__/working_directory"),
+                std::env::current_dir()?,
+            ))
+            .working_directory(Path::new("/__antlir2__/working_directory"));
+        if let Some(efi_boot_image) = &self.efi_boot_image {
+            isol_context.inputs((
+                Path::new("/__antlir2__/efiboot.img"),
+                efi_boot_image.as_path(),
+            ));
+        }
+        let isol_context = isol_context.build();
+
+        let mut xorriso = unshare(isol_context)?.command("/usr/bin/xorriso")?;
+        xorriso
+            .arg("-as")
+            .arg("mkisofs")
+            .arg("-o")
+            .arg("/__antlir2__/out/iso")
+            // Rock Ridge (with the layer's ownership and permissions) for
+            // unix-like systems and Joliet for everything else
+            .arg("-R")
+            .arg("-J")
+            .arg("-joliet-long")
+            .arg("-graft-points");
+        if let Some(label) = &self.volume_label {
+            xorriso.arg("-V").arg(label);
+        }
+
+        if self.bios_boot_image.is_some() || self.efi_boot_image.is_some() {
+            xorriso.arg("-c").arg(&self.boot_catalog);
+        }
+        if let Some(image) = &self.bios_boot_image {
+            xorriso
+                .arg("-b")
+                .arg(image.strip_prefix("/").unwrap_or(image))
+                .arg("-no-emul-boot")
+                .arg("-boot-load-size")
+                .arg(self.bios_boot_load_size.to_string());
+            if self.bios_boot_info_table {
+                xorriso.arg("-boot-info-table");
+            }
+        }
+        if self.efi_boot_image.is_some() {
+            if self.bios_boot_image.is_some() {
+                xorriso.arg("-eltorito-alt-boot");
+            }
+            xorriso.arg("-e").arg(EFI_BOOT_IMAGE).arg("-no-emul-boot");
+        }
+
+        xorriso.arg("/=/__antlir2__/root");
+        if self.efi_boot_image.is_some() {
+            xorriso.arg(format!("/{EFI_BOOT_IMAGE}=/__antlir2__/efiboot.img"));
+        }
+
+        run_cmd(&mut xorriso).context("while running xorriso")?;
+
+        if let Some(manifest) = manifest {
+            manifest.add_tree(layer)?;
+            if let Some(efi_boot_image) = &self.efi_boot_image {
+                manifest.add_file(Path::new(EFI_BOOT_IMAGE), efi_boot_image)?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -10,32 +10,43 @@ use std::path::PathBuf;
 use std::process::Command;
 
 use anyhow::anyhow;
+use anyhow::ensure;
 use anyhow::Context;
 use anyhow::Result;
 use clap::Parser;
 use json_arg::JsonFile;
 
 mod btrfs;
+mod chunk_store;
+mod composefs;
+mod compression;
 mod cpio;
+mod deb;
 mod docker_archive;
 mod erofs;
 mod ext;
 mod gpt;
+mod iso;
+mod manifest;
 mod oci;
+mod reproducible;
 mod rpm;
 mod sendstream;
 mod spec;
 mod squashfs;
 mod tar;
+mod uki;
 mod unprivileged_dir;
+mod verity;
 mod vfat;
 mod xar;
+use manifest::Manifest;
 use spec::Spec;
 mod build_appliance;
 pub(crate) use build_appliance::BuildAppliance;
 
 pub(crate) trait PackageFormat {
-    fn build(&self, out: &Path, layer: &Path) -> Result<()>;
+    fn build(&self, out: &Path, layer: &Path, manifest: Option<&mut Manifest>) -> Result<()>;
 }
 
 #[derive(Parser, Debug)]
@@ -54,6 +65,9 @@ pub(crate) struct PackageArgs {
     out: PathBuf,
     #[clap(long)]
     rootless: bool,
+    #[clap(long)]
+    /// Also write a json listing of everything that was packaged here
+    manifest: Option<PathBuf>,
 }
 
 pub(crate) fn run_cmd(command: &mut Command) -> Result<std::process::Output> {
@@ -83,6 +97,12 @@ fn main() -> Result<()> {
     if !args.dir {
         std::fs::File::create(&args.out)?;
     }
+    let manifest_out = args
+        .manifest
+        .as_deref()
+        .map(std::fs::File::create)
+        .transpose()
+        .context("while creating manifest")?;
 
     if args.rootless {
         antlir2_rootless::unshare_new_userns().context("while setting up userns")?;
@@ -100,24 +120,111 @@ fn main() -> Result<()> {
 
     let layer = args.layer.as_deref();
 
+    // every packager records what it packages as it goes, so that nothing
+    // has to be walked (or read) a second time
+    let mut manifest = manifest_out.as_ref().map(|_| Manifest::default());
+    let m = manifest.as_mut();
+
     match args.spec.into_inner() {
-        Spec::Btrfs(p) => p.build(&args.out),
-        Spec::Cpio(p) => p.build(&args.out, layer.context("layer required for this format")?),
-        Spec::DockerArchive(p) => p.build(&args.out),
-        Spec::Erofs(p) => p.build(&args.out, layer.context("layer required for this format")?),
-        Spec::Ext3(p) => p.build(&args.out, layer.context("layer required for this format")?),
-        Spec::Gpt(p) => p.build(&args.out),
-        Spec::Oci(p) => p.build(&args.out),
-        Spec::Rpm(p) => p.build(&args.out, layer.context("layer required for this format")?),
-        Spec::Sendstream(p) => p.build(&args.out, layer.context("layer required for this format")?),
-        Spec::Squashfs(p) => p.build(&args.out, layer.context("layer required for this format")?),
-        Spec::Tar(p) => p.build(&args.out, layer.context("layer required for this format")?),
+        Spec::Btrfs(p) => p.build(&args.out, m),
+        Spec::ChunkStore(p) => p.build(
+            &args.out,
+            layer.context("layer required for this format")?,
+            m,
+        ),
+        Spec::Composefs(p) => p.build(
+            &args.out,
+            layer.context("layer required for this format")?,
+            m,
+        ),
+        Spec::Cpio(p) => p.build(
+            &args.out,
+            layer.context("layer required for this format")?,
+            m,
+        ),
+        Spec::Deb(p) => p.build(
+            &args.out,
+            layer.context("layer required for this format")?,
+            m,
+        ),
+        Spec::DockerArchive(p) => {
+            ensure!(
+                m.is_none(),
+                "docker-archive manifests are the manifest of their oci image, not built here"
+            );
+            p.build(&args.out)
+        }
+        Spec::Erofs(p) => p.build(
+            &args.out,
+            layer.context("layer required for this format")?,
+            m,
+        ),
+        Spec::Ext3(p) => p.build(
+            &args.out,
+            layer.context("layer required for this format")?,
+            m,
+        ),
+        Spec::Ext4(p) => p.build(
+            &args.out,
+            layer.context("layer required for this format")?,
+            m,
+        ),
+        Spec::Gpt(p) => p.build(&args.out, m),
+        Spec::Iso(p) => p.build(
+            &args.out,
+            layer.context("layer required for this format")?,
+            m,
+        ),
+        Spec::Oci(p) => p.build(&args.out, m),
+        Spec::Rpm(p) => p.build(
+            &args.out,
+            layer.context("layer required for this format")?,
+            m,
+        ),
+        Spec::Sendstream(p) => p.build(
+            &args.out,
+            layer.context("layer required for this format")?,
+            m,
+        ),
+        Spec::Squashfs(p) => p.build(
+            &args.out,
+            layer.context("layer required for this format")?,
+            m,
+        ),
+        Spec::Tar(p) => p.build(
+            &args.out,
+            layer.context("layer required for this format")?,
+            m,
+        ),
+        Spec::Uki(p) => p.build(
+            &args.out,
+            layer.context("layer required for this format")?,
+            m,
+        ),
         Spec::UnprivilegedDir(p) => p.build(
             &args.out,
             layer.context("layer required for this format")?,
             root_guard,
+            m,
         ),
-        Spec::Vfat(p) => p.build(&args.out, layer.context("layer required for this format")?),
-        Spec::Xar(p) => p.build(&args.out),
+        Spec::Verity(p) => p.build(&args.out, m),
+        Spec::Vfat(p) => p.build(
+            &args.out,
+            layer.context("layer required for this format")?,
+            m,
+        ),
+        Spec::Xar(p) => {
+            ensure!(
+                m.is_none(),
+                "xar manifests are the manifest of their squashfs, not built here"
+            );
+            p.build(&args.out)
+        }
+    }?;
+
+    if let (Some(manifest), Some(manifest_out)) = (manifest, manifest_out) {
+        manifest.write(manifest_out)?;
     }
+
+    Ok(())
 }
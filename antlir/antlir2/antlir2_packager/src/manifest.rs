@@ -0,0 +1,690 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Machine-readable listing of everything that went into a package, so that
+//! two packages can be compared without having to unpack (or mount) them.
+//!
+//! Packagers record entries as they walk whatever they are packaging, and
+//! pass along the sha256 of any file contents that they read anyway, so that
+//! nothing is read a second time just for the manifest.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs::File;
+use std::fs::Metadata;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+use nix::sys::stat::makedev;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest as _;
+use sha2::Sha256;
+use tar::EntryType;
+use walkdir::WalkDir;
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+#[derive(Debug, Default)]
+pub(crate) struct Manifest {
+    /// Sorted by path, which is the same order that a sorted walk visits
+    /// them in
+    entries: BTreeMap<PathBuf, Entry>,
+    /// Regular files whose contents the packager never read, with where to
+    /// find them and their (dev, ino). These are hashed when the manifest is
+    /// written.
+    unhashed: Vec<(PathBuf, PathBuf, (u64, u64))>,
+    /// Hex sha256 of the contents of every (dev, ino) that has been hashed,
+    /// so that hardlinks are only read once
+    hashed: HashMap<(u64, u64), String>,
+}
+
+/// What is actually written out
+#[derive(Debug, Serialize, Deserialize)]
+struct Listing {
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    /// Absolute path inside the package
+    path: PathBuf,
+    #[serde(rename = "type")]
+    file_type: FileType,
+    /// Permission bits (including setuid/setgid/sticky) in octal
+    mode: String,
+    uid: u32,
+    gid: u32,
+    /// Directory sizes depend on the filesystem they're on, so they're left
+    /// out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    symlink_target: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rdev: Option<u64>,
+    /// Hex-encoded xattr values
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    xattrs: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum FileType {
+    File,
+    Directory,
+    Symlink,
+    BlockDevice,
+    CharDevice,
+    Fifo,
+    Socket,
+}
+
+impl FileType {
+    fn of(meta: &Metadata) -> Self {
+        let ft = meta.file_type();
+        if ft.is_dir() {
+            Self::Directory
+        } else if ft.is_symlink() {
+            Self::Symlink
+        } else if ft.is_block_device() {
+            Self::BlockDevice
+        } else if ft.is_char_device() {
+            Self::CharDevice
+        } else if ft.is_fifo() {
+            Self::Fifo
+        } else if ft.is_socket() {
+            Self::Socket
+        } else {
+            Self::File
+        }
+    }
+}
+
+impl Manifest {
+    /// Record an entry that the packager came across at `relpath` under
+    /// `root`. If the packager read the contents of a regular file, it passes
+    /// the hasher that it fed them to, otherwise the file is hashed when the
+    /// manifest is written.
+    pub(crate) fn add(
+        &mut self,
+        root: &Path,
+        relpath: &Path,
+        meta: &Metadata,
+        sha256: Option<Sha256>,
+    ) -> Result<()> {
+        let full_path = root.join(relpath);
+        let file_type = FileType::of(meta);
+        let path = Path::new("/").join(relpath);
+        let inode = (meta.dev(), meta.ino());
+        let sha256 = match (file_type, sha256) {
+            (FileType::File, Some(hasher)) => {
+                let sha256 = hex::encode(hasher.finalize());
+                self.hashed.insert(inode, sha256.clone());
+                Some(sha256)
+            }
+            (FileType::File, None) => match self.hashed.get(&inode) {
+                Some(sha256) => Some(sha256.clone()),
+                None => {
+                    self.unhashed.push((path.clone(), full_path.clone(), inode));
+                    None
+                }
+            },
+            _ => None,
+        };
+        let symlink_target = match file_type {
+            FileType::Symlink => Some(
+                std::fs::read_link(&full_path)
+                    .with_context(|| format!("while reading link {}", full_path.display()))?,
+            ),
+            _ => None,
+        };
+        let mut xattrs = BTreeMap::new();
+        for name in xattr::list(&full_path)
+            .with_context(|| format!("while listing xattrs on {}", full_path.display()))?
+        {
+            let value = xattr::get(&full_path, &name)
+                .with_context(|| format!("while reading xattrs on {}", full_path.display()))?
+                .unwrap_or_default();
+            xattrs.insert(name.to_string_lossy().into_owned(), hex::encode(value));
+        }
+        self.entries.insert(
+            path.clone(),
+            Entry {
+                path,
+                file_type,
+                mode: format!("{:04o}", meta.mode() & 0o7777),
+                uid: meta.uid(),
+                gid: meta.gid(),
+                size: match file_type {
+                    FileType::Directory => None,
+                    _ => Some(meta.len()),
+                },
+                sha256,
+                symlink_target,
+                rdev: match file_type {
+                    FileType::BlockDevice | FileType::CharDevice => Some(meta.rdev()),
+                    _ => None,
+                },
+                xattrs,
+            },
+        );
+        Ok(())
+    }
+
+    /// Record everything under `root`, for packagers that hand the whole tree
+    /// off to some other tool and never walk it themselves
+    pub(crate) fn add_tree(&mut self, root: &Path) -> Result<()> {
+        for entry in WalkDir::new(root).sort_by_file_name() {
+            let entry = entry.with_context(|| format!("while walking {}", root.display()))?;
+            let relpath = entry.path().strip_prefix(root)?;
+            let meta = entry.metadata()?;
+            self.add(root, relpath, &meta, None)?;
+        }
+        Ok(())
+    }
+
+    /// Record a single file (like a partition image) at `path` in the
+    /// package
+    pub(crate) fn add_file(&mut self, path: &Path, file: &Path) -> Result<()> {
+        let meta = std::fs::metadata(file)
+            .with_context(|| format!("while statting {}", file.display()))?;
+        let path = Path::new("/").join(path);
+        self.unhashed
+            .push((path.clone(), file.to_owned(), (meta.dev(), meta.ino())));
+        self.insert_file(path, meta.len(), None);
+        Ok(())
+    }
+
+    /// Record a file that the packager generated (or assembled) at `path` in
+    /// the package, with the hasher that its contents were fed to
+    pub(crate) fn add_generated(&mut self, path: &Path, size: u64, sha256: Sha256) {
+        self.insert_file(
+            Path::new("/").join(path),
+            size,
+            Some(hex::encode(sha256.finalize())),
+        );
+    }
+
+    fn insert_file(&mut self, path: PathBuf, size: u64, sha256: Option<String>) {
+        self.entries.insert(
+            path.clone(),
+            Entry {
+                path,
+                file_type: FileType::File,
+                mode: "0444".to_owned(),
+                uid: 0,
+                gid: 0,
+                size: Some(size),
+                sha256,
+                symlink_target: None,
+                rdev: None,
+                xattrs: BTreeMap::new(),
+            },
+        );
+    }
+
+    /// Apply an entry from an OCI layer tarball on top of everything that
+    /// has been recorded so far, including deleting anything that it whites
+    /// out. Packagers that read the contents of a regular file pass the
+    /// hasher that they fed them to.
+    pub(crate) fn add_tar_entry<R: Read>(
+        &mut self,
+        entry: &mut tar::Entry<'_, R>,
+        sha256: Option<Sha256>,
+    ) -> Result<()> {
+        let raw_path = entry.path().context("while reading entry path")?;
+        let path = Path::new("/").join(
+            raw_path
+                .components()
+                .filter(|c| matches!(c, Component::Normal(_)))
+                .collect::<PathBuf>(),
+        );
+        if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+            let parent = path.parent().unwrap_or(Path::new("/")).to_owned();
+            if name == OPAQUE_WHITEOUT {
+                self.remove_children(&parent);
+                return Ok(());
+            }
+            if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+                self.remove(&parent.join(hidden));
+                return Ok(());
+            }
+        }
+
+        let mut xattrs = BTreeMap::new();
+        if let Some(pax) = entry
+            .pax_extensions()
+            .context("while reading pax headers")?
+        {
+            for ext in pax {
+                let ext = ext.context("while reading pax header")?;
+                if let Some(name) = ext.key().ok().and_then(|k| k.strip_prefix("SCHILY.xattr.")) {
+                    xattrs.insert(name.to_owned(), hex::encode(ext.value_bytes()));
+                }
+            }
+        }
+        let header = entry.header().clone();
+        if header.entry_type() == EntryType::Link {
+            let target = entry
+                .link_name()
+                .context("while reading hardlink target")?
+                .context("hardlink has no target")?;
+            let target = Path::new("/").join(
+                target
+                    .components()
+                    .filter(|c| matches!(c, Component::Normal(_)))
+                    .collect::<PathBuf>(),
+            );
+            let mut linked = self
+                .entries
+                .get(&target)
+                .with_context(|| format!("hardlink target {} is missing", target.display()))?
+                .clone();
+            linked.path = path.clone();
+            self.entries.insert(path, linked);
+            return Ok(());
+        }
+        let file_type = match header.entry_type() {
+            EntryType::Directory => FileType::Directory,
+            EntryType::Symlink => FileType::Symlink,
+            EntryType::Block => FileType::BlockDevice,
+            EntryType::Char => FileType::CharDevice,
+            EntryType::Fifo => FileType::Fifo,
+            _ => FileType::File,
+        };
+        let symlink_target = match file_type {
+            FileType::Symlink => Some(
+                entry
+                    .link_name()
+                    .context("while reading symlink target")?
+                    .context("symlink has no target")?
+                    .into_owned(),
+            ),
+            _ => None,
+        };
+        let size = match file_type {
+            FileType::Directory => None,
+            FileType::File => Some(entry.size()),
+            // the same as what lstat reports
+            FileType::Symlink => symlink_target
+                .as_ref()
+                .map(|t| t.as_os_str().as_bytes().len() as u64),
+            _ => Some(0),
+        };
+        let sha256 = match file_type {
+            FileType::File => {
+                let hasher = match sha256 {
+                    Some(hasher) => hasher,
+                    None => {
+                        let mut hasher = Sha256::new();
+                        std::io::copy(entry, &mut hasher).context("while hashing entry")?;
+                        hasher
+                    }
+                };
+                Some(hex::encode(hasher.finalize()))
+            }
+            _ => None,
+        };
+        let rdev = match file_type {
+            FileType::BlockDevice | FileType::CharDevice => Some(makedev(
+                header.device_major()?.unwrap_or(0).into(),
+                header.device_minor()?.unwrap_or(0).into(),
+            )),
+            _ => None,
+        };
+        // a directory that's in multiple layers keeps its children, anything
+        // else replaces whatever was there
+        if file_type != FileType::Directory {
+            self.remove_children(&path);
+        }
+        self.entries.insert(
+            path.clone(),
+            Entry {
+                path,
+                file_type,
+                mode: format!("{:04o}", header.mode()? & 0o7777),
+                uid: header.uid()?.try_into().context("uid is too large")?,
+                gid: header.gid()?.try_into().context("gid is too large")?,
+                size,
+                sha256,
+                symlink_target,
+                rdev,
+                xattrs,
+            },
+        );
+        Ok(())
+    }
+
+    /// Replace the ownership of everything recorded so far, for packagers
+    /// that can squash it
+    pub(crate) fn force_owner(&mut self, uid: Option<u32>, gid: Option<u32>) {
+        for entry in self.entries.values_mut() {
+            if let Some(uid) = uid {
+                entry.uid = uid;
+            }
+            if let Some(gid) = gid {
+                entry.gid = gid;
+            }
+        }
+    }
+
+    /// Forget `path` and, if it is a directory, everything underneath it
+    fn remove(&mut self, path: &Path) {
+        self.entries.retain(|p, _| !p.starts_with(path));
+        self.unhashed.retain(|(p, _, _)| !p.starts_with(path));
+    }
+
+    fn remove_children(&mut self, dir: &Path) {
+        self.entries
+            .retain(|path, _| path == dir || !path.starts_with(dir));
+        self.unhashed
+            .retain(|(path, _, _)| path == dir || !path.starts_with(dir));
+    }
+
+    /// Include everything from another package's manifest, under `prefix`
+    pub(crate) fn nest(&mut self, prefix: &Path, other: Self) {
+        let prefix = Path::new("/").join(prefix);
+        for (path, mut entry) in other.entries {
+            let path = prefix.join(path.strip_prefix("/").unwrap_or(&path));
+            entry.path = path.clone();
+            self.entries.insert(path, entry);
+        }
+        for (path, file, inode) in other.unhashed {
+            let path = prefix.join(path.strip_prefix("/").unwrap_or(&path));
+            self.unhashed.push((path, file, inode));
+        }
+        self.hashed.extend(other.hashed);
+    }
+
+    /// Read a manifest that was written by another package
+    pub(crate) fn read(path: &Path) -> Result<Self> {
+        let listing: Listing = serde_json::from_reader(BufReader::new(
+            File::open(path).with_context(|| format!("while opening {}", path.display()))?,
+        ))
+        .with_context(|| format!("while parsing manifest {}", path.display()))?;
+        Ok(Self {
+            entries: listing
+                .entries
+                .into_iter()
+                .map(|e| (e.path.clone(), e))
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    pub(crate) fn write(mut self, out: File) -> Result<()> {
+        for (path, file, inode) in std::mem::take(&mut self.unhashed) {
+            let sha256 = match self.hashed.get(&inode) {
+                Some(sha256) => sha256.clone(),
+                None => {
+                    let mut hasher = Sha256::new();
+                    let mut f = BufReader::new(
+                        File::open(&file)
+                            .with_context(|| format!("while opening file {}", file.display()))?,
+                    );
+                    std::io::copy(&mut f, &mut hasher)
+                        .with_context(|| format!("while hashing file {}", file.display()))?;
+                    let sha256 = hex::encode(hasher.finalize());
+                    self.hashed.insert(inode, sha256.clone());
+                    sha256
+                }
+            };
+            if let Some(entry) = self.entries.get_mut(&path) {
+                entry.sha256 = Some(sha256);
+            }
+        }
+        let listing = Listing {
+            entries: self.entries.into_values().collect(),
+        };
+        let mut out = BufWriter::new(out);
+        serde_json::to_writer_pretty(&mut out, &listing).context("while serializing manifest")?;
+        out.write_all(b"\n")?;
+        out.flush().context("while writing manifest")
+    }
+}
+
+/// Feeds everything that is read through it to a sha256 hasher (if there is
+/// one), so that packagers can hash file contents as they copy them
+pub(crate) struct HashingReader<R> {
+    inner: R,
+    hasher: Option<Sha256>,
+}
+
+impl<R: Read> HashingReader<R> {
+    /// Only hash if `hash` is set, so that packagers don't have to pay for it
+    /// unless a manifest was asked for
+    pub(crate) fn new(inner: R, hash: bool) -> Self {
+        Self {
+            inner,
+            hasher: hash.then(Sha256::new),
+        }
+    }
+
+    pub(crate) fn into_hasher(self) -> Option<Sha256> {
+        self.hasher
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reproducible::Digest;
+
+    fn paths(manifest: &Manifest) -> Vec<&Path> {
+        manifest.entries.keys().map(PathBuf::as_path).collect()
+    }
+
+    #[test]
+    fn lists_tree() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        std::fs::create_dir(dir.path().join("dir")).expect("failed to create dir");
+        std::fs::write(dir.path().join("dir/file"), "hello\n").expect("failed to write file");
+        std::fs::hard_link(dir.path().join("dir/file"), dir.path().join("hardlink"))
+            .expect("failed to create hardlink");
+        std::os::unix::fs::symlink("dir/file", dir.path().join("link"))
+            .expect("failed to create symlink");
+        let mut manifest = Manifest::default();
+        // hashed in the same walk that computes the digest
+        Digest::of_tree(dir.path(), Some(&mut manifest)).expect("failed to walk tree");
+        assert!(manifest.unhashed.is_empty());
+        assert_eq!(
+            paths(&manifest),
+            [
+                Path::new("/"),
+                Path::new("/dir"),
+                Path::new("/dir/file"),
+                Path::new("/hardlink"),
+                Path::new("/link"),
+            ]
+        );
+        let file = &manifest.entries[Path::new("/dir/file")];
+        assert_eq!(FileType::File, file.file_type);
+        assert_eq!(Some(6), file.size);
+        assert_eq!(
+            Some("5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03"),
+            file.sha256.as_deref(),
+        );
+        assert_eq!(file.sha256, manifest.entries[Path::new("/hardlink")].sha256);
+        let link = &manifest.entries[Path::new("/link")];
+        assert_eq!(FileType::Symlink, link.file_type);
+        assert_eq!(Some(Path::new("dir/file")), link.symlink_target.as_deref());
+        assert_eq!(None, link.sha256);
+
+        // a packager that never reads the files gets the same result
+        let mut walked = Manifest::default();
+        walked.add_tree(dir.path()).expect("failed to walk tree");
+        let out = tempfile::NamedTempFile::new().expect("failed to create tempfile");
+        walked
+            .write(out.reopen().expect("failed to reopen"))
+            .expect("failed to write manifest");
+        let read = Manifest::read(out.path()).expect("failed to read manifest");
+        assert_eq!(manifest.entries, read.entries);
+    }
+
+    fn tar_layer(build: impl FnOnce(&mut tar::Builder<&mut Vec<u8>>)) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut builder = tar::Builder::new(&mut buf);
+        build(&mut builder);
+        builder.finish().expect("failed to finish tar");
+        drop(builder);
+        buf
+    }
+
+    fn header(entry_type: EntryType, size: u64) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(0o755);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_size(size);
+        header
+    }
+
+    fn apply(manifest: &mut Manifest, layer: &[u8]) {
+        let mut archive = tar::Archive::new(layer);
+        for entry in archive.entries().expect("failed to read tar") {
+            let mut entry = entry.expect("failed to read entry");
+            manifest
+                .add_tar_entry(&mut entry, None)
+                .expect("failed to add entry");
+        }
+    }
+
+    #[test]
+    fn oci_layers() {
+        let mut manifest = Manifest::default();
+        apply(
+            &mut manifest,
+            &tar_layer(|b| {
+                b.append_data(&mut header(EntryType::Directory, 0), "./", &[][..])
+                    .expect("append");
+                b.append_data(&mut header(EntryType::Directory, 0), "dir/", &[][..])
+                    .expect("append");
+                b.append_data(&mut header(EntryType::Regular, 6), "dir/a", &b"hello\n"[..])
+                    .expect("append");
+                b.append_data(&mut header(EntryType::Regular, 0), "dir/b", &[][..])
+                    .expect("append");
+                b.append_data(&mut header(EntryType::Directory, 0), "opaque/", &[][..])
+                    .expect("append");
+                b.append_data(&mut header(EntryType::Regular, 0), "opaque/c", &[][..])
+                    .expect("append");
+                b.append_link(&mut header(EntryType::Link, 0), "link", "dir/a")
+                    .expect("append");
+            }),
+        );
+        apply(
+            &mut manifest,
+            &tar_layer(|b| {
+                b.append_data(&mut header(EntryType::Regular, 0), "dir/.wh.b", &[][..])
+                    .expect("append");
+                b.append_data(
+                    &mut header(EntryType::Regular, 0),
+                    "opaque/.wh..wh..opq",
+                    &[][..],
+                )
+                .expect("append");
+                b.append_data(&mut header(EntryType::Regular, 0), "opaque/d", &[][..])
+                    .expect("append");
+            }),
+        );
+        assert_eq!(
+            paths(&manifest),
+            [
+                Path::new("/"),
+                Path::new("/dir"),
+                Path::new("/dir/a"),
+                Path::new("/link"),
+                Path::new("/opaque"),
+                Path::new("/opaque/d"),
+            ]
+        );
+        assert_eq!(
+            Some("5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03"),
+            manifest.entries[Path::new("/link")].sha256.as_deref(),
+        );
+    }
+
+    #[test]
+    fn whiteouts() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        std::fs::write(dir.path().join("file"), "hello\n").expect("failed to write file");
+        let mut manifest = Manifest::default();
+        manifest
+            .add_file(Path::new("unhashed"), &dir.path().join("file"))
+            .expect("failed to add file");
+        apply(
+            &mut manifest,
+            &tar_layer(|b| {
+                b.append_data(&mut header(EntryType::Directory, 0), "dir/", &[][..])
+                    .expect("append");
+                b.append_data(&mut header(EntryType::Directory, 0), "dir/sub/", &[][..])
+                    .expect("append");
+                b.append_data(&mut header(EntryType::Regular, 0), "dir/sub/a", &[][..])
+                    .expect("append");
+                b.append_data(&mut header(EntryType::Regular, 0), "dirt", &[][..])
+                    .expect("append");
+            }),
+        );
+        apply(
+            &mut manifest,
+            &tar_layer(|b| {
+                b.append_data(&mut header(EntryType::Regular, 0), "dir/.wh.sub", &[][..])
+                    .expect("append");
+                b.append_data(&mut header(EntryType::Regular, 0), ".wh.unhashed", &[][..])
+                    .expect("append");
+            }),
+        );
+        assert_eq!(paths(&manifest), [Path::new("/dir"), Path::new("/dirt")]);
+        assert!(manifest.unhashed.is_empty());
+    }
+
+    #[test]
+    fn nested() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        std::fs::write(dir.path().join("file"), "hello\n").expect("failed to write file");
+        let mut inner = Manifest::default();
+        inner.add_tree(dir.path()).expect("failed to walk tree");
+        let mut outer = Manifest::default();
+        outer.nest(Path::new("root"), inner);
+        outer
+            .add_file(Path::new("esp"), &dir.path().join("file"))
+            .expect("failed to add file");
+        assert_eq!(
+            paths(&outer),
+            [
+                Path::new("/esp"),
+                Path::new("/root"),
+                Path::new("/root/file"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,712 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
+use cap_std::fs::Dir;
+use chrono::DateTime;
+use chrono::SecondsFormat;
+use maplit::hashmap;
+use oci_spec::image::Arch;
+use oci_spec::image::Config;
+use oci_spec::image::ConfigBuilder;
+use oci_spec::image::Descriptor;
+use oci_spec::image::DescriptorBuilder;
+use oci_spec::image::HistoryBuilder;
+use oci_spec::image::ImageConfigurationBuilder;
+use oci_spec::image::ImageIndexBuilder;
+use oci_spec::image::ImageManifestBuilder;
+use oci_spec::image::MediaType;
+use oci_spec::image::OciLayoutBuilder;
+use oci_spec::image::Platform;
+use oci_spec::image::PlatformBuilder;
+use oci_spec::image::RootFsBuilder;
+use oci_spec::image::ANNOTATION_REF_NAME;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::compression::Compression;
+use crate::manifest::Manifest as PackageManifest;
+use crate::reproducible;
+
+mod seekable;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Oci {
+    #[serde(rename = "ref")]
+    refname: String,
+    /// One image manifest per architecture
+    manifests: Vec<Manifest>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    deltas: Vec<Delta>,
+    target_arch: TargetArch,
+    entrypoint: Vec<String>,
+    #[serde(default)]
+    cmd: Option<Vec<String>>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    /// If set, variables in this layer's /etc/environment are used as
+    /// defaults for anything not explicitly set in `env` (most usefully PATH)
+    #[serde(default)]
+    env_defaults_from_layer: Option<PathBuf>,
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    working_dir: Option<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    /// Ports in the form `port[/proto]`, where proto defaults to tcp
+    #[serde(default)]
+    exposed_ports: Vec<String>,
+    #[serde(default)]
+    volumes: Vec<String>,
+    #[serde(default)]
+    stop_signal: Option<String>,
+    #[serde(default)]
+    layer_compression: LayerCompression,
+}
+
+/// Not every registry or runtime understands zstd layers, so allow falling back
+/// to gzip (or no compression at all)
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerCompression {
+    Uncompressed,
+    Gzip,
+    #[default]
+    Zstd,
+    /// Seekable zstd layers that can be lazily pulled by containers/storage
+    /// (podman, cri-o)
+    ZstdChunked,
+    /// Seekable gzip layers that can be lazily pulled by the stargz
+    /// snapshotter (containerd)
+    Estargz,
+}
+
+impl LayerCompression {
+    fn media_type(self) -> MediaType {
+        match self {
+            Self::Uncompressed => MediaType::ImageLayer,
+            Self::Gzip | Self::Estargz => MediaType::ImageLayerGzip,
+            Self::Zstd | Self::ZstdChunked => MediaType::ImageLayerZstd,
+        }
+    }
+}
+
+impl Manifest {
+    fn config(&self) -> Result<Config> {
+        let mut env = self.env.clone();
+        if let Some(layer) = &self.env_defaults_from_layer {
+            let path = layer.join("etc/environment");
+            match std::fs::read_to_string(&path) {
+                Ok(contents) => {
+                    for (key, value) in parse_environment(&contents) {
+                        env.entry(key).or_insert(value);
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("while reading {}", path.display()));
+                }
+            }
+        }
+
+        let mut config = ConfigBuilder::default().entrypoint(self.entrypoint.clone());
+        if let Some(cmd) = &self.cmd {
+            config = config.cmd(cmd.clone());
+        }
+        if !env.is_empty() {
+            config = config.env(
+                env.into_iter()
+                    .map(|(k, v)| format!("{k}={v}"))
+                    .collect::<Vec<_>>(),
+            );
+        }
+        if let Some(user) = &self.user {
+            config = config.user(user.clone());
+        }
+        if let Some(working_dir) = &self.working_dir {
+            config = config.working_dir(working_dir.clone());
+        }
+        if !self.labels.is_empty() {
+            config = config.labels(
+                self.labels
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect::<HashMap<_, _>>(),
+            );
+        }
+        if !self.exposed_ports.is_empty() {
+            config = config.exposed_ports(
+                self.exposed_ports
+                    .iter()
+                    .map(|p| normalize_port(p))
+                    .collect::<Result<Vec<_>>>()?,
+            );
+        }
+        if !self.volumes.is_empty() {
+            config = config.volumes(self.volumes.clone());
+        }
+        if let Some(stop_signal) = &self.stop_signal {
+            config = config.stop_signal(stop_signal.clone());
+        }
+        config.build().context("while building image config")
+    }
+}
+
+/// Parse the simple KEY=VALUE format of /etc/environment (as understood by
+/// pam_env), ignoring comments and stripping optional quotes.
+fn parse_environment(contents: &str) -> BTreeMap<String, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            let value = value.trim();
+            let value = ['"', '\'']
+                .into_iter()
+                .find_map(|q| value.strip_prefix(q).and_then(|v| v.strip_suffix(q)))
+                .unwrap_or(value);
+            (key.trim().to_owned(), value.to_owned())
+        })
+        .collect()
+}
+
+/// Exposed ports must always have a protocol, but default to tcp to match what
+/// docker does for `EXPOSE`
+fn normalize_port(port: &str) -> Result<String> {
+    let (num, proto) = port.split_once('/').unwrap_or((port, "tcp"));
+    num.parse::<u16>()
+        .with_context(|| format!("invalid port number in '{port}'"))?;
+    ensure!(
+        matches!(proto, "tcp" | "udp" | "sctp"),
+        "invalid protocol in '{port}'"
+    );
+    Ok(format!("{num}/{proto}"))
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+pub enum TargetArch {
+    #[serde(rename = "x86_64")]
+    X86_64,
+    #[serde(rename = "aarch64")]
+    Aarch64,
+}
+
+impl TargetArch {
+    /// Architecture that this is running on
+    pub(crate) fn host() -> Result<Self> {
+        match std::env::consts::ARCH {
+            "x86_64" => Ok(Self::X86_64),
+            "aarch64" => Ok(Self::Aarch64),
+            other => bail!("unsupported host architecture '{other}'"),
+        }
+    }
+
+    /// OCI uses GOARCH values for architectures (and GOARM for variants), not
+    /// the names that antlir uses everywhere else.
+    pub(crate) fn platform(self) -> Result<Platform> {
+        let mut builder = PlatformBuilder::default();
+        builder = match self {
+            Self::X86_64 => builder.architecture(Arch::Amd64),
+            Self::Aarch64 => builder.architecture(Arch::ARM64).variant("v8"),
+        };
+        builder
+            .os("linux")
+            .build()
+            .context("while building platform")
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Delta {
+    tar: PathBuf,
+    /// Human-readable description of where this layer came from, recorded in
+    /// the image history
+    label: String,
+}
+
+trait Blob {
+    fn to_bytes(&self) -> Result<Arc<Vec<u8>>>;
+}
+
+trait OciObject: Blob {
+    const MEDIA_TYPE: MediaType;
+}
+
+impl OciObject for oci_spec::image::ImageManifest {
+    const MEDIA_TYPE: MediaType = MediaType::ImageManifest;
+}
+
+impl OciObject for oci_spec::image::ImageIndex {
+    const MEDIA_TYPE: MediaType = MediaType::ImageIndex;
+}
+
+impl OciObject for oci_spec::image::ImageConfiguration {
+    const MEDIA_TYPE: MediaType = MediaType::ImageConfig;
+}
+
+impl<T> Blob for T
+where
+    T: Serialize,
+{
+    fn to_bytes(&self) -> Result<Arc<Vec<u8>>> {
+        // oci_spec uses HashMaps for things like labels and exposed ports, so
+        // go through a Value first to get sorted keys and a stable digest
+        let value = serde_json::to_value(self).context("while serializing object")?;
+        serde_json::to_vec_pretty(&value)
+            .context("while serializing object")
+            .map(Arc::new)
+    }
+}
+
+/// Take some OCI object, write it to the blobs dir and return a descriptor
+fn write<O: OciObject>(blobs_dir: &Dir, obj: &O) -> Result<Descriptor> {
+    let bytes = obj.to_bytes().context("while serializing object")?;
+    let sha256 = hex::encode(Sha256::digest(bytes.as_ref()));
+    let mut f = BufWriter::new(
+        blobs_dir
+            .create(&sha256)
+            .context("while creating blob file")?,
+    );
+    f.write_all(bytes.as_ref()).context("while writing blob")?;
+    DescriptorBuilder::default()
+        .media_type(O::MEDIA_TYPE)
+        .digest(format!("sha256:{sha256}"))
+        .size(bytes.as_ref().len() as i64)
+        .build()
+        .context("while building descriptor")
+}
+
+/// Passes everything through to the inner writer while computing its digest
+pub(crate) struct DigestWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<W: Write> DigestWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            len: 0,
+        }
+    }
+
+    /// Number of bytes written so far
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Hex-encoded sha256 of everything written so far
+    pub(crate) fn hex_digest(&self) -> String {
+        hex::encode(self.hasher.clone().finalize())
+    }
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Compress a layer tarball into the blobs dir, returning its descriptor and
+/// the diff_id (digest of the uncompressed tar)
+fn write_layer(
+    blobs_dir: &Dir,
+    tar: &Path,
+    compression: LayerCompression,
+    manifest: Option<&mut PackageManifest>,
+) -> Result<(Descriptor, String)> {
+    // the name of the blob isn't known until it's completely written, so
+    // start with a temporary name
+    let tmp_name = format!(".tmp-{}", std::process::id());
+    let blob = blobs_dir
+        .create(&tmp_name)
+        .context("while creating blob file")?;
+    let mut out = DigestWriter::new(BufWriter::new(blob));
+    let (diff_id, annotations) = match compression {
+        LayerCompression::Uncompressed => {
+            (compress(&mut out, tar, Compression::None, manifest)?, None)
+        }
+        LayerCompression::Gzip => (compress(&mut out, tar, Compression::Gzip, manifest)?, None),
+        LayerCompression::Zstd => (compress(&mut out, tar, Compression::Zstd, manifest)?, None),
+        LayerCompression::ZstdChunked => {
+            let layer = seekable::write(&mut out, tar, seekable::Flavor::ZstdChunked, manifest)?;
+            (layer.diff_id, Some(layer.annotations))
+        }
+        LayerCompression::Estargz => {
+            let layer = seekable::write(&mut out, tar, seekable::Flavor::Estargz, manifest)?;
+            (layer.diff_id, Some(layer.annotations))
+        }
+    };
+    out.flush().context("while flushing layer")?;
+    let sha256 = out.hex_digest();
+    blobs_dir
+        .rename(&tmp_name, blobs_dir, &sha256)
+        .context("while moving blob into place")?;
+
+    let mut descriptor = DescriptorBuilder::default()
+        .media_type(compression.media_type())
+        .digest(format!("sha256:{sha256}"))
+        .size(out.len() as i64)
+        .build()
+        .context("while building descriptor")?;
+    descriptor.set_annotations(annotations);
+    Ok((descriptor, diff_id))
+}
+
+/// Stream-compress a tarball, returning the diff_id (digest of the
+/// uncompressed tar). If there is a `manifest`, the tar is parsed as it
+/// streams by and every entry is applied to it.
+fn compress<W: Write>(
+    out: W,
+    tar: &Path,
+    compression: Compression,
+    manifest: Option<&mut PackageManifest>,
+) -> Result<String> {
+    let compressed = compression
+        .compressor(out, None, None)
+        .context("while setting up compression")?;
+    let mut tee = TeeReader {
+        inner: BufReader::with_capacity(1024 * 1024, File::open(tar).context("while opening tar")?),
+        diff_id: Sha256::new(),
+        out: compressed,
+    };
+    if let Some(manifest) = manifest {
+        let mut archive = tar::Archive::new(&mut tee);
+        for entry in archive.entries().context("while reading tar entries")? {
+            let mut entry = entry.context("while reading tar entry")?;
+            manifest
+                .add_tar_entry(&mut entry, None)
+                .context("while adding tar entry to manifest")?;
+        }
+    }
+    // whatever is left after the end-of-archive marker (or everything, if
+    // there was no need to parse it)
+    std::io::copy(&mut tee, &mut std::io::sink()).context("while writing layer")?;
+    tee.out.finish().context("while finishing compression")?;
+    Ok(format!("sha256:{}", hex::encode(tee.diff_id.finalize())))
+}
+
+/// Copies everything that is read from `inner` to `out` (and the diff_id)
+struct TeeReader<R: Read, W: Write> {
+    inner: R,
+    diff_id: Sha256,
+    out: W,
+}
+
+impl<R: Read, W: Write> Read for TeeReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.diff_id.update(&buf[..n]);
+        self.out.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+impl Oci {
+    pub(crate) fn build(&self, out: &Path, manifest: Option<&mut PackageManifest>) -> Result<()> {
+        ensure!(
+            manifest.is_none() || self.manifests.len() == 1,
+            "manifests can only be made for single-arch images"
+        );
+        std::fs::create_dir_all(out).context("while creating output directory")?;
+        let out = Dir::open_ambient_dir(out, cap_std::ambient_authority())
+            .context("while opening output dir")?;
+
+        let layout = OciLayoutBuilder::default()
+            .image_layout_version("1.0.0")
+            .build()
+            .context("while building oci-layout")?;
+        layout
+            .to_writer_pretty(&mut BufWriter::new(
+                out.create("oci-layout")
+                    .context("while creating oci-layout")?,
+            ))
+            .context("while writing oci-layout")?;
+
+        out.create_dir_all("blobs/sha256")
+            .context("while creating blobs dir")?;
+        let blobs_dir = out
+            .open_dir("blobs/sha256")
+            .context("while opening blobs dir")?;
+
+        ensure!(
+            !self.manifests.is_empty(),
+            "at least one manifest is required"
+        );
+        let mut manifests = self.manifests.iter().collect::<Vec<_>>();
+        // make the index deterministic regardless of the order that the
+        // per-arch images were given in
+        manifests.sort_by_key(|m| m.target_arch);
+        if let Some(dupe) = manifests
+            .windows(2)
+            .find(|pair| pair[0].target_arch == pair[1].target_arch)
+        {
+            bail!("multiple manifests given for {:?}", dupe[0].target_arch);
+        }
+        let mut package_manifest = manifest;
+        let mut manifest_descriptors = Vec::new();
+        for manifest in manifests {
+            manifest_descriptors.push(
+                self.write_manifest(&blobs_dir, manifest, package_manifest.as_deref_mut())
+                    .with_context(|| format!("while writing {:?} image", manifest.target_arch))?,
+            );
+        }
+
+        // a multi-arch image is a nested index of all the per-arch manifests,
+        // so that the ref names the whole image instead of being repeated on
+        // every platform's manifest
+        let mut image_descriptor = match <[_; 1]>::try_from(manifest_descriptors) {
+            Ok([descriptor]) => descriptor,
+            Err(manifest_descriptors) => {
+                let nested = ImageIndexBuilder::default()
+                    .schema_version(2u32)
+                    .media_type(MediaType::ImageIndex)
+                    .manifests(manifest_descriptors)
+                    .build()
+                    .context("while building image index")?;
+                write(&blobs_dir, &nested).context("while writing image index")?
+            }
+        };
+        image_descriptor.set_annotations(Some(hashmap! {
+            ANNOTATION_REF_NAME.to_owned() => self.refname.clone(),
+            "built.by.exec".to_owned() => "antlir2".to_owned(),
+        }));
+
+        let index = ImageIndexBuilder::default()
+            .schema_version(2u32)
+            .manifests(vec![image_descriptor])
+            .build()
+            .context("while building index.json")?;
+        // not a blob, but it has the same HashMap ordering problem
+        out.write("index.json", index.to_bytes()?.as_ref())
+            .context("while writing index.json")?;
+
+        Ok(())
+    }
+
+    /// Write out all the blobs that make up a single-arch image and return the
+    /// descriptor of its manifest to put in an index. Every layer is applied
+    /// to `package_manifest` (if there is one) in order, so that it ends up
+    /// listing the image's root filesystem.
+    fn write_manifest(
+        &self,
+        blobs_dir: &Dir,
+        manifest: &Manifest,
+        mut package_manifest: Option<&mut PackageManifest>,
+    ) -> Result<Descriptor> {
+        let platform = manifest.target_arch.platform()?;
+        let created = DateTime::from_timestamp(
+            reproducible::source_date_epoch()?
+                .try_into()
+                .context("SOURCE_DATE_EPOCH is too large")?,
+            0,
+        )
+        .context("SOURCE_DATE_EPOCH is out of range")?
+        .to_rfc3339_opts(SecondsFormat::Secs, true);
+
+        let mut layer_descriptors = Vec::new();
+        let mut rootfs_digest_chain = Vec::new();
+        let mut history = Vec::new();
+        for delta in &manifest.deltas {
+            let (mut layer_descriptor, diff_id) = write_layer(
+                blobs_dir,
+                &delta.tar,
+                manifest.layer_compression,
+                package_manifest.as_deref_mut(),
+            )
+            .with_context(|| format!("while writing layer '{}'", delta.label))?;
+            layer_descriptor.set_platform(Some(platform.clone()));
+            layer_descriptors.push(layer_descriptor);
+            rootfs_digest_chain.push(diff_id);
+            history.push(
+                HistoryBuilder::default()
+                    .created(created.clone())
+                    .created_by(delta.label.clone())
+                    .build()
+                    .context("while building history")?,
+            );
+        }
+
+        let mut image_configuration = ImageConfigurationBuilder::default();
+        if let Some(variant) = platform.variant() {
+            image_configuration = image_configuration.variant(variant.clone());
+        }
+        let image_configuration = image_configuration
+            .architecture(platform.architecture().clone())
+            .os("linux")
+            .created(created)
+            .config(manifest.config()?)
+            .history(history)
+            .rootfs(
+                RootFsBuilder::default()
+                    .typ("layers")
+                    .diff_ids(rootfs_digest_chain)
+                    .build()
+                    .context("while building rootfs")?,
+            )
+            .build()
+            .context("while building image configuration")?;
+        let image_config_descriptor =
+            write(blobs_dir, &image_configuration).context("while writing image configuration")?;
+
+        let image_manifest = ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .media_type(MediaType::ImageManifest)
+            .config(image_config_descriptor)
+            .layers(layer_descriptors)
+            .build()
+            .context("while building image manifest")?;
+        let mut image_manifest_descriptor =
+            write(blobs_dir, &image_manifest).context("while writing image manifest")?;
+        image_manifest_descriptor.set_platform(Some(platform));
+        Ok(image_manifest_descriptor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use oci_spec::image::ImageIndex;
+
+    use super::*;
+
+    #[test]
+    fn etc_environment() {
+        assert_eq!(
+            parse_environment(
+                "# comment\n\nPATH=\"/usr/local/bin:/usr/bin\"\nLANG='C.UTF-8'\n  FOO=bar  \n"
+            ),
+            BTreeMap::from([
+                ("PATH".to_owned(), "/usr/local/bin:/usr/bin".to_owned()),
+                ("LANG".to_owned(), "C.UTF-8".to_owned()),
+                ("FOO".to_owned(), "bar".to_owned()),
+            ]),
+        );
+    }
+
+    fn oci(tar: &Path, arches: &[&str]) -> Oci {
+        serde_json::from_value(serde_json::json!({
+            "ref": "test",
+            "manifests": arches.iter().map(|arch| serde_json::json!({
+                "deltas": [{"tar": tar, "label": "layer"}],
+                "target_arch": arch,
+                "entrypoint": ["/bin/sh"],
+                "layer_compression": "uncompressed",
+            })).collect::<Vec<_>>(),
+        }))
+        .expect("invalid spec")
+    }
+
+    #[test]
+    fn multi_arch_index() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let tar = dir.path().join("layer.tar");
+        tar::Builder::new(File::create(&tar).expect("failed to create tar"))
+            .into_inner()
+            .expect("failed to write tar");
+        let out = dir.path().join("oci");
+        oci(&tar, &["aarch64", "x86_64"])
+            .build(&out, None)
+            .expect("failed to build");
+        let blob = |d: &Descriptor| out.join("blobs/sha256").join(&d.digest()[7..]);
+
+        let index =
+            ImageIndex::from_file(out.join("index.json")).expect("failed to read index.json");
+        let [image] = index.manifests().as_slice() else {
+            panic!("expected exactly one image in index.json");
+        };
+        assert_eq!(&MediaType::ImageIndex, image.media_type());
+        assert_eq!(
+            Some("test"),
+            image
+                .annotations()
+                .as_ref()
+                .and_then(|a| a.get(ANNOTATION_REF_NAME))
+                .map(String::as_str)
+        );
+
+        let nested = ImageIndex::from_file(blob(image)).expect("failed to read nested index");
+        let arches: Vec<_> = nested
+            .manifests()
+            .iter()
+            .map(|m| {
+                assert_eq!(&MediaType::ImageManifest, m.media_type());
+                // the ref is only on the index
+                assert!(m.annotations().is_none());
+                m.platform()
+                    .as_ref()
+                    .expect("no platform")
+                    .architecture()
+                    .clone()
+            })
+            .collect();
+        assert_eq!(arches, [Arch::Amd64, Arch::ARM64]);
+    }
+
+    #[test]
+    fn single_arch_index() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let tar = dir.path().join("layer.tar");
+        tar::Builder::new(File::create(&tar).expect("failed to create tar"))
+            .into_inner()
+            .expect("failed to write tar");
+        let out = dir.path().join("oci");
+        oci(&tar, &["x86_64"])
+            .build(&out, None)
+            .expect("failed to build");
+        let index =
+            ImageIndex::from_file(out.join("index.json")).expect("failed to read index.json");
+        let [image] = index.manifests().as_slice() else {
+            panic!("expected exactly one image in index.json");
+        };
+        assert_eq!(&MediaType::ImageManifest, image.media_type());
+        assert!(image
+            .annotations()
+            .as_ref()
+            .is_some_and(|a| a.contains_key(ANNOTATION_REF_NAME)));
+    }
+
+    #[test]
+    fn ports() {
+        assert_eq!(normalize_port("80").expect("valid"), "80/tcp");
+        assert_eq!(normalize_port("53/udp").expect("valid"), "53/udp");
+        assert!(normalize_port("http").is_err());
+        assert!(normalize_port("80/icmp").is_err());
+    }
+}
@@ -0,0 +1,923 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Seekable layers (zstd:chunked and eStargz) that lazy-pulling snapshotters
+//! can mount without downloading the entire layer.
+//!
+//! Both formats work the same way: every file's contents are compressed into
+//! their own independent frames (gzip members for eStargz, zstd frames for
+//! zstd:chunked), and a table of contents at the end of the blob records where
+//! each file (and each chunk of large files) lives. Clients that don't know
+//! about any of this just see an ordinary compressed tar, since concatenated
+//! gzip members / zstd frames decompress to the concatenation of their
+//! contents, and the extra metadata is either a valid tar entry (eStargz) or
+//! hidden in zstd skippable frames (zstd:chunked).
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::DateTime;
+use chrono::SecondsFormat;
+use flate2::GzBuilder;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tar::EntryType;
+use tar::Header;
+
+use crate::manifest::Manifest;
+
+/// Files larger than this are split into multiple independently-fetchable
+/// chunks (this is the same default used by the reference eStargz writer).
+const CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+const ESTARGZ_GZIP_LEVEL: u32 = 9;
+const ZSTD_LEVEL: i32 = 15;
+
+const ESTARGZ_TOC_NAME: &str = "stargz.index.json";
+/// Tells the stargz snapshotter that there is no prioritized set of files to
+/// prefetch
+const ESTARGZ_NO_PREFETCH_LANDMARK: &str = ".no.prefetch.landmark";
+const ESTARGZ_LANDMARK_CONTENTS: u8 = 0xf;
+const ESTARGZ_TOC_DIGEST_ANNOTATION: &str = "containerd.io/snapshot/stargz/toc.digest";
+const ESTARGZ_UNCOMPRESSED_SIZE_ANNOTATION: &str = "io.containers.estargz.uncompressed-size";
+
+const ZSTD_SKIPPABLE_FRAME_MAGIC: u32 = 0x184D2A50;
+const ZSTD_CHUNKED_FRAME_MAGIC: &[u8; 8] = b"GNUlInUx";
+/// The only manifest type defined by containers/storage
+const ZSTD_CHUNKED_MANIFEST_TYPE_CRFS: u64 = 1;
+const ZSTD_CHUNKED_MANIFEST_CHECKSUM_ANNOTATION: &str =
+    "io.github.containers.zstd-chunked.manifest-checksum";
+const ZSTD_CHUNKED_MANIFEST_POSITION_ANNOTATION: &str =
+    "io.github.containers.zstd-chunked.manifest-position";
+const ZSTD_CHUNKED_TARSPLIT_POSITION_ANNOTATION: &str =
+    "io.github.containers.zstd-chunked.tarsplit-position";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(super) enum Flavor {
+    ZstdChunked,
+    Estargz,
+}
+
+pub(super) struct SeekableLayer {
+    pub(super) diff_id: String,
+    /// Annotations that must be set on the layer descriptor so that clients
+    /// can find the table of contents
+    pub(super) annotations: HashMap<String, String>,
+}
+
+fn is_zero(v: &u64) -> bool {
+    *v == 0
+}
+
+/// Entry in the table of contents. This is a superset of the fields used by
+/// eStargz and zstd:chunked, which are almost (but not quite) identical.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TocEntry {
+    name: String,
+    #[serde(rename = "type")]
+    typ: &'static str,
+    #[serde(skip_serializing_if = "is_zero")]
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modtime: Option<String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    link_name: String,
+    #[serde(skip_serializing_if = "is_zero")]
+    mode: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    uid: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    gid: u64,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    user_name: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    group_name: String,
+    #[serde(skip_serializing_if = "is_zero")]
+    offset: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    end_offset: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    dev_major: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    dev_minor: u64,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    xattrs: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    digest: String,
+    #[serde(skip_serializing_if = "is_zero")]
+    chunk_offset: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    chunk_size: u64,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    chunk_digest: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Toc {
+    version: u32,
+    entries: Vec<TocEntry>,
+    #[serde(rename = "tarsplitdigest", skip_serializing_if = "Option::is_none")]
+    tar_split_digest: Option<String>,
+}
+
+/// zstd:chunked additionally embeds tar-split metadata so that the exact
+/// original tar stream (and thus the diff_id) can be reconstructed from the
+/// individual files.
+/// See https://github.com/vbatts/tar-split
+#[derive(Debug, Serialize)]
+struct TarSplitEntry {
+    #[serde(rename = "type")]
+    typ: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name_raw: Option<String>,
+    #[serde(skip_serializing_if = "is_zero")]
+    size: u64,
+    payload: Option<String>,
+    position: u64,
+}
+
+const TAR_SPLIT_FILE: u8 = 1;
+const TAR_SPLIT_SEGMENT: u8 = 2;
+
+#[derive(Default)]
+struct TarSplit {
+    data: Vec<u8>,
+    position: u64,
+}
+
+impl TarSplit {
+    fn push(&mut self, mut entry: TarSplitEntry) -> Result<()> {
+        entry.position = self.position;
+        self.position += 1;
+        serde_json::to_writer(&mut self.data, &entry).context("while serializing tar-split")?;
+        self.data.push(b'\n');
+        Ok(())
+    }
+
+    fn segment(&mut self, bytes: &[u8]) -> Result<()> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        self.push(TarSplitEntry {
+            typ: TAR_SPLIT_SEGMENT,
+            name: None,
+            name_raw: None,
+            size: 0,
+            payload: Some(BASE64.encode(bytes)),
+            position: 0,
+        })
+    }
+
+    fn file(&mut self, name: &[u8], size: u64, crc: Option<u64>) -> Result<()> {
+        let (name, name_raw) = match std::str::from_utf8(name) {
+            Ok(name) => (Some(name.to_owned()), None),
+            Err(_) => (None, Some(BASE64.encode(name))),
+        };
+        self.push(TarSplitEntry {
+            typ: TAR_SPLIT_FILE,
+            name,
+            name_raw,
+            size,
+            payload: crc.map(|crc| BASE64.encode(crc.to_be_bytes())),
+            position: 0,
+        })
+    }
+}
+
+/// CRC-64 with the ISO polynomial, as used by tar-split to checksum file
+/// payloads
+struct Crc64 {
+    table: [u64; 256],
+    crc: u64,
+}
+
+impl Crc64 {
+    fn new() -> Self {
+        let mut table = [0u64; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = i as u64;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xD800000000000000
+                } else {
+                    crc >> 1
+                };
+            }
+            *entry = crc;
+        }
+        Self { table, crc: 0 }
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        let mut crc = !self.crc;
+        for b in buf {
+            crc = self.table[((crc as u8) ^ b) as usize] ^ (crc >> 8);
+        }
+        self.crc = !crc;
+    }
+}
+
+/// Writes each chunk of data as an independent compressed frame, keeping track
+/// of the compressed offset and the digest of the uncompressed stream.
+struct Framer<W: Write> {
+    out: W,
+    flavor: Flavor,
+    offset: u64,
+    uncompressed: Sha256,
+    uncompressed_len: u64,
+}
+
+impl<W: Write> Framer<W> {
+    fn frame(&mut self, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.uncompressed.update(data);
+        self.uncompressed_len += data.len() as u64;
+        let compressed = match self.flavor {
+            Flavor::Estargz => {
+                let mut enc = GzBuilder::new()
+                    .mtime(0)
+                    .write(Vec::new(), flate2::Compression::new(ESTARGZ_GZIP_LEVEL));
+                enc.write_all(data).context("while compressing")?;
+                enc.finish().context("while compressing")?
+            }
+            Flavor::ZstdChunked => {
+                zstd::bulk::compress(data, ZSTD_LEVEL).context("while compressing")?
+            }
+        };
+        self.raw(&compressed)
+    }
+
+    /// Write bytes directly to the output without compressing them
+    fn raw(&mut self, data: &[u8]) -> Result<()> {
+        self.out.write_all(data).context("while writing layer")?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    fn skippable_frame(&mut self, data: &[u8]) -> Result<()> {
+        self.raw(&ZSTD_SKIPPABLE_FRAME_MAGIC.to_le_bytes())?;
+        self.raw(&(data.len() as u32).to_le_bytes())?;
+        self.raw(data)
+    }
+}
+
+fn read_at(f: &File, start: u64, end: u64) -> Result<Vec<u8>> {
+    let mut buf = vec![0; (end - start) as usize];
+    f.read_exact_at(&mut buf, start)
+        .context("while reading tar")?;
+    Ok(buf)
+}
+
+fn padding(size: u64) -> u64 {
+    (512 - (size % 512)) % 512
+}
+
+/// eStargz entries are always relative paths with no leading './'
+fn estargz_name(name: &str) -> String {
+    let mut clean = Vec::new();
+    for component in Path::new(name).components() {
+        match component {
+            std::path::Component::Normal(c) => clean.push(c.to_string_lossy()),
+            std::path::Component::ParentDir => {
+                clean.pop();
+            }
+            _ => {}
+        }
+    }
+    clean.join("/")
+}
+
+/// Rewrite an uncompressed layer tarball as a seekable compressed layer.
+///
+/// Every entry is also applied to `manifest`, if there is one.
+pub(super) fn write<W: Write>(
+    out: W,
+    tar: &Path,
+    flavor: Flavor,
+    mut manifest: Option<&mut Manifest>,
+) -> Result<SeekableLayer> {
+    let raw = File::open(tar).context("while opening tar")?;
+    let tar_len = raw.metadata().context("while statting tar")?.len();
+    let mut archive = tar::Archive::new(File::open(tar).context("while opening tar")?);
+
+    let mut framer = Framer {
+        out,
+        flavor,
+        offset: 0,
+        uncompressed: Sha256::new(),
+        uncompressed_len: 0,
+    };
+    let mut toc = Vec::new();
+    let mut tar_split = TarSplit::default();
+
+    // everything in the tar that is not file contents (headers and padding) is
+    // buffered and written out right before the next file's contents
+    let mut pending = Vec::new();
+
+    if flavor == Flavor::Estargz {
+        let mut header = Header::new_ustar();
+        header.set_path(ESTARGZ_NO_PREFETCH_LANDMARK)?;
+        header.set_entry_type(EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_size(1);
+        header.set_mtime(0);
+        header.set_cksum();
+        framer.frame(header.as_bytes())?;
+        let offset = framer.offset;
+        framer.frame(&[ESTARGZ_LANDMARK_CONTENTS])?;
+        pending.resize(padding(1) as usize, 0);
+        let digest = format!(
+            "sha256:{}",
+            hex::encode(Sha256::digest([ESTARGZ_LANDMARK_CONTENTS]))
+        );
+        toc.push(TocEntry {
+            name: ESTARGZ_NO_PREFETCH_LANDMARK.to_owned(),
+            typ: "reg",
+            size: 1,
+            mode: 0o644,
+            offset,
+            digest: digest.clone(),
+            chunk_digest: digest,
+            ..Default::default()
+        });
+    }
+
+    let mut pos = 0;
+    for entry in archive
+        .entries_with_seek()
+        .context("while reading tar entries")?
+    {
+        let mut entry = entry.context("while reading tar entry")?;
+        let data_start = entry.raw_file_position();
+        let size = entry.size();
+        pending.extend(read_at(&raw, pos, data_start)?);
+        framer.frame(&pending)?;
+        if flavor == Flavor::ZstdChunked {
+            tar_split.segment(&pending)?;
+        }
+        pending.clear();
+
+        let path_bytes = entry.path_bytes().into_owned();
+        let name = String::from_utf8_lossy(&path_bytes).into_owned();
+        let header = entry.header();
+        let typ = match header.entry_type() {
+            EntryType::Regular | EntryType::Continuous => "reg",
+            EntryType::Directory => "dir",
+            EntryType::Symlink => "symlink",
+            EntryType::Link => "hardlink",
+            EntryType::Char => "char",
+            EntryType::Block => "block",
+            EntryType::Fifo => "fifo",
+            other => bail!("'{name}' has unsupported entry type {other:?}"),
+        };
+        let mtime = header.mtime().context("while reading mtime")?;
+        let mut toc_entry = TocEntry {
+            name: match flavor {
+                Flavor::Estargz => estargz_name(&name),
+                Flavor::ZstdChunked => name.clone(),
+            },
+            typ,
+            size: if typ == "reg" { size } else { 0 },
+            modtime: DateTime::from_timestamp(mtime as i64, 0)
+                .map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            link_name: entry
+                .link_name_bytes()
+                .map(|l| String::from_utf8_lossy(&l).into_owned())
+                .unwrap_or_default(),
+            mode: header.mode().context("while reading mode")?.into(),
+            uid: header.uid().context("while reading uid")?,
+            gid: header.gid().context("while reading gid")?,
+            user_name: header
+                .username()
+                .ok()
+                .flatten()
+                .unwrap_or_default()
+                .to_owned(),
+            group_name: header
+                .groupname()
+                .ok()
+                .flatten()
+                .unwrap_or_default()
+                .to_owned(),
+            dev_major: header.device_major().ok().flatten().unwrap_or(0).into(),
+            dev_minor: header.device_minor().ok().flatten().unwrap_or(0).into(),
+            ..Default::default()
+        };
+        if let Some(pax) = entry
+            .pax_extensions()
+            .context("while reading pax headers")?
+        {
+            for ext in pax {
+                let ext = ext.context("while reading pax header")?;
+                if let Some(name) = ext.key().ok().and_then(|k| k.strip_prefix("SCHILY.xattr.")) {
+                    toc_entry
+                        .xattrs
+                        .insert(name.to_owned(), BASE64.encode(ext.value_bytes()));
+                }
+            }
+        }
+
+        let mut chunks = Vec::new();
+        let mut crc = None;
+        let mut contents_digest = None;
+        if typ == "reg" && size > 0 {
+            let mut digest = Sha256::new();
+            let mut crc64 = Crc64::new();
+            let mut chunk_offset = 0;
+            while chunk_offset < size {
+                let chunk_size = std::cmp::min(CHUNK_SIZE, size - chunk_offset);
+                let chunk = read_at(
+                    &raw,
+                    data_start + chunk_offset,
+                    data_start + chunk_offset + chunk_size,
+                )?;
+                digest.update(&chunk);
+                crc64.update(&chunk);
+                let frame_offset = framer.offset;
+                framer.frame(&chunk)?;
+                let mut chunk_entry = TocEntry {
+                    name: toc_entry.name.clone(),
+                    typ: "chunk",
+                    offset: frame_offset,
+                    chunk_offset,
+                    // eStargz leaves the size of the last chunk implicit
+                    chunk_size: match flavor {
+                        Flavor::Estargz if chunk_offset + chunk_size == size => 0,
+                        _ => chunk_size,
+                    },
+                    chunk_digest: format!("sha256:{}", hex::encode(Sha256::digest(&chunk))),
+                    ..Default::default()
+                };
+                if flavor == Flavor::ZstdChunked {
+                    chunk_entry.end_offset = framer.offset;
+                }
+                chunks.push(chunk_entry);
+                chunk_offset += chunk_size;
+            }
+            contents_digest = Some(digest.clone());
+            toc_entry.digest = format!("sha256:{}", hex::encode(digest.finalize()));
+            crc = Some(crc64.crc);
+            // the first chunk is described by the file entry itself
+            let first = chunks.remove(0);
+            toc_entry.offset = first.offset;
+            toc_entry.chunk_size = first.chunk_size;
+            toc_entry.end_offset = first.end_offset;
+            toc_entry.chunk_digest = first.chunk_digest;
+        }
+        if flavor == Flavor::ZstdChunked {
+            tar_split.file(&path_bytes, size, crc)?;
+        }
+        toc.push(toc_entry);
+        toc.extend(chunks);
+        if let Some(manifest) = manifest.as_deref_mut() {
+            manifest
+                .add_tar_entry(&mut entry, contents_digest)
+                .with_context(|| format!("while adding '{name}' to manifest"))?;
+        }
+
+        pos = data_start + size;
+        pending.extend(read_at(&raw, pos, pos + padding(size))?);
+        pos += padding(size);
+    }
+
+    match flavor {
+        Flavor::Estargz => {
+            // the end-of-archive marker from the original tar is dropped, since
+            // the toc must be the last entry in the archive
+            framer.frame(&pending)?;
+
+            let toc_offset = framer.offset;
+            let toc = serde_json::to_vec_pretty(&Toc {
+                version: 1,
+                entries: toc,
+                tar_split_digest: None,
+            })
+            .context("while serializing toc")?;
+            let mut header = Header::new_ustar();
+            header.set_path(ESTARGZ_TOC_NAME)?;
+            header.set_entry_type(EntryType::Regular);
+            header.set_mode(0o644);
+            header.set_size(toc.len() as u64);
+            header.set_mtime(0);
+            header.set_cksum();
+            let mut toc_entry = header.as_bytes().to_vec();
+            toc_entry.extend_from_slice(&toc);
+            toc_entry.resize(toc_entry.len() + padding(toc.len() as u64) as usize, 0);
+            // end of archive
+            toc_entry.resize(toc_entry.len() + 1024, 0);
+            framer.frame(&toc_entry)?;
+            framer.raw(&estargz_footer(toc_offset))?;
+
+            Ok(SeekableLayer {
+                diff_id: format!("sha256:{}", hex::encode(framer.uncompressed.finalize())),
+                annotations: HashMap::from([
+                    (
+                        ESTARGZ_TOC_DIGEST_ANNOTATION.to_owned(),
+                        format!("sha256:{}", hex::encode(Sha256::digest(&toc))),
+                    ),
+                    (
+                        ESTARGZ_UNCOMPRESSED_SIZE_ANNOTATION.to_owned(),
+                        framer.uncompressed_len.to_string(),
+                    ),
+                ]),
+            })
+        }
+        Flavor::ZstdChunked => {
+            // the tar stream is reproduced exactly, including the
+            // end-of-archive marker and any trailing padding
+            pending.extend(read_at(&raw, pos, tar_len)?);
+            framer.frame(&pending)?;
+            tar_split.segment(&pending)?;
+
+            let tar_split_compressed = zstd::bulk::compress(&tar_split.data, ZSTD_LEVEL)
+                .context("while compressing tar-split")?;
+            let manifest = serde_json::to_vec(&Toc {
+                version: 1,
+                entries: toc,
+                tar_split_digest: Some(format!(
+                    "sha256:{}",
+                    hex::encode(Sha256::digest(&tar_split_compressed))
+                )),
+            })
+            .context("while serializing toc")?;
+            let manifest_compressed =
+                zstd::bulk::compress(&manifest, ZSTD_LEVEL).context("while compressing toc")?;
+
+            // skip past the skippable frame header
+            let manifest_offset = framer.offset + 8;
+            framer.skippable_frame(&manifest_compressed)?;
+            let tar_split_offset = framer.offset + 8;
+            framer.skippable_frame(&tar_split_compressed)?;
+
+            let mut footer = Vec::with_capacity(64);
+            for v in [
+                manifest_offset,
+                manifest_compressed.len() as u64,
+                manifest.len() as u64,
+                ZSTD_CHUNKED_MANIFEST_TYPE_CRFS,
+                tar_split_offset,
+                tar_split_compressed.len() as u64,
+                tar_split.data.len() as u64,
+            ] {
+                footer.extend(v.to_le_bytes());
+            }
+            footer.extend(ZSTD_CHUNKED_FRAME_MAGIC);
+            framer.skippable_frame(&footer)?;
+
+            Ok(SeekableLayer {
+                diff_id: format!("sha256:{}", hex::encode(framer.uncompressed.finalize())),
+                annotations: HashMap::from([
+                    (
+                        ZSTD_CHUNKED_MANIFEST_CHECKSUM_ANNOTATION.to_owned(),
+                        format!(
+                            "sha256:{}",
+                            hex::encode(Sha256::digest(&manifest_compressed))
+                        ),
+                    ),
+                    (
+                        ZSTD_CHUNKED_MANIFEST_POSITION_ANNOTATION.to_owned(),
+                        format!(
+                            "{}:{}:{}:{}",
+                            manifest_offset,
+                            manifest_compressed.len(),
+                            manifest.len(),
+                            ZSTD_CHUNKED_MANIFEST_TYPE_CRFS
+                        ),
+                    ),
+                    (
+                        ZSTD_CHUNKED_TARSPLIT_POSITION_ANNOTATION.to_owned(),
+                        format!(
+                            "{}:{}:{}",
+                            tar_split_offset,
+                            tar_split_compressed.len(),
+                            tar_split.data.len()
+                        ),
+                    ),
+                ]),
+            })
+        }
+    }
+}
+
+/// The eStargz footer is an empty gzip member whose extra field records the
+/// offset of the gzip member containing the toc. It is always exactly 51
+/// bytes, so readers can find it at the end of the blob.
+fn estargz_footer(toc_offset: u64) -> Vec<u8> {
+    let subfield = format!("{toc_offset:016x}STARGZ");
+    let mut footer = vec![
+        0x1f, 0x8b, // magic
+        0x08, // deflate
+        0x04, // FEXTRA
+        0x00, 0x00, 0x00, 0x00, // mtime
+        0x00, // XFL
+        0xff, // OS (unknown)
+    ];
+    footer.extend(((4 + subfield.len()) as u16).to_le_bytes());
+    footer.extend(b"SG");
+    footer.extend((subfield.len() as u16).to_le_bytes());
+    footer.extend(subfield.as_bytes());
+    // final, empty stored deflate block
+    footer.extend([0x01, 0x00, 0x00, 0xff, 0xff]);
+    // crc32 and size of the (empty) contents
+    footer.extend([0; 8]);
+    footer
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use cap_std::fs::Dir;
+    use flate2::read::GzDecoder;
+    use flate2::read::MultiGzDecoder;
+    use oci_spec::image::Descriptor;
+
+    use super::*;
+    use crate::oci::write_layer;
+    use crate::oci::LayerCompression;
+
+    const HELLO: &[u8] = b"hello world\n";
+
+    fn header(entry_type: EntryType, mode: u32, size: u64) -> Header {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_size(size);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header
+    }
+
+    /// Layer tarball with a directory, a small file and a symlink
+    fn layer_tar(dir: &Path) -> std::path::PathBuf {
+        let path = dir.join("layer.tar");
+        let mut builder = tar::Builder::new(File::create(&path).expect("failed to create tar"));
+        builder
+            .append_data(
+                &mut header(EntryType::Directory, 0o755, 0),
+                "etc/",
+                std::io::empty(),
+            )
+            .expect("failed to append dir");
+        builder
+            .append_data(
+                &mut header(EntryType::Regular, 0o644, HELLO.len() as u64),
+                "etc/hello",
+                HELLO,
+            )
+            .expect("failed to append file");
+        builder
+            .append_link(
+                &mut header(EntryType::Symlink, 0o777, 0),
+                "etc/greeting",
+                "hello",
+            )
+            .expect("failed to append symlink");
+        builder.finish().expect("failed to finish tar");
+        path
+    }
+
+    struct Built {
+        descriptor: Descriptor,
+        diff_id: String,
+        blob: Vec<u8>,
+        /// The layer tarball that the blob was built from
+        tar: Vec<u8>,
+    }
+
+    /// Write a seekable layer into a blobs dir
+    fn build(flavor: LayerCompression) -> Built {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let tar = layer_tar(dir.path());
+        let blobs = dir.path().join("blobs");
+        std::fs::create_dir(&blobs).expect("failed to create blobs dir");
+        let blobs_dir = Dir::open_ambient_dir(&blobs, cap_std::ambient_authority())
+            .expect("failed to open blobs dir");
+        let (descriptor, diff_id) =
+            write_layer(&blobs_dir, &tar, flavor, None).expect("failed to write layer");
+        let blob =
+            std::fs::read(blobs.join(&descriptor.digest()[7..])).expect("failed to read blob");
+        let tar = std::fs::read(&tar).expect("failed to read tar");
+        Built {
+            descriptor,
+            diff_id,
+            blob,
+            tar,
+        }
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        format!("sha256:{}", hex::encode(Sha256::digest(data)))
+    }
+
+    fn annotation<'a>(descriptor: &'a Descriptor, key: &str) -> &'a str {
+        descriptor
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get(key))
+            .unwrap_or_else(|| panic!("missing annotation {key}"))
+    }
+
+    fn toc_entry<'a>(toc: &'a serde_json::Value, name: &str) -> &'a serde_json::Value {
+        toc["entries"]
+            .as_array()
+            .expect("entries is not an array")
+            .iter()
+            .find(|e| e["name"] == name && e["type"] != "chunk")
+            .unwrap_or_else(|| panic!("{name} is not in the toc"))
+    }
+
+    #[test]
+    fn estargz_toc() {
+        let Built {
+            descriptor,
+            diff_id,
+            blob,
+            tar,
+        } = build(LayerCompression::Estargz);
+
+        // the whole blob is still an ordinary gzipped tar
+        let mut uncompressed = Vec::new();
+        MultiGzDecoder::new(&blob[..])
+            .read_to_end(&mut uncompressed)
+            .expect("failed to decompress layer");
+        assert_eq!(sha256(&uncompressed), diff_id);
+        assert_eq!(
+            uncompressed.len().to_string(),
+            annotation(&descriptor, ESTARGZ_UNCOMPRESSED_SIZE_ANNOTATION)
+        );
+        let names: Vec<_> = tar::Archive::new(&uncompressed[..])
+            .entries()
+            .expect("failed to read entries")
+            .map(|e| {
+                e.expect("bad entry")
+                    .path()
+                    .expect("bad path")
+                    .display()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ESTARGZ_NO_PREFETCH_LANDMARK,
+                "etc/",
+                "etc/hello",
+                "etc/greeting",
+                ESTARGZ_TOC_NAME
+            ]
+        );
+        // the original tar (minus its end-of-archive marker) is in there
+        assert!(uncompressed.windows(512).any(|w| w == &tar[..512]));
+
+        // the footer is the last 51 bytes, and points to the toc
+        let footer = &blob[blob.len() - 51..];
+        assert_eq!(&footer[..4], &[0x1f, 0x8b, 0x08, 0x04]);
+        assert_eq!(&footer[12..14], b"SG");
+        assert_eq!(&footer[32..38], b"STARGZ");
+        let toc_offset = u64::from_str_radix(
+            std::str::from_utf8(&footer[16..32]).expect("offset is not utf8"),
+            16,
+        )
+        .expect("offset is not hex") as usize;
+
+        let mut toc_tar = tar::Archive::new(GzDecoder::new(&blob[toc_offset..blob.len() - 51]));
+        let mut toc_member = toc_tar
+            .entries()
+            .expect("failed to read toc tar")
+            .next()
+            .expect("toc tar is empty")
+            .expect("bad toc entry");
+        assert_eq!(
+            Path::new(ESTARGZ_TOC_NAME),
+            toc_member.path().expect("bad path")
+        );
+        let mut toc_bytes = Vec::new();
+        toc_member
+            .read_to_end(&mut toc_bytes)
+            .expect("failed to read toc");
+        assert_eq!(
+            sha256(&toc_bytes),
+            annotation(&descriptor, ESTARGZ_TOC_DIGEST_ANNOTATION)
+        );
+
+        let toc: serde_json::Value = serde_json::from_slice(&toc_bytes).expect("invalid toc");
+        assert_eq!(toc["version"], 1);
+        assert_eq!(toc_entry(&toc, "etc")["type"], "dir");
+        assert_eq!(toc_entry(&toc, "etc/greeting")["linkName"], "hello");
+        // the file's contents are in their own gzip member at its offset
+        let hello = toc_entry(&toc, "etc/hello");
+        assert_eq!(hello["size"], HELLO.len());
+        assert_eq!(hello["digest"], sha256(HELLO));
+        let offset = hello["offset"].as_u64().expect("no offset") as usize;
+        let mut contents = Vec::new();
+        GzDecoder::new(&blob[offset..])
+            .read_to_end(&mut contents)
+            .expect("failed to decompress file");
+        assert_eq!(HELLO, contents);
+    }
+
+    #[test]
+    fn zstd_chunked_toc() {
+        let Built {
+            descriptor,
+            diff_id,
+            blob,
+            tar,
+        } = build(LayerCompression::ZstdChunked);
+
+        // the whole blob decompresses to exactly the original tar, since the
+        // metadata is all in skippable frames
+        assert_eq!(sha256(&tar), diff_id);
+        assert_eq!(
+            tar,
+            zstd::decode_all(&blob[..]).expect("failed to decompress layer")
+        );
+
+        // the footer is the last skippable frame
+        let footer = &blob[blob.len() - 72..];
+        assert_eq!(&footer[..4], &ZSTD_SKIPPABLE_FRAME_MAGIC.to_le_bytes());
+        assert_eq!(&footer[4..8], &64u32.to_le_bytes());
+        assert_eq!(&footer[64..], ZSTD_CHUNKED_FRAME_MAGIC);
+        let fields: Vec<u64> = footer[8..64]
+            .chunks(8)
+            .map(|b| u64::from_le_bytes(b.try_into().expect("8 bytes")))
+            .collect();
+        let [manifest_offset, manifest_len, manifest_uncompressed_len, manifest_type, tar_split_offset, tar_split_len, tar_split_uncompressed_len] =
+            fields[..]
+        else {
+            panic!("footer has the wrong number of fields");
+        };
+        assert_eq!(ZSTD_CHUNKED_MANIFEST_TYPE_CRFS, manifest_type);
+        assert_eq!(
+            format!("{manifest_offset}:{manifest_len}:{manifest_uncompressed_len}:{manifest_type}"),
+            annotation(&descriptor, ZSTD_CHUNKED_MANIFEST_POSITION_ANNOTATION)
+        );
+        assert_eq!(
+            format!("{tar_split_offset}:{tar_split_len}:{tar_split_uncompressed_len}"),
+            annotation(&descriptor, ZSTD_CHUNKED_TARSPLIT_POSITION_ANNOTATION)
+        );
+
+        let manifest_compressed =
+            &blob[manifest_offset as usize..(manifest_offset + manifest_len) as usize];
+        assert_eq!(
+            sha256(manifest_compressed),
+            annotation(&descriptor, ZSTD_CHUNKED_MANIFEST_CHECKSUM_ANNOTATION)
+        );
+        let manifest = zstd::decode_all(manifest_compressed).expect("failed to decompress toc");
+        assert_eq!(manifest_uncompressed_len as usize, manifest.len());
+        let toc: serde_json::Value = serde_json::from_slice(&manifest).expect("invalid toc");
+
+        let tar_split_compressed =
+            &blob[tar_split_offset as usize..(tar_split_offset + tar_split_len) as usize];
+        assert_eq!(toc["tarsplitdigest"], sha256(tar_split_compressed));
+        let tar_split =
+            zstd::decode_all(tar_split_compressed).expect("failed to decompress tar-split");
+        assert_eq!(tar_split_uncompressed_len as usize, tar_split.len());
+
+        assert_eq!(toc["version"], 1);
+        assert_eq!(toc_entry(&toc, "etc/")["type"], "dir");
+        assert_eq!(toc_entry(&toc, "etc/greeting")["linkName"], "hello");
+        // the file's contents are in their own zstd frame
+        let hello = toc_entry(&toc, "etc/hello");
+        assert_eq!(hello["digest"], sha256(HELLO));
+        let offset = hello["offset"].as_u64().expect("no offset") as usize;
+        let end_offset = hello["endOffset"].as_u64().expect("no endOffset") as usize;
+        assert_eq!(
+            HELLO,
+            zstd::decode_all(&blob[offset..end_offset]).expect("failed to decompress file")
+        );
+    }
+
+    #[test]
+    fn footer_size() {
+        assert_eq!(estargz_footer(1234).len(), 51);
+    }
+
+    #[test]
+    fn crc64_iso() {
+        // check value for the ISO polynomial
+        let mut crc = Crc64::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.crc, 0xb90956c775a41001);
+    }
+}
@@ -0,0 +1,231 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Packaging the same layer twice must always produce identical bytes.
+//!
+//! Timestamps follow the `SOURCE_DATE_EPOCH` convention
+//! (https://reproducible-builds.org/specs/source-date-epoch/): no timestamp
+//! recorded in a package is ever later than it, which still preserves any
+//! older timestamps that came from something reproducible (like an rpm
+//! payload).
+//!
+//! Anything that mkfs-like tools would otherwise generate randomly (filesystem
+//! uuids, directory hash seeds, volume ids) is instead derived from a
+//! [Digest] of the input.
+
+use std::env::VarError;
+use std::fs::File;
+use std::fs::Metadata;
+use std::io::BufReader;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use anyhow::Context;
+use anyhow::Result;
+use uuid::Uuid;
+use walkdir::WalkDir;
+
+use crate::manifest::HashingReader;
+use crate::manifest::Manifest;
+
+/// Used when `SOURCE_DATE_EPOCH` is not set. February 4 2004 is an arbitrary
+/// time that is recent enough that tools which don't tolerate 0 timestamps
+/// are happy.
+pub(crate) const DEFAULT_SOURCE_DATE_EPOCH: u64 = 1075852800;
+
+const BLAKE3_KEY: &str = "74dbd000-062c-498b-92fc-3e4b7efdcab4";
+
+/// Latest timestamp that may appear in a package, from the `SOURCE_DATE_EPOCH`
+/// environment variable.
+pub(crate) fn source_date_epoch() -> Result<u64> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch
+            .parse()
+            .with_context(|| format!("SOURCE_DATE_EPOCH '{epoch}' is not a unix timestamp")),
+        Err(VarError::NotPresent) => Ok(DEFAULT_SOURCE_DATE_EPOCH),
+        Err(e) => Err(e).context("while reading SOURCE_DATE_EPOCH"),
+    }
+}
+
+/// `time`, but never later than `epoch`
+pub(crate) fn clamp(time: SystemTime, epoch: u64) -> SystemTime {
+    std::cmp::min(time, SystemTime::UNIX_EPOCH + Duration::from_secs(epoch))
+}
+
+/// Identifies the contents of some input to a package, to derive otherwise
+/// random identifiers from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Digest(blake3::Hash);
+
+impl Digest {
+    /// Digest of all the contents and metadata of a directory tree (except
+    /// timestamps, which are rarely reproducible). Every entry is also
+    /// recorded in `manifest` (if there is one) from the same walk.
+    pub(crate) fn of_tree(root: &Path, manifest: Option<&mut Manifest>) -> Result<Self> {
+        Self::of_tree_with(root, manifest, |_, _| Ok(()))
+    }
+
+    /// Like [Digest::of_tree], but also call `visit` with the relative path
+    /// and metadata of every entry, so that callers that need to walk the
+    /// tree anyway don't have to do it twice.
+    pub(crate) fn of_tree_with(
+        root: &Path,
+        mut manifest: Option<&mut Manifest>,
+        mut visit: impl FnMut(&Path, &Metadata) -> Result<()>,
+    ) -> Result<Self> {
+        let mut hasher = blake3::Hasher::new_derive_key(BLAKE3_KEY);
+        for entry in WalkDir::new(root).sort_by_file_name() {
+            let entry = entry.with_context(|| format!("while walking {}", root.display()))?;
+            let relpath = entry.path().strip_prefix(root)?;
+            let meta = entry.metadata()?;
+            // every field is fixed-size or length-prefixed so that
+            // different trees can never hash the same
+            let relpath_bytes = relpath.as_os_str().as_bytes();
+            hasher.update(&(relpath_bytes.len() as u64).to_le_bytes());
+            hasher.update(relpath_bytes);
+            hasher.update(&meta.mode().to_le_bytes());
+            hasher.update(&meta.uid().to_le_bytes());
+            hasher.update(&meta.gid().to_le_bytes());
+            let ft = meta.file_type();
+            if ft.is_block_device() || ft.is_char_device() {
+                hasher.update(&meta.rdev().to_le_bytes());
+            }
+            if ft.is_symlink() {
+                let target = std::fs::read_link(entry.path())?;
+                let target = target.as_os_str().as_bytes();
+                hasher.update(&(target.len() as u64).to_le_bytes());
+                hasher.update(target);
+            }
+            let mut xattrs = xattr::list(entry.path())
+                .with_context(|| format!("while listing xattrs on {}", entry.path().display()))?
+                .collect::<Vec<_>>();
+            xattrs.sort();
+            for name in xattrs {
+                let value = xattr::get(entry.path(), &name)
+                    .with_context(|| format!("while reading xattrs on {}", entry.path().display()))?
+                    .unwrap_or_default();
+                hasher.update(&(name.len() as u64).to_le_bytes());
+                hasher.update(name.as_bytes());
+                hasher.update(&(value.len() as u64).to_le_bytes());
+                hasher.update(&value);
+            }
+            let mut sha256 = None;
+            if ft.is_file() {
+                hasher.update(&meta.len().to_le_bytes());
+                let mut file = HashingReader::new(
+                    BufReader::new(File::open(entry.path()).with_context(|| {
+                        format!("while opening file {}", entry.path().display())
+                    })?),
+                    manifest.is_some(),
+                );
+                hasher
+                    .update_reader(&mut file)
+                    .with_context(|| format!("while hashing file {}", entry.path().display()))?;
+                sha256 = file.into_hasher();
+            }
+            if let Some(manifest) = manifest.as_deref_mut() {
+                manifest.add(root, relpath, &meta, sha256)?;
+            }
+            visit(relpath, &meta)?;
+        }
+        Ok(Self(hasher.finalize()))
+    }
+
+    /// Digest of the contents of some files, in order
+    pub(crate) fn of_files<P: AsRef<Path>>(paths: impl IntoIterator<Item = P>) -> Result<Self> {
+        let mut hasher = blake3::Hasher::new_derive_key(BLAKE3_KEY);
+        for path in paths {
+            let path = path.as_ref();
+            let file = File::open(path)
+                .with_context(|| format!("while opening file {}", path.display()))?;
+            let len = file.metadata()?.len();
+            hasher.update(&len.to_le_bytes());
+            hasher
+                .update_reader(BufReader::new(file))
+                .with_context(|| format!("while hashing file {}", path.display()))?;
+        }
+        Ok(Self(hasher.finalize()))
+    }
+
+    /// Deterministic stand-in for random bytes, distinct for each `purpose`
+    fn derive(&self, purpose: &str) -> blake3::Hash {
+        let mut hasher = blake3::Hasher::new_derive_key(BLAKE3_KEY);
+        hasher.update(&(purpose.len() as u64).to_le_bytes());
+        hasher.update(purpose.as_bytes());
+        hasher.update(self.0.as_bytes());
+        hasher.finalize()
+    }
+
+    /// Random-looking (v4) uuid that is unique to this digest and `purpose`
+    pub(crate) fn uuid(&self, purpose: &str) -> Uuid {
+        uuid::Builder::from_random_bytes(
+            self.derive(purpose).as_bytes()[..16]
+                .try_into()
+                .expect("blake3 hash is 32 bytes"),
+        )
+        .into_uuid()
+    }
+
+    /// Random-looking 32-bit id that is unique to this digest and `purpose`
+    pub(crate) fn u32(&self, purpose: &str) -> u32 {
+        u32::from_le_bytes(
+            self.derive(purpose).as_bytes()[..4]
+                .try_into()
+                .expect("blake3 hash is 32 bytes"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(contents: &str) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        std::fs::write(dir.path().join("file"), contents).expect("failed to write file");
+        dir
+    }
+
+    #[test]
+    fn clamps_to_epoch() {
+        let epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(DEFAULT_SOURCE_DATE_EPOCH);
+        assert_eq!(epoch, clamp(SystemTime::now(), DEFAULT_SOURCE_DATE_EPOCH));
+        let old = SystemTime::UNIX_EPOCH + Duration::from_nanos(1_000_000_001);
+        assert_eq!(old, clamp(old, DEFAULT_SOURCE_DATE_EPOCH));
+    }
+
+    #[test]
+    fn digest_ignores_timestamps() {
+        let dir = tree("hello");
+        let before = Digest::of_tree(dir.path(), None).expect("digest failed");
+        File::options()
+            .write(true)
+            .open(dir.path().join("file"))
+            .expect("failed to open file")
+            .set_modified(SystemTime::UNIX_EPOCH)
+            .expect("failed to set mtime");
+        let after = Digest::of_tree(dir.path(), None).expect("digest failed");
+        assert_eq!(before, after);
+        assert_ne!(
+            before,
+            Digest::of_tree(tree("goodbye").path(), None).expect("digest failed")
+        );
+    }
+
+    #[test]
+    fn derived_ids_depend_on_purpose() {
+        let digest = Digest::of_tree(tree("hello").path(), None).expect("digest failed");
+        assert_eq!(digest.uuid("a"), digest.uuid("a"));
+        assert_ne!(digest.uuid("a"), digest.uuid("b"));
+        assert_ne!(digest.u32("a"), digest.u32("b"));
+        assert_eq!(Some(uuid::Version::Random), digest.uuid("a").get_version());
+    }
+}
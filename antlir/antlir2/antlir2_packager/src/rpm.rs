@@ -28,6 +28,8 @@ use nix::unistd::User;
 use serde::Deserialize;
 use tempfile::NamedTempFile;
 
+use crate::manifest::Manifest;
+use crate::reproducible;
 use crate::run_cmd;
 use crate::BuildAppliance;
 use crate::PackageFormat;
@@ -88,7 +90,7 @@ pub struct Rpm {
 }
 
 impl PackageFormat for Rpm {
-    fn build(&self, out: &Path, layer: &Path) -> Result<()> {
+    fn build(&self, out: &Path, layer: &Path, mut manifest: Option<&mut Manifest>) -> Result<()> {
         let build_requires = self
             .build_requires
             .iter()
@@ -262,6 +264,10 @@ AutoProv: {autoprov}
         if let Some(binary_payload) = &self.binary_payload {
             spec.push_str(&format!("%define _binary_payload {binary_payload}\n"));
         }
+        // Clamp file mtimes in the payload and use SOURCE_DATE_EPOCH instead of
+        // the current time for the rpm's build time
+        spec.push_str("%define clamp_mtime_to_source_date_epoch 1\n");
+        spec.push_str("%define use_source_date_epoch_as_buildtime 1\n");
         // rpmbuild may silently change shebangs from /bin/bash to /usr/bin/bash (see
         // https://asamalik.fedorapeople.org/tmp-docs-preview/packaging-guidelines/#_shebang_lines).
         // This breaks PARs, so we want to disable it.
@@ -301,6 +307,14 @@ AutoProv: {autoprov}
                 }
                 let metadata = std::fs::symlink_metadata(entry.path())
                     .context("while getting file metadata")?;
+                if let Some(manifest) = manifest.as_deref_mut() {
+                    manifest.add(
+                        layer,
+                        relpath.strip_prefix("/").expect("relpath is absolute"),
+                        &metadata,
+                        None,
+                    )?;
+                }
                 let group_name = Group::from_gid(Gid::from_raw(metadata.st_gid()))
                     .context("while getting group name")?
                     .expect("must be a valid group");
@@ -342,11 +356,13 @@ AutoProv: {autoprov}
         // owned by the build user on the host, not root
         std::fs::create_dir(output_dir.path().join(arch)).context("while creating output dir")?;
 
+        let epoch = reproducible::source_date_epoch()?.to_string();
         let mut isol_context = IsolationContext::builder(self.build_appliance.path());
         isol_context
             .ephemeral(false)
             .readonly()
             .hostname("antlir2")
+            .setenv(("SOURCE_DATE_EPOCH", epoch.as_str()))
             // random buck-out paths that might be being used (for installing .rpms)
             .inputs((
                 PathBuf::from("/__antlir2__/working_directory"),
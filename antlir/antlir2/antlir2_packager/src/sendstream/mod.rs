@@ -11,6 +11,7 @@ use std::path::PathBuf;
 use anyhow::Result;
 use serde::Deserialize;
 
+use crate::manifest::Manifest;
 use crate::PackageFormat;
 mod btrfs_send;
 mod userspace;
@@ -21,16 +22,28 @@ pub struct Sendstream {
     volume_name: String,
     #[serde(default)]
     incremental_parent: Option<PathBuf>,
+    /// Buck label of the layer, used to derive deterministic subvolume uuids
+    /// for userspace sendstreams
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    incremental_parent_label: Option<String>,
     subvol_symlink: Option<PathBuf>,
     userspace: bool,
     compression_level: u32,
 }
 
 impl PackageFormat for Sendstream {
-    fn build(&self, out: &Path, layer: &Path) -> Result<()> {
+    fn build(&self, out: &Path, layer: &Path, manifest: Option<&mut Manifest>) -> Result<()> {
         match self.userspace {
-            false => btrfs_send::build(self, out, layer),
-            true => userspace::build(self, out, layer),
+            false => {
+                btrfs_send::build(self, out, layer)?;
+                match manifest {
+                    Some(manifest) => manifest.add_tree(layer),
+                    None => Ok(()),
+                }
+            }
+            true => userspace::build(self, out, layer, manifest),
         }
     }
 }
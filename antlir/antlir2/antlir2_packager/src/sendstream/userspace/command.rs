@@ -111,6 +111,30 @@ where
         .finish()
 }
 
+pub(crate) fn clone<P1, P2>(
+    path: P1,
+    offset: u64,
+    len: u64,
+    clone_uuid: Uuid,
+    clone_ctransid: u64,
+    clone_path: P2,
+    clone_offset: u64,
+) -> Vec<u8>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    CommandBuilder::new(16)
+        .tlv(&Tlv::Path(path.as_ref()))
+        .tlv(&Tlv::FileOffset(offset))
+        .tlv(&Tlv::CloneLen(len))
+        .tlv(&Tlv::CloneUuid(clone_uuid))
+        .tlv(&Tlv::CloneCtransid(clone_ctransid))
+        .tlv(&Tlv::ClonePath(clone_path.as_ref()))
+        .tlv(&Tlv::CloneOffset(clone_offset))
+        .finish()
+}
+
 pub(crate) fn hardlink<P1, P2>(original: P1, link: P2) -> Vec<u8>
 where
     P1: AsRef<Path>,
@@ -5,41 +5,71 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+//! Produce a sendstream from any directory tree, without needing btrfs for
+//! either the layer or its incremental parent.
+//!
+//! A real btrfs subvolume has a random uuid, but this has to be able to
+//! package layers that were not built on btrfs at all, so instead the
+//! subvolume uuids in the sendstream are derived from the layer's buck label
+//! and contents. Since the parent's uuid is computed exactly the same way when
+//! it is packaged, an incremental sendstream will be received on top of the
+//! subvolume that was received from the parent's sendstream.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
-use std::collections::HashSet;
 use std::ffi::OsString;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::Read;
 use std::io::Write;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::FileTypeExt;
 use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
 use std::process::Stdio;
-use std::time::Duration;
-use std::time::UNIX_EPOCH;
 
+use antlir2_change_stream::Iter;
+use antlir2_change_stream::Operation;
 use anyhow::anyhow;
 use anyhow::Context;
-use anyhow::Error;
 use anyhow::Result;
-use nix::sys::stat::Mode;
 use tracing::trace;
 use uuid::Uuid;
-use walkdir::DirEntryExt;
-use walkdir::WalkDir;
 
 use super::Sendstream;
+use crate::manifest::Manifest;
+use crate::reproducible;
+use crate::reproducible::Digest;
 mod command;
 mod tlv;
 mod writer;
 
-const BLAKE3_KEY: &str = "74dbd000-062c-498b-92fc-3e4b7efdcab4";
+/// There is no real transaction id without btrfs, but the receiver checks
+/// that the parent's ctransid matches what the incremental sendstream expects,
+/// so it must be the same for every sendstream that this produces.
+const CTRANSID: u64 = 1;
+
+/// The first inode number that btrfs uses for files in a subvolume. The
+/// receiver ignores inode numbers, so these are just a deterministic sequence.
+const FIRST_INO: u64 = 257;
+
+/// Clones must be aligned to the filesystem block size (except for the end
+/// of a file)
+const BLOCK_SIZE: u64 = 4096;
+
+/// 60k because we need a little bit of space to to store metadata (so can't
+/// use a full 16-bit size), and it is a multiple of the block size so that
+/// every chunk can be cloned
+const CHUNK_SIZE: usize = 61440;
 
-pub(super) fn build(spec: &Sendstream, out: &Path, layer: &Path) -> Result<()> {
+pub(super) fn build(
+    spec: &Sendstream,
+    out: &Path,
+    layer: &Path,
+    manifest: Option<&mut Manifest>,
+) -> Result<()> {
     let rootless = antlir2_rootless::init().context("while initializing rootless")?;
     let canonical_layer = layer.canonicalize()?;
 
@@ -62,354 +92,593 @@ pub(super) fn build(spec: &Sendstream, out: &Path, layer: &Path) -> Result<()> {
 
     let _root = rootless.escalate()?;
 
-    let subvol = antlir2_btrfs::Subvolume::open(&canonical_layer)
-        .with_context(|| format!("while opening subvol {}", canonical_layer.display()))?;
-    let info = subvol.info().context("while getting subvol info")?;
-
-    // NOTE on uuids: each subvolume has its own true (unique) uuid, but we
-    // want to put the "received uuid" (if it exists) in the sendstream so that
-    // they can be accurately identified by the receiver for use with
-    // incremental send/receive.
-    if let Some(parent) = &spec.incremental_parent {
-        let parent_info = antlir2_btrfs::Subvolume::open(parent)
-            .with_context(|| format!("while opening parent subvol {}", parent.display()))?
-            .info()
-            .with_context(|| format!("while getting info of parent subvol {}", parent.display()))?;
-        f.write_all(&command::snapshot(
-            &spec.volume_name,
-            info.uuid(),
-            info.ctransid(),
-            parent_info.received_uuid().unwrap_or(parent_info.uuid()),
-            parent_info.ctransid(),
-        ))?;
+    let label = spec
+        .label
+        .as_deref()
+        .context("label is required for userspace sendstreams")?;
+    let tree = Tree::scan(&canonical_layer, manifest)?;
+    let uuid = tree.digest.uuid(label);
+
+    let stream: Iter<File> = match &spec.incremental_parent {
+        Some(parent) => {
+            let parent_label = spec
+                .incremental_parent_label
+                .as_deref()
+                .context("incremental_parent_label is required with incremental_parent")?;
+            let parent_tree = Tree::scan(parent, None)?;
+            f.write_all(&command::snapshot(
+                &spec.volume_name,
+                uuid,
+                CTRANSID,
+                parent_tree.digest.uuid(parent_label),
+                CTRANSID,
+            ))?;
+            Iter::diff(parent, &canonical_layer).context("while diffing parent and layer")?
+        }
+        None => {
+            f.write_all(&command::subvol(&spec.volume_name, uuid, CTRANSID))?;
+            Iter::from_empty(&canonical_layer).context("while walking layer")?
+        }
+    };
+
+    let mut sender = Sender {
+        out: &mut f,
+        layer: &canonical_layer,
+        parent: spec.incremental_parent.as_deref(),
+        tree: &tree,
+        next_ino: FIRST_INO,
+        uuid,
+        source_date_epoch: reproducible::source_date_epoch()?,
+        chunks: HashMap::new(),
+        links: HashMap::new(),
+    };
+    sender.send_all(stream)?;
+
+    f.write_all(&command::end())?;
+    drop(f);
+
+    let status = upgrade
+        .wait()
+        .context("while waiting for sendstream-upgrade to finish")?;
+    if !status.success() {
+        Err(anyhow!("sendstream-upgrade failed: {status}"))
     } else {
-        f.write_all(&command::subvol(
-            &spec.volume_name,
-            info.received_uuid().unwrap_or(info.uuid()),
-            info.ctransid(),
-        ))?;
+        Ok(())
     }
+}
 
-    // map ino -> relpath so that hardlinks can be detected
-    let mut inodes: HashMap<u64, PathBuf> = HashMap::new();
-    // keep track of relpaths which are seen in this subvol in case they
-    // need to be deleted from the parent
-    let mut present_relpaths: HashSet<PathBuf> = HashSet::new();
-
-    for entry in WalkDir::new(&canonical_layer) {
-        let entry = entry.context("while walking layer")?;
-        let relpath = entry.path().strip_prefix(&canonical_layer)?;
-        present_relpaths.insert(relpath.to_owned());
-        let span = tracing::trace_span!("file", path = relpath.display().to_string());
-        let _enter = span.enter();
-        trace!("processing dir entry");
-        let meta = entry.metadata()?;
-
-        match inodes.entry(entry.ino()) {
-            std::collections::hash_map::Entry::Occupied(e) => {
-                if let Some(parent) = &spec.incremental_parent {
-                    let parent_path = parent.join(relpath);
-                    match parent_path.symlink_metadata() {
-                        Ok(parent_meta) => {
-                            // hardlink already exists in child, skip
-                            if parent_meta.ino() == meta.ino() {
-                                continue;
-                            }
-                            // otherwise, continue on to create it below
-                        }
-                        // new file in child, fallthrough to non-incremental path
-                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
-                        Err(e) => {
-                            return Err(
-                                Error::from(e).context("while getting parent file metadata")
-                            );
-                        }
-                    }
-                }
-                let tmp = Uuid::new_v4().to_string();
-                f.write_all(&command::hardlink(e.get(), &tmp))?;
-                f.write_all(&command::rename(tmp, relpath))?;
+/// Everything that needs to be known about a whole tree before any commands
+/// can be sent.
+struct Tree {
+    digest: Digest,
+    /// (dev, ino) -> every path of each file that has more than one link
+    hardlinks: HashMap<(u64, u64), BTreeSet<PathBuf>>,
+}
+
+impl Tree {
+    fn scan(root: &Path, manifest: Option<&mut Manifest>) -> Result<Self> {
+        let mut hardlinks: HashMap<(u64, u64), BTreeSet<PathBuf>> = HashMap::new();
+        let digest = Digest::of_tree_with(root, manifest, |relpath, meta| {
+            if meta.is_file() && meta.nlink() > 1 {
+                hardlinks
+                    .entry((meta.dev(), meta.ino()))
+                    .or_default()
+                    .insert(relpath.to_owned());
+            }
+            Ok(())
+        })?;
+        Ok(Self { digest, hardlinks })
+    }
+}
+
+/// The order in which operations on the same path are sent: creating the file
+/// (and writing its contents) has to come first, chown has to come before
+/// chmod since it clears setuid bits, and xattrs come after both of those
+/// since chown clears security.capability. Timestamps are last so that
+/// nothing else changes them.
+fn rank(op: &Operation<File>) -> u8 {
+    match op {
+        Operation::Chown { .. } => 1,
+        Operation::Chmod { .. } => 2,
+        Operation::SetXattr { .. } | Operation::RemoveXattr { .. } => 3,
+        Operation::SetTimes { .. } => 4,
+        _ => 0,
+    }
+}
+
+/// Check that a link to `key` in the layer is the very same, unmodified file
+/// in the parent, so that a new link to it can be created instead of sending
+/// the contents again.
+fn unchanged(parent: &Path, layer: &Path, key: (u64, u64)) -> Result<bool> {
+    let (Ok(old), Ok(new)) = (parent.symlink_metadata(), layer.symlink_metadata()) else {
+        return Ok(false);
+    };
+    // The device number is not compared across trees, since every btrfs
+    // subvolume (including snapshots) gets its own, but a snapshot does
+    // preserve inode numbers
+    if !old.is_file()
+        || (new.dev(), new.ino()) != key
+        || old.ino() != new.ino()
+        || old.len() != new.len()
+        || old.mode() != new.mode()
+        || old.uid() != new.uid()
+        || old.gid() != new.gid()
+        || (old.mtime(), old.mtime_nsec()) != (new.mtime(), new.mtime_nsec())
+    {
+        return Ok(false);
+    }
+    if xattrs(parent)? != xattrs(layer)? {
+        return Ok(false);
+    }
+    let mut old = BufReader::new(File::open(parent)?);
+    let mut new = BufReader::new(File::open(layer)?);
+    let mut old_buf = vec![0; BLOCK_SIZE as usize];
+    let mut new_buf = vec![0; BLOCK_SIZE as usize];
+    loop {
+        let n = old.read(&mut old_buf)?;
+        if n == 0 {
+            // lengths are already known to be equal
+            return Ok(true);
+        }
+        new.read_exact(&mut new_buf[..n])?;
+        if old_buf[..n] != new_buf[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+fn xattrs(path: &Path) -> Result<BTreeMap<OsString, Option<Vec<u8>>>> {
+    xattr::list(path)?
+        .map(|name| {
+            let value = xattr::get(path, &name)?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+struct Sender<'a, W: Write> {
+    out: &'a mut W,
+    layer: &'a Path,
+    /// Incremental parent, if any
+    parent: Option<&'a Path>,
+    tree: &'a Tree,
+    next_ino: u64,
+    /// Subvolume uuid of this sendstream, which clones refer to
+    uuid: Uuid,
+    /// Latest timestamp that may be sent
+    source_date_epoch: u64,
+    /// Hash of every (full or EOF) chunk that was written -> where it was
+    /// written to
+    chunks: HashMap<blake3::Hash, (PathBuf, u64)>,
+    /// (dev, ino) -> path that changes were sent for, for files with more
+    /// than one link
+    links: HashMap<(u64, u64), PathBuf>,
+}
+
+impl<W: Write> Sender<'_, W> {
+    fn ino(&mut self) -> u64 {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        ino
+    }
+
+    /// Find an existing path that a new file can be hardlinked to, either one
+    /// that was already sent, or one that is unchanged from the parent (and
+    /// thus will not appear in the change stream at all).
+    fn link_target(&self, path: &Path, key: (u64, u64)) -> Result<Option<PathBuf>> {
+        if let Some(target) = self.links.get(&key) {
+            return Ok(Some(target.clone()));
+        }
+        let Some(parent) = self.parent else {
+            return Ok(None);
+        };
+        for sibling in self.tree.hardlinks.get(&key).into_iter().flatten() {
+            if sibling == path {
                 continue;
             }
-            std::collections::hash_map::Entry::Vacant(e) => {
-                e.insert(relpath.to_owned());
+            if unchanged(&parent.join(sibling), &self.layer.join(sibling), key)
+                .with_context(|| format!("while comparing {} to parent", sibling.display()))?
+            {
+                return Ok(Some(sibling.clone()));
             }
         }
+        Ok(None)
+    }
 
-        if let Some(parent) = &spec.incremental_parent {
-            let parent_path = parent.join(relpath);
-            match parent_path.symlink_metadata() {
-                Ok(parent_meta) => {
-                    if meta.is_dir() {
-                        // If this is a directory, the only thing we need to
-                        // send over are metadata updates
-                        if (meta.uid() != parent_meta.uid()) || (meta.gid() != parent_meta.gid()) {
-                            f.write_all(&command::chown(
-                                relpath,
-                                meta.uid() as u64,
-                                meta.gid() as u64,
-                            ))?;
-                        }
-                        if meta.mode() != parent_meta.mode() {
-                            f.write_all(&command::chmod(relpath, meta.mode() as u64))?;
-                        }
-                        let mut parent_xattrs: HashMap<_, _> = xattr::list(&parent_path)?
-                            .map(|name| {
-                                xattr::get(&parent_path, &name)
-                                    .map(|val| (name, val))
-                                    .context("while getting xattr on parent")
-                            })
-                            .collect::<Result<_>>()?;
-                        let self_xattrs: HashMap<_, _> = xattr::list(entry.path())?
-                            .map(|name| {
-                                xattr::get(entry.path(), &name)
-                                    .map(|val| (name, val))
-                                    .context("while getting xattr on self")
-                            })
-                            .collect::<Result<_>>()?;
-                        for (name, val) in self_xattrs
-                            .into_iter()
-                            .filter_map(|(n, v)| v.map(|v| (n, v)))
-                        {
-                            if let Some(parent_val) = parent_xattrs.remove(&name) {
-                                if Some(&val) != parent_val.as_ref() {
-                                    f.write_all(&command::set_xattr(
-                                        relpath,
-                                        name.as_bytes(),
-                                        val,
-                                    ))?;
-                                }
-                            }
-                        }
-                        for name in parent_xattrs.keys() {
-                            f.write_all(&command::rm_xattr(relpath, name.as_bytes()))?;
-                        }
-                        continue;
+    fn send_all(&mut self, stream: Iter<File>) -> Result<()> {
+        // All the changes for a single path are yielded consecutively, but
+        // they need to be reordered before sending (see Sender::send)
+        let mut pending: Option<(PathBuf, Vec<Operation<File>>)> = None;
+        for change in stream {
+            let change = change.context("while computing changes")?;
+            match &mut pending {
+                Some((path, ops)) if path == change.path() => ops.push(change.into_operation()),
+                _ => {
+                    if let Some((path, ops)) = pending.take() {
+                        self.send(&path, ops)?;
                     }
+                    pending = Some((change.path().to_owned(), vec![change.into_operation()]));
+                }
+            }
+        }
+        if let Some((path, ops)) = pending.take() {
+            self.send(&path, ops)?;
+        }
+        Ok(())
+    }
 
-                    if meta.is_file() {
-                        let file_contents_changed = if parent_meta.len() != meta.len() {
-                            true
-                        } else {
-                            let parent_file =
-                                BufReader::new(File::open(&parent_path).with_context(|| {
-                                    format!("while opening file {}", parent_path.display())
-                                })?);
-                            let mut hasher = blake3::Hasher::new_derive_key(BLAKE3_KEY);
-                            hasher.update_reader(parent_file).with_context(|| {
-                                format!("while hashing file {}", parent_path.display())
-                            })?;
-                            let parent_hash = hasher.finalize();
-                            let infile =
-                                BufReader::new(File::open(entry.path()).with_context(|| {
-                                    format!("while opening file {}", entry.path().display())
-                                })?);
-                            let mut hasher = blake3::Hasher::new_derive_key(BLAKE3_KEY);
-                            hasher.update_reader(infile).with_context(|| {
-                                format!("while hashing file {}", entry.path().display())
-                            })?;
-                            let new_hash = hasher.finalize();
-                            parent_hash != new_hash
-                        };
-
-                        if file_contents_changed {
-                            // TODO: support more efficient updates on
-                            // append-only files (where len > parent_len but
-                            // hash(parent[..parent_len]) == hash(file[..parent_len]))
-                            f.write_all(&command::truncate(relpath, meta.size()))?;
-                            let mut infile =
-                                BufReader::new(File::open(entry.path()).with_context(|| {
-                                    format!("while opening file {}", entry.path().display())
-                                })?);
-                            // 60k because we need a little bit of space to to store
-                            // metadata (so can't use a full 16-bit size), and 4k
-                            // boundaries are nice
-                            let mut buf = [0u8; 61440];
-                            let mut offset = 0;
-                            loop {
-                                let read = infile.read(&mut buf).with_context(|| {
-                                    format!("1while reading from file {}", entry.path().display())
-                                })?;
-                                if read == 0 {
-                                    break;
-                                }
-                                f.write_all(&command::write(relpath, offset, &buf[..read]))?;
-                                offset += read as u64;
-                            }
-                        }
-                    }
+    fn send(&mut self, path: &Path, mut ops: Vec<Operation<File>>) -> Result<()> {
+        let span = tracing::trace_span!("file", path = path.display().to_string());
+        let _enter = span.enter();
+        trace!("sending {} operations", ops.len());
 
-                    if meta.is_symlink() {
-                        let original_target =
-                            std::fs::read_link(&parent_path).with_context(|| {
-                                format!("while reading link target of {}", parent_path.display())
-                            })?;
-                        let new_target = std::fs::read_link(entry.path()).with_context(|| {
-                            format!("while reading link target of {}", entry.path().display())
-                        })?;
-                        if new_target != original_target {
-                            f.write_all(&command::unlink(relpath))?;
-                            f.write_all(&command::symlink(new_target, relpath, entry.ino()))?;
-                            f.write_all(&command::chown(
-                                relpath,
-                                meta.uid().into(),
-                                meta.gid().into(),
-                            ))?;
-                        }
-                    }
+        let created = ops.iter().any(|op| matches!(op, Operation::Create { .. }));
+        let inode = match self.layer.join(path).symlink_metadata() {
+            Ok(meta) if meta.is_file() && meta.nlink() > 1 => Some((meta.dev(), meta.ino())),
+            _ => None,
+        };
+        if let Some(key) = inode {
+            if created {
+                if let Some(target) = self.link_target(path, key)? {
+                    // all the metadata is shared with the existing link, so
+                    // there's nothing else to do
+                    self.out.write_all(&command::hardlink(target, path))?;
+                    return Ok(());
+                }
+            } else if let Some(target) = self.links.get(&key) {
+                // the full state of this inode was already sent for another
+                // link, so replace whatever the parent had here with a link
+                // to that instead of applying the same changes again
+                trace!("changes were already sent for another link");
+                self.out.write_all(&command::unlink(path))?;
+                self.out.write_all(&command::hardlink(target, path))?;
+                return Ok(());
+            }
+            self.links.insert(key, path.to_owned());
+        }
 
-                    if parent_meta.uid() != meta.uid() || parent_meta.gid() != meta.gid() {
-                        f.write_all(&command::chown(
-                            relpath,
-                            meta.uid().into(),
-                            meta.gid().into(),
-                        ))?;
-                    }
-                    if !meta.file_type().is_symlink() && (parent_meta.mode() != meta.mode()) {
-                        let mode = Mode::from_bits_truncate(meta.mode());
-                        f.write_all(&command::chmod(relpath, mode.bits().into()))?;
-                    }
-                    let mut parent_xattrs = get_xattrs(&parent_path)?;
-                    let self_xattrs = get_xattrs(entry.path())?;
-                    for (name, val) in self_xattrs.into_iter().collect::<HashMap<_, _>>() {
-                        match parent_xattrs.remove(&name) {
-                            Some(parent_val) => {
-                                // skip this xattr if the value has not changed
-                                if val == parent_val {
-                                    continue;
-                                }
-                            }
-                            None => {}
-                        }
-                        f.write_all(&command::set_xattr(relpath, name.as_bytes(), val))?;
+        // the mode that a new file was created with has to be set separately
+        let mut implied = Vec::new();
+        for op in &ops {
+            match op {
+                Operation::Create { mode }
+                | Operation::Mkdir { mode }
+                | Operation::Mkfifo { mode }
+                | Operation::Mksock { mode } => implied.push(Operation::Chmod { mode: *mode }),
+                Operation::Mknod { mode, .. } => implied.push(Operation::Chmod {
+                    mode: mode & 0o7777,
+                }),
+                _ => {}
+            }
+        }
+        ops.extend(implied);
+        ops.sort_by_key(rank);
+
+        for op in ops {
+            match op {
+                // the file is created along with its contents
+                Operation::Create { .. } => {}
+                Operation::Contents { contents } => {
+                    if created {
+                        let ino = self.ino();
+                        self.out.write_all(&command::mkfile(path, ino))?;
+                    } else {
+                        let len = contents.metadata()?.len();
+                        // TODO: support more efficient updates on
+                        // append-only files (where len > parent_len but
+                        // hash(parent[..parent_len]) == hash(file[..parent_len]))
+                        self.out.write_all(&command::truncate(path, len))?;
                     }
-                    for del in parent_xattrs.into_keys() {
-                        f.write_all(&command::rm_xattr(relpath, del.as_bytes()))?;
+                    self.write_contents(path, contents)?;
+                }
+                Operation::Mkdir { .. } => {
+                    // we don't need to send a mkdir for the root directory,
+                    // but all the other things that follow (chown, chmod, etc)
+                    // should still happen
+                    if path != Path::new("") {
+                        let ino = self.ino();
+                        self.out.write_all(&command::mkdir(path, ino))?;
                     }
-                    continue;
                 }
-                // completely new file, fallthrough to non-incremental path
-                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
-                Err(e) => {
-                    return Err(Error::from(e).context("while getting parent file metadata"));
+                Operation::Mkfifo { .. } => {
+                    let ino = self.ino();
+                    self.out.write_all(&command::mkfifo(path, ino))?;
+                }
+                Operation::Mksock { .. } => {
+                    let ino = self.ino();
+                    self.out.write_all(&command::mksock(path, ino))?;
+                }
+                Operation::Mknod { rdev, mode } => {
+                    self.out
+                        .write_all(&command::mknod(path, mode.into(), rdev))?;
+                }
+                Operation::Symlink { target } => {
+                    // create symlink at a temporary path, then rename it to
+                    // the real destination - this matches the in-kernel
+                    // implementation
+                    let ino = self.ino();
+                    let tmp = format!("o{ino}-0-0");
+                    self.out.write_all(&command::symlink(target, &tmp, ino))?;
+                    self.out.write_all(&command::rename(tmp, path))?;
+                }
+                Operation::HardLink { target } => {
+                    self.out.write_all(&command::hardlink(target, path))?;
+                }
+                Operation::Rename { to } => {
+                    self.out.write_all(&command::rename(path, to))?;
+                }
+                Operation::Unlink => {
+                    self.out.write_all(&command::unlink(path))?;
+                }
+                Operation::Rmdir => {
+                    self.out.write_all(&command::rmdir(path))?;
+                }
+                Operation::Chown { uid, gid } => {
+                    self.out
+                        .write_all(&command::chown(path, uid.into(), gid.into()))?;
                 }
-            };
+                Operation::Chmod { mode } => {
+                    self.out.write_all(&command::chmod(path, mode.into()))?;
+                }
+                Operation::SetXattr { name, value } => {
+                    self.out
+                        .write_all(&command::set_xattr(path, name.as_bytes(), value))?;
+                }
+                Operation::RemoveXattr { name } => {
+                    self.out
+                        .write_all(&command::rm_xattr(path, name.as_bytes()))?;
+                }
+                Operation::SetTimes { atime, mtime } => {
+                    let atime = reproducible::clamp(atime, self.source_date_epoch);
+                    let mtime = reproducible::clamp(mtime, self.source_date_epoch);
+                    // there is no way to set ctime, so receivers ignore it
+                    self.out
+                        .write_all(&command::utimes(path, atime, mtime, mtime))?;
+                }
+            }
         }
+        Ok(())
+    }
 
-        // we don't need to send a mkdir for the root directory, but all the
-        // other things that follow (chown, chmod, etc) should still happen
-        if relpath != Path::new("") {
-            if meta.file_type().is_dir() {
-                f.write_all(&command::mkdir(relpath, entry.ino()))?;
-            } else if meta.file_type().is_symlink() {
-                let target = std::fs::read_link(entry.path())?;
-                // create symlink at a temporary path, then rename it to the
-                // real destination - this matches the in-kernel implementation
-                let tmp = Uuid::new_v4().to_string();
-                f.write_all(&command::symlink(target, &tmp, entry.ino()))?;
-                f.write_all(&command::rename(tmp, relpath))?;
-            } else if meta.file_type().is_file() {
-                if meta.file_type().is_block_device() || meta.file_type().is_char_device() {
-                    f.write_all(&command::mknod(relpath, meta.mode().into(), meta.rdev()))?;
-                } else if meta.file_type().is_fifo() {
-                    f.write_all(&command::mkfifo(relpath, entry.ino()))?;
-                } else if meta.file_type().is_socket() {
-                    f.write_all(&command::mksock(relpath, entry.ino()))?;
-                } else {
-                    f.write_all(&command::mkfile(relpath, entry.ino()))?;
-                    let mut infile =
-                        BufReader::new(File::open(entry.path()).with_context(|| {
-                            format!("while opening file {}", entry.path().display())
-                        })?);
-                    // 60k because we need a little bit of space to to store
-                    // metadata (so can't use a full 16-bit size), and 4k
-                    // boundaries are nice
-                    let mut buf = [0u8; 61440];
-                    let mut offset = 0;
-                    loop {
-                        let read = infile.read(&mut buf).with_context(|| {
-                            format!("while reading from file {}", entry.path().display())
-                        })?;
-                        if read == 0 {
-                            break;
-                        }
-                        f.write_all(&command::write(relpath, offset, &buf[..read]))?;
-                        offset += read as u64;
+    /// Send the contents of a file, cloning any chunk that is identical to
+    /// one that was already sent instead of writing it again, so that the
+    /// data is shared on the receiving side.
+    fn write_contents(&mut self, path: &Path, file: File) -> Result<()> {
+        // files smaller than a block might be stored inline, which can't
+        // always be cloned
+        let dedupe = file.metadata()?.len() >= BLOCK_SIZE;
+        let mut infile = BufReader::new(file);
+        let mut buf = [0u8; CHUNK_SIZE];
+        let mut offset = 0;
+        loop {
+            let read = read_chunk(&mut infile, &mut buf)
+                .with_context(|| format!("while reading from file {}", path.display()))?;
+            if read == 0 {
+                break;
+            }
+            let chunk = &buf[..read];
+            let hash = dedupe.then(|| blake3::hash(chunk));
+            match hash.and_then(|hash| self.chunks.get(&hash)) {
+                Some((src, src_offset)) => {
+                    self.out.write_all(&command::clone(
+                        path,
+                        offset,
+                        read as u64,
+                        self.uuid,
+                        CTRANSID,
+                        src,
+                        *src_offset,
+                    ))?;
+                }
+                None => {
+                    self.out.write_all(&command::write(path, offset, chunk))?;
+                    if let Some(hash) = hash {
+                        self.chunks.insert(hash, (path.to_owned(), offset));
                     }
                 }
-            } else {
-                anyhow::bail!("exactly one of is_dir, is_symlink, is_file must be true");
             }
+            offset += read as u64;
         }
+        Ok(())
+    }
+}
 
-        f.write_all(&command::chown(
-            relpath,
-            meta.uid().into(),
-            meta.gid().into(),
-        ))?;
-        if !meta.file_type().is_symlink() {
-            let mode = Mode::from_bits_truncate(meta.mode());
-            f.write_all(&command::chmod(relpath, mode.bits().into()))?;
+/// Fill `buf` from `r`, only returning less than a full buffer at EOF.
+fn read_chunk<R: Read>(r: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match r.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
         }
+    }
+    Ok(filled)
+}
 
-        for (name, val) in get_xattrs(entry.path())? {
-            f.write_all(&command::set_xattr(relpath, name.as_bytes(), val))?;
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let atime = meta.accessed().unwrap_or(UNIX_EPOCH);
-        let mtime = meta.modified().unwrap_or(UNIX_EPOCH);
-        let ctime = UNIX_EPOCH + Duration::new(meta.ctime() as u64, meta.ctime_nsec() as u32);
-        f.write_all(&command::utimes(relpath, atime, mtime, ctime))?;
+    fn tree(contents: &str) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        std::fs::write(dir.path().join("file"), contents).expect("failed to write file");
+        dir
     }
 
-    if let Some(parent) = &spec.incremental_parent {
-        for entry in WalkDir::new(parent).contents_first(true) {
-            let entry = entry.context("while walking layer")?;
-            let relpath = entry.path().strip_prefix(parent)?;
-            if !present_relpaths.contains(relpath) {
-                if entry.file_type().is_dir() {
-                    f.write_all(&command::rmdir(relpath))?;
-                } else {
-                    f.write_all(&command::unlink(relpath))?;
-                }
+    #[test]
+    fn uuid_from_label_and_contents() {
+        let a = Tree::scan(tree("hello").path(), None).expect("scan failed");
+        let b = Tree::scan(tree("hello").path(), None).expect("scan failed");
+        let c = Tree::scan(tree("goodbye").path(), None).expect("scan failed");
+        assert_eq!(a.digest.uuid("//a:a"), b.digest.uuid("//a:a"));
+        assert_ne!(a.digest.uuid("//a:a"), a.digest.uuid("//a:b"));
+        assert_ne!(a.digest.uuid("//a:a"), c.digest.uuid("//a:a"));
+    }
+
+    #[test]
+    fn read_chunk_fills_buffer() {
+        // a reader that only returns a few bytes per read
+        let data = vec![1u8; 10000];
+        let mut reader = std::io::Read::chain(&data[..3], &data[3..]);
+        let mut buf = [0u8; 4096];
+        assert_eq!(
+            4096,
+            read_chunk(&mut reader, &mut buf).expect("read failed")
+        );
+        assert_eq!(
+            4096,
+            read_chunk(&mut reader, &mut buf).expect("read failed")
+        );
+        assert_eq!(
+            10000 - 8192,
+            read_chunk(&mut reader, &mut buf).expect("read failed")
+        );
+        assert_eq!(0, read_chunk(&mut reader, &mut buf).expect("read failed"));
+    }
+
+    /// (tlv type, tlv data)
+    type Tlvs = Vec<(u16, Vec<u8>)>;
+
+    /// (command, tlvs) for every command in a v1 sendstream (without the
+    /// stream header)
+    fn parse_commands(mut buf: &[u8]) -> Vec<(u16, Tlvs)> {
+        let mut commands = Vec::new();
+        while !buf.is_empty() {
+            let len = u32::from_le_bytes(buf[..4].try_into().expect("4 bytes")) as usize;
+            let cmd = u16::from_le_bytes(buf[4..6].try_into().expect("2 bytes"));
+            let (mut payload, rest) = buf[10..].split_at(len);
+            let mut tlvs = Vec::new();
+            while !payload.is_empty() {
+                let ty = u16::from_le_bytes(payload[..2].try_into().expect("2 bytes"));
+                let len = u16::from_le_bytes(payload[2..4].try_into().expect("2 bytes")) as usize;
+                tlvs.push((ty, payload[4..4 + len].to_vec()));
+                payload = &payload[4 + len..];
             }
+            commands.push((cmd, tlvs));
+            buf = rest;
         }
+        commands
     }
 
-    f.write_all(&command::end())?;
-    drop(f);
+    /// Parsed commands of a (possibly incremental) sendstream for `layer`
+    fn send(layer: &Path, parent: Option<&Path>) -> Vec<(u16, Tlvs)> {
+        let tree = Tree::scan(layer, None).expect("scan failed");
+        let mut out = Vec::new();
+        let mut sender = Sender {
+            out: &mut out,
+            layer,
+            parent,
+            tree: &tree,
+            next_ino: FIRST_INO,
+            uuid: tree.digest.uuid("//test:test"),
+            source_date_epoch: reproducible::DEFAULT_SOURCE_DATE_EPOCH,
+            chunks: HashMap::new(),
+            links: HashMap::new(),
+        };
+        let stream = match parent {
+            Some(parent) => Iter::diff(parent, layer),
+            None => Iter::from_empty(layer),
+        };
+        sender
+            .send_all(stream.expect("failed to walk tree"))
+            .expect("failed to send");
+        parse_commands(&out)
+    }
 
-    let status = upgrade
-        .wait()
-        .context("while waiting for sendstream-upgrade to finish")?;
-    if !status.success() {
-        Err(anyhow!("sendstream-upgrade failed: {status}"))
-    } else {
-        Ok(())
+    /// (command, path) for every command that has a path (other than the root
+    /// directory)
+    fn paths(commands: &[(u16, Tlvs)]) -> Vec<(u16, Vec<u8>)> {
+        commands
+            .iter()
+            .filter_map(|(cmd, tlvs)| {
+                tlvs.iter()
+                    .find(|(ty, path)| *ty == 15 && !path.is_empty())
+                    .map(|(_, path)| (*cmd, path.clone()))
+            })
+            .collect()
     }
-}
 
-fn get_xattrs(path: &Path) -> Result<HashMap<OsString, Vec<u8>>> {
-    let mut xattrs = HashMap::new();
-    for xattr_name in
-        xattr::list(path).with_context(|| format!("while listing xattrs on {}", path.display()))?
-    {
-        let val = xattr::get(path, &xattr_name)
-            .with_context(|| {
-                format!(
-                    "while reading xattr {} on {}",
-                    xattr_name.to_string_lossy(),
-                    path.display()
-                )
-            })?
-            .with_context(|| {
-                format!(
-                    "xattr {} on {} disappeared while reading",
-                    xattr_name.to_string_lossy(),
-                    path.display()
-                )
-            })?;
-        xattrs.insert(xattr_name, val);
+    #[test]
+    fn sockets_are_sent() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let _listener = std::os::unix::net::UnixListener::bind(dir.path().join("sock"))
+            .expect("failed to bind socket");
+        let commands = paths(&send(dir.path(), None));
+        // commands: mksock = 7, chmod = 18
+        assert!(commands.contains(&(7, b"sock".to_vec())), "{commands:?}");
+        assert!(commands.contains(&(18, b"sock".to_vec())), "{commands:?}");
+    }
+
+    #[test]
+    fn unchanged_parent_link_is_reused() {
+        let parent = tempfile::tempdir().expect("failed to create tempdir");
+        let layer = tempfile::tempdir().expect("failed to create tempdir");
+        std::fs::write(parent.path().join("z"), "hello").expect("failed to write file");
+        // the very same inode in both trees
+        std::fs::hard_link(parent.path().join("z"), layer.path().join("z"))
+            .expect("failed to link");
+        std::fs::hard_link(layer.path().join("z"), layer.path().join("a")).expect("failed to link");
+        let commands = paths(&send(layer.path(), Some(parent.path())));
+        // commands: link = 10
+        assert_eq!(vec![(10, b"a".to_vec())], commands);
+    }
+
+    #[test]
+    fn changed_parent_link_is_not_reused() {
+        let parent = tempfile::tempdir().expect("failed to create tempdir");
+        let layer = tempfile::tempdir().expect("failed to create tempdir");
+        std::fs::write(parent.path().join("z"), "hello").expect("failed to write file");
+        // 'z' is a different inode than in the parent, and has different
+        // contents
+        std::fs::write(layer.path().join("z"), "goodbye").expect("failed to write file");
+        std::fs::hard_link(layer.path().join("z"), layer.path().join("a")).expect("failed to link");
+        let commands = paths(&send(layer.path(), Some(parent.path())));
+        // commands: mkfile = 3, write = 15, link = 10, unlink = 11
+        assert_eq!(Some(&(3, b"a".to_vec())), commands.first(), "{commands:?}");
+        assert!(commands.contains(&(15, b"a".to_vec())), "{commands:?}");
+        // the changes are not applied to the parent's 'z', it is replaced
+        // with a link to the new file
+        let z: Vec<_> = commands.iter().filter(|(_, p)| p == b"z").collect();
+        assert_eq!(vec![&(11, b"z".to_vec()), &(10, b"z".to_vec())], z);
+    }
+
+    #[test]
+    fn identical_files_are_cloned() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        // no two chunks are the same, so only whole files can be deduped
+        let contents: Vec<u8> = (0..CHUNK_SIZE as u32 * 2 + 5000)
+            .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+            .collect();
+        std::fs::write(dir.path().join("a"), &contents).expect("failed to write file");
+        std::fs::write(dir.path().join("b"), &contents).expect("failed to write file");
+
+        let commands = send(dir.path(), None);
+        let tlv = |tlvs: &[(u16, Vec<u8>)], ty: u16| -> Vec<u8> {
+            tlvs.iter()
+                .find(|(t, _)| *t == ty)
+                .map(|(_, v)| v.clone())
+                .expect("missing tlv")
+        };
+        // commands: write = 15, clone = 16
+        // tlvs: path = 15, data = 19, clone path = 22, clone len = 24
+        let mut written = HashMap::<Vec<u8>, usize>::new();
+        let mut cloned = HashMap::<Vec<u8>, usize>::new();
+        for (cmd, tlvs) in &commands {
+            match cmd {
+                15 => *written.entry(tlv(tlvs, 15)).or_default() += tlv(tlvs, 19).len(),
+                16 => {
+                    assert_eq!(b"a".to_vec(), tlv(tlvs, 22), "cloned from the wrong file");
+                    let len = u64::from_le_bytes(tlv(tlvs, 24).try_into().expect("8 bytes"));
+                    *cloned.entry(tlv(tlvs, 15)).or_default() += len as usize;
+                }
+                _ => {}
+            }
+        }
+        // the data is only ever written once, and the second file is cloned
+        // entirely from the first
+        assert_eq!(HashMap::from([(b"a".to_vec(), contents.len())]), written);
+        assert_eq!(HashMap::from([(b"b".to_vec(), contents.len())]), cloned);
     }
-    Ok(xattrs)
 }
@@ -29,6 +29,9 @@ pub(crate) enum Tlv<'a> {
     Data(&'a [u8]),
     CloneUuid(Uuid),
     CloneCtransid(u64),
+    ClonePath(&'a Path),
+    CloneOffset(u64),
+    CloneLen(u64),
     Atime(SystemTime),
     Mtime(SystemTime),
     Ctime(SystemTime),
@@ -70,6 +73,9 @@ impl Tlv<'_> {
             Self::Data(_) => 19,
             Self::CloneUuid(_) => 20,
             Self::CloneCtransid(_) => 21,
+            Self::ClonePath(_) => 22,
+            Self::CloneOffset(_) => 23,
+            Self::CloneLen(_) => 24,
             Self::Ctime(_) => 9,
             Self::Mtime(_) => 10,
             Self::Atime(_) => 11,
@@ -87,9 +93,13 @@ impl Tlv<'_> {
             | Self::Gid(_)
             | Self::Rdev(_)
             | Self::FileOffset(_)
-            | Self::CloneCtransid(_) => 8,
+            | Self::CloneCtransid(_)
+            | Self::CloneOffset(_)
+            | Self::CloneLen(_) => 8,
             Self::XattrName(x) | Self::XattrData(x) => x.len() as u16,
-            Self::Path(p) | Self::PathTo(p) | Self::PathLink(p) => p.as_os_str().len() as u16,
+            Self::Path(p) | Self::PathTo(p) | Self::PathLink(p) | Self::ClonePath(p) => {
+                p.as_os_str().len() as u16
+            }
             Self::Data(v) => v.len() as u16,
             Self::Atime(_) | Self::Mtime(_) | Self::Ctime(_) => 12, // 64bit sec + 32bit nsec
         }
@@ -106,9 +116,11 @@ impl Tlv<'_> {
             | Self::Gid(v)
             | Self::Rdev(v)
             | Self::FileOffset(v)
-            | Self::CloneCtransid(v) => TlvData::U64(v.to_le_bytes()),
+            | Self::CloneCtransid(v)
+            | Self::CloneOffset(v)
+            | Self::CloneLen(v) => TlvData::U64(v.to_le_bytes()),
             Self::XattrName(x) | Self::XattrData(x) => TlvData::Bytes(x),
-            Self::Path(p) | Self::PathTo(p) | Self::PathLink(p) => {
+            Self::Path(p) | Self::PathTo(p) | Self::PathLink(p) | Self::ClonePath(p) => {
                 TlvData::Bytes(p.as_os_str().as_bytes())
             }
             Self::Data(v) => TlvData::Bytes(v),
@@ -11,17 +11,24 @@ use serde::Deserialize;
 #[serde(rename_all = "snake_case")]
 pub enum Spec {
     Btrfs(crate::btrfs::Btrfs),
+    ChunkStore(crate::chunk_store::ChunkStore),
+    Composefs(crate::composefs::Composefs),
     Cpio(crate::cpio::Cpio),
+    Deb(crate::deb::Deb),
     DockerArchive(crate::docker_archive::DockerArchive),
     Erofs(crate::erofs::Erofs),
     Ext3(crate::ext::Ext3),
+    Ext4(crate::ext::Ext4),
     Gpt(crate::gpt::Gpt),
+    Iso(crate::iso::Iso),
     Oci(crate::oci::Oci),
     Rpm(crate::rpm::Rpm),
     Sendstream(crate::sendstream::Sendstream),
     Squashfs(crate::squashfs::Squashfs),
     Tar(crate::tar::Tar),
+    Uki(crate::uki::Uki),
     UnprivilegedDir(crate::unprivileged_dir::UnprivilegedDir),
+    Verity(crate::verity::Verity),
     Vfat(crate::vfat::Vfat),
     Xar(crate::xar::Xar),
 }
@@ -6,15 +6,24 @@
  */
 
 use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::path::PathBuf;
 
 use antlir2_isolate::unshare;
 use antlir2_isolate::IsolationContext;
+use anyhow::ensure;
 use anyhow::Context;
 use anyhow::Result;
 use serde::Deserialize;
+use tempfile::NamedTempFile;
+use walkdir::WalkDir;
 
+use crate::manifest::Manifest;
+use crate::reproducible;
 use crate::run_cmd;
 use crate::BuildAppliance;
 use crate::PackageFormat;
@@ -28,16 +37,66 @@ pub struct Squashfs {
     force_gid: Option<u32>,
 }
 
+const MAPPED_CLAMP_TIMES: &str = "/__antlir2__/clamp_times";
+
+/// mksquashfs cannot clamp timestamps (`-all-time` sets every one of them), so
+/// write a pseudo file that moves anything newer than `source_date_epoch` back
+/// to it. The root directory is not in here since it gets `-root-time`.
+fn clamp_times_pseudo_file(layer: &Path, source_date_epoch: u64) -> Result<NamedTempFile> {
+    let mut pseudo = NamedTempFile::new().context("while creating pseudo file")?;
+    let mut w = BufWriter::new(pseudo.as_file_mut());
+    for entry in WalkDir::new(layer).min_depth(1) {
+        let entry = entry.context("while walking layer")?;
+        let meta = entry.metadata().context("while getting metadata")?;
+        if meta.mtime() <= source_date_epoch as i64 {
+            continue;
+        }
+        let relpath = entry.path().strip_prefix(layer)?.as_os_str().as_bytes();
+        ensure!(
+            !relpath.contains(&b'\n'),
+            "mksquashfs pseudo files cannot have newlines in paths: {}",
+            entry.path().display()
+        );
+        // pseudo file paths are quoted with '"', and a literal '"' or '\' is
+        // escaped with a '\'
+        w.write_all(b"\"")?;
+        for b in relpath {
+            if matches!(b, b'"' | b'\\') {
+                w.write_all(b"\\")?;
+            }
+            w.write_all(&[*b])?;
+        }
+        writeln!(
+            w,
+            "\" M {source_date_epoch} {:o} {} {}",
+            meta.mode() & 0o7777,
+            meta.uid(),
+            meta.gid()
+        )?;
+    }
+    w.flush().context("while writing pseudo file")?;
+    drop(w);
+    Ok(pseudo)
+}
+
 impl PackageFormat for Squashfs {
-    fn build(&self, out: &Path, layer: &Path) -> Result<()> {
+    fn build(&self, out: &Path, layer: &Path, manifest: Option<&mut Manifest>) -> Result<()> {
         File::create(out).context("failed to create output file")?;
 
+        let source_date_epoch = reproducible::source_date_epoch()?;
+        let clamp_times = clamp_times_pseudo_file(layer, source_date_epoch)?;
+        let root_time = std::fs::metadata(layer)
+            .context("while getting root metadata")?
+            .mtime()
+            .clamp(0, source_date_epoch as i64);
+
         let isol_context = IsolationContext::builder(self.build_appliance.path())
             .ephemeral(false)
             .readonly()
             .tmpfs(Path::new("/__antlir2__/out"))
             .outputs(("/__antlir2__/out/squashfs", out))
             .inputs((Path::new("/__antlir2__/root"), layer))
+            .inputs((Path::new(MAPPED_CLAMP_TIMES), clamp_times.path()))
             .inputs((
                 PathBuf::from("/__antlir2__/working_directory"),
                 std::env::current_dir()?,
@@ -52,7 +111,13 @@ impl PackageFormat for Squashfs {
             .arg("/__antlir2__/root")
             .arg("/__antlir2__/out/squashfs")
             .arg("-noappend")
-            .arg("-one-file-system");
+            .arg("-one-file-system")
+            .arg("-mkfs-time")
+            .arg(source_date_epoch.to_string())
+            .arg("-root-time")
+            .arg(root_time.to_string())
+            .arg("-pf")
+            .arg(MAPPED_CLAMP_TIMES);
 
         // Options from the rule
         if let Some(compressor) = &self.compressor {
@@ -67,6 +132,45 @@ impl PackageFormat for Squashfs {
 
         run_cmd(&mut mksquashfs).context("Failed to build squashfs")?;
 
+        if let Some(manifest) = manifest {
+            manifest.add_tree(layer)?;
+            manifest.force_owner(self.force_uid, self.force_gid);
+        }
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    use super::*;
+
+    #[test]
+    fn clamp_times_only_newer() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        for (name, mtime) in [("old", 1000), ("we\"ird\\name", 3000)] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, "hello").expect("failed to write file");
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o4755))
+                .expect("failed to chmod");
+            File::options()
+                .write(true)
+                .open(&path)
+                .expect("failed to open file")
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime))
+                .expect("failed to set mtime");
+        }
+        let pseudo = clamp_times_pseudo_file(dir.path(), 2000).expect("failed to write file");
+        let pseudo = std::fs::read_to_string(pseudo.path()).expect("failed to read file");
+        let uid = nix::unistd::Uid::current();
+        let gid = nix::unistd::Gid::current();
+        assert_eq!(
+            pseudo,
+            format!("\"we\\\"ird\\\\name\" M 2000 4755 {uid} {gid}\n")
+        );
+    }
+}
@@ -5,67 +5,538 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::ffi::OsString;
 use std::fs::File;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
 use std::path::PathBuf;
-use std::process::Stdio;
+use std::time::SystemTime;
 
-use antlir2_isolate::unshare;
-use antlir2_isolate::IsolationContext;
+use antlir2_change_stream::Contents;
+use antlir2_change_stream::Iter;
+use antlir2_change_stream::Operation;
+use anyhow::bail;
+use anyhow::ensure;
 use anyhow::Context;
 use anyhow::Result;
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::sys::stat::major;
+use nix::sys::stat::minor;
+use nix::sys::stat::SFlag;
+use nix::unistd::Gid;
+use nix::unistd::Group;
+use nix::unistd::Uid;
+use nix::unistd::User;
+use nix::unistd::Whence;
 use serde::Deserialize;
+use sha2::Sha256;
+use tar::EntryType;
+use tar::GnuExtSparseHeader;
+use tar::Header;
 
-use crate::run_cmd;
-use crate::BuildAppliance;
+use crate::compression::Compression;
+use crate::manifest::HashingReader;
+use crate::manifest::Manifest;
+use crate::reproducible;
 use crate::PackageFormat;
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Tar {
-    build_appliance: BuildAppliance,
+    #[serde(default)]
+    compression: Compression,
+    #[serde(default)]
+    compression_level: Option<u32>,
+    #[serde(default)]
+    compression_threads: Option<NonZeroUsize>,
+}
+
+/// Number of sparse entries that fit in the main GNU header.
+const GNU_SPARSE_HEADERS_COUNT: usize = 4;
+/// Number of sparse entries that fit in each extended sparse header.
+const GNU_EXT_SPARSE_HEADERS_COUNT: usize = 21;
+
+/// Instead of holding an open fd for every file in the layer until the
+/// archive is written, just remember which inode it was and re-open it from
+/// the layer when it's time to write the contents.
+#[derive(Debug)]
+struct Inode {
+    dev: u64,
+    ino: u64,
+    nlink: u64,
+}
+
+impl Contents for Inode {
+    fn from_file(file: File) -> std::io::Result<Self> {
+        let meta = file.metadata()?;
+        Ok(Self {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            nlink: meta.nlink(),
+        })
+    }
+
+    fn differs(&mut self, other: &mut Self) -> std::io::Result<bool> {
+        Ok(self.dev != other.dev || self.ino != other.ino)
+    }
+}
+
+struct Entry {
+    header: Header,
+    inode: Option<Inode>,
+    link: Option<PathBuf>,
+    xattrs: BTreeMap<OsString, Vec<u8>>,
+}
+
+impl Default for Entry {
+    fn default() -> Self {
+        Self {
+            header: Header::new_gnu(),
+            inode: None,
+            link: None,
+            xattrs: BTreeMap::new(),
+        }
+    }
 }
 
 impl PackageFormat for Tar {
-    fn build(&self, out: &Path, layer: &Path) -> Result<()> {
-        File::create(out).context("failed to create output file")?;
-
-        let isol_context = IsolationContext::builder(self.build_appliance.path())
-            .ephemeral(false)
-            .readonly()
-            .tmpfs(Path::new("/__antlir2__/out"))
-            .outputs(("/__antlir2__/out/tar", out))
-            .inputs((Path::new("/__antlir2__/root"), layer))
-            .inputs((
-                PathBuf::from("/__antlir2__/working_directory"),
-                std::env::current_dir()?,
-            ))
-            .working_directory(Path::new("/__antlir2__/working_directory"))
-            .build();
-
-        run_cmd(
-            unshare(isol_context)?
-                .command("tar")?
-                .arg("--sparse")
-                .arg("--one-file-system")
-                .arg("--acls")
-                .arg("--xattrs")
-                // Sorted by name to ensure reproducibility, as well as
-                // predictable ordering when the tar is read as a byte stream.
-                // Some use cases require consumption of tar's contents with a
-                // known ordering, such as when the tar contains incremental
-                // btrfs snapshots that may be opportunistically skipped.
-                .arg("--sort=name")
-                .arg("-C")
-                .arg("/__antlir2__/root")
-                .arg("-c")
-                .arg("-f")
-                .arg("/__antlir2__/out/tar")
-                .arg(".")
-                .stdout(Stdio::piped()),
+    fn build(&self, out: &Path, layer: &Path, manifest: Option<&mut Manifest>) -> Result<()> {
+        let mut builder = tar::Builder::new(
+            self.compression
+                .compressor(
+                    BufWriter::new(File::create(out).context("while creating output file")?),
+                    self.compression_level,
+                    self.compression_threads,
+                )
+                .context("while setting up compression")?,
+        );
+        append_layer(&mut builder, layer, false, manifest)?;
+        builder
+            .into_inner()
+            .context("while finishing archive")?
+            .finish()
+            .context("while finishing compression")?
+            .flush()
+            .context("while flushing output")?;
+        Ok(())
+    }
+}
+
+/// Add every file in the layer to the archive, preserving ownership, modes,
+/// xattrs, hardlinks and sparse files.
+///
+/// If `owner_names` is set, user and group names are recorded in addition to
+/// the numeric ids (looked up on the host, like the rpm packager does).
+pub(crate) fn append_layer<W: Write>(
+    builder: &mut tar::Builder<W>,
+    layer: &Path,
+    owner_names: bool,
+    mut manifest: Option<&mut Manifest>,
+) -> Result<()> {
+    // Timestamps make things non-deterministic even if everything else is
+    // 100% equal, since most files in a layer are created at build time
+    let source_date_epoch = reproducible::source_date_epoch()?;
+    let stream: Iter<Inode> = Iter::from_empty(layer).context("while walking layer")?;
+    // Sorted by name to ensure reproducibility, as well as predictable
+    // ordering when the tar is read as a byte stream. Some use cases
+    // require consumption of tar's contents with a known ordering, such as
+    // when the tar contains incremental btrfs snapshots that may be
+    // opportunistically skipped.
+    let mut entries: BTreeMap<PathBuf, Entry> = BTreeMap::new();
+    for change in stream {
+        let change = change.context("while walking layer")?;
+        let path = change.path().to_owned();
+        let entry = entries.entry(path.clone()).or_default();
+        match change.into_operation() {
+            Operation::Create { mode } => {
+                entry.header.set_mode(mode);
+                entry.header.set_entry_type(EntryType::Regular);
+            }
+            Operation::Mkdir { mode } => {
+                entry.header.set_mode(mode);
+                entry.header.set_entry_type(EntryType::Directory);
+            }
+            Operation::Mkfifo { mode } => {
+                entry.header.set_mode(mode);
+                entry.header.set_entry_type(EntryType::Fifo);
+            }
+            Operation::Mknod { rdev, mode } => {
+                entry.header.set_mode(mode & 0o7777);
+                let sflag = SFlag::from_bits_truncate(mode);
+                entry
+                    .header
+                    .set_entry_type(if sflag.contains(SFlag::S_IFBLK) {
+                        EntryType::Block
+                    } else {
+                        EntryType::Char
+                    });
+                entry.header.set_device_major(major(rdev) as u32)?;
+                entry.header.set_device_minor(minor(rdev) as u32)?;
+            }
+            Operation::Chmod { mode } => {
+                entry.header.set_mode(mode);
+            }
+            Operation::Chown { uid, gid } => {
+                entry.header.set_uid(uid.into());
+                entry.header.set_gid(gid.into());
+            }
+            Operation::SetTimes { atime: _, mtime } => {
+                let mtime = reproducible::clamp(mtime, source_date_epoch)
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+                entry.header.set_mtime(mtime);
+            }
+            Operation::Symlink { target } => {
+                entry.header.set_entry_type(EntryType::Symlink);
+                // symlink permissions are meaningless, but this is what
+                // every other tool reports them as
+                entry.header.set_mode(0o777);
+                entry.link = Some(target);
+            }
+            Operation::HardLink { target } => {
+                entry.header.set_entry_type(EntryType::Link);
+                entry.link = Some(target);
+            }
+            Operation::Contents { contents } => {
+                entry.inode = Some(contents);
+            }
+            Operation::SetXattr { name, value } => {
+                entry.xattrs.insert(name, value);
+            }
+            Operation::Mksock { .. } => {
+                bail!(
+                    "unix sockets cannot be represented in a tar: {}",
+                    path.display()
+                );
+            }
+            op @ (Operation::Rmdir
+            | Operation::Unlink
+            | Operation::Rename { .. }
+            | Operation::RemoveXattr { .. }) => {
+                bail!("unexpected operation while walking new layer: {op:?}");
+            }
+        }
+    }
+
+    // map (dev, ino) -> archive path of the first occurrence, so that
+    // subsequent occurrences can be written as hardlinks
+    let mut inodes: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    let mut names = OwnerNames::default();
+    for (path, mut entry) in entries {
+        let name = if path == Path::new("") {
+            PathBuf::from("./")
+        } else {
+            path.clone()
+        };
+        if owner_names {
+            set_owner_names(&mut entry.header, &mut names)?;
+        }
+        let sha256 = append_entry(
+            builder,
+            layer,
+            &path,
+            &name,
+            entry,
+            &mut inodes,
+            manifest.is_some(),
         )
-        .context("Failed to build tar")?;
+        .with_context(|| format!("while adding '{}' to archive", path.display()))?;
+        if let Some(manifest) = manifest.as_deref_mut() {
+            let meta = std::fs::symlink_metadata(layer.join(&path))
+                .with_context(|| format!("while statting '{}'", path.display()))?;
+            manifest.add(layer, &path, &meta, sha256)?;
+        }
+    }
+    Ok(())
+}
 
-        Ok(())
+#[derive(Default)]
+struct OwnerNames {
+    users: HashMap<u64, Option<String>>,
+    groups: HashMap<u64, Option<String>>,
+}
+
+fn set_owner_names(header: &mut Header, names: &mut OwnerNames) -> Result<()> {
+    let uid = header.uid()?;
+    let user = match names.users.get(&uid) {
+        Some(user) => user.clone(),
+        None => {
+            let user = User::from_uid(Uid::from_raw(uid as u32))
+                .context("while getting user name")?
+                .map(|u| u.name);
+            names.users.insert(uid, user.clone());
+            user
+        }
+    };
+    let gid = header.gid()?;
+    let group = match names.groups.get(&gid) {
+        Some(group) => group.clone(),
+        None => {
+            let group = Group::from_gid(Gid::from_raw(gid as u32))
+                .context("while getting group name")?
+                .map(|g| g.name);
+            names.groups.insert(gid, group.clone());
+            group
+        }
+    };
+    if let Some(user) = user {
+        header.set_username(&user)?;
+    }
+    if let Some(group) = group {
+        header.set_groupname(&group)?;
+    }
+    Ok(())
+}
+
+/// Returns the sha256 of the file contents if `hash` is set and they were
+/// read in full
+fn append_entry<W: Write>(
+    builder: &mut tar::Builder<W>,
+    layer: &Path,
+    path: &Path,
+    name: &Path,
+    mut entry: Entry,
+    inodes: &mut HashMap<(u64, u64), PathBuf>,
+    hash: bool,
+) -> Result<Option<Sha256>> {
+    let mut pax: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+    for (key, value) in std::mem::take(&mut entry.xattrs) {
+        let key = key
+            .into_string()
+            .map_err(|key| anyhow::anyhow!("xattr name '{key:?}' is not valid UTF-8"))?;
+        // ACLs are stored in the format understood by GNU tar's --acls
+        // instead of as their raw binary xattr
+        match key.as_str() {
+            "system.posix_acl_access" => {
+                pax.insert(
+                    "SCHILY.acl.access".into(),
+                    acl_to_text(&value)?.into_bytes(),
+                );
+            }
+            "system.posix_acl_default" => {
+                pax.insert(
+                    "SCHILY.acl.default".into(),
+                    acl_to_text(&value)?.into_bytes(),
+                );
+            }
+            _ => {
+                pax.insert(format!("SCHILY.xattr.{key}"), value);
+            }
+        }
+    }
+
+    if entry.header.set_path(name).is_err() {
+        // too long to fit in the header, use a pax extension instead
+        pax.insert("path".into(), name.as_os_str().as_encoded_bytes().to_vec());
+        entry.header.set_path("././@LongLink")?;
+    }
+
+    let mut sparse = None;
+    let mut contents = None;
+    if let Some(inode) = &entry.inode {
+        match inodes.get(&(inode.dev, inode.ino)) {
+            Some(first) => {
+                entry.header.set_entry_type(EntryType::Link);
+                entry.link = Some(first.clone());
+            }
+            None => {
+                if inode.nlink > 1 {
+                    inodes.insert((inode.dev, inode.ino), name.to_owned());
+                }
+                let f = std::fs::OpenOptions::new()
+                    .read(true)
+                    .custom_flags(OFlag::O_NOFOLLOW.bits())
+                    .open(layer.join(path))
+                    .context("while opening file")?;
+                let len = f.metadata().context("while statting file")?.len();
+                sparse = data_extents(&f, len)?;
+                match &sparse {
+                    Some(extents) => {
+                        entry.header.set_entry_type(EntryType::GNUSparse);
+                        entry
+                            .header
+                            .set_size(extents.iter().map(|(_, len)| len).sum());
+                        let gnu = entry.header.as_gnu_mut().context("header is always gnu")?;
+                        gnu.set_real_size(len);
+                        for (slot, (offset, len)) in gnu.sparse.iter_mut().zip(extents) {
+                            slot.set_offset(*offset);
+                            slot.set_length(*len);
+                        }
+                        gnu.set_is_extended(extents.len() > GNU_SPARSE_HEADERS_COUNT);
+                    }
+                    None => entry.header.set_size(len),
+                }
+                contents = Some(f);
+            }
+        }
+    }
+
+    if let Some(link) = &entry.link {
+        if entry.header.set_link_name(link).is_err() {
+            pax.insert(
+                "linkpath".into(),
+                link.as_os_str().as_encoded_bytes().to_vec(),
+            );
+            entry.header.set_link_name("././@LongLink")?;
+        }
+    }
+
+    if !pax.is_empty() {
+        builder
+            .append_pax_extensions(pax.iter().map(|(k, v)| (k.as_str(), v.as_slice())))
+            .context("while writing pax extensions")?;
+    }
+    entry.header.set_cksum();
+    let w = builder.get_mut();
+    w.write_all(entry.header.as_bytes())?;
+
+    let Some(mut f) = contents else {
+        return Ok(None);
+    };
+    let mut sha256 = None;
+    let written = match sparse {
+        Some(extents) => {
+            // anything that didn't fit in the main header goes into a series
+            // of extended headers immediately following it
+            let overflow = extents.get(GNU_SPARSE_HEADERS_COUNT..).unwrap_or_default();
+            let mut chunks = overflow.chunks(GNU_EXT_SPARSE_HEADERS_COUNT).peekable();
+            while let Some(chunk) = chunks.next() {
+                let mut ext = GnuExtSparseHeader::new();
+                for (slot, (offset, len)) in ext.sparse.iter_mut().zip(chunk) {
+                    slot.set_offset(*offset);
+                    slot.set_length(*len);
+                }
+                ext.set_is_extended(chunks.peek().is_some());
+                w.write_all(ext.as_bytes())?;
+            }
+            let mut written = 0;
+            for (offset, len) in extents {
+                f.seek(SeekFrom::Start(offset))?;
+                let copied = std::io::copy(&mut (&mut f).take(len), w)?;
+                ensure!(copied == len, "file changed while being archived");
+                written += copied;
+            }
+            written
+        }
+        None => {
+            let len = entry.header.size()?;
+            // finding data extents moved the file offset
+            f.rewind()?;
+            let mut data = HashingReader::new(f.take(len), hash);
+            let copied = std::io::copy(&mut data, w)?;
+            ensure!(copied == len, "file changed while being archived");
+            sha256 = data.into_hasher();
+            copied
+        }
+    };
+    // pad data out to the next block boundary
+    let padding = (512 - (written % 512)) % 512;
+    w.write_all(&vec![0; padding as usize])?;
+    Ok(sha256)
+}
+
+/// Find the regions of a file that actually contain data, or None if the file
+/// is not sparse (or the filesystem can't tell us).
+fn data_extents(f: &File, len: u64) -> Result<Option<Vec<(u64, u64)>>> {
+    let mut extents = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let start = match nix::unistd::lseek(f.as_raw_fd(), offset as i64, Whence::SeekData) {
+            Ok(start) => start as u64,
+            // nothing but a hole from here to the end of the file
+            Err(Errno::ENXIO) => break,
+            // the filesystem doesn't support finding holes
+            Err(Errno::EINVAL) => return Ok(None),
+            Err(e) => return Err(e).context("while seeking to data"),
+        };
+        let end = nix::unistd::lseek(f.as_raw_fd(), start as i64, Whence::SeekHole)
+            .context("while seeking to hole")? as u64;
+        // some filesystems report holes at block granularity, which may be
+        // past the actual end of the file
+        let end = std::cmp::min(end, len);
+        extents.push((start, end - start));
+        offset = end;
+    }
+    if len == 0 || extents == [(0, len)] {
+        return Ok(None);
+    }
+    // A trailing hole is represented by an empty entry at the end of the file
+    if !matches!(extents.last(), Some((off, l)) if off + l == len) {
+        extents.push((len, 0));
+    }
+    Ok(Some(extents))
+}
+
+/// Convert a binary system.posix_acl_* xattr to the short text form that GNU
+/// tar stores in SCHILY.acl.* records (with numeric ids, since the names from
+/// the layer are meaningless on the host doing the packaging).
+fn acl_to_text(value: &[u8]) -> Result<String> {
+    ensure!(value.len() >= 4, "acl xattr is too short");
+    let version = u32::from_le_bytes(value[..4].try_into().expect("checked length"));
+    ensure!(version == 2, "unsupported acl version {version}");
+    let entries = &value[4..];
+    ensure!(
+        entries.chunks_exact(8).remainder().is_empty(),
+        "acl xattr has a partial entry"
+    );
+    let mut text = Vec::new();
+    for e in entries.chunks_exact(8) {
+        let tag = u16::from_le_bytes([e[0], e[1]]);
+        let perm = u16::from_le_bytes([e[2], e[3]]);
+        let id = u32::from_le_bytes([e[4], e[5], e[6], e[7]]);
+        let (tag, id) = match tag {
+            0x01 => ("user", None),
+            0x02 => ("user", Some(id)),
+            0x04 => ("group", None),
+            0x08 => ("group", Some(id)),
+            0x10 => ("mask", None),
+            0x20 => ("other", None),
+            _ => bail!("unknown acl tag {tag:#x}"),
+        };
+        text.push(format!(
+            "{tag}:{}:{}{}{}",
+            id.map(|id| id.to_string()).unwrap_or_default(),
+            if perm & 4 != 0 { 'r' } else { '-' },
+            if perm & 2 != 0 { 'w' } else { '-' },
+            if perm & 1 != 0 { 'x' } else { '-' },
+        ));
+    }
+    Ok(text.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acl_text() {
+        let mut acl = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in [
+            (0x01u16, 6u16, u32::MAX),
+            (0x02, 4, 1000),
+            (0x04, 4, u32::MAX),
+            (0x10, 5, u32::MAX),
+            (0x20, 0, u32::MAX),
+        ] {
+            acl.extend(tag.to_le_bytes());
+            acl.extend(perm.to_le_bytes());
+            acl.extend(id.to_le_bytes());
+        }
+        assert_eq!(
+            acl_to_text(&acl).expect("valid acl"),
+            "user::rw-,user:1000:r--,group::r--,mask::r-x,other::---"
+        );
     }
 }
@@ -0,0 +1,425 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Unified Kernel Images, as described by the UAPI group's UKI specification.
+//!
+//! A UKI is just an EFI stub (usually systemd-stub) with some extra PE
+//! sections appended that contain the kernel, initrd and other boot
+//! parameters. The stub reads these sections back out of its own image when it
+//! is started by the firmware or a boot loader.
+
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use sha2::Digest as _;
+use sha2::Sha256;
+
+use crate::manifest::Manifest;
+use crate::PackageFormat;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Uki {
+    /// EFI stub that the other sections are added to
+    stub: PathBuf,
+    kernel: PathBuf,
+    #[serde(default)]
+    initrds: Vec<PathBuf>,
+    #[serde(default)]
+    cmdline: Vec<String>,
+    /// Defaults to the os-release file in the layer
+    os_release: Option<PathBuf>,
+    /// Kernel release (`uname -r`). If not set, it is read from the kernel
+    /// image when possible.
+    uname: Option<String>,
+}
+
+impl Uki {
+    /// Absolute paths are looked up in the layer, anything else is a file
+    /// produced by another target.
+    fn resolve(layer: &Path, path: &Path) -> PathBuf {
+        match path.strip_prefix("/") {
+            Ok(relpath) => layer.join(relpath),
+            Err(_) => path.to_owned(),
+        }
+    }
+
+    fn read(layer: &Path, path: &Path) -> Result<Vec<u8>> {
+        let resolved = Self::resolve(layer, path);
+        std::fs::read(&resolved).with_context(|| format!("while reading {}", path.display()))
+    }
+}
+
+impl PackageFormat for Uki {
+    fn build(&self, out: &Path, layer: &Path, mut manifest: Option<&mut Manifest>) -> Result<()> {
+        let mut pe =
+            PeImage::parse(Self::read(layer, &self.stub)?).context("while parsing stub")?;
+
+        let os_release = match &self.os_release {
+            Some(path) => Self::read(layer, path)?,
+            None => ["/etc/os-release", "/usr/lib/os-release"]
+                .iter()
+                .map(|p| Self::read(layer, Path::new(p)))
+                .find_map(Result::ok)
+                .context("layer does not have an os-release file")?,
+        };
+        let kernel = Self::read(layer, &self.kernel)?;
+        let uname = self
+            .uname
+            .clone()
+            .or_else(|| bzimage_version(&kernel).map(str::to_owned));
+        let mut initrd = Vec::new();
+        for path in &self.initrds {
+            initrd.extend(Self::read(layer, path)?);
+            // concatenated cpio archives must each start 4-byte aligned
+            initrd.resize(initrd.len().next_multiple_of(4), 0);
+        }
+
+        // same order as ukify, the kernel always goes last since it may
+        // decompress itself in place
+        let mut sections = vec![(".osrel", os_release)];
+        if !self.cmdline.is_empty() {
+            sections.push((".cmdline", self.cmdline.join(" ").into_bytes()));
+        }
+        if let Some(uname) = uname {
+            sections.push((".uname", uname.into_bytes()));
+        }
+        if !initrd.is_empty() {
+            sections.push((".initrd", initrd));
+        }
+        sections.push((".linux", kernel));
+
+        for (name, data) in sections {
+            // the sections are the only things that go into a uki, so that's
+            // what is listed
+            if let Some(manifest) = manifest.as_deref_mut() {
+                manifest.add_generated(
+                    Path::new(name),
+                    data.len() as u64,
+                    Sha256::new_with_prefix(&data),
+                );
+            }
+            pe.add_section(name, data)?;
+        }
+
+        std::fs::write(out, pe.finish()).context("while writing uki")?;
+        Ok(())
+    }
+}
+
+/// Find the kernel release in the header of an x86 bzImage
+/// See https://www.kernel.org/doc/html/latest/arch/x86/boot.html
+fn bzimage_version(kernel: &[u8]) -> Option<&str> {
+    if kernel.get(0x202..0x206)? != b"HdrS" {
+        return None;
+    }
+    let offset = u16::from_le_bytes(kernel.get(0x20e..0x210)?.try_into().ok()?);
+    if offset == 0 {
+        return None;
+    }
+    let start = usize::from(offset) + 0x200;
+    let version = kernel.get(start..start + 256)?;
+    let version = &version[..version.iter().position(|b| *b == 0)?];
+    // the version string is "<release> (<builder>) #<build> ..."
+    std::str::from_utf8(version).ok()?.split_whitespace().next()
+}
+
+const PE_SIGNATURE: &[u8; 4] = b"PE\0\0";
+const PE32_PLUS_MAGIC: u16 = 0x20b;
+const COFF_HEADER_SIZE: usize = 20;
+const SECTION_HEADER_SIZE: usize = 40;
+const IMAGE_DIRECTORY_ENTRY_SECURITY: usize = 4;
+const IMAGE_SCN_CNT_INITIALIZED_DATA: u32 = 0x40;
+const IMAGE_SCN_MEM_READ: u32 = 0x4000_0000;
+
+/// Just enough of a PE32+ image to append new data sections to it
+struct PeImage {
+    buf: Vec<u8>,
+    coff_offset: usize,
+    opt_offset: usize,
+}
+
+fn align(v: u64, alignment: u64) -> u64 {
+    v.next_multiple_of(alignment)
+}
+
+impl PeImage {
+    fn parse(buf: Vec<u8>) -> Result<Self> {
+        ensure!(buf.get(0..2) == Some(b"MZ"), "missing DOS header");
+        let mut pe = Self {
+            buf,
+            coff_offset: 0,
+            opt_offset: 0,
+        };
+        let pe_offset = pe.u32(0x3c)? as usize;
+        ensure!(
+            pe.buf.get(pe_offset..pe_offset + 4) == Some(PE_SIGNATURE),
+            "missing PE signature"
+        );
+        pe.coff_offset = pe_offset + 4;
+        pe.opt_offset = pe.coff_offset + COFF_HEADER_SIZE;
+        ensure!(
+            pe.u16(pe.opt_offset)? == PE32_PLUS_MAGIC,
+            "only PE32+ stubs are supported"
+        );
+
+        // any signature on the stub is invalidated by adding sections, so drop
+        // it and anything else past the end of the last section
+        let end = pe
+            .sections()?
+            .map(|s| u64::from(s.raw_ptr) + u64::from(s.raw_size))
+            .max()
+            .context("stub has no sections")?;
+        pe.buf.truncate(end as usize);
+        let security = pe.data_directory(IMAGE_DIRECTORY_ENTRY_SECURITY)?;
+        if let Some(security) = security {
+            pe.buf[security..security + 8].fill(0);
+        }
+        Ok(pe)
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16> {
+        Ok(u16::from_le_bytes(
+            self.buf
+                .get(offset..offset + 2)
+                .context("truncated PE image")?
+                .try_into()
+                .expect("slice is 2 bytes"),
+        ))
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32> {
+        Ok(u32::from_le_bytes(
+            self.buf
+                .get(offset..offset + 4)
+                .context("truncated PE image")?
+                .try_into()
+                .expect("slice is 4 bytes"),
+        ))
+    }
+
+    fn set_u16(&mut self, offset: usize, v: u16) {
+        self.buf[offset..offset + 2].copy_from_slice(&v.to_le_bytes());
+    }
+
+    fn set_u32(&mut self, offset: usize, v: u32) {
+        self.buf[offset..offset + 4].copy_from_slice(&v.to_le_bytes());
+    }
+
+    fn num_sections(&self) -> Result<usize> {
+        Ok(self.u16(self.coff_offset + 2)?.into())
+    }
+
+    fn section_table_offset(&self) -> Result<usize> {
+        Ok(self.opt_offset + usize::from(self.u16(self.coff_offset + 16)?))
+    }
+
+    fn section_alignment(&self) -> Result<u64> {
+        Ok(self.u32(self.opt_offset + 32)?.into())
+    }
+
+    fn file_alignment(&self) -> Result<u64> {
+        Ok(self.u32(self.opt_offset + 36)?.into())
+    }
+
+    fn size_of_headers(&self) -> Result<usize> {
+        Ok(self.u32(self.opt_offset + 60)? as usize)
+    }
+
+    /// Offset of the given data directory entry, if the image has it
+    fn data_directory(&self, index: usize) -> Result<Option<usize>> {
+        let count = self.u32(self.opt_offset + 108)? as usize;
+        Ok((index < count).then_some(self.opt_offset + 112 + index * 8))
+    }
+
+    fn sections(&self) -> Result<impl Iterator<Item = Section> + '_> {
+        let table = self.section_table_offset()?;
+        let sections = (0..self.num_sections()?)
+            .map(|i| Section::parse(self, table + i * SECTION_HEADER_SIZE))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(sections.into_iter())
+    }
+
+    fn add_section(&mut self, name: &str, data: Vec<u8>) -> Result<()> {
+        ensure!(name.len() <= 8, "section name '{name}' is too long");
+        let table = self.section_table_offset()?;
+        let header_offset = table + self.num_sections()? * SECTION_HEADER_SIZE;
+        let first_raw = self
+            .sections()?
+            .map(|s| s.raw_ptr as usize)
+            .filter(|p| *p != 0)
+            .min()
+            .unwrap_or(usize::MAX);
+        if header_offset + SECTION_HEADER_SIZE > self.size_of_headers()?.min(first_raw) {
+            bail!("no room in stub headers for section '{name}'");
+        }
+        if self.sections()?.any(|s| s.name == name) {
+            bail!("stub already has a '{name}' section");
+        }
+
+        let section_alignment = self.section_alignment()?;
+        let file_alignment = self.file_alignment()?;
+        let virtual_address = align(
+            self.sections()?
+                .map(|s| u64::from(s.virtual_address) + u64::from(s.virtual_size))
+                .max()
+                .unwrap_or_default(),
+            section_alignment,
+        );
+        let raw_ptr = align(self.buf.len() as u64, file_alignment);
+        let raw_size = align(data.len() as u64, file_alignment);
+        let virtual_size = data.len() as u64;
+
+        let mut header = [0u8; SECTION_HEADER_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[8..12].copy_from_slice(&u32::try_from(virtual_size)?.to_le_bytes());
+        header[12..16].copy_from_slice(&u32::try_from(virtual_address)?.to_le_bytes());
+        header[16..20].copy_from_slice(&u32::try_from(raw_size)?.to_le_bytes());
+        header[20..24].copy_from_slice(&u32::try_from(raw_ptr)?.to_le_bytes());
+        header[36..40]
+            .copy_from_slice(&(IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ).to_le_bytes());
+        self.buf[header_offset..header_offset + SECTION_HEADER_SIZE].copy_from_slice(&header);
+        let num_sections = u16::try_from(self.num_sections()? + 1)?;
+        self.set_u16(self.coff_offset + 2, num_sections);
+
+        self.buf.resize(raw_ptr as usize, 0);
+        self.buf.extend(data);
+        self.buf.resize((raw_ptr + raw_size) as usize, 0);
+
+        let size_of_image = align(virtual_address + virtual_size, section_alignment);
+        self.set_u32(self.opt_offset + 56, u32::try_from(size_of_image)?);
+        let initialized_data = u64::from(self.u32(self.opt_offset + 8)?) + raw_size;
+        self.set_u32(
+            self.opt_offset + 8,
+            u32::try_from(initialized_data).unwrap_or(u32::MAX),
+        );
+        Ok(())
+    }
+
+    /// Update the image checksum and return the final image
+    fn finish(mut self) -> Vec<u8> {
+        let checksum_offset = self.opt_offset + 64;
+        self.set_u32(checksum_offset, 0);
+        let mut sum: u64 = 0;
+        for chunk in self.buf.chunks(2) {
+            let word = match chunk {
+                [lo, hi] => u16::from_le_bytes([*lo, *hi]),
+                [lo] => u16::from(*lo),
+                _ => unreachable!(),
+            };
+            sum += u64::from(word);
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        let checksum = (sum as u32).wrapping_add(self.buf.len() as u32);
+        self.set_u32(checksum_offset, checksum);
+        self.buf
+    }
+}
+
+struct Section {
+    name: String,
+    virtual_size: u32,
+    virtual_address: u32,
+    raw_size: u32,
+    raw_ptr: u32,
+}
+
+impl Section {
+    fn parse(pe: &PeImage, offset: usize) -> Result<Self> {
+        let name = pe
+            .buf
+            .get(offset..offset + 8)
+            .context("truncated section table")?;
+        let name = String::from_utf8_lossy(&name[..name.iter().position(|b| *b == 0).unwrap_or(8)])
+            .into_owned();
+        Ok(Self {
+            name,
+            virtual_size: pe.u32(offset + 8)?,
+            virtual_address: pe.u32(offset + 12)?,
+            raw_size: pe.u32(offset + 16)?,
+            raw_ptr: pe.u32(offset + 20)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Smallest PE32+ image with a single .text section
+    fn stub() -> Vec<u8> {
+        let mut buf = vec![0u8; 0x600];
+        buf[0..2].copy_from_slice(b"MZ");
+        buf[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        buf[0x40..0x44].copy_from_slice(PE_SIGNATURE);
+        let coff = 0x44;
+        buf[coff..coff + 2].copy_from_slice(&0x8664u16.to_le_bytes());
+        buf[coff + 2..coff + 4].copy_from_slice(&1u16.to_le_bytes());
+        buf[coff + 16..coff + 18].copy_from_slice(&240u16.to_le_bytes());
+        let opt = coff + COFF_HEADER_SIZE;
+        buf[opt..opt + 2].copy_from_slice(&PE32_PLUS_MAGIC.to_le_bytes());
+        buf[opt + 32..opt + 36].copy_from_slice(&0x1000u32.to_le_bytes());
+        buf[opt + 36..opt + 40].copy_from_slice(&0x200u32.to_le_bytes());
+        buf[opt + 56..opt + 60].copy_from_slice(&0x2000u32.to_le_bytes());
+        buf[opt + 60..opt + 64].copy_from_slice(&0x400u32.to_le_bytes());
+        buf[opt + 108..opt + 112].copy_from_slice(&16u32.to_le_bytes());
+        let section = opt + 240;
+        buf[section..section + 5].copy_from_slice(b".text");
+        buf[section + 8..section + 12].copy_from_slice(&0x10u32.to_le_bytes());
+        buf[section + 12..section + 16].copy_from_slice(&0x1000u32.to_le_bytes());
+        buf[section + 16..section + 20].copy_from_slice(&0x200u32.to_le_bytes());
+        buf[section + 20..section + 24].copy_from_slice(&0x400u32.to_le_bytes());
+        // pretend there is a signature after the last section
+        buf.extend(b"signature");
+        buf
+    }
+
+    #[test]
+    fn add_sections() {
+        let mut pe = PeImage::parse(stub()).expect("valid stub");
+        pe.add_section(".osrel", b"ID=test\n".to_vec())
+            .expect("add .osrel");
+        pe.add_section(".linux", vec![0xaa; 0x1234])
+            .expect("add .linux");
+        let pe = PeImage::parse(pe.finish()).expect("valid uki");
+        let sections: Vec<_> = pe.sections().expect("sections").collect();
+        assert_eq!(
+            sections.iter().map(|s| s.name.as_str()).collect::<Vec<_>>(),
+            vec![".text", ".osrel", ".linux"]
+        );
+        assert_eq!(sections[1].virtual_address, 0x2000);
+        assert_eq!(sections[2].virtual_address, 0x3000);
+        let linux = &sections[2];
+        assert_eq!(linux.virtual_size, 0x1234);
+        assert_eq!(
+            &pe.buf[linux.raw_ptr as usize..][..0x1234],
+            vec![0xaa; 0x1234].as_slice()
+        );
+        assert_eq!(pe.u32(pe.opt_offset + 56).expect("size of image"), 0x5000);
+        assert_eq!(
+            pe.buf.len(),
+            (linux.raw_ptr + linux.raw_size) as usize,
+            "stub signature should be dropped"
+        );
+    }
+
+    #[test]
+    fn bzimage_release() {
+        let mut kernel = vec![0u8; 0x1000];
+        kernel[0x202..0x206].copy_from_slice(b"HdrS");
+        kernel[0x20e..0x210].copy_from_slice(&0x300u16.to_le_bytes());
+        let version = b"6.4.3-0_fbk1 (builder@host) #1 SMP\0";
+        kernel[0x500..0x500 + version.len()].copy_from_slice(version);
+        assert_eq!(bzimage_version(&kernel), Some("6.4.3-0_fbk1"));
+        assert_eq!(bzimage_version(&[0; 0x1000]), None);
+    }
+}
@@ -5,6 +5,7 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+use std::fs::File;
 use std::fs::Permissions;
 use std::os::unix::fs::MetadataExt;
 use std::os::unix::fs::PermissionsExt;
@@ -15,6 +16,9 @@ use anyhow::Result;
 use serde::Deserialize;
 use walkdir::WalkDir;
 
+use crate::manifest::HashingReader;
+use crate::manifest::Manifest;
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct UnprivilegedDir {}
@@ -25,6 +29,7 @@ impl UnprivilegedDir {
         out: &Path,
         layer: &Path,
         root_guard: Option<antlir2_rootless::EscalationGuard>,
+        mut manifest: Option<&mut Manifest>,
     ) -> Result<()> {
         let layer = layer.canonicalize()?;
         std::fs::create_dir(out).context("while creating root")?;
@@ -40,12 +45,18 @@ impl UnprivilegedDir {
                 .map(|i| i.as_raw()),
         )
         .context("while chowning root")?;
-        for entry in WalkDir::new(&layer) {
+        // ownership and modes are changed, so the manifest lists what ends up
+        // in the output
+        if let Some(manifest) = manifest.as_deref_mut() {
+            manifest.add(out, Path::new(""), &std::fs::metadata(out)?, None)?;
+        }
+        for entry in WalkDir::new(&layer).sort_by_file_name() {
             let entry = entry?;
             let relpath = entry.path().strip_prefix(&layer)?;
             if relpath == Path::new("") {
                 continue;
             }
+            let mut sha256 = None;
             let dst = out.join(relpath);
             if entry.file_type().is_dir() {
                 std::fs::create_dir(&dst)
@@ -56,8 +67,15 @@ impl UnprivilegedDir {
                 std::os::unix::fs::symlink(target, &dst)
                     .with_context(|| format!("while creating symlink '{}'", dst.display()))?;
             } else if entry.file_type().is_file() {
-                std::fs::copy(entry.path(), &dst)
-                    .with_context(|| format!("while copying file '{}'", dst.display()))?;
+                if manifest.is_some() {
+                    let mut src = HashingReader::new(File::open(entry.path())?, true);
+                    std::io::copy(&mut src, &mut File::create(&dst)?)
+                        .with_context(|| format!("while copying file '{}'", dst.display()))?;
+                    sha256 = src.into_hasher();
+                } else {
+                    std::fs::copy(entry.path(), &dst)
+                        .with_context(|| format!("while copying file '{}'", dst.display()))?;
+                }
                 let mut mode = entry.metadata()?.mode();
                 // preserve executable bit
                 if (mode & 0o111) != 0 {
@@ -81,6 +99,9 @@ impl UnprivilegedDir {
                     .map(|i| i.as_raw()),
             )
             .with_context(|| format!("while chowning '{}'", dst.display()))?;
+            if let Some(manifest) = manifest.as_deref_mut() {
+                manifest.add(out, relpath, &std::fs::symlink_metadata(&dst)?, sha256)?;
+            }
         }
         Ok(())
     }
@@ -0,0 +1,348 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! dm-verity hash tree generation, compatible with `veritysetup format`
+//! (hash format version 1).
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::manifest::Manifest;
+
+const DIGEST_SIZE: usize = 32;
+const HASH_ALGORITHM: &str = "sha256";
+const HASH_TYPE: u32 = 1;
+const SUPERBLOCK_SIGNATURE: &[u8; 8] = b"verity\0\0";
+const SUPERBLOCK_VERSION: u32 = 1;
+const SUPERBLOCK_SIZE: usize = 512;
+const MAX_SALT_SIZE: usize = 256;
+
+const IMAGE: &str = "image";
+const HASH_TREE: &str = "hashtree";
+const PARAMS: &str = "verity.json";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Verity {
+    /// Read-only filesystem image to protect
+    src: PathBuf,
+    /// Manifest of the package that `src` came from, if it was built with one
+    #[serde(default)]
+    manifest: Option<PathBuf>,
+    #[serde(default)]
+    hash_tree: HashTree,
+    /// Hex-encoded salt. Defaults to a digest of the image contents so that
+    /// the output is reproducible.
+    salt: Option<String>,
+    /// Superblock UUID. Defaults to one derived from the root hash.
+    uuid: Option<Uuid>,
+    #[serde(default = "default_block_size")]
+    data_block_size: u32,
+    #[serde(default = "default_block_size")]
+    hash_block_size: u32,
+}
+
+fn default_block_size() -> u32 {
+    4096
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum HashTree {
+    /// The hash tree immediately follows the (block-aligned) data in the same
+    /// file, as with `veritysetup format --hash-offset`
+    #[default]
+    Appended,
+    /// The hash tree is written to a separate file, suitable for its own
+    /// partition
+    Sidecar,
+}
+
+/// Everything needed to set up the verity device, written as JSON next to the
+/// image.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct VerityParams {
+    pub(crate) root_hash: String,
+    salt: String,
+    uuid: Uuid,
+    hash_algorithm: String,
+    hash_type: u32,
+    data_block_size: u32,
+    hash_block_size: u32,
+    data_blocks: u64,
+    hash_tree: HashTree,
+    /// Byte offset of the verity superblock in the hash device
+    hash_offset: u64,
+    /// Extra arguments for `veritysetup open|verify <data> [name] <hash>
+    /// <root_hash>`. All other parameters are read from the superblock.
+    veritysetup_args: Vec<String>,
+}
+
+impl VerityParams {
+    pub(crate) fn root_hash_bytes(&self) -> Result<Vec<u8>> {
+        hex::decode(&self.root_hash).context("while decoding root hash")
+    }
+}
+
+impl Verity {
+    pub(crate) fn build(&self, out: &Path, manifest: Option<&mut Manifest>) -> Result<()> {
+        for size in [self.data_block_size, self.hash_block_size] {
+            ensure!(
+                size.is_power_of_two() && (512..=(1 << 20)).contains(&size),
+                "block size {size} must be a power of two between 512 bytes and 1M"
+            );
+        }
+        let data_block_size = u64::from(self.data_block_size);
+        let hash_block_size = self.hash_block_size as usize;
+
+        std::fs::create_dir(out).context("while creating output dir")?;
+        let image_path = out.join(IMAGE);
+        std::fs::copy(&self.src, &image_path).context("while copying src image")?;
+        let mut image = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&image_path)
+            .context("while opening image")?;
+        let len = image.metadata().context("while statting image")?.len();
+        ensure!(len > 0, "src image is empty");
+        // the data device must be made of whole blocks
+        let data_blocks = len.div_ceil(data_block_size);
+        let data_len = data_blocks * data_block_size;
+        image.set_len(data_len).context("while padding image")?;
+
+        let salt = match &self.salt {
+            Some(salt) => hex::decode(salt).context("salt must be hex-encoded")?,
+            None => {
+                let mut hasher = Sha256::new();
+                image.rewind()?;
+                std::io::copy(&mut BufReader::new(&image), &mut hasher)
+                    .context("while hashing image for salt")?;
+                hasher.finalize().to_vec()
+            }
+        };
+        ensure!(
+            salt.len() <= MAX_SALT_SIZE,
+            "salt must be at most {MAX_SALT_SIZE} bytes"
+        );
+
+        image.rewind()?;
+        let (levels, root_hash) = hash_tree(
+            BufReader::new(&image),
+            data_blocks,
+            self.data_block_size as usize,
+            hash_block_size,
+            &salt,
+        )?;
+
+        let uuid = self.uuid.unwrap_or_else(|| {
+            let digest = Sha256::new()
+                .chain_update(b"verity-superblock")
+                .chain_update(root_hash)
+                .finalize();
+            uuid::Builder::from_random_bytes(digest[..16].try_into().expect("digest is 32 bytes"))
+                .into_uuid()
+        });
+
+        let (mut hash_dev, hash_offset) = match self.hash_tree {
+            HashTree::Appended => {
+                image
+                    .seek(SeekFrom::Start(data_len))
+                    .context("while seeking to end of image")?;
+                (image, data_len)
+            }
+            HashTree::Sidecar => (
+                File::create(out.join(HASH_TREE)).context("while creating hash tree")?,
+                0,
+            ),
+        };
+        {
+            let mut w = BufWriter::new(&mut hash_dev);
+            let mut sb = superblock(
+                &uuid,
+                self.data_block_size,
+                self.hash_block_size,
+                data_blocks,
+                &salt,
+            );
+            // the tree starts at the first hash block after the superblock
+            let tree_start =
+                (hash_offset + SUPERBLOCK_SIZE as u64).next_multiple_of(hash_block_size as u64);
+            sb.resize((tree_start - hash_offset) as usize, 0);
+            w.write_all(&sb).context("while writing superblock")?;
+            // veritysetup stores the levels starting from the root
+            for level in levels.iter().rev() {
+                w.write_all(level).context("while writing hash tree")?;
+            }
+            w.flush().context("while writing hash tree")?;
+        }
+
+        let params = VerityParams {
+            root_hash: hex::encode(root_hash),
+            salt: hex::encode(&salt),
+            uuid,
+            hash_algorithm: HASH_ALGORITHM.to_owned(),
+            hash_type: HASH_TYPE,
+            data_block_size: self.data_block_size,
+            hash_block_size: self.hash_block_size,
+            data_blocks,
+            hash_tree: self.hash_tree,
+            hash_offset,
+            veritysetup_args: match self.hash_tree {
+                HashTree::Appended => vec![format!("--hash-offset={hash_offset}")],
+                HashTree::Sidecar => vec![],
+            },
+        };
+        let f = File::create(out.join(PARAMS)).context("while creating params file")?;
+        serde_json::to_writer_pretty(f, &params).context("while writing params")?;
+
+        // the hash tree doesn't change what's in the image, so this lists
+        // the same thing as the filesystem it protects
+        if let Some(manifest) = manifest {
+            match &self.manifest {
+                Some(src_manifest) => manifest.nest(Path::new(""), Manifest::read(src_manifest)?),
+                None => manifest.add_file(Path::new(IMAGE), &image_path)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compute the hash tree over `data_blocks` blocks read from `data`.
+///
+/// Returns each level of the tree (starting with the one that hashes the data
+/// blocks, each padded to a whole number of hash blocks) and the root hash.
+fn hash_tree<R: Read>(
+    mut data: R,
+    data_blocks: u64,
+    data_block_size: usize,
+    hash_block_size: usize,
+    salt: &[u8],
+) -> Result<(Vec<Vec<u8>>, [u8; DIGEST_SIZE])> {
+    let hash = |block: &[u8]| -> [u8; DIGEST_SIZE] {
+        Sha256::new()
+            .chain_update(salt)
+            .chain_update(block)
+            .finalize()
+            .into()
+    };
+    // same as cryptsetup: enough levels that a single hash block at the top
+    // covers every data block
+    let hashes_per_block_bits = (hash_block_size / DIGEST_SIZE).ilog2();
+    let mut num_levels = 0;
+    while hashes_per_block_bits * num_levels < 64
+        && (data_blocks - 1) >> (hashes_per_block_bits * num_levels) != 0
+    {
+        num_levels += 1;
+    }
+
+    let mut block = vec![0; data_block_size];
+    if num_levels == 0 {
+        data.read_exact(&mut block)
+            .context("while reading data block")?;
+        return Ok((vec![], hash(&block)));
+    }
+
+    let pad = |level: &mut Vec<u8>| {
+        level.resize(level.len().next_multiple_of(hash_block_size), 0);
+    };
+    let mut level = Vec::new();
+    for _ in 0..data_blocks {
+        data.read_exact(&mut block)
+            .context("while reading data block")?;
+        level.extend(hash(&block));
+    }
+    pad(&mut level);
+    let mut levels = vec![level];
+    for _ in 1..num_levels {
+        let mut next: Vec<u8> = levels
+            .last()
+            .expect("always at least one level")
+            .chunks(hash_block_size)
+            .flat_map(hash)
+            .collect();
+        pad(&mut next);
+        levels.push(next);
+    }
+    let top = levels.last().expect("always at least one level");
+    debug_assert_eq!(top.len(), hash_block_size);
+    let root = hash(top);
+    Ok((levels, root))
+}
+
+fn superblock(
+    uuid: &Uuid,
+    data_block_size: u32,
+    hash_block_size: u32,
+    data_blocks: u64,
+    salt: &[u8],
+) -> Vec<u8> {
+    let mut sb = Vec::with_capacity(SUPERBLOCK_SIZE);
+    sb.extend(SUPERBLOCK_SIGNATURE);
+    sb.extend(SUPERBLOCK_VERSION.to_le_bytes());
+    sb.extend(HASH_TYPE.to_le_bytes());
+    sb.extend(uuid.as_bytes());
+    let mut algorithm = [0u8; 32];
+    algorithm[..HASH_ALGORITHM.len()].copy_from_slice(HASH_ALGORITHM.as_bytes());
+    sb.extend(algorithm);
+    sb.extend(data_block_size.to_le_bytes());
+    sb.extend(hash_block_size.to_le_bytes());
+    sb.extend(data_blocks.to_le_bytes());
+    sb.extend((salt.len() as u16).to_le_bytes());
+    sb.extend([0; 6]);
+    let mut salt_field = [0u8; MAX_SALT_SIZE];
+    salt_field[..salt.len()].copy_from_slice(salt);
+    sb.extend(salt_field);
+    sb.resize(SUPERBLOCK_SIZE, 0);
+    sb
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_block_root_is_data_hash() {
+        let data = vec![0xab; 4096];
+        let (levels, root) = hash_tree(&data[..], 1, 4096, 4096, b"salt").expect("hash tree");
+        assert!(levels.is_empty());
+        let expected: [u8; DIGEST_SIZE] = Sha256::new()
+            .chain_update(b"salt")
+            .chain_update(&data)
+            .finalize()
+            .into();
+        assert_eq!(root, expected);
+    }
+
+    #[test]
+    fn level_sizes() {
+        // 129 data blocks need two hash blocks in the first level and a single
+        // block above that
+        let data = vec![0; 4096 * 129];
+        let (levels, _) = hash_tree(&data[..], 129, 4096, 4096, b"").expect("hash tree");
+        assert_eq!(
+            levels.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![8192, 4096]
+        );
+    }
+}
@@ -5,90 +5,1365 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+//! FAT12/16/32 filesystem images (mostly for EFI system partitions), written
+//! directly instead of with mkfs.vfat+mcopy so that no build appliance is
+//! needed. Everything is laid out in one pass, with each file and directory
+//! in a single contiguous run of clusters.
+
+use std::collections::HashSet;
 use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::path::PathBuf;
-use std::process::Stdio;
 
-use antlir2_isolate::unshare;
-use antlir2_isolate::IsolationContext;
+use anyhow::bail;
+use anyhow::ensure;
 use anyhow::Context;
 use anyhow::Result;
+use chrono::DateTime;
+use chrono::Datelike;
+use chrono::Timelike;
 use serde::Deserialize;
 
-use crate::run_cmd;
-use crate::BuildAppliance;
+use crate::manifest::Manifest;
+use crate::reproducible;
+use crate::reproducible::Digest;
 use crate::PackageFormat;
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Vfat {
-    build_appliance: BuildAppliance,
     fat_size: Option<u16>,
     label: Option<String>,
     size_mb: u64,
 }
 
+/// This is what the image has always been formatted with (`mkfs.vfat -S
+/// 4096`), and every FAT implementation that matters supports it
+const SECTOR_SIZE: u64 = 4096;
+/// Clusters larger than this are not supported by some implementations
+const MAX_CLUSTER_SIZE: u64 = 32 * 1024;
+const DIRENT_SIZE: usize = 32;
+const NUM_FATS: u64 = 2;
+const MEDIA_DESCRIPTOR: u8 = 0xf8;
+/// Minimum number of root directory entries on FAT12/16 (where the root
+/// directory has a fixed size)
+const MIN_ROOT_ENTRIES: u64 = 512;
+/// Characters in a long file name directory entry
+const LFN_CHARS_PER_ENTRY: usize = 13;
+const MAX_LFN_LEN: usize = 255;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+const FAT32_FSINFO_SECTOR: u64 = 1;
+const FAT32_BACKUP_BOOT_SECTOR: u64 = 6;
+const FAT32_RESERVED_SECTORS: u64 = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    fn bits(self) -> u64 {
+        match self {
+            Self::Fat12 => 12,
+            Self::Fat16 => 16,
+            Self::Fat32 => 32,
+        }
+    }
+
+    /// Largest cluster count before the filesystem would be detected as the
+    /// next bigger FAT type
+    fn max_clusters(self) -> u64 {
+        match self {
+            Self::Fat12 => 4084,
+            Self::Fat16 => 65524,
+            Self::Fat32 => 0x0ffffff4,
+        }
+    }
+
+    fn end_of_chain(self) -> u32 {
+        match self {
+            Self::Fat12 => 0xfff,
+            Self::Fat16 => 0xffff,
+            Self::Fat32 => 0x0fffffff,
+        }
+    }
+
+    fn reserved_sectors(self) -> u64 {
+        match self {
+            Self::Fat12 | Self::Fat16 => 1,
+            Self::Fat32 => FAT32_RESERVED_SECTORS,
+        }
+    }
+
+    fn name(self) -> &'static [u8; 8] {
+        match self {
+            Self::Fat12 => b"FAT12   ",
+            Self::Fat16 => b"FAT16   ",
+            Self::Fat32 => b"FAT32   ",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Geometry {
+    fat_type: FatType,
+    total_sectors: u64,
+    sectors_per_cluster: u64,
+    fat_sectors: u64,
+    root_entries: u64,
+    clusters: u64,
+}
+
+impl Geometry {
+    fn new(fat_type: FatType, total_sectors: u64, root_entries: u64) -> Result<Self> {
+        let root_entries = match fat_type {
+            FatType::Fat32 => 0,
+            _ => root_entries
+                .max(MIN_ROOT_ENTRIES)
+                .next_multiple_of(SECTOR_SIZE / DIRENT_SIZE as u64),
+        };
+        let mut sectors_per_cluster = 1;
+        loop {
+            let mut geo = Self {
+                fat_type,
+                total_sectors,
+                sectors_per_cluster,
+                fat_sectors: 1,
+                root_entries,
+                clusters: 0,
+            };
+            // the FAT needs an entry for every cluster, but takes space away
+            // from the clusters
+            loop {
+                let before_data = geo.first_data_sector();
+                ensure!(
+                    total_sectors > before_data,
+                    "{} MB is too small for a {fat_type:?} filesystem",
+                    total_sectors * SECTOR_SIZE / 1024 / 1024
+                );
+                geo.clusters = (total_sectors - before_data) / sectors_per_cluster;
+                let fat_bytes = ((geo.clusters + 2) * fat_type.bits()).div_ceil(8);
+                let fat_sectors = fat_bytes.div_ceil(SECTOR_SIZE);
+                if fat_sectors <= geo.fat_sectors {
+                    break;
+                }
+                geo.fat_sectors = fat_sectors;
+            }
+            if geo.clusters <= fat_type.max_clusters() {
+                // the fat type of FAT12/16 is detected solely by the number
+                // of clusters
+                ensure!(
+                    fat_type != FatType::Fat16 || geo.clusters > FatType::Fat12.max_clusters(),
+                    "{} MB is too small for a FAT16 filesystem",
+                    total_sectors * SECTOR_SIZE / 1024 / 1024
+                );
+                return Ok(geo);
+            }
+            sectors_per_cluster *= 2;
+            ensure!(
+                sectors_per_cluster * SECTOR_SIZE <= MAX_CLUSTER_SIZE,
+                "{} MB is too large for a {fat_type:?} filesystem",
+                total_sectors * SECTOR_SIZE / 1024 / 1024
+            );
+        }
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.sectors_per_cluster * SECTOR_SIZE
+    }
+
+    fn root_dir_sectors(&self) -> u64 {
+        (self.root_entries * DIRENT_SIZE as u64).div_ceil(SECTOR_SIZE)
+    }
+
+    fn first_fat_sector(&self) -> u64 {
+        self.fat_type.reserved_sectors()
+    }
+
+    fn root_dir_sector(&self) -> u64 {
+        self.first_fat_sector() + NUM_FATS * self.fat_sectors
+    }
+
+    fn first_data_sector(&self) -> u64 {
+        self.root_dir_sector() + self.root_dir_sectors()
+    }
+
+    /// Byte offset of a cluster (clusters are numbered starting at 2)
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        (self.first_data_sector() + (u64::from(cluster) - 2) * self.sectors_per_cluster)
+            * SECTOR_SIZE
+    }
+}
+
+/// Everything in the layer, as it will be laid out on the filesystem
+#[derive(Debug)]
+struct Node {
+    name: String,
+    mtime: i64,
+    /// First cluster (0 for empty files and the FAT12/16 root directory)
+    cluster: u32,
+    /// Length of the contiguous chain starting at `cluster`
+    clusters: u64,
+    kind: NodeKind,
+}
+
+#[derive(Debug)]
+enum NodeKind {
+    File {
+        src: PathBuf,
+        len: u32,
+    },
+    Dir {
+        children: Vec<Node>,
+        names: Vec<DirEntryName>,
+    },
+}
+
+impl Node {
+    fn from_layer(layer: &Path) -> Result<Self> {
+        let layer = layer
+            .canonicalize()
+            .with_context(|| format!("while resolving {}", layer.display()))?;
+        let meta = std::fs::metadata(&layer)
+            .with_context(|| format!("while statting {}", layer.display()))?;
+        Self::from_path(&layer, &layer, String::new(), &meta, &mut HashSet::new())
+    }
+
+    /// `ancestors` is the (dev, ino) of every directory above `path`, so that a
+    /// symlink to one of them (which would make the tree infinitely deep) can
+    /// be detected
+    fn from_path(
+        layer: &Path,
+        path: &Path,
+        name: String,
+        meta: &std::fs::Metadata,
+        ancestors: &mut HashSet<(u64, u64)>,
+    ) -> Result<Self> {
+        let kind = if meta.is_dir() {
+            ancestors.insert((meta.dev(), meta.ino()));
+            let mut entries = std::fs::read_dir(path)
+                .and_then(|entries| {
+                    entries
+                        .map(|e| e.map(|e| e.path()))
+                        .collect::<std::io::Result<Vec<_>>>()
+                })
+                .with_context(|| format!("while listing {}", path.display()))?;
+            entries.sort();
+            let mut children = Vec::with_capacity(entries.len());
+            for child in entries {
+                let name = child
+                    .file_name()
+                    .expect("always has a name")
+                    .to_str()
+                    .with_context(|| format!("{} is not utf8", child.display()))?
+                    .to_owned();
+                let resolved = resolve_in_layer(layer, &child)?;
+                let meta = std::fs::metadata(&resolved)
+                    .with_context(|| format!("while statting {}", resolved.display()))?;
+                ensure!(
+                    !(meta.is_dir() && ancestors.contains(&(meta.dev(), meta.ino()))),
+                    "{} is a symlink to one of its own parent directories, so it cannot be copied onto vfat",
+                    child.display()
+                );
+                children.push(Self::from_path(layer, &resolved, name, &meta, ancestors)?);
+            }
+            ancestors.remove(&(meta.dev(), meta.ino()));
+            let names = dir_entry_names(&children)
+                .with_context(|| format!("while naming entries in {}", path.display()))?;
+            NodeKind::Dir { children, names }
+        } else if meta.is_file() {
+            NodeKind::File {
+                src: path.to_owned(),
+                len: meta.len().try_into().with_context(|| {
+                    format!("{} is too large for vfat (max 4GiB)", path.display())
+                })?,
+            }
+        } else {
+            bail!(
+                "{} is not a regular file or directory, which is all that vfat can hold",
+                path.display()
+            );
+        };
+        Ok(Self {
+            name,
+            mtime: meta.mtime(),
+            cluster: 0,
+            clusters: 0,
+            kind,
+        })
+    }
+
+    /// Number of 32-byte entries in a directory, including the '.' and '..'
+    /// entries or the volume label in the root
+    fn dirents(&self, is_root: bool, has_label: bool) -> u64 {
+        match &self.kind {
+            NodeKind::File { .. } => 0,
+            NodeKind::Dir { names, .. } => {
+                let extra = if is_root { u64::from(has_label) } else { 2 };
+                names.iter().map(DirEntryName::dirents).sum::<u64>() + extra
+            }
+        }
+    }
+
+    /// Allocate contiguous clusters for this node and everything under it.
+    /// A directory gets its own clusters first, then its files and then its
+    /// subdirectories, so that related data stays close together.
+    fn allocate(&mut self, geo: &Geometry, next: &mut u64, is_root: bool, has_label: bool) {
+        let bytes = match &self.kind {
+            NodeKind::File { len, .. } => u64::from(*len),
+            NodeKind::Dir { .. } if is_root && geo.fat_type != FatType::Fat32 => 0,
+            // directories always need at least one cluster, even if empty
+            NodeKind::Dir { .. } => (self.dirents(is_root, has_label) * DIRENT_SIZE as u64).max(1),
+        };
+        self.clusters = bytes.div_ceil(geo.cluster_size());
+        if self.clusters > 0 {
+            // the caller checks that everything fits before this is used
+            self.cluster = *next as u32;
+            *next += self.clusters;
+        }
+        if let NodeKind::Dir { children, .. } = &mut self.kind {
+            for child in children.iter_mut() {
+                if let NodeKind::File { .. } = child.kind {
+                    child.allocate(geo, next, false, false);
+                }
+            }
+            for child in children.iter_mut() {
+                if let NodeKind::Dir { .. } = child.kind {
+                    child.allocate(geo, next, false, false);
+                }
+            }
+        }
+    }
+
+    /// Every allocated cluster chain as (first cluster, length)
+    fn chains(&self, out: &mut Vec<(u32, u64)>) {
+        if self.clusters > 0 {
+            out.push((self.cluster, self.clusters));
+        }
+        if let NodeKind::Dir { children, .. } = &self.kind {
+            for child in children {
+                child.chains(out);
+            }
+        }
+    }
+}
+
+/// Follow a symlink (relative to the layer root if it's absolute), since vfat
+/// has no way to represent them
+fn resolve_in_layer(layer: &Path, path: &Path) -> Result<PathBuf> {
+    let mut path = path.to_owned();
+    // (dev, ino) of every symlink followed so far
+    let mut visited = HashSet::new();
+    for _ in 0..40 {
+        let meta = std::fs::symlink_metadata(&path)
+            .with_context(|| format!("while statting {}", path.display()))?;
+        if !meta.is_symlink() {
+            let resolved = path
+                .canonicalize()
+                .with_context(|| format!("while resolving {}", path.display()))?;
+            ensure!(
+                resolved.starts_with(layer),
+                "{} resolves to {}, which is outside of the layer",
+                path.display(),
+                resolved.display()
+            );
+            return Ok(path);
+        }
+        ensure!(
+            visited.insert((meta.dev(), meta.ino())),
+            "{} is part of a symlink loop",
+            path.display()
+        );
+        let target = std::fs::read_link(&path)
+            .with_context(|| format!("while reading link {}", path.display()))?;
+        path = match target.strip_prefix("/") {
+            Ok(relative_to_root) => layer.join(relative_to_root),
+            Err(_) => path.parent().expect("always has a parent").join(target),
+        };
+    }
+    bail!("too many levels of symlinks at {}", path.display())
+}
+
+/// Name of a single node as stored in its parent directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DirEntryName {
+    short: [u8; 11],
+    /// Windows NT extension (understood by basically everything) to mark the
+    /// basename and/or extension of a short name as entirely lowercase
+    case: u8,
+    /// Only present if the name can't be represented exactly by the short
+    /// name
+    long: Option<Vec<u16>>,
+}
+
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+impl DirEntryName {
+    fn dirents(&self) -> u64 {
+        1 + self
+            .long
+            .as_ref()
+            .map_or(0, |l| (l.len() as u64).div_ceil(LFN_CHARS_PER_ENTRY as u64))
+    }
+}
+
+fn is_valid_short_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"$%'-_@~`!(){}^#&".contains(&c)
+}
+
+/// Uppercase 8.3 name that exactly matches `name` (ignoring case), if there
+/// is one
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let upper = name.to_ascii_uppercase();
+    let (base, ext) = upper.split_once('.').unwrap_or((&upper, ""));
+    if base.is_empty()
+        || base.len() > 8
+        || ext.len() > 3
+        || upper.ends_with('.')
+        || !base.bytes().chain(ext.bytes()).all(is_valid_short_char)
+    {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(short)
+}
+
+/// Case flags that make `short` display as `name`, if it only differs by
+/// having an all-lowercase basename and/or extension
+fn case_flags(name: &str) -> Option<u8> {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    let flag = |part: &str, lower_flag: u8| {
+        if !part.bytes().any(|c| c.is_ascii_lowercase()) {
+            Some(0)
+        } else if !part.bytes().any(|c| c.is_ascii_uppercase()) {
+            Some(lower_flag)
+        } else {
+            None
+        }
+    };
+    Some(flag(base, CASE_LOWER_BASE)? | flag(ext, CASE_LOWER_EXT)?)
+}
+
+/// Generate a unique short name (with a numeric tail) for a name that needs a
+/// long file name entry, roughly the same way that Windows does
+fn generate_short_name(name: &str, taken: &HashSet<[u8; 11]>) -> Result<[u8; 11]> {
+    let sanitize = |s: &str| -> Vec<u8> {
+        s.chars()
+            .filter(|c| *c != ' ' && *c != '.')
+            .map(|c| {
+                let c = c.to_ascii_uppercase();
+                if c.is_ascii() && is_valid_short_char(c as u8) {
+                    c as u8
+                } else {
+                    b'_'
+                }
+            })
+            .collect()
+    };
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rsplit_once('.') {
+        Some((base, ext)) => (sanitize(base), sanitize(ext)),
+        None => (sanitize(trimmed), Vec::new()),
+    };
+    let base = if base.is_empty() { b"_".to_vec() } else { base };
+    let mut short = [b' '; 11];
+    let ext_len = ext.len().min(3);
+    short[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+    for n in 1..1_000_000 {
+        let tail = format!("~{n}");
+        let base_len = base.len().min(8 - tail.len());
+        short[..8].fill(b' ');
+        short[..base_len].copy_from_slice(&base[..base_len]);
+        short[base_len..base_len + tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken.contains(&short) {
+            return Ok(short);
+        }
+    }
+    bail!("could not generate a unique short name for '{name}'")
+}
+
+/// Work out the names of everything in a directory, in order
+fn dir_entry_names(children: &[Node]) -> Result<Vec<DirEntryName>> {
+    let mut folded = HashSet::new();
+    for child in children {
+        ensure!(
+            folded.insert(child.name.to_uppercase()),
+            "'{}' conflicts with another name in the same directory (vfat is case-insensitive)",
+            child.name
+        );
+        ensure!(
+            !child
+                .name
+                .chars()
+                .any(|c| c.is_control() || "\"*/:<>?\\|".contains(c)),
+            "'{}' contains characters that are not allowed in vfat names",
+            child.name
+        );
+    }
+    // exact short names get first dibs, so that generated short names never
+    // steal them
+    let exact: Vec<_> = children.iter().map(|c| exact_short_name(&c.name)).collect();
+    let mut taken: HashSet<_> = exact.iter().flatten().copied().collect();
+    let mut names = Vec::with_capacity(children.len());
+    for (child, exact) in children.iter().zip(exact) {
+        let long = || -> Result<Vec<u16>> {
+            let long: Vec<u16> = child.name.encode_utf16().collect();
+            ensure!(
+                long.len() <= MAX_LFN_LEN,
+                "'{}' is too long for vfat",
+                child.name
+            );
+            Ok(long)
+        };
+        names.push(match exact {
+            Some(short) => match case_flags(&child.name) {
+                Some(case) => DirEntryName {
+                    short,
+                    case,
+                    long: None,
+                },
+                // mixed case needs a long name to preserve it, but can still
+                // have the obvious short name
+                None => DirEntryName {
+                    short,
+                    case: 0,
+                    long: Some(long()?),
+                },
+            },
+            None => {
+                let short = generate_short_name(&child.name, &taken)?;
+                taken.insert(short);
+                DirEntryName {
+                    short,
+                    case: 0,
+                    long: Some(long()?),
+                }
+            }
+        });
+    }
+    Ok(names)
+}
+
+fn lfn_checksum(short: &[u8; 11]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, b| sum.rotate_right(1).wrapping_add(*b))
+}
+
+/// Directory entries for a long file name, in the order that they appear on
+/// disk (which is backwards)
+fn lfn_entries(long: &[u16], short: &[u8; 11]) -> Vec<[u8; DIRENT_SIZE]> {
+    let checksum = lfn_checksum(short);
+    let count = long.len().div_ceil(LFN_CHARS_PER_ENTRY);
+    let mut chars = long.to_vec();
+    // NUL-terminated unless it exactly fills the last entry, then padded
+    // with 0xffff
+    if !chars.len().is_multiple_of(LFN_CHARS_PER_ENTRY) {
+        chars.push(0);
+    }
+    chars.resize(count * LFN_CHARS_PER_ENTRY, 0xffff);
+    (0..count)
+        .rev()
+        .map(|i| {
+            let mut entry = [0u8; DIRENT_SIZE];
+            entry[0] = (i + 1) as u8 | if i == count - 1 { 0x40 } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            let chunk = &chars[i * LFN_CHARS_PER_ENTRY..(i + 1) * LFN_CHARS_PER_ENTRY];
+            for (c, offset) in chunk.iter().zip(LFN_CHAR_OFFSETS) {
+                entry[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+/// Where each UTF-16 character of a long file name is stored in its entry
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS_PER_ENTRY] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// FAT timestamps are in local time, which for reproducible images is always
+/// UTC, and can only represent 1980-2107 with 2 second granularity
+fn fat_datetime(time: i64) -> (u16, u16) {
+    let min = 315532800; // 1980-01-01
+    let max = 4354819198; // 2107-12-31 23:59:58
+    let dt = DateTime::from_timestamp(time.clamp(min, max), 0).expect("always in range");
+    let date = ((dt.year() as u16 - 1980) << 9) | ((dt.month() as u16) << 5) | dt.day() as u16;
+    let time = ((dt.hour() as u16) << 11) | ((dt.minute() as u16) << 5) | (dt.second() as u16 / 2);
+    (date, time)
+}
+
+fn short_entry(
+    short: &[u8; 11],
+    case: u8,
+    attr: u8,
+    cluster: u32,
+    size: u32,
+    mtime: i64,
+) -> [u8; DIRENT_SIZE] {
+    let mut entry = [0u8; DIRENT_SIZE];
+    entry[..11].copy_from_slice(short);
+    // 0xe5 marks a deleted entry, so a name that actually starts with it is
+    // stored as 0x05
+    if entry[0] == 0xe5 {
+        entry[0] = 0x05;
+    }
+    entry[11] = attr;
+    entry[12] = case;
+    let (date, time) = fat_datetime(mtime);
+    // creation, access and modification times are all the same
+    entry[14..16].copy_from_slice(&time.to_le_bytes());
+    entry[16..18].copy_from_slice(&date.to_le_bytes());
+    entry[18..20].copy_from_slice(&date.to_le_bytes());
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[22..24].copy_from_slice(&time.to_le_bytes());
+    entry[24..26].copy_from_slice(&date.to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+/// Volume labels are stored as uppercase short names
+fn volume_label(label: &str) -> Result<[u8; 11]> {
+    let label = label.to_ascii_uppercase();
+    ensure!(
+        label.len() <= 11,
+        "vfat labels can be at most 11 characters"
+    );
+    ensure!(
+        label.bytes().all(|c| c == b' ' || is_valid_short_char(c)),
+        "'{label}' contains characters that are not allowed in vfat labels"
+    );
+    let mut out = [b' '; 11];
+    out[..label.len()].copy_from_slice(label.as_bytes());
+    Ok(out)
+}
+
+struct Image<'a> {
+    file: &'a File,
+    geo: Geometry,
+    source_date_epoch: u64,
+}
+
+impl Image<'_> {
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+        self.file
+            .write_all_at(buf, offset)
+            .with_context(|| format!("while writing at offset {offset}"))
+    }
+
+    fn boot_sector(&self, volume_id: u32, label: Option<&[u8; 11]>) -> Vec<u8> {
+        let geo = &self.geo;
+        let mut bs = vec![0u8; SECTOR_SIZE as usize];
+        bs[3..11].copy_from_slice(b"MSWIN4.1");
+        bs[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+        bs[13] = geo.sectors_per_cluster as u8;
+        bs[14..16].copy_from_slice(&(geo.fat_type.reserved_sectors() as u16).to_le_bytes());
+        bs[16] = NUM_FATS as u8;
+        bs[17..19].copy_from_slice(&(geo.root_entries as u16).to_le_bytes());
+        if geo.total_sectors < 0x10000 {
+            bs[19..21].copy_from_slice(&(geo.total_sectors as u16).to_le_bytes());
+        } else {
+            bs[32..36].copy_from_slice(&(geo.total_sectors as u32).to_le_bytes());
+        }
+        bs[21] = MEDIA_DESCRIPTOR;
+        // the geometry doesn't matter for anything that isn't a floppy, but
+        // these are the conventional values
+        bs[24..26].copy_from_slice(&32u16.to_le_bytes());
+        bs[26..28].copy_from_slice(&64u16.to_le_bytes());
+        let ext = match geo.fat_type {
+            FatType::Fat12 | FatType::Fat16 => {
+                bs[0..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+                bs[22..24].copy_from_slice(&(geo.fat_sectors as u16).to_le_bytes());
+                36
+            }
+            FatType::Fat32 => {
+                bs[0..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+                bs[36..40].copy_from_slice(&(geo.fat_sectors as u32).to_le_bytes());
+                // the root directory is always allocated first
+                bs[44..48].copy_from_slice(&2u32.to_le_bytes());
+                bs[48..50].copy_from_slice(&(FAT32_FSINFO_SECTOR as u16).to_le_bytes());
+                bs[50..52].copy_from_slice(&(FAT32_BACKUP_BOOT_SECTOR as u16).to_le_bytes());
+                64
+            }
+        };
+        bs[ext] = 0x80; // drive number
+        bs[ext + 2] = 0x29; // extended boot signature
+        bs[ext + 3..ext + 7].copy_from_slice(&volume_id.to_le_bytes());
+        bs[ext + 7..ext + 18].copy_from_slice(label.unwrap_or(b"NO NAME    "));
+        bs[ext + 18..ext + 26].copy_from_slice(geo.fat_type.name());
+        // this is not bootable, so the boot code just asks the BIOS to try
+        // the next device (int 0x18) and halts if that returns
+        bs[ext + 26..ext + 31].copy_from_slice(&[0xcd, 0x18, 0xf4, 0xeb, 0xfd]);
+        bs[510] = 0x55;
+        bs[511] = 0xaa;
+        bs
+    }
+
+    fn fsinfo_sector(&self, free_clusters: u32, next_free: u32) -> Vec<u8> {
+        let mut fsinfo = vec![0u8; SECTOR_SIZE as usize];
+        fsinfo[0..4].copy_from_slice(&0x41615252u32.to_le_bytes());
+        fsinfo[484..488].copy_from_slice(&0x61417272u32.to_le_bytes());
+        fsinfo[488..492].copy_from_slice(&free_clusters.to_le_bytes());
+        fsinfo[492..496].copy_from_slice(&next_free.to_le_bytes());
+        fsinfo[508..512].copy_from_slice(&0xaa550000u32.to_le_bytes());
+        fsinfo
+    }
+
+    fn fat(&self, chains: &[(u32, u64)]) -> Vec<u8> {
+        let fat_type = self.geo.fat_type;
+        let eoc = fat_type.end_of_chain();
+        let mut entries = vec![0u32; self.geo.clusters as usize + 2];
+        entries[0] = (eoc & !0xff) | u32::from(MEDIA_DESCRIPTOR);
+        entries[1] = eoc;
+        for (first, len) in chains {
+            let last = first + *len as u32 - 1;
+            for cluster in *first..last {
+                entries[cluster as usize] = cluster + 1;
+            }
+            entries[last as usize] = eoc;
+        }
+
+        let mut fat = vec![0u8; (self.geo.fat_sectors * SECTOR_SIZE) as usize];
+        for (n, entry) in entries.into_iter().enumerate() {
+            match fat_type {
+                // two 12-bit entries are packed into every 3 bytes
+                FatType::Fat12 => {
+                    let offset = n * 3 / 2;
+                    if n.is_multiple_of(2) {
+                        fat[offset] = entry as u8;
+                        fat[offset + 1] |= (entry >> 8) as u8 & 0x0f;
+                    } else {
+                        fat[offset] |= (entry as u8 & 0x0f) << 4;
+                        fat[offset + 1] = (entry >> 4) as u8;
+                    }
+                }
+                FatType::Fat16 => {
+                    fat[n * 2..n * 2 + 2].copy_from_slice(&(entry as u16).to_le_bytes())
+                }
+                FatType::Fat32 => fat[n * 4..n * 4 + 4].copy_from_slice(&entry.to_le_bytes()),
+            }
+        }
+        fat
+    }
+
+    fn mtime(&self, node: &Node) -> i64 {
+        node.mtime.min(self.source_date_epoch as i64)
+    }
+
+    /// Write a directory and everything underneath it. `parent_cluster` is
+    /// `None` for the root directory, and 0 for directories in the root.
+    fn write_dir(
+        &self,
+        dir: &Node,
+        parent_cluster: Option<u32>,
+        label: Option<&[u8; 11]>,
+    ) -> Result<()> {
+        let NodeKind::Dir { children, names } = &dir.kind else {
+            unreachable!("only called on directories");
+        };
+        let mut contents = Vec::new();
+        match parent_cluster {
+            None => {
+                if let Some(label) = label {
+                    contents.extend(short_entry(
+                        label,
+                        0,
+                        ATTR_VOLUME_ID,
+                        0,
+                        0,
+                        self.source_date_epoch as i64,
+                    ));
+                }
+            }
+            Some(parent_cluster) => {
+                contents.extend(short_entry(
+                    b".          ",
+                    0,
+                    ATTR_DIRECTORY,
+                    dir.cluster,
+                    0,
+                    self.mtime(dir),
+                ));
+                contents.extend(short_entry(
+                    b"..         ",
+                    0,
+                    ATTR_DIRECTORY,
+                    parent_cluster,
+                    0,
+                    self.mtime(dir),
+                ));
+            }
+        }
+        for (child, name) in children.iter().zip(names) {
+            if let Some(long) = &name.long {
+                contents.extend(lfn_entries(long, &name.short).into_iter().flatten());
+            }
+            let (attr, size) = match &child.kind {
+                NodeKind::File { len, .. } => (ATTR_ARCHIVE, *len),
+                NodeKind::Dir { .. } => (ATTR_DIRECTORY, 0),
+            };
+            contents.extend(short_entry(
+                &name.short,
+                name.case,
+                attr,
+                child.cluster,
+                size,
+                self.mtime(child),
+            ));
+        }
+        if dir.clusters == 0 {
+            self.write_at(&contents, self.geo.root_dir_sector() * SECTOR_SIZE)?;
+        } else {
+            self.write_at(&contents, self.geo.cluster_offset(dir.cluster))?;
+        }
+
+        // '..' of a directory in the root is always 0, even on FAT32 where
+        // the root directory really is in a cluster
+        let cluster_for_children = match parent_cluster {
+            None => 0,
+            Some(_) => dir.cluster,
+        };
+        for child in children {
+            match &child.kind {
+                NodeKind::Dir { .. } => {
+                    self.write_dir(child, Some(cluster_for_children), None)?;
+                }
+                NodeKind::File { src, .. } => {
+                    if child.clusters > 0 {
+                        self.copy_file(src, child.cluster)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn copy_file(&self, src: &Path, cluster: u32) -> Result<()> {
+        let mut f = File::open(src).with_context(|| format!("while opening {}", src.display()))?;
+        let mut offset = self.geo.cluster_offset(cluster);
+        let mut buf = vec![0; 1024 * 1024];
+        loop {
+            let n = f
+                .read(&mut buf)
+                .with_context(|| format!("while reading {}", src.display()))?;
+            if n == 0 {
+                return Ok(());
+            }
+            self.write_at(&buf[..n], offset)?;
+            offset += n as u64;
+        }
+    }
+}
+
+/// Build a complete filesystem image from a layer into `file`, which must
+/// already be the full size of the image
+fn write_image(
+    file: &File,
+    layer: &Path,
+    fat_size: Option<u16>,
+    label: Option<&str>,
+    volume_id: u32,
+    source_date_epoch: u64,
+) -> Result<()> {
+    let size = file.metadata().context("while statting output")?.len();
+    let total_sectors = size / SECTOR_SIZE;
+    let label = label.map(volume_label).transpose()?;
+
+    let mut root = Node::from_layer(layer)?;
+    let root_entries = root.dirents(true, label.is_some());
+    let geo = match fat_size {
+        Some(12) => Geometry::new(FatType::Fat12, total_sectors, root_entries)?,
+        Some(16) => Geometry::new(FatType::Fat16, total_sectors, root_entries)?,
+        Some(32) => Geometry::new(FatType::Fat32, total_sectors, root_entries)?,
+        Some(other) => bail!("fat_size must be one of 12, 16 or 32, not {other}"),
+        // same thresholds as mkfs.vfat
+        None if size >= 512 * 1024 * 1024 => {
+            Geometry::new(FatType::Fat32, total_sectors, root_entries)?
+        }
+        None => Geometry::new(FatType::Fat16, total_sectors, root_entries)
+            .or_else(|_| Geometry::new(FatType::Fat12, total_sectors, root_entries))?,
+    };
+
+    let mut next_free = 2;
+    root.allocate(&geo, &mut next_free, true, label.is_some());
+    let used = next_free - 2;
+    ensure!(
+        used <= geo.clusters,
+        "layer needs {} KiB but the {:?} filesystem only has {} KiB of space",
+        used * geo.cluster_size() / 1024,
+        geo.fat_type,
+        geo.clusters * geo.cluster_size() / 1024,
+    );
+
+    let image = Image {
+        file,
+        geo,
+        source_date_epoch,
+    };
+
+    let mut chains = Vec::new();
+    root.chains(&mut chains);
+    let fat = image.fat(&chains);
+    for i in 0..NUM_FATS {
+        image.write_at(
+            &fat,
+            (geo.first_fat_sector() + i * geo.fat_sectors) * SECTOR_SIZE,
+        )?;
+    }
+
+    let boot_sector = image.boot_sector(volume_id, label.as_ref());
+    image.write_at(&boot_sector, 0)?;
+    if geo.fat_type == FatType::Fat32 {
+        let fsinfo = image.fsinfo_sector((geo.clusters - used) as u32, next_free as u32);
+        image.write_at(&fsinfo, FAT32_FSINFO_SECTOR * SECTOR_SIZE)?;
+        image.write_at(&boot_sector, FAT32_BACKUP_BOOT_SECTOR * SECTOR_SIZE)?;
+        image.write_at(&fsinfo, (FAT32_BACKUP_BOOT_SECTOR + 1) * SECTOR_SIZE)?;
+    }
+
+    image.write_dir(&root, None, label.as_ref())
+}
+
 impl PackageFormat for Vfat {
-    fn build(&self, out: &Path, layer: &Path) -> Result<()> {
+    fn build(&self, out: &Path, layer: &Path, manifest: Option<&mut Manifest>) -> Result<()> {
         let file = File::create(out).context("failed to create output file")?;
         file.set_len(self.size_mb * 1024 * 1024)
             .context("failed to set output to specified size")?;
+        write_image(
+            &file,
+            layer,
+            self.fat_size,
+            self.label.as_deref(),
+            Digest::of_tree(layer, manifest)?.u32("vfat"),
+            reproducible::source_date_epoch()?,
+        )?;
         file.sync_all()
             .context("Failed to sync output file to disk")?;
-        drop(file);
-
-        let isol_context = IsolationContext::builder(self.build_appliance.path())
-            .ephemeral(false)
-            .readonly()
-            .tmpfs(Path::new("/__antlir2__/out"))
-            .outputs(("/__antlir2__/out/vfat", out))
-            .inputs((Path::new("/__antlir2__/root"), layer))
-            .inputs((
-                PathBuf::from("/__antlir2__/working_directory"),
-                std::env::current_dir()?,
-            ))
-            .working_directory(Path::new("/__antlir2__/working_directory"))
-            .setenv(("RUST_LOG", std::env::var_os("RUST_LOG").unwrap_or_default()))
-            .setenv(("MTOOLS_SKIP_CHECK", "1"))
-            .build();
-
-        // Build the vfat disk file first
-        let mut mkfs = unshare(isol_context.clone())?.command("/usr/sbin/mkfs.vfat")?;
-        if let Some(fat_size) = &self.fat_size {
-            mkfs.arg(format!("-F{}", fat_size));
-        }
-        if let Some(label) = &self.label {
-            mkfs.arg("-n").arg(label);
-        }
-        mkfs.arg("-S").arg("4096");
-
-        run_cmd(mkfs.arg("/__antlir2__/out/vfat").stdout(Stdio::piped()))
-            .context("failed to mkfs.vfat")?;
-
-        // mcopy all the files from the input layer directly into the vfat image.
-        let paths = std::fs::read_dir(layer).context("Failed to list input directory")?;
-        let mut sources = Vec::new();
-        for path in paths {
-            sources.push(
-                Path::new("/__antlir2__/root")
-                    .join(path.context("failed to read next input path")?.file_name()),
-            );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    /// Minimal FAT reader that only follows the on-disk format, so that
+    /// mistakes in the writer can't cancel themselves out
+    struct Reader {
+        data: Vec<u8>,
+        fat_type: FatType,
+        cluster_size: usize,
+        fat_offset: usize,
+        root_offset: usize,
+        root_len: usize,
+        root_cluster: u32,
+        data_offset: usize,
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Entry {
+        File(Vec<u8>),
+        Dir(BTreeMap<String, Entry>),
+    }
+
+    impl Reader {
+        fn new(data: Vec<u8>) -> Self {
+            let u16_at = |o: usize| u16::from_le_bytes([data[o], data[o + 1]]) as usize;
+            let u32_at = |o: usize| u32::from_le_bytes(data[o..o + 4].try_into().unwrap());
+            assert_eq!(&data[510..512], &[0x55, 0xaa]);
+            let sector_size = u16_at(11);
+            let cluster_size = data[13] as usize * sector_size;
+            let reserved = u16_at(14);
+            let fats = data[16] as usize;
+            let root_entries = u16_at(17);
+            let total_sectors = match u16_at(19) {
+                0 => u32_at(32) as usize,
+                n => n,
+            };
+            let fat_sectors = match u16_at(22) {
+                0 => u32_at(36) as usize,
+                n => n,
+            };
+            let root_sectors = (root_entries * 32).div_ceil(sector_size);
+            let data_sector = reserved + fats * fat_sectors + root_sectors;
+            let clusters = (total_sectors - data_sector) / data[13] as usize;
+            // this is how the spec says that the FAT type must be determined
+            let fat_type = if clusters < 4085 {
+                FatType::Fat12
+            } else if clusters < 65525 {
+                FatType::Fat16
+            } else {
+                FatType::Fat32
+            };
+            assert_eq!(data.len(), total_sectors * sector_size);
+            Self {
+                fat_type,
+                cluster_size,
+                fat_offset: reserved * sector_size,
+                root_offset: (reserved + fats * fat_sectors) * sector_size,
+                root_len: root_entries * 32,
+                root_cluster: if fat_type == FatType::Fat32 {
+                    u32_at(44)
+                } else {
+                    0
+                },
+                data_offset: data_sector * sector_size,
+                data,
+            }
+        }
+
+        fn next_cluster(&self, cluster: u32) -> Option<u32> {
+            let n = cluster as usize;
+            let o = self.fat_offset;
+            let (next, eoc) = match self.fat_type {
+                FatType::Fat12 => {
+                    let v = u16::from_le_bytes([
+                        self.data[o + n * 3 / 2],
+                        self.data[o + n * 3 / 2 + 1],
+                    ]) as u32;
+                    (
+                        if n.is_multiple_of(2) {
+                            v & 0xfff
+                        } else {
+                            v >> 4
+                        },
+                        0xff8,
+                    )
+                }
+                FatType::Fat16 => (
+                    u16::from_le_bytes([self.data[o + n * 2], self.data[o + n * 2 + 1]]) as u32,
+                    0xfff8,
+                ),
+                FatType::Fat32 => (
+                    u32::from_le_bytes(self.data[o + n * 4..o + n * 4 + 4].try_into().unwrap())
+                        & 0x0fffffff,
+                    0x0ffffff8,
+                ),
+            };
+            assert_ne!(next, 0, "cluster {cluster} is free but in a chain");
+            if next >= eoc {
+                None
+            } else {
+                Some(next)
+            }
+        }
+
+        fn chain(&self, first: u32) -> Vec<u8> {
+            let mut out = Vec::new();
+            let mut cluster = Some(first);
+            while let Some(c) = cluster {
+                let start = self.data_offset + (c as usize - 2) * self.cluster_size;
+                out.extend_from_slice(&self.data[start..start + self.cluster_size]);
+                cluster = self.next_cluster(c);
+            }
+            out
         }
 
-        run_cmd(
-            unshare(isol_context)?
-                .command("/usr/bin/mcopy")?
-                .arg("-v")
-                .arg("-i")
-                .arg("/__antlir2__/out/vfat")
-                .arg("-sp")
-                .args(sources)
-                .arg("::")
-                .stdout(Stdio::piped()),
+        fn read_dir(&self, raw: &[u8], self_cluster: u32, parent: u32) -> BTreeMap<String, Entry> {
+            let mut out = BTreeMap::new();
+            let mut lfn: Vec<(u8, u8, Vec<u16>)> = Vec::new();
+            for entry in raw.chunks(32) {
+                if entry[0] == 0 {
+                    break;
+                }
+                let attr = entry[11];
+                if attr == ATTR_LONG_NAME {
+                    let chars = LFN_CHAR_OFFSETS
+                        .iter()
+                        .map(|&o| u16::from_le_bytes([entry[o], entry[o + 1]]))
+                        .collect();
+                    lfn.push((entry[0], entry[13], chars));
+                    continue;
+                }
+                let mut short: [u8; 11] = entry[..11].try_into().unwrap();
+                if short[0] == 0x05 {
+                    short[0] = 0xe5;
+                }
+                let cluster = (u32::from(u16::from_le_bytes([entry[20], entry[21]])) << 16)
+                    | u32::from(u16::from_le_bytes([entry[26], entry[27]]));
+                if attr & ATTR_VOLUME_ID != 0 {
+                    out.insert(
+                        format!("label:{}", String::from_utf8_lossy(&short).trim_end()),
+                        Entry::File(Vec::new()),
+                    );
+                    continue;
+                }
+                if &short == b".          " {
+                    assert_eq!(cluster, self_cluster);
+                    continue;
+                }
+                if &short == b"..         " {
+                    assert_eq!(cluster, parent);
+                    continue;
+                }
+                let name = if lfn.is_empty() {
+                    let case = entry[12];
+                    let part = |s: &[u8], lower: bool| {
+                        let s = String::from_utf8(s.to_vec()).unwrap();
+                        let s = s.trim_end();
+                        if lower {
+                            s.to_ascii_lowercase()
+                        } else {
+                            s.to_owned()
+                        }
+                    };
+                    let base = part(&short[..8], case & CASE_LOWER_BASE != 0);
+                    let ext = part(&short[8..], case & CASE_LOWER_EXT != 0);
+                    if ext.is_empty() {
+                        base
+                    } else {
+                        format!("{base}.{ext}")
+                    }
+                } else {
+                    let checksum = lfn_checksum(&short);
+                    let count = lfn.len();
+                    let mut chars = Vec::new();
+                    for (i, (seq, sum, part)) in lfn.drain(..).rev().enumerate() {
+                        assert_eq!(sum, checksum);
+                        assert_eq!(seq & 0x3f, i as u8 + 1);
+                        assert_eq!(seq & 0x40 != 0, i + 1 == count);
+                        chars.extend(part);
+                    }
+                    let end = chars.iter().position(|c| *c == 0).unwrap_or(chars.len());
+                    String::from_utf16(&chars[..end]).unwrap()
+                };
+                let entry = if attr & ATTR_DIRECTORY != 0 {
+                    let raw = self.chain(cluster);
+                    let parent_for_children = if self_cluster == self.root_cluster {
+                        0
+                    } else {
+                        self_cluster
+                    };
+                    Entry::Dir(self.read_dir(&raw, cluster, parent_for_children))
+                } else {
+                    let size = u32::from_le_bytes(entry[28..32].try_into().unwrap()) as usize;
+                    if size == 0 {
+                        assert_eq!(cluster, 0);
+                        Entry::File(Vec::new())
+                    } else {
+                        let mut data = self.chain(cluster);
+                        assert!(data.len() - size < self.cluster_size);
+                        data.truncate(size);
+                        Entry::File(data)
+                    }
+                };
+                assert!(out.insert(name, entry).is_none());
+            }
+            out
+        }
+
+        fn root(&self) -> BTreeMap<String, Entry> {
+            if self.fat_type == FatType::Fat32 {
+                let raw = self.chain(self.root_cluster);
+                self.read_dir(&raw, self.root_cluster, 0)
+            } else {
+                let raw = &self.data[self.root_offset..self.root_offset + self.root_len];
+                self.read_dir(raw, 0, 0)
+            }
+        }
+    }
+
+    fn build(
+        layer: &Path,
+        size_mb: u64,
+        fat_size: Option<u16>,
+        label: Option<&str>,
+    ) -> Result<Reader> {
+        let out = tempfile::tempfile()?;
+        out.set_len(size_mb * 1024 * 1024)?;
+        write_image(&out, layer, fat_size, label, 0x1234abcd, 1700000000)?;
+        let mut data = Vec::new();
+        let mut out = out;
+        std::io::Seek::rewind(&mut out)?;
+        out.read_to_end(&mut data)?;
+        Ok(Reader::new(data))
+    }
+
+    fn layer() -> (tempfile::TempDir, BTreeMap<String, Entry>) {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("EFI/BOOT")).unwrap();
+        std::fs::write(
+            root.join("EFI/BOOT/BOOTX64.EFI"),
+            b"not really an efi binary",
         )
-        .context("Failed to mcopy layer into new fs")?;
+        .unwrap();
+        std::fs::create_dir_all(root.join("loader/entries")).unwrap();
+        std::fs::write(root.join("loader/loader.conf"), b"timeout 3\n").unwrap();
+        let big: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(root.join("loader/entries/A Long Name.conf"), &big).unwrap();
+        std::fs::write(root.join("Mixed.Txt"), b"mixed").unwrap();
+        std::fs::write(root.join("empty"), b"").unwrap();
+        std::fs::create_dir(root.join("many")).unwrap();
+        for i in 0..200 {
+            std::fs::write(
+                root.join(format!("many/file number {i} with a long name")),
+                i.to_string(),
+            )
+            .unwrap();
+        }
+        std::os::unix::fs::symlink("/loader/loader.conf", root.join("link")).unwrap();
 
-        Ok(())
+        let file = |b: &[u8]| Entry::File(b.to_vec());
+        let expected = BTreeMap::from([
+            (
+                "EFI".into(),
+                Entry::Dir(BTreeMap::from([(
+                    "BOOT".into(),
+                    Entry::Dir(BTreeMap::from([(
+                        "BOOTX64.EFI".into(),
+                        file(b"not really an efi binary"),
+                    )])),
+                )])),
+            ),
+            (
+                "loader".into(),
+                Entry::Dir(BTreeMap::from([
+                    (
+                        "entries".into(),
+                        Entry::Dir(BTreeMap::from([("A Long Name.conf".into(), file(&big))])),
+                    ),
+                    ("loader.conf".into(), file(b"timeout 3\n")),
+                ])),
+            ),
+            ("Mixed.Txt".into(), file(b"mixed")),
+            ("empty".into(), file(b"")),
+            (
+                "many".into(),
+                Entry::Dir(
+                    (0..200)
+                        .map(|i| {
+                            (
+                                format!("file number {i} with a long name"),
+                                file(i.to_string().as_bytes()),
+                            )
+                        })
+                        .collect(),
+                ),
+            ),
+            ("link".into(), file(b"timeout 3\n")),
+        ]);
+        (dir, expected)
+    }
+
+    #[test]
+    fn roundtrip() {
+        let (layer, expected) = layer();
+        for (size_mb, fat_size, fat_type) in [
+            (4, None, FatType::Fat12),
+            (64, None, FatType::Fat16),
+            (600, None, FatType::Fat32),
+            (64, Some(12), FatType::Fat12),
+        ] {
+            let reader = build(layer.path(), size_mb, fat_size, None).expect("failed to build");
+            assert_eq!(reader.fat_type, fat_type);
+            assert_eq!(reader.root(), expected, "{size_mb}MB {fat_type:?}");
+        }
+    }
+
+    #[test]
+    fn label() {
+        let (layer, mut expected) = layer();
+        expected.insert("label:ESP".into(), Entry::File(Vec::new()));
+        let reader = build(layer.path(), 4, None, Some("esp")).expect("failed to build");
+        assert_eq!(&reader.data[43..54], b"ESP        ");
+        assert_eq!(reader.root(), expected);
+        assert!(build(layer.path(), 4, None, Some("much too long")).is_err());
+    }
+
+    #[test]
+    fn too_small() {
+        let (layer, _) = layer();
+        assert!(build(layer.path(), 4, Some(16), None).is_err());
+        std::fs::write(layer.path().join("big"), vec![0; 2 * 1024 * 1024]).unwrap();
+        assert!(build(layer.path(), 1, None, None).is_err());
+    }
+
+    #[test]
+    fn case_insensitive_conflict() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        std::fs::write(dir.path().join("foo"), b"").unwrap();
+        std::fs::write(dir.path().join("FOO"), b"").unwrap();
+        assert!(build(dir.path(), 4, None, None).is_err());
+    }
+
+    #[test]
+    fn symlink_loops() {
+        for (link, target) in [("dir/loop", ".."), ("dir/loop", "/"), ("dir/self", ".")] {
+            let dir = tempfile::tempdir().expect("failed to create tempdir");
+            std::fs::create_dir(dir.path().join("dir")).unwrap();
+            std::os::unix::fs::symlink(target, dir.path().join(link)).unwrap();
+            let err = Node::from_layer(dir.path()).expect_err("symlink loop was allowed");
+            assert!(
+                err.to_string().contains("parent directories"),
+                "{link} -> {target}: {err:#}"
+            );
+        }
+
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        std::os::unix::fs::symlink("b", dir.path().join("a")).unwrap();
+        std::os::unix::fs::symlink("a", dir.path().join("b")).unwrap();
+        let err = Node::from_layer(dir.path()).expect_err("symlink loop was allowed");
+        assert!(err.to_string().contains("symlink loop"), "{err:#}");
+
+        // the same directory can still appear more than once, as long as it
+        // is not inside itself
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        std::fs::create_dir(dir.path().join("dir")).unwrap();
+        std::fs::write(dir.path().join("dir/file"), b"hello").unwrap();
+        std::os::unix::fs::symlink("dir", dir.path().join("alias")).unwrap();
+        let root = build(dir.path(), 4, None, None)
+            .expect("failed to build")
+            .root();
+        assert_eq!(root["alias"], root["dir"]);
+    }
+
+    #[test]
+    fn short_names() {
+        assert_eq!(exact_short_name("BOOTX64.EFI"), Some(*b"BOOTX64 EFI"));
+        assert_eq!(exact_short_name("efi"), Some(*b"EFI        "));
+        assert_eq!(exact_short_name("toolongname"), None);
+        assert_eq!(exact_short_name("a.b.c"), None);
+        assert_eq!(exact_short_name(".hidden"), None);
+        assert_eq!(case_flags("efi"), Some(CASE_LOWER_BASE));
+        assert_eq!(case_flags("BOOT.efi"), Some(CASE_LOWER_EXT));
+        assert_eq!(case_flags("Mixed"), None);
+        let mut taken = HashSet::new();
+        let short = generate_short_name("A Long Name.conf", &taken).unwrap();
+        assert_eq!(&short, b"ALONGN~1CON");
+        taken.insert(short);
+        assert_eq!(
+            &generate_short_name("A Long Name.conf", &taken).unwrap(),
+            b"ALONGN~2CON"
+        );
+        assert_eq!(
+            &generate_short_name(".hidden", &taken).unwrap(),
+            b"HIDDEN~1   "
+        );
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(fat_datetime(0), (0x21, 0));
+        // 2023-11-14 22:13:20 UTC
+        assert_eq!(
+            fat_datetime(1700000000),
+            ((43 << 9) | (11 << 5) | 14, (22 << 11) | (13 << 5) | 10)
+        );
     }
 }
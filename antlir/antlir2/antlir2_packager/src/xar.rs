@@ -14,11 +14,12 @@ use std::io::Write;
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::path::PathBuf;
-use std::time::SystemTime;
 
 use anyhow::Result;
 use serde::Deserialize;
-use uuid::Uuid;
+
+use crate::reproducible;
+use crate::reproducible::Digest;
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -47,10 +48,10 @@ impl Xar {
             exit 1\n\
             # Actual squashfs file begins at {SQUASHFS_OFFSET}",
             executable = self.executable.display(),
-            uuid = &Uuid::new_v4().to_string()[..8],
-            timestamp = SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)?
-                .as_secs(),
+            // xarexec_fuse mounts each uuid+version at most once, so these
+            // only need to change when the contents do
+            uuid = &Digest::of_files([&self.squashfs])?.uuid("xar").to_string()[..8],
+            timestamp = reproducible::source_date_epoch()?,
             target_name = self.target_name,
         )?;
         out.seek(SeekFrom::Start(SQUASHFS_OFFSET))?;
@@ -0,0 +1,1206 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Reader for erofs images.
+//!
+//! See https://erofs.docs.kernel.org/en/latest/core_ondisk.html for a
+//! description of the on-disk format. Every data layout is supported, but
+//! compressed files can only use lz4, deflate or zstd (not microlzma) and
+//! must not be stored in fragments of the packed inode.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::FileExt;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
+use flate2::read::DeflateDecoder;
+use nix::sys::stat::SFlag;
+use nix::sys::time::TimeSpec;
+
+use super::decode_dev;
+use super::lz4;
+use super::simple_kind;
+use super::Image;
+use super::Kind;
+use super::Metadata;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_LEN: usize = 128;
+const MAGIC: u32 = 0xe0f5e1e2;
+/// Inodes are addressed in units of this many bytes from the start of the
+/// metadata area
+const NID_SLOT: u64 = 32;
+const COMPACT_INODE_LEN: usize = 32;
+const EXTENDED_INODE_LEN: usize = 64;
+const DIRENT_LEN: usize = 12;
+const XATTR_IBODY_HEADER_LEN: usize = 12;
+const XATTR_ENTRY_LEN: usize = 4;
+const XATTR_LONG_PREFIX: u8 = 0x80;
+/// Every incompat feature that only concerns compressed or chunked files (or
+/// xattrs), the parts of which that are not supported are refused when they
+/// are encountered
+const KNOWN_INCOMPAT_FEATURES: u32 = 0x7f;
+/// Compressed data is padded with zeros before it (instead of after), which
+/// is optional for lz4 and always the case for everything else
+const FEATURE_ZERO_PADDING: u32 = 0x1;
+/// Longest symlink target that Linux allows
+const PATH_MAX: u64 = 4096;
+
+const LAYOUT_FLAT_PLAIN: u16 = 0;
+const LAYOUT_COMPRESSED_FULL: u16 = 1;
+const LAYOUT_FLAT_INLINE: u16 = 2;
+const LAYOUT_COMPRESSED_COMPACT: u16 = 3;
+const LAYOUT_CHUNK_BASED: u16 = 4;
+
+/// Chunk-based files store the chunk size in the low bits of i_u
+const CHUNK_FORMAT_BLKBITS_MASK: u32 = 0x1f;
+/// Chunk-based files have 8-byte indexes instead of plain block addresses
+const CHUNK_FORMAT_INDEXES: u32 = 0x20;
+const NULL_ADDR: u32 = u32::MAX;
+
+/// Compressed files start with a map header (right after their xattrs,
+/// aligned to 8 bytes) that is followed by an index of every logical cluster
+const MAP_HEADER_LEN: u64 = 8;
+const FULL_INDEX_LEN: u64 = 8;
+const ADVISE_COMPACTED_2B: u16 = 0x1;
+const ADVISE_BIG_PCLUSTER_1: u16 = 0x2;
+const ADVISE_BIG_PCLUSTER_2: u16 = 0x4;
+const ADVISE_INLINE_PCLUSTER: u16 = 0x8;
+const ADVISE_INTERLACED_PCLUSTER: u16 = 0x10;
+const ADVISE_FRAGMENT_PCLUSTER: u16 = 0x20;
+/// Set in h_clusterbits if the whole file is in the packed inode
+const FRAGMENT_INODE_BIT: u8 = 0x80;
+/// Set in delta[0] of the first non-head logical cluster of a big physical
+/// cluster, in which case the rest is its length in blocks
+const D0_CBLKCNT: u32 = 1 << 11;
+
+const LCLUSTER_PLAIN: u8 = 0;
+const LCLUSTER_HEAD1: u8 = 1;
+const LCLUSTER_NONHEAD: u8 = 2;
+const LCLUSTER_HEAD2: u8 = 3;
+
+const ALGORITHM_LZ4: u8 = 0;
+const ALGORITHM_LZMA: u8 = 1;
+const ALGORITHM_DEFLATE: u8 = 2;
+const ALGORITHM_ZSTD: u8 = 3;
+
+pub(super) struct Erofs {
+    file: File,
+    block_size: u64,
+    features: u32,
+    meta_start: u64,
+    xattr_start: u64,
+    root_nid: u64,
+    build_time: u64,
+    build_time_nsec: u32,
+}
+
+pub(super) struct Inode {
+    meta: Metadata,
+    nid: u64,
+    layout: u16,
+    size: u64,
+    /// Start block of the data
+    raw_blkaddr: u32,
+    /// Where the (inline) data starts after the inode and its xattrs
+    inline_pos: u64,
+}
+
+impl AsRef<Metadata> for Inode {
+    fn as_ref(&self) -> &Metadata {
+        &self.meta
+    }
+}
+
+impl Erofs {
+    pub(super) fn open(file: File) -> Result<Self> {
+        let mut sb = [0; SUPERBLOCK_LEN];
+        file.read_exact_at(&mut sb, SUPERBLOCK_OFFSET)
+            .context("while reading superblock")?;
+        ensure!(le32(&sb, 0) == MAGIC, "not an erofs image");
+        let blkszbits = sb[12];
+        ensure!(
+            (9..=16).contains(&blkszbits),
+            "invalid block size bits {blkszbits}"
+        );
+        let block_size = 1 << blkszbits;
+        let features = le32(&sb, 80);
+        ensure!(
+            features & !KNOWN_INCOMPAT_FEATURES == 0,
+            "erofs image uses unsupported features {:#x}",
+            features & !KNOWN_INCOMPAT_FEATURES
+        );
+        ensure!(
+            sb[90] == 0,
+            "erofs image has unsupported directory block size"
+        );
+        ensure!(
+            le16(&sb, 86) == 0,
+            "erofs images with extra devices are not supported"
+        );
+        Ok(Self {
+            file,
+            block_size,
+            features,
+            root_nid: le16(&sb, 14).into(),
+            build_time: le64(&sb, 24),
+            build_time_nsec: le32(&sb, 32),
+            meta_start: u64::from(le32(&sb, 40)) * block_size,
+            xattr_start: u64::from(le32(&sb, 44)) * block_size,
+        })
+    }
+
+    fn read_at(&self, len: usize, pos: u64) -> Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        self.file
+            .read_exact_at(&mut buf, pos)
+            .with_context(|| format!("while reading {len} bytes at {pos}"))?;
+        Ok(buf)
+    }
+
+    fn inode(&self, nid: u64) -> Result<Inode> {
+        let pos = self.meta_start + nid * NID_SLOT;
+        let mut buf = self.read_at(COMPACT_INODE_LEN, pos)?;
+        let format = le16(&buf, 0);
+        let extended = format & 1 == 1;
+        let layout = (format >> 1) & 0x7;
+        if extended {
+            buf = self.read_at(EXTENDED_INODE_LEN, pos)?;
+        }
+        let xattr_icount = le16(&buf, 2);
+        let mode = le16(&buf, 4).into();
+        let raw_blkaddr = le32(&buf, 16);
+        let (size, uid, gid, mtime, nlink) = if extended {
+            (
+                le64(&buf, 8),
+                le32(&buf, 24),
+                le32(&buf, 28),
+                TimeSpec::new(le64(&buf, 32) as i64, le32(&buf, 40).into()),
+                le32(&buf, 44),
+            )
+        } else {
+            (
+                le32(&buf, 8).into(),
+                le16(&buf, 24).into(),
+                le16(&buf, 26).into(),
+                // compact inodes don't have their own timestamp, just an
+                // optional offset from the build time
+                TimeSpec::new(
+                    (self.build_time + u64::from(le32(&buf, 12))) as i64,
+                    self.build_time_nsec.into(),
+                ),
+                le16(&buf, 6).into(),
+            )
+        };
+        let xattr_len = match xattr_icount {
+            0 => 0,
+            n => XATTR_IBODY_HEADER_LEN + (usize::from(n) - 1) * XATTR_ENTRY_LEN,
+        };
+        let xattr_pos = pos + buf.len() as u64;
+        let mut inode = Inode {
+            meta: Metadata {
+                ino: nid,
+                kind: Kind::Dir,
+                perm: mode & 0o7777,
+                uid,
+                gid,
+                mtime,
+                nlink,
+                xattrs: self
+                    .xattrs(xattr_pos, xattr_len)
+                    .context("while reading xattrs")?,
+            },
+            nid,
+            layout,
+            size,
+            raw_blkaddr,
+            inline_pos: xattr_pos + xattr_len as u64,
+        };
+        let file_type = mode & SFlag::S_IFMT.bits();
+        inode.meta.kind = if file_type == SFlag::S_IFLNK.bits() {
+            ensure!(size <= PATH_MAX, "symlink target is too long");
+            Kind::Symlink(OsString::from_vec(self.read_data(&inode)?))
+        } else if file_type == SFlag::S_IFCHR.bits() {
+            Kind::Device(SFlag::S_IFCHR, decode_dev(raw_blkaddr))
+        } else if file_type == SFlag::S_IFBLK.bits() {
+            Kind::Device(SFlag::S_IFBLK, decode_dev(raw_blkaddr))
+        } else {
+            simple_kind(mode)?
+        };
+        Ok(inode)
+    }
+
+    fn xattrs(&self, pos: u64, len: usize) -> Result<Vec<(OsString, Vec<u8>)>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let buf = self.read_at(len, pos)?;
+        let shared_count = usize::from(buf[4]);
+        let mut offset = XATTR_IBODY_HEADER_LEN + shared_count * XATTR_ENTRY_LEN;
+        ensure!(offset <= len, "too many shared xattrs");
+        let mut xattrs = Vec::new();
+        // shared xattrs are stored once and referred to by every inode that
+        // has the same one
+        for i in 0..shared_count {
+            let id = le32(&buf, XATTR_IBODY_HEADER_LEN + i * XATTR_ENTRY_LEN);
+            let entry_pos = self.xattr_start + u64::from(id) * XATTR_ENTRY_LEN as u64;
+            let header = self.read_at(XATTR_ENTRY_LEN, entry_pos)?;
+            let entry_len = xattr_entry_len(&header);
+            let entry = self.read_at(entry_len, entry_pos)?;
+            xattrs.push(parse_xattr(&entry)?);
+        }
+        while offset + XATTR_ENTRY_LEN <= len {
+            let entry_len = xattr_entry_len(&buf[offset..]);
+            let entry = buf
+                .get(offset..offset + entry_len)
+                .context("xattr entry overruns the inode")?;
+            xattrs.push(parse_xattr(entry)?);
+            // entries are padded to 4 bytes
+            offset += entry_len.next_multiple_of(XATTR_ENTRY_LEN);
+        }
+        Ok(xattrs)
+    }
+
+    /// Read all the data of a (small) inode into memory
+    fn read_data(&self, inode: &Inode) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        for extent in self.data_extents(inode)? {
+            match extent {
+                Extent::Raw { pos, len } => data.extend(self.read_at(len as usize, pos)?),
+                Extent::Hole { len } => data.resize(data.len() + len as usize, 0),
+                Extent::Encoded(encoded) => data.extend(self.decode(&encoded)?),
+            }
+        }
+        Ok(data)
+    }
+
+    /// Where each contiguous piece of an inode's data comes from
+    fn data_extents(&self, inode: &Inode) -> Result<Vec<Extent>> {
+        let start = u64::from(inode.raw_blkaddr) * self.block_size;
+        match inode.layout {
+            LAYOUT_FLAT_PLAIN => Ok(vec![Extent::Raw {
+                pos: start,
+                len: inode.size,
+            }]),
+            // all the full blocks are stored normally, and the rest is
+            // right after the inode
+            LAYOUT_FLAT_INLINE => {
+                let tail = inode.size % self.block_size;
+                ensure!(
+                    (inode.inline_pos % self.block_size) + tail <= self.block_size,
+                    "inline data of nid {} crosses a block boundary",
+                    inode.nid
+                );
+                Ok(vec![
+                    Extent::Raw {
+                        pos: start,
+                        len: inode.size - tail,
+                    },
+                    Extent::Raw {
+                        pos: inode.inline_pos,
+                        len: tail,
+                    },
+                ])
+            }
+            LAYOUT_COMPRESSED_FULL | LAYOUT_COMPRESSED_COMPACT => self
+                .compressed_extents(inode)
+                .with_context(|| format!("while mapping compressed nid {}", inode.nid)),
+            LAYOUT_CHUNK_BASED => self
+                .chunk_extents(inode)
+                .with_context(|| format!("while mapping chunks of nid {}", inode.nid)),
+            layout => bail!("unknown data layout {layout} (nid {})", inode.nid),
+        }
+    }
+
+    /// Chunk-based files are split into equally sized chunks that are each
+    /// either stored in consecutive blocks or not at all
+    fn chunk_extents(&self, inode: &Inode) -> Result<Vec<Extent>> {
+        let format = inode.raw_blkaddr & 0xffff;
+        let chunk_size = self.block_size << (format & CHUNK_FORMAT_BLKBITS_MASK);
+        let unit = if format & CHUNK_FORMAT_INDEXES != 0 {
+            8
+        } else {
+            4
+        };
+        let count = inode.size.div_ceil(chunk_size);
+        let table = self.read_at(
+            (count * unit) as usize,
+            inode.inline_pos.next_multiple_of(unit),
+        )?;
+        let mut extents = Vec::new();
+        for (i, entry) in table.chunks(unit as usize).enumerate() {
+            // the block address is at the end of an index, after the advise
+            // and device id (which is meaningless without extra devices)
+            let blkaddr = le32(entry, entry.len() - 4);
+            let len = std::cmp::min(chunk_size, inode.size - i as u64 * chunk_size);
+            extents.push(if blkaddr == NULL_ADDR {
+                Extent::Hole { len }
+            } else {
+                Extent::Raw {
+                    pos: u64::from(blkaddr) * self.block_size,
+                    len,
+                }
+            });
+        }
+        Ok(extents)
+    }
+
+    /// Compressed files are made of logical clusters (of a fixed size), each
+    /// of which is indexed. An extent starts in every "head" cluster, and
+    /// its data is in a physical cluster that is decoded into all of the
+    /// extent (and possibly more, which is discarded).
+    fn compressed_extents(&self, inode: &Inode) -> Result<Vec<Extent>> {
+        let header_pos = inode.inline_pos.next_multiple_of(8);
+        let header = self.read_at(MAP_HEADER_LEN as usize, header_pos)?;
+        let advise = le16(&header, 4);
+        ensure!(
+            header[7] & FRAGMENT_INODE_BIT == 0 && advise & ADVISE_FRAGMENT_PCLUSTER == 0,
+            "erofs files stored in fragments are not supported"
+        );
+        let lclusterbits = self.block_size.trailing_zeros() + u32::from(header[7] & 0x7);
+        let compact = inode.layout == LAYOUT_COMPRESSED_COMPACT;
+        let count = inode.size.div_ceil(1 << lclusterbits);
+        let index = if compact {
+            let base = header_pos + MAP_HEADER_LEN;
+            // 4-byte indexes until the 2-byte ones are aligned to 32 bytes
+            let initial = ((32 - base % 32) / 4) % 8;
+            let two_byte = if advise & ADVISE_COMPACTED_2B != 0 && initial < count {
+                (count - initial) / 16 * 16
+            } else {
+                0
+            };
+            ensure!(
+                two_byte == 0 || lclusterbits == 12,
+                "2-byte compact indexes need 4k logical clusters"
+            );
+            ensure!(
+                lclusterbits <= 14,
+                "4-byte compact indexes need logical clusters of at most 16k"
+            );
+            Index::Compact {
+                base,
+                initial,
+                two_byte,
+            }
+        } else {
+            // the first full index is reserved
+            Index::Full {
+                base: header_pos + MAP_HEADER_LEN + FULL_INDEX_LEN,
+            }
+        };
+        let map = ZMap {
+            advise,
+            algorithms: [header[6] & 0xf, header[6] >> 4],
+            lclusterbits,
+            index,
+        };
+
+        let lclusters = (0..count)
+            .map(|lcn| self.lcluster(&map, lcn))
+            .collect::<Result<Vec<_>>>()?;
+        // (lcn, logical address) of every head
+        let heads: Vec<(usize, u64)> = lclusters
+            .iter()
+            .enumerate()
+            .filter(|(_, lcluster)| lcluster.ty != LCLUSTER_NONHEAD)
+            .map(|(lcn, lcluster)| (lcn, ((lcn as u64) << lclusterbits) + lcluster.clusterofs))
+            .filter(|(_, la)| *la < inode.size)
+            .collect();
+        ensure!(
+            heads.first().is_some_and(|(_, la)| *la == 0) || inode.size == 0,
+            "first logical cluster is not a head"
+        );
+
+        let mut extents = Vec::new();
+        for (i, (lcn, la)) in heads.iter().enumerate() {
+            let lcluster = &lclusters[*lcn];
+            let end = heads.get(i + 1).map_or(inode.size, |(_, next)| *next);
+            let big = match lcluster.ty {
+                LCLUSTER_HEAD1 => advise & ADVISE_BIG_PCLUSTER_1 != 0,
+                LCLUSTER_HEAD2 => advise & ADVISE_BIG_PCLUSTER_2 != 0,
+                _ => false,
+            };
+            let (pos, plen) = if i + 1 == heads.len() && advise & ADVISE_INLINE_PCLUSTER != 0 {
+                // the last physical cluster is packed right after the
+                // indexes
+                let last = (inode.size - 1) >> lclusterbits;
+                (map.index.next_pack(last), u64::from(le16(&header, 2)))
+            } else if big {
+                // the next logical cluster records how many blocks it is,
+                // unless it's only one logical cluster
+                let blocks = match lclusters.get(lcn + 1) {
+                    Some(next) if next.ty == LCLUSTER_NONHEAD => next
+                        .compressed_blocks
+                        .context("big physical cluster has no block count")?,
+                    _ => 1 << (lclusterbits - self.block_size.trailing_zeros()),
+                };
+                (lcluster.pblk * self.block_size, blocks * self.block_size)
+            } else {
+                (lcluster.pblk * self.block_size, 1 << lclusterbits)
+            };
+            let format = match lcluster.ty {
+                LCLUSTER_PLAIN if advise & ADVISE_INTERLACED_PCLUSTER != 0 => Format::Interlaced,
+                LCLUSTER_PLAIN => Format::Shifted,
+                ty => match map.algorithms[usize::from(ty == LCLUSTER_HEAD2)] {
+                    ALGORITHM_LZ4 => Format::Lz4,
+                    ALGORITHM_DEFLATE => Format::Deflate,
+                    ALGORITHM_ZSTD => Format::Zstd,
+                    ALGORITHM_LZMA => bail!("erofs files compressed with lzma are not supported"),
+                    algorithm => bail!("unknown compression algorithm {algorithm}"),
+                },
+            };
+            extents.push(Extent::Encoded(Encoded {
+                format,
+                pos,
+                plen,
+                la: *la,
+                len: end - la,
+            }));
+        }
+        Ok(extents)
+    }
+
+    /// Read the index of logical cluster `lcn`
+    fn lcluster(&self, map: &ZMap, lcn: u64) -> Result<Lcluster> {
+        let lcluster_size = 1 << map.lclusterbits;
+        match map.index {
+            Index::Full { base } => {
+                let buf = self.read_at(FULL_INDEX_LEN as usize, base + lcn * FULL_INDEX_LEN)?;
+                let ty = (le16(&buf, 0) & 0x3) as u8;
+                if ty == LCLUSTER_NONHEAD {
+                    let delta0 = u32::from(le16(&buf, 4));
+                    Ok(Lcluster {
+                        ty,
+                        clusterofs: lcluster_size,
+                        pblk: 0,
+                        compressed_blocks: (delta0 & D0_CBLKCNT != 0)
+                            .then_some(u64::from(delta0 & !D0_CBLKCNT)),
+                    })
+                } else {
+                    let clusterofs = u64::from(le16(&buf, 2));
+                    ensure!(
+                        clusterofs < lcluster_size,
+                        "invalid cluster offset {clusterofs} in lcluster {lcn}"
+                    );
+                    Ok(Lcluster {
+                        ty,
+                        clusterofs,
+                        pblk: u64::from(le32(&buf, 4)),
+                        compressed_blocks: None,
+                    })
+                }
+            }
+            Index::Compact { .. } => {
+                let (pos, shift) = map.index.compact_pos(lcn);
+                let vcnt: u64 = if shift == 2 { 2 } else { 16 };
+                let pack_len = vcnt << shift;
+                let pack_start = pos / pack_len * pack_len;
+                let pack = self.read_at(pack_len as usize, pack_start)?;
+                let encodebits = (pack_len - 4) * 8 / vcnt;
+                let lobits = std::cmp::max(map.lclusterbits, D0_CBLKCNT.trailing_zeros() + 1);
+                let decode = |i: u64| -> (u32, u8) {
+                    let bit = encodebits * i;
+                    let v = le32(&pack, (bit / 8) as usize) >> (bit % 8);
+                    (v & ((1 << lobits) - 1), ((v >> lobits) & 0x3) as u8)
+                };
+                let mut i = (pos - pack_start) >> shift;
+                let (lo, ty) = decode(i);
+                if ty == LCLUSTER_NONHEAD {
+                    return Ok(Lcluster {
+                        ty,
+                        clusterofs: lcluster_size,
+                        pblk: 0,
+                        compressed_blocks: (lo & D0_CBLKCNT != 0)
+                            .then_some(u64::from(lo & !D0_CBLKCNT)),
+                    });
+                }
+                // the pack only records the block of its first physical
+                // cluster, so count the blocks of every one before this
+                let big = map.advise & ADVISE_BIG_PCLUSTER_1 != 0;
+                let mut blocks: u64 = if big { 0 } else { 1 };
+                while i > 0 {
+                    i -= 1;
+                    let (lo, ty) = decode(i);
+                    if ty != LCLUSTER_NONHEAD {
+                        blocks += 1;
+                    } else if !big {
+                        // skip back to this cluster's head
+                        match i.checked_sub(u64::from(lo)) {
+                            Some(head) => {
+                                blocks += 1;
+                                i = head;
+                            }
+                            None => break,
+                        }
+                    } else if lo & D0_CBLKCNT != 0 {
+                        // the head before this is that many blocks
+                        blocks += u64::from(lo & !D0_CBLKCNT);
+                        i = i.saturating_sub(1);
+                    } else {
+                        ensure!(lo > 1, "corrupt compact index of lcluster {lcn}");
+                        // skip back to the block count right after the head
+                        i = (i + 2).saturating_sub(u64::from(lo));
+                    }
+                }
+                Ok(Lcluster {
+                    ty,
+                    clusterofs: u64::from(lo),
+                    pblk: u64::from(le32(&pack, (pack_len - 4) as usize)) + blocks,
+                    compressed_blocks: None,
+                })
+            }
+        }
+    }
+
+    /// Decode a physical cluster into the `len` bytes of its extent
+    fn decode(&self, encoded: &Encoded) -> Result<Vec<u8>> {
+        let input = self.read_at(encoded.plen as usize, encoded.pos)?;
+        let len = encoded.len as usize;
+        let padded = match encoded.format {
+            Format::Shifted | Format::Interlaced => false,
+            Format::Lz4 => self.features & FEATURE_ZERO_PADDING != 0,
+            Format::Deflate | Format::Zstd => true,
+        };
+        let input = if padded {
+            // the padding is never more than a block
+            let margin = input
+                .iter()
+                .take(self.block_size as usize)
+                .position(|b| *b != 0)
+                .context("physical cluster is all padding")?;
+            &input[margin..]
+        } else {
+            &input[..]
+        };
+        let mut out = Vec::with_capacity(len);
+        match encoded.format {
+            Format::Shifted => out.extend_from_slice(
+                input
+                    .get(..len)
+                    .context("extent is longer than its physical cluster")?,
+            ),
+            // uncompressed data is rotated so that it is at the same offset
+            // in the physical cluster as in its block of the file
+            Format::Interlaced => {
+                ensure!(
+                    len as u64 <= encoded.plen,
+                    "extent is longer than its physical cluster"
+                );
+                let shift = (encoded.la % self.block_size) as usize;
+                out.extend((0..len).map(|i| input[(shift + i) % input.len()]));
+            }
+            Format::Lz4 => {
+                out = lz4::decompress(input, len).context("while decompressing lz4")?;
+            }
+            Format::Deflate => {
+                DeflateDecoder::new(input)
+                    .take(len as u64)
+                    .read_to_end(&mut out)
+                    .context("while decompressing deflate")?;
+            }
+            Format::Zstd => {
+                zstd::stream::read::Decoder::with_buffer(input)
+                    .context("while decompressing zstd")?
+                    .take(len as u64)
+                    .read_to_end(&mut out)
+                    .context("while decompressing zstd")?;
+            }
+        }
+        ensure!(
+            out.len() == len,
+            "physical cluster at {} decoded to {} bytes instead of {len}",
+            encoded.pos,
+            out.len()
+        );
+        Ok(out)
+    }
+}
+
+/// Where a contiguous piece of an inode's data comes from
+enum Extent {
+    /// Stored as-is
+    Raw {
+        pos: u64,
+        len: u64,
+    },
+    /// Not stored at all (it's all zeros)
+    Hole {
+        len: u64,
+    },
+    Encoded(Encoded),
+}
+
+/// A piece of a compressed file, which has to be decoded from its physical
+/// cluster
+struct Encoded {
+    format: Format,
+    /// Position and length of the physical cluster
+    pos: u64,
+    plen: u64,
+    /// Logical address and length of the extent
+    la: u64,
+    len: u64,
+}
+
+enum Format {
+    /// Uncompressed
+    Shifted,
+    /// Uncompressed, but rotated by the offset of the extent in its block
+    Interlaced,
+    Lz4,
+    Deflate,
+    Zstd,
+}
+
+/// How the logical clusters of a compressed inode are mapped
+struct ZMap {
+    advise: u16,
+    /// Algorithms of HEAD1 and HEAD2 clusters
+    algorithms: [u8; 2],
+    lclusterbits: u32,
+    index: Index,
+}
+
+enum Index {
+    /// 8 bytes per logical cluster, starting at `base`
+    Full { base: u64 },
+    /// Packs of 4-byte indexes, then 2-byte ones, then 4-byte ones again
+    Compact {
+        base: u64,
+        /// Number of initial 4-byte indexes
+        initial: u64,
+        /// Number of 2-byte indexes
+        two_byte: u64,
+    },
+}
+
+impl Index {
+    /// Position of the index of a compacted lcluster, and log2 of the
+    /// (amortized) size of its index
+    fn compact_pos(&self, mut lcn: u64) -> (u64, u64) {
+        let Self::Compact {
+            base,
+            initial,
+            two_byte,
+        } = *self
+        else {
+            unreachable!("only compact indexes are packed");
+        };
+        let mut pos = base;
+        if lcn < initial {
+            return (pos + lcn * 4, 2);
+        }
+        pos += initial * 4;
+        lcn -= initial;
+        if lcn < two_byte {
+            return (pos + lcn * 2, 1);
+        }
+        pos += two_byte * 2;
+        lcn -= two_byte;
+        (pos + lcn * 4, 2)
+    }
+
+    /// Where the pack with the index of `lcn` ends, which is where the tail
+    /// physical cluster is when it is inline
+    fn next_pack(&self, lcn: u64) -> u64 {
+        match self {
+            Self::Full { base } => base + (lcn + 1) * FULL_INDEX_LEN,
+            Self::Compact { .. } => {
+                let (pos, shift) = self.compact_pos(lcn);
+                let pack_len = if shift == 2 { 8 } else { 32 };
+                pos / pack_len * pack_len + pack_len
+            }
+        }
+    }
+}
+
+/// What is known about a single logical cluster from its index
+struct Lcluster {
+    ty: u8,
+    /// Where in this logical cluster the extent of a head starts
+    clusterofs: u64,
+    /// Block of the physical cluster of a head
+    pblk: u64,
+    /// Size of a big physical cluster, recorded in the first non-head
+    /// cluster after its head
+    compressed_blocks: Option<u64>,
+}
+
+impl Image for Erofs {
+    type Inode = Inode;
+
+    fn root(&self) -> Result<Inode> {
+        self.inode(self.root_nid)
+            .context("while reading root inode")
+    }
+
+    fn read_dir(&self, dir: &Inode) -> Result<Vec<(Vec<u8>, Inode)>> {
+        ensure!(dir.meta.kind == Kind::Dir, "not a directory");
+        let data = self.read_data(dir)?;
+        let mut entries = Vec::new();
+        // directories are made of blocks that each start with an array of
+        // dirents, followed by all of their names
+        for block in data.chunks(self.block_size as usize) {
+            let dirent = |i: usize| -> Result<(u64, usize)> {
+                let dirent = block
+                    .get(i * DIRENT_LEN..(i + 1) * DIRENT_LEN)
+                    .context("dirent overruns the directory block")?;
+                Ok((le64(dirent, 0), le16(dirent, 8).into()))
+            };
+            let (_, first_name) = dirent(0)?;
+            ensure!(
+                first_name % DIRENT_LEN == 0 && first_name > 0,
+                "corrupt directory block"
+            );
+            let count = first_name / DIRENT_LEN;
+            for i in 0..count {
+                let (nid, start) = dirent(i)?;
+                let end = if i + 1 < count {
+                    dirent(i + 1)?.1
+                } else {
+                    block.len()
+                };
+                let mut name = block
+                    .get(start..end)
+                    .context("dirent name overruns the directory block")?;
+                // the last name in a block is padded with NULs
+                if i + 1 == count {
+                    if let Some(nul) = name.iter().position(|b| *b == 0) {
+                        name = &name[..nul];
+                    }
+                }
+                if name == b"." || name == b".." {
+                    continue;
+                }
+                let inode = self.inode(nid).with_context(|| {
+                    format!("while reading inode of {}", String::from_utf8_lossy(name))
+                })?;
+                entries.push((name.to_vec(), inode));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn write_contents(&self, file: &Inode, dst: &File) -> Result<()> {
+        // copy in chunks, since files may be arbitrarily large
+        const CHUNK: u64 = 1024 * 1024;
+        let mut offset = 0;
+        for extent in self.data_extents(file)? {
+            match extent {
+                Extent::Raw { mut pos, mut len } => {
+                    while len > 0 {
+                        let n = std::cmp::min(len, CHUNK);
+                        let data = self.read_at(n as usize, pos)?;
+                        dst.write_all_at(&data, offset)
+                            .context("while writing data")?;
+                        pos += n;
+                        len -= n;
+                        offset += n;
+                    }
+                }
+                Extent::Hole { len } => offset += len,
+                Extent::Encoded(encoded) => {
+                    let data = self.decode(&encoded)?;
+                    dst.write_all_at(&data, offset)
+                        .context("while writing data")?;
+                    offset += encoded.len;
+                }
+            }
+        }
+        // holes at the end are not written
+        dst.set_len(file.size).context("while setting file size")?;
+        Ok(())
+    }
+}
+
+/// Length of an xattr entry (without padding) from its header
+fn xattr_entry_len(header: &[u8]) -> usize {
+    XATTR_ENTRY_LEN + usize::from(header[0]) + usize::from(le16(header, 2))
+}
+
+fn parse_xattr(entry: &[u8]) -> Result<(OsString, Vec<u8>)> {
+    let name_len = usize::from(entry[0]);
+    let index = entry[1];
+    let value_start = XATTR_ENTRY_LEN + name_len;
+    let prefix: &[u8] = match index {
+        1 => b"user.",
+        2 => b"system.posix_acl_access",
+        3 => b"system.posix_acl_default",
+        4 => b"trusted.",
+        5 => b"lustre.",
+        6 => b"security.",
+        i if i & XATTR_LONG_PREFIX != 0 => {
+            bail!("erofs images with long xattr name prefixes are not supported")
+        }
+        i => bail!("unknown xattr name index {i}"),
+    };
+    let mut name = prefix.to_vec();
+    name.extend_from_slice(&entry[XATTR_ENTRY_LEN..value_start]);
+    Ok((OsString::from_vec(name), entry[value_start..].to_vec()))
+}
+
+fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(
+        buf[offset..offset + 4]
+            .try_into()
+            .expect("slice is 4 bytes"),
+    )
+}
+
+fn le64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(
+        buf[offset..offset + 8]
+            .try_into()
+            .expect("slice is 8 bytes"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::DeflateEncoder;
+
+    use super::super::extract_erofs;
+    use super::super::tests::*;
+    use super::*;
+
+    const BLOCK_SIZE: usize = 4096;
+    const BUILD_TIME: u64 = 100;
+    const META_BLKADDR: usize = 1;
+    const XATTR_BLKADDR: usize = 2;
+    const DATA_BLKADDR: usize = 3;
+
+    fn put(image: &mut [u8], pos: usize, data: &[u8]) {
+        image[pos..pos + data.len()].copy_from_slice(data);
+    }
+
+    /// A 32-byte compact inode, with an mtime relative to the build time
+    fn compact(layout: u16, mode: u32, size: u32, mtime: u32, i_u: u32, owned: bool) -> Vec<u8> {
+        let (uid, gid) = if owned { (42u16, 43u16) } else { (0, 0) };
+        [
+            &[layout << 1, 0, mode as u16, 1]
+                .map(u16::to_le_bytes)
+                .concat()[..],
+            &[size, mtime, i_u, 0].map(u32::to_le_bytes).concat(),
+            &[uid, gid].map(u16::to_le_bytes).concat(),
+            &0u32.to_le_bytes(),
+        ]
+        .concat()
+    }
+
+    fn dirents(entries: &[(u64, &str)]) -> Vec<u8> {
+        let mut dirents = Vec::new();
+        let mut names: Vec<u8> = Vec::new();
+        let mut nameoff = entries.len() * DIRENT_LEN;
+        for (nid, name) in entries {
+            dirents.extend(nid.to_le_bytes());
+            dirents.extend((nameoff as u16).to_le_bytes());
+            dirents.extend([0, 0]);
+            names.extend(name.as_bytes());
+            nameoff += name.len();
+        }
+        dirents.extend(names);
+        dirents
+    }
+
+    /// How /file stores its data
+    #[derive(Debug, Clone, Copy)]
+    enum FileData {
+        /// Uncompressed in consecutive blocks
+        Flat,
+        /// Compact indexes of an lz4 extent and an uncompressed one
+        Compact,
+        /// Full indexes of a big deflate extent and an inline lz4 one
+        Full,
+        /// Chunks of one block, one of which is a hole
+        Chunks,
+    }
+
+    const FILE_DATA: [FileData; 4] = [
+        FileData::Flat,
+        FileData::Compact,
+        FileData::Full,
+        FileData::Chunks,
+    ];
+
+    /// A physical cluster of one block, with `data` at its end
+    fn padded(data: &[u8]) -> Vec<u8> {
+        let mut block = vec![0; BLOCK_SIZE - data.len()];
+        block.extend(data);
+        block
+    }
+
+    /// 4-byte compact indexes of two logical clusters, followed by the block
+    /// that the pack is relative to
+    fn compact_pack(lclusters: [(u8, u16); 2], blkaddr: usize) -> Vec<u8> {
+        let mut pack: Vec<u8> = lclusters
+            .iter()
+            .flat_map(|(ty, lo)| ((u16::from(*ty) << 12) | lo).to_le_bytes())
+            .collect();
+        pack.extend((blkaddr as u32).to_le_bytes());
+        pack
+    }
+
+    /// Full index of a logical cluster
+    fn full_index(ty: u8, clusterofs: u16, u: [u16; 2]) -> Vec<u8> {
+        [u16::from(ty), clusterofs, u[0], u[1]]
+            .map(u16::to_le_bytes)
+            .concat()
+    }
+
+    /// Build an image of the tree that [check_tree] expects, with a mix of
+    /// compact and extended inodes, shared and inline xattrs and plain and
+    /// inline data
+    fn build(data: FileData) -> Vec<u8> {
+        let contents = file_contents();
+        let mut image = vec![0; (DATA_BLKADDR + 4) * BLOCK_SIZE];
+        let mut superblock = vec![0; SUPERBLOCK_LEN];
+        put(&mut superblock, 0, &MAGIC.to_le_bytes());
+        superblock[12] = 12;
+        put(&mut superblock, 24, &BUILD_TIME.to_le_bytes());
+        put(&mut superblock, 40, &(META_BLKADDR as u32).to_le_bytes());
+        put(&mut superblock, 44, &(XATTR_BLKADDR as u32).to_le_bytes());
+        put(&mut superblock, 80, &FEATURE_ZERO_PADDING.to_le_bytes());
+
+        let meta = META_BLKADDR * BLOCK_SIZE;
+        let slot = |nid: u64| meta + (nid * NID_SLOT) as usize;
+
+        // the capability is a shared xattr
+        let mut shared = vec![10, 6];
+        shared.extend((CAPABILITY.len() as u16).to_le_bytes());
+        shared.extend(b"capability");
+        shared.extend(CAPABILITY);
+        put(&mut image, XATTR_BLKADDR * BLOCK_SIZE, &shared);
+
+        // /file is an extended inode, with its data in the first data blocks
+        let file_nid = 0;
+        let (layout, i_u) = match data {
+            FileData::Flat => (LAYOUT_FLAT_PLAIN, DATA_BLKADDR as u32),
+            // the number of compressed blocks, which is only informational
+            FileData::Compact => (LAYOUT_COMPRESSED_COMPACT, 2),
+            FileData::Full => (LAYOUT_COMPRESSED_FULL, 1),
+            // the chunk format: chunks of one block, with plain block
+            // addresses
+            FileData::Chunks => (LAYOUT_CHUNK_BASED, 0),
+        };
+        let mut file = vec![0; EXTENDED_INODE_LEN];
+        put(
+            &mut file,
+            0,
+            &[1 | (layout << 1), 5, 0o104755, 0]
+                .map(u16::to_le_bytes)
+                .concat(),
+        );
+        put(&mut file, 8, &(contents.len() as u64).to_le_bytes());
+        put(
+            &mut file,
+            16,
+            &[i_u, 0, 42, 43].map(u32::to_le_bytes).concat(),
+        );
+        put(&mut file, 32, &300u64.to_le_bytes());
+        put(&mut file, 44, &2u32.to_le_bytes());
+        // xattr header with one shared xattr, followed by an inline one
+        let mut xattrs = vec![0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+        xattrs.extend(0u32.to_le_bytes());
+        xattrs.extend([3, 1, 3, 0]);
+        xattrs.extend(b"foobar");
+        file.extend(xattrs);
+        // anything after the xattrs is aligned within the image
+        let align = |file: &mut Vec<u8>, to: usize| {
+            let end = slot(file_nid) + file.len();
+            file.resize(end.next_multiple_of(to) - slot(file_nid), 0);
+        };
+        let data_pos = DATA_BLKADDR * BLOCK_SIZE;
+        match data {
+            FileData::Flat => put(&mut image, data_pos, &contents),
+            FileData::Compact => {
+                align(&mut file, 8);
+                // map header: lz4 for both kinds of heads
+                file.extend([0; MAP_HEADER_LEN as usize]);
+                // the lz4 extent covers the first two logical clusters (and
+                // the last index of a pack has the distance to the next
+                // head), and then the tail is uncompressed. Packs are
+                // relative to the block before their first head.
+                file.extend(compact_pack(
+                    [(LCLUSTER_HEAD1, 0), (LCLUSTER_NONHEAD, 1)],
+                    DATA_BLKADDR - 1,
+                ));
+                file.extend(compact_pack(
+                    [(LCLUSTER_PLAIN, 0), (LCLUSTER_PLAIN, 0)],
+                    DATA_BLKADDR,
+                ));
+                put(
+                    &mut image,
+                    data_pos,
+                    &padded(&lz4_compress(&contents[..2 * BLOCK_SIZE])),
+                );
+                put(
+                    &mut image,
+                    data_pos + BLOCK_SIZE,
+                    &contents[2 * BLOCK_SIZE..],
+                );
+            }
+            FileData::Full => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(&contents[..2 * BLOCK_SIZE])
+                    .expect("failed to compress");
+                let head = encoder.finish().expect("failed to compress");
+                let tail = lz4_compress(&contents[2 * BLOCK_SIZE..]);
+                align(&mut file, 8);
+                // map header: inline size, advise, deflate for HEAD2 and lz4
+                // for HEAD1
+                file.extend([0, tail.len() as u16].map(u16::to_le_bytes).concat());
+                file.extend((ADVISE_BIG_PCLUSTER_2 | ADVISE_INLINE_PCLUSTER).to_le_bytes());
+                file.extend([(ALGORITHM_DEFLATE << 4) | ALGORITHM_LZ4, 0]);
+                // the first full index is reserved
+                file.extend([0; FULL_INDEX_LEN as usize]);
+                file.extend(full_index(LCLUSTER_HEAD2, 0, [DATA_BLKADDR as u16, 0]));
+                // the big physical cluster is only one block
+                file.extend(full_index(LCLUSTER_NONHEAD, 0, [D0_CBLKCNT as u16 | 1, 1]));
+                file.extend(full_index(LCLUSTER_HEAD1, 0, [0, 0]));
+                file.extend(tail);
+                put(&mut image, data_pos, &padded(&head));
+            }
+            FileData::Chunks => {
+                align(&mut file, 4);
+                file.extend(
+                    [DATA_BLKADDR as u32, NULL_ADDR, DATA_BLKADDR as u32 + 2]
+                        .map(u32::to_le_bytes)
+                        .concat(),
+                );
+                put(&mut image, data_pos, &contents[..BLOCK_SIZE]);
+                put(
+                    &mut image,
+                    data_pos + 2 * BLOCK_SIZE,
+                    &contents[2 * BLOCK_SIZE..],
+                );
+            }
+        }
+        put(&mut image, slot(file_nid), &file);
+
+        // the symlink target is inline
+        // leave room for everything after the /file inode
+        let symlink_nid = 16;
+        let mut symlink = compact(LAYOUT_FLAT_INLINE, 0o120777, 4, 300, 0, true);
+        symlink.extend(b"file");
+        put(&mut image, slot(symlink_nid), &symlink);
+
+        let fifo_nid = 18;
+        put(
+            &mut image,
+            slot(fifo_nid),
+            &compact(LAYOUT_FLAT_PLAIN, 0o10600, 0, 400, 0, false),
+        );
+        let null_nid = 19;
+        put(
+            &mut image,
+            slot(null_nid),
+            &compact(LAYOUT_FLAT_PLAIN, 0o20666, 0, 400, 0x103, false),
+        );
+
+        // /subdir has its dirents inline, / has them in a data block
+        let subdir_nid = 20;
+        let root_nid = 23;
+        let listing = dirents(&[(subdir_nid, "."), (root_nid, ".."), (file_nid, "hardlink")]);
+        let mut subdir = compact(
+            LAYOUT_FLAT_INLINE,
+            0o40700,
+            listing.len() as u32,
+            100,
+            0,
+            true,
+        );
+        subdir.extend(listing);
+        put(&mut image, slot(subdir_nid), &subdir);
+
+        let listing = dirents(&[
+            (root_nid, "."),
+            (root_nid, ".."),
+            (fifo_nid, "fifo"),
+            (file_nid, "file"),
+            (null_nid, "null"),
+            (subdir_nid, "subdir"),
+            (symlink_nid, "symlink"),
+        ]);
+        let root_blkaddr = DATA_BLKADDR + 3;
+        put(
+            &mut image,
+            slot(root_nid),
+            &compact(
+                LAYOUT_FLAT_PLAIN,
+                0o40755,
+                listing.len() as u32,
+                0,
+                root_blkaddr as u32,
+                false,
+            ),
+        );
+        put(&mut image, root_blkaddr * BLOCK_SIZE, &listing);
+
+        put(&mut superblock, 14, &(root_nid as u16).to_le_bytes());
+        put(&mut image, SUPERBLOCK_OFFSET as usize, &superblock);
+        image
+    }
+
+    /// Contents of /file, read without extracting anything
+    fn read_file(image: &[u8]) -> Result<Vec<u8>> {
+        let tmp = tempfile::NamedTempFile::new().expect("failed to create tempfile");
+        std::fs::write(tmp.path(), image).expect("failed to write image");
+        let erofs = Erofs::open(File::open(tmp.path()).expect("failed to open image"))
+            .expect("failed to open erofs");
+        let root = erofs.root().expect("failed to read root");
+        let (_, file) = erofs
+            .read_dir(&root)
+            .expect("failed to read root")
+            .into_iter()
+            .find(|(name, _)| name == b"file")
+            .expect("/file is missing");
+        erofs.read_data(&file)
+    }
+
+    #[test]
+    fn data_layouts() {
+        for data in FILE_DATA {
+            assert_eq!(
+                file_contents(),
+                read_file(&build(data)).expect("failed to read /file"),
+                "{data:?}"
+            );
+        }
+    }
+
+    #[test]
+    #[ignore = "must run as root"]
+    fn extract() {
+        for data in FILE_DATA {
+            let (_tmp, root) = extract_image(&build(data), extract_erofs);
+            check_tree(&root);
+        }
+    }
+
+    #[test]
+    fn unsupported_algorithm() {
+        let mut image = build(FileData::Full);
+        // the map header of /file is right after its inode and xattrs
+        let header = (META_BLKADDR * BLOCK_SIZE + EXTENDED_INODE_LEN + 28).next_multiple_of(8);
+        assert_eq!((ALGORITHM_DEFLATE << 4) | ALGORITHM_LZ4, image[header + 6]);
+        image[header + 6] = (ALGORITHM_LZMA << 4) | ALGORITHM_LZ4;
+        let err = read_file(&image).expect_err("lzma should be rejected");
+        assert!(format!("{err:#}").contains("lzma"), "{err:#}");
+    }
+}
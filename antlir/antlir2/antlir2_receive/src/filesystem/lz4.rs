@@ -0,0 +1,134 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Decoder for the LZ4 block format, which squashfs and erofs both store
+//! without any frame around it.
+//!
+//! See https://github.com/lz4/lz4/blob/dev/doc/lz4_Block_format.md
+
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
+
+const MIN_MATCH: usize = 4;
+
+/// Decompress a block, stopping as soon as `max` bytes have been produced
+/// (erofs sometimes only needs the start of a block)
+pub(super) fn decompress(mut input: &[u8], max: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(max);
+    while out.len() < max {
+        let Some((&token, rest)) = input.split_first() else {
+            break;
+        };
+        input = rest;
+        let literals = length(&mut input, token >> 4)?;
+        let literal = input
+            .get(..literals)
+            .context("literals overrun the block")?;
+        out.extend_from_slice(&literal[..std::cmp::min(literals, max - out.len())]);
+        input = &input[literals..];
+        // the last sequence is only literals
+        if input.is_empty() || out.len() == max {
+            break;
+        }
+        let offset = input
+            .get(..2)
+            .map(|b| usize::from(u16::from_le_bytes([b[0], b[1]])))
+            .context("match offset overruns the block")?;
+        input = &input[2..];
+        ensure!(
+            offset != 0 && offset <= out.len(),
+            "invalid match offset {offset}"
+        );
+        let len = length(&mut input, token & 0xf)? + MIN_MATCH;
+        // the match may overlap what it is producing, so it has to be copied
+        // one byte at a time
+        let start = out.len() - offset;
+        for i in start..start + std::cmp::min(len, max - out.len()) {
+            out.push(out[i]);
+        }
+    }
+    Ok(out)
+}
+
+/// A length is stored in 4 bits of the token, and if that is all ones it
+/// continues in the following bytes for as long as they are all ones
+fn length(input: &mut &[u8], nibble: u8) -> Result<usize> {
+    let mut len = usize::from(nibble);
+    if nibble == 0xf {
+        loop {
+            let (&byte, rest) = input.split_first().context("length overruns the block")?;
+            *input = rest;
+            len += usize::from(byte);
+            if byte != 0xff {
+                break;
+            }
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::file_contents;
+    use super::super::tests::lz4_compress;
+    use super::*;
+
+    /// Contents of [COMPRESSED]
+    fn uncompressed() -> Vec<u8> {
+        let mut data: Vec<u8> = (0..40)
+            .flat_map(|i| format!("antlir {} ", i % 7).into_bytes())
+            .collect();
+        data.extend([b'a'; 46]);
+        data.extend(b" the end");
+        data
+    }
+
+    /// `uncompressed()` compressed by the lz4 cli, which has literals and
+    /// matches with long lengths, and overlapping matches
+    const COMPRESSED: &[u8] = &[
+        0x93, 0x61, 0x6e, 0x74, 0x6c, 0x69, 0x72, 0x20, 0x30, 0x20, 0x09, 0x00, 0x14, 0x31, 0x09,
+        0x00, 0x14, 0x32, 0x09, 0x00, 0x14, 0x33, 0x09, 0x00, 0x14, 0x34, 0x09, 0x00, 0x14, 0x35,
+        0x09, 0x00, 0x14, 0x36, 0x09, 0x00, 0x0f, 0x3f, 0x00, 0xff, 0x11, 0x0f, 0x01, 0x00, 0x1a,
+        0x80, 0x20, 0x74, 0x68, 0x65, 0x20, 0x65, 0x6e, 0x64,
+    ];
+
+    #[test]
+    fn decompress_block() {
+        let expected = uncompressed();
+        assert_eq!(
+            expected,
+            decompress(COMPRESSED, 4096).expect("failed to decompress")
+        );
+        // stopping early gives exactly the start of the data
+        for max in [0, 5, 100, 300, expected.len()] {
+            assert_eq!(
+                expected[..max],
+                decompress(COMPRESSED, max).expect("failed to decompress")
+            );
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = file_contents();
+        let compressed = lz4_compress(&data);
+        assert!(compressed.len() < 100, "{}", compressed.len());
+        assert_eq!(
+            data,
+            decompress(&compressed, data.len()).expect("failed to decompress")
+        );
+    }
+
+    #[test]
+    fn corrupt() {
+        // match before the start of the output
+        assert!(decompress(&[0x10, b'a', 0x02, 0x00], 100).is_err());
+        // literals past the end of the input
+        assert!(decompress(&[0x50, b'a'], 100).is_err());
+    }
+}
@@ -0,0 +1,436 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Extract filesystem images (squashfs and erofs) by reading them directly.
+//! Mounting them requires privileges that rootless builds don't have, and
+//! the host may not have the tools to unpack them.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
+use nix::errno::Errno;
+use nix::sys::stat::fchmodat;
+use nix::sys::stat::makedev;
+use nix::sys::stat::mknod;
+use nix::sys::stat::utimensat;
+use nix::sys::stat::FchmodatFlags;
+use nix::sys::stat::Mode;
+use nix::sys::stat::SFlag;
+use nix::sys::stat::UtimensatFlags;
+use nix::sys::time::TimeSpec;
+use nix::unistd::fchownat;
+use nix::unistd::FchownatFlags;
+use nix::unistd::Gid;
+use nix::unistd::Uid;
+use tracing::trace;
+use tracing::warn;
+
+mod erofs;
+mod lz4;
+mod squashfs;
+
+/// Extract every file (with ownership, permissions, timestamps and xattrs)
+/// from a squashfs image into `dst`
+pub(crate) fn extract_squashfs(image: File, dst: &Path) -> Result<()> {
+    extract(squashfs::Squashfs::open(image)?, dst)
+}
+
+/// Extract every file (with ownership, permissions, timestamps and xattrs)
+/// from an erofs image into `dst`
+pub(crate) fn extract_erofs(image: File, dst: &Path) -> Result<()> {
+    extract(erofs::Erofs::open(image)?, dst)
+}
+
+/// What kind of file an inode is, along with anything that is specific to
+/// that kind (other than file contents, which are read separately)
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Kind {
+    Dir,
+    File,
+    Symlink(OsString),
+    Device(SFlag, u64),
+    Fifo,
+    Socket,
+}
+
+/// Everything about an inode that is needed to recreate it, regardless of
+/// the image format
+#[derive(Debug, Clone)]
+struct Metadata {
+    /// Identifies hardlinks to the same inode within the image
+    ino: u64,
+    kind: Kind,
+    /// Permission bits (including setuid/setgid/sticky)
+    perm: u32,
+    uid: u32,
+    gid: u32,
+    mtime: TimeSpec,
+    nlink: u32,
+    xattrs: Vec<(OsString, Vec<u8>)>,
+}
+
+/// A filesystem image that can be walked from its root
+trait Image {
+    /// Format-specific handle to an inode (and its contents)
+    type Inode: AsRef<Metadata>;
+
+    fn root(&self) -> Result<Self::Inode>;
+
+    /// All the entries of a directory, except for `.` and `..`
+    fn read_dir(&self, dir: &Self::Inode) -> Result<Vec<(Vec<u8>, Self::Inode)>>;
+
+    /// Write the contents of a regular file to `dst`
+    fn write_contents(&self, file: &Self::Inode, dst: &File) -> Result<()>;
+}
+
+/// Recreate the whole tree of `image` in `dst`, which must be an empty
+/// directory
+fn extract<I: Image>(image: I, dst: &Path) -> Result<()> {
+    let root = image.root()?;
+    ensure!(
+        root.as_ref().kind == Kind::Dir,
+        "root inode is not a directory"
+    );
+    // paths of inodes that have already been extracted, to hardlink to
+    let mut links: HashMap<u64, PathBuf> = HashMap::new();
+    // directories are never hardlinked, so seeing one twice means the image
+    // is corrupt (and would otherwise be walked forever)
+    let mut dirs = HashSet::from([root.as_ref().ino]);
+    // extracting children bumps the mtime of the parent directory, so they
+    // are all set at the very end
+    let mut dir_mtimes = vec![(dst.to_owned(), root.as_ref().mtime)];
+    set_metadata(dst, root.as_ref()).context("while setting metadata of /")?;
+
+    let mut queue = vec![(root, dst.to_owned())];
+    while let Some((dir, dir_path)) = queue.pop() {
+        let entries = image
+            .read_dir(&dir)
+            .with_context(|| format!("while reading dir {}", dir_path.display()))?;
+        for (name, inode) in entries {
+            let path = dir_path.join(check_name(&name)?);
+            let meta = inode.as_ref();
+            let relpath = path.strip_prefix(dst).unwrap_or(&path).to_owned();
+            trace!("extracting /{}", relpath.display());
+            if meta.kind != Kind::Dir && meta.nlink > 1 {
+                if let Some(target) = links.get(&meta.ino) {
+                    std::fs::hard_link(target, &path).with_context(|| {
+                        format!(
+                            "while hardlinking /{} -> {}",
+                            relpath.display(),
+                            target.display()
+                        )
+                    })?;
+                    continue;
+                }
+            }
+            match &meta.kind {
+                Kind::Dir => {
+                    ensure!(
+                        dirs.insert(meta.ino),
+                        "directory /{} appears more than once in the image",
+                        relpath.display()
+                    );
+                    std::fs::create_dir(&path)
+                        .with_context(|| format!("while creating /{}", relpath.display()))?;
+                    dir_mtimes.push((path.clone(), meta.mtime));
+                }
+                Kind::File => {
+                    let f = File::create_new(&path)
+                        .with_context(|| format!("while creating /{}", relpath.display()))?;
+                    image
+                        .write_contents(&inode, &f)
+                        .with_context(|| format!("while extracting /{}", relpath.display()))?;
+                }
+                Kind::Symlink(target) => {
+                    std::os::unix::fs::symlink(target, &path)
+                        .with_context(|| format!("while creating /{}", relpath.display()))?;
+                }
+                Kind::Device(kind, rdev) => {
+                    match mknod(&path, *kind, Mode::empty(), *rdev) {
+                        Ok(()) => {}
+                        // an unprivileged user namespace can't create device
+                        // nodes, but everything else in the layer is still
+                        // worth having
+                        Err(Errno::EPERM) => {
+                            warn!(
+                                "skipping device node /{} that cannot be created without real root",
+                                relpath.display()
+                            );
+                            continue;
+                        }
+                        Err(e) => {
+                            return Err(e)
+                                .with_context(|| format!("while creating /{}", relpath.display()));
+                        }
+                    }
+                }
+                Kind::Fifo => mknod(&path, SFlag::S_IFIFO, Mode::empty(), 0)
+                    .with_context(|| format!("while creating /{}", relpath.display()))?,
+                Kind::Socket => mknod(&path, SFlag::S_IFSOCK, Mode::empty(), 0)
+                    .with_context(|| format!("while creating /{}", relpath.display()))?,
+            }
+            set_metadata(&path, meta)
+                .with_context(|| format!("while setting metadata of /{}", relpath.display()))?;
+            if meta.kind == Kind::Dir {
+                queue.push((inode, path));
+            } else {
+                set_mtime(&path, meta.mtime)?;
+                if meta.nlink > 1 {
+                    links.insert(meta.ino, path);
+                }
+            }
+        }
+    }
+    // children before parents
+    for (path, mtime) in dir_mtimes.into_iter().rev() {
+        set_mtime(&path, mtime)?;
+    }
+    Ok(())
+}
+
+/// Names come straight from the image, so make sure that they can't be used
+/// to escape the directory they're in
+fn check_name(name: &[u8]) -> Result<&OsStr> {
+    ensure!(
+        !name.is_empty() && name != b"." && name != b".." && !name.contains(&b'/'),
+        "illegal file name '{}' in image",
+        String::from_utf8_lossy(name)
+    );
+    ensure!(
+        !name.contains(&0),
+        "file name '{}' contains a NUL byte",
+        String::from_utf8_lossy(name)
+    );
+    Ok(OsStr::from_bytes(name))
+}
+
+/// Ownership is set first, since chown clears setuid bits and file
+/// capabilities
+fn set_metadata(path: &Path, meta: &Metadata) -> Result<()> {
+    fchownat(
+        None,
+        path,
+        Some(Uid::from_raw(meta.uid)),
+        Some(Gid::from_raw(meta.gid)),
+        FchownatFlags::NoFollowSymlink,
+    )
+    .context("while chowning")?;
+    // the mode of a symlink is meaningless (and chmod would follow it)
+    if !matches!(meta.kind, Kind::Symlink(_)) {
+        fchmodat(
+            None,
+            path,
+            Mode::from_bits_truncate(meta.perm),
+            FchmodatFlags::FollowSymlink,
+        )
+        .context("while chmodding")?;
+    }
+    for (name, value) in &meta.xattrs {
+        xattr::set(path, name, value)
+            .with_context(|| format!("while setting xattr {}", name.to_string_lossy()))?;
+    }
+    Ok(())
+}
+
+fn set_mtime(path: &Path, mtime: TimeSpec) -> Result<()> {
+    utimensat(None, path, &mtime, &mtime, UtimensatFlags::NoFollowSymlink)
+        .with_context(|| format!("while setting mtime of {}", path.display()))
+}
+
+/// Both formats store device numbers the way the kernel's `new_encode_dev`
+/// does
+fn decode_dev(dev: u32) -> u64 {
+    let major = (dev & 0xfff00) >> 8;
+    let minor = (dev & 0xff) | ((dev >> 12) & 0xfff00);
+    makedev(major.into(), minor.into())
+}
+
+/// Map the file type bits of a mode to a [Kind] (for types that have nothing
+/// else to them)
+fn simple_kind(mode: u32) -> Result<Kind> {
+    Ok(
+        match SFlag::from_bits_truncate(mode & SFlag::S_IFMT.bits()) {
+            SFlag::S_IFDIR => Kind::Dir,
+            SFlag::S_IFREG => Kind::File,
+            SFlag::S_IFIFO => Kind::Fifo,
+            SFlag::S_IFSOCK => Kind::Socket,
+            _ => bail!("unsupported file type in mode {mode:o}"),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+
+    /// File capability (v2) granting CAP_NET_RAW
+    pub(super) const CAPABILITY: [u8; 20] = [
+        0x01, 0, 0, 0x02, 0, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    /// Contents of /file in the test images: a full block, a hole and then a
+    /// partial block
+    pub(super) fn file_contents() -> Vec<u8> {
+        let mut contents = vec![b'a'; 4096];
+        contents.resize(8192, 0);
+        contents.resize(8292, b'b');
+        contents
+    }
+
+    /// A valid lz4 block of `data`, which only compresses runs of the same
+    /// byte
+    pub(super) fn lz4_compress(data: &[u8]) -> Vec<u8> {
+        fn length(block: &mut Vec<u8>, len: usize) {
+            if len >= 15 {
+                let mut rest = len - 15;
+                while rest >= 255 {
+                    block.push(255);
+                    rest -= 255;
+                }
+                block.push(rest as u8);
+            }
+        }
+        fn sequence(block: &mut Vec<u8>, literals: &[u8], run: Option<usize>) {
+            let match_len = run.map_or(0, |run| run - 4);
+            block.push(((literals.len().min(15) as u8) << 4) | match_len.min(15) as u8);
+            length(block, literals.len());
+            block.extend_from_slice(literals);
+            if run.is_some() {
+                block.extend(1u16.to_le_bytes());
+                length(block, match_len);
+            }
+        }
+        let mut block = Vec::new();
+        let mut literals_start = 0;
+        let mut i = 0;
+        // the last 5 bytes are always literals
+        while i + 5 < data.len() {
+            let run = data[i + 1..data.len() - 5]
+                .iter()
+                .take_while(|b| **b == data[i])
+                .count();
+            if run >= 4 {
+                sequence(&mut block, &data[literals_start..=i], Some(run));
+                i += 1 + run;
+                literals_start = i;
+            } else {
+                i += 1;
+            }
+        }
+        sequence(&mut block, &data[literals_start..], None);
+        block
+    }
+
+    /// Write out an image and extract it into a fresh directory
+    pub(super) fn extract_image(
+        image: &[u8],
+        extract: impl FnOnce(File, &Path) -> Result<()>,
+    ) -> (tempfile::TempDir, PathBuf) {
+        let tmp = tempfile::tempdir().expect("failed to create tempdir");
+        let image_path = tmp.path().join("image");
+        std::fs::write(&image_path, image).expect("failed to write image");
+        let root = tmp.path().join("root");
+        std::fs::create_dir(&root).expect("failed to create root");
+        extract(
+            File::open(&image_path).expect("failed to open image"),
+            &root,
+        )
+        .expect("failed to extract image");
+        (tmp, root)
+    }
+
+    /// Check the tree that is in both the squashfs and erofs test images
+    pub(super) fn check_tree(root: &Path) {
+        let stat = |path: &str| {
+            std::fs::symlink_metadata(root.join(path))
+                .unwrap_or_else(|e| panic!("failed to stat {path}: {e}"))
+        };
+
+        let meta = stat("");
+        assert_eq!((meta.uid(), meta.gid()), (0, 0));
+        assert_eq!(meta.mode() & 0o7777, 0o755);
+        assert_eq!(meta.mtime(), 100);
+
+        let file = stat("file");
+        assert!(file.is_file());
+        assert_eq!((file.uid(), file.gid()), (42, 43));
+        // setuid must survive the chown
+        assert_eq!(file.mode() & 0o7777, 0o4755);
+        assert_eq!(file.mtime(), 300);
+        assert_eq!(file.nlink(), 2);
+        assert_eq!(
+            std::fs::read(root.join("file")).expect("failed to read file"),
+            file_contents()
+        );
+        assert_eq!(
+            xattr::get(root.join("file"), "user.foo").expect("failed to get xattr"),
+            Some(b"bar".to_vec())
+        );
+        assert_eq!(
+            xattr::get(root.join("file"), "security.capability").expect("failed to get xattr"),
+            Some(CAPABILITY.to_vec())
+        );
+
+        let hardlink = stat("subdir/hardlink");
+        assert_eq!(hardlink.ino(), file.ino());
+
+        let subdir = stat("subdir");
+        assert!(subdir.is_dir());
+        assert_eq!((subdir.uid(), subdir.gid()), (42, 43));
+        assert_eq!(subdir.mode() & 0o7777, 0o700);
+        // not clobbered by creating the hardlink inside of it
+        assert_eq!(subdir.mtime(), 200);
+
+        let symlink = stat("symlink");
+        assert!(symlink.is_symlink());
+        assert_eq!((symlink.uid(), symlink.gid()), (42, 43));
+        assert_eq!(symlink.mtime(), 400);
+        assert_eq!(
+            std::fs::read_link(root.join("symlink")).expect("failed to readlink"),
+            Path::new("file")
+        );
+
+        let fifo = stat("fifo");
+        assert!(fifo.file_type().is_fifo());
+        assert_eq!(fifo.mode() & 0o7777, 0o600);
+
+        let dev = stat("null");
+        assert!(dev.file_type().is_char_device());
+        assert_eq!(dev.rdev(), makedev(1, 3));
+        assert_eq!(dev.mode() & 0o7777, 0o666);
+    }
+
+    #[test]
+    fn names() {
+        assert!(check_name(b"hello").is_ok());
+        assert!(check_name(b"..hidden").is_ok());
+        for bad in [&b""[..], b".", b"..", b"../etc", b"a/b", b"nul\0"] {
+            assert!(check_name(bad).is_err(), "{bad:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn devices() {
+        // /dev/null
+        assert_eq!(decode_dev(0x103), makedev(1, 3));
+        // a minor number that doesn't fit in 8 bits
+        assert_eq!(decode_dev(0x100845), makedev(8, 0x145));
+    }
+}
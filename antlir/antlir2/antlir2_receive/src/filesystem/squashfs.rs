@@ -0,0 +1,911 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Reader for squashfs (v4) images.
+//!
+//! See https://dr-emann.github.io/squashfs/squashfs.html for a description of
+//! the on-disk format.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::FileExt;
+use std::rc::Rc;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
+use flate2::read::ZlibDecoder;
+use nix::sys::stat::SFlag;
+use nix::sys::time::TimeSpec;
+use xz2::read::XzDecoder;
+
+use super::decode_dev;
+use super::lz4;
+use super::Image;
+use super::Kind;
+use super::Metadata;
+
+const MAGIC: u32 = 0x73717368;
+const SUPERBLOCK_LEN: usize = 96;
+const METADATA_BLOCK_SIZE: usize = 8192;
+/// Set in the header of a metadata block that is stored uncompressed
+const METADATA_UNCOMPRESSED: u16 = 0x8000;
+/// Set in the size of a data block that is stored uncompressed
+const DATA_UNCOMPRESSED: u32 = 1 << 24;
+const NONE: u32 = 0xffffffff;
+const NO_XATTR_TABLE: u64 = u64::MAX;
+/// Longest symlink target that Linux allows
+const PATH_MAX: usize = 4096;
+const XATTR_SIZE_MAX: usize = 65536;
+
+#[derive(Debug, Clone, Copy)]
+enum Compressor {
+    Gzip,
+    Xz,
+    Lz4,
+    Zstd,
+}
+
+#[derive(Debug)]
+struct Superblock {
+    block_size: u32,
+    fragment_count: u32,
+    compressor: Compressor,
+    id_count: u16,
+    root_inode: u64,
+    id_table_start: u64,
+    xattr_id_table_start: u64,
+    inode_table_start: u64,
+    directory_table_start: u64,
+    fragment_table_start: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Fragment {
+    start: u64,
+    size: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct XattrId {
+    reference: u64,
+    count: u32,
+}
+
+pub(super) struct Squashfs {
+    file: File,
+    sb: Superblock,
+    ids: Vec<u32>,
+    fragments: Vec<Fragment>,
+    /// Start of the key/value pairs and the per-inode lookup entries
+    xattrs: Option<(u64, Vec<XattrId>)>,
+    /// Decompressed metadata blocks by their position in the image. The same
+    /// blocks are read for many inodes and directories, and all the metadata
+    /// is small compared to the image.
+    metadata: RefCell<HashMap<u64, Rc<MetadataBlock>>>,
+    /// Small files are packed together into fragment blocks, so the last one
+    /// is likely to be used again for the next file
+    fragment_cache: RefCell<Option<(u32, Rc<Vec<u8>>)>>,
+}
+
+struct MetadataBlock {
+    data: Vec<u8>,
+    /// Position of the block that follows this one
+    next: u64,
+}
+
+pub(super) struct Inode {
+    meta: Metadata,
+    contents: Contents,
+}
+
+impl AsRef<Metadata> for Inode {
+    fn as_ref(&self) -> &Metadata {
+        &self.meta
+    }
+}
+
+enum Contents {
+    None,
+    Dir {
+        /// Metadata reference to the start of the listing
+        listing: u64,
+        size: u32,
+    },
+    File {
+        blocks_start: u64,
+        /// On-disk size of each (full) data block
+        blocks: Vec<u32>,
+        fragment: Option<(u32, u32)>,
+        size: u64,
+    },
+}
+
+impl Squashfs {
+    pub(super) fn open(file: File) -> Result<Self> {
+        let mut buf = [0; SUPERBLOCK_LEN];
+        file.read_exact_at(&mut buf, 0)
+            .context("while reading superblock")?;
+        let mut r = &buf[..];
+        ensure!(u32(&mut r)? == MAGIC, "not a squashfs image");
+        let _inode_count = u32(&mut r)?;
+        let _mkfs_time = u32(&mut r)?;
+        let block_size = u32(&mut r)?;
+        let fragment_count = u32(&mut r)?;
+        let compressor = match u16(&mut r)? {
+            1 => Compressor::Gzip,
+            4 => Compressor::Xz,
+            5 => Compressor::Lz4,
+            6 => Compressor::Zstd,
+            2 => bail!("squashfs images compressed with lzma are not supported"),
+            3 => bail!("squashfs images compressed with lzo are not supported"),
+            id => bail!("unknown squashfs compressor {id}"),
+        };
+        let block_log = u16(&mut r)?;
+        let _flags = u16(&mut r)?;
+        let id_count = u16(&mut r)?;
+        let version = (u16(&mut r)?, u16(&mut r)?);
+        ensure!(
+            version == (4, 0),
+            "only squashfs 4.0 is supported, not {}.{}",
+            version.0,
+            version.1
+        );
+        ensure!(
+            (12..=20).contains(&block_log) && block_size == 1 << block_log,
+            "invalid block size {block_size}"
+        );
+        let root_inode = u64(&mut r)?;
+        let _bytes_used = u64(&mut r)?;
+        let sb = Superblock {
+            block_size,
+            fragment_count,
+            compressor,
+            id_count,
+            root_inode,
+            id_table_start: u64(&mut r)?,
+            xattr_id_table_start: u64(&mut r)?,
+            inode_table_start: u64(&mut r)?,
+            directory_table_start: u64(&mut r)?,
+            fragment_table_start: u64(&mut r)?,
+        };
+        let mut fs = Self {
+            file,
+            sb,
+            ids: Vec::new(),
+            fragments: Vec::new(),
+            xattrs: None,
+            metadata: RefCell::new(HashMap::new()),
+            fragment_cache: RefCell::new(None),
+        };
+        fs.ids = fs
+            .read_table(fs.sb.id_table_start, fs.sb.id_count.into(), |r| u32(r))
+            .context("while reading id table")?;
+        fs.fragments = fs
+            .read_table(fs.sb.fragment_table_start, fs.sb.fragment_count, |r| {
+                let start = u64(r)?;
+                let size = u32(r)?;
+                let _unused = u32(r)?;
+                Ok(Fragment { start, size })
+            })
+            .context("while reading fragment table")?;
+        if fs.sb.xattr_id_table_start != NO_XATTR_TABLE {
+            let mut buf = [0; 16];
+            fs.file
+                .read_exact_at(&mut buf, fs.sb.xattr_id_table_start)
+                .context("while reading xattr table header")?;
+            let mut r = &buf[..];
+            let kv_start = u64(&mut r)?;
+            let count = u32(&mut r)?;
+            let ids = fs
+                .read_table(fs.sb.xattr_id_table_start + 16, count, |r| {
+                    let reference = u64(r)?;
+                    let count = u32(r)?;
+                    let _size = u32(r)?;
+                    Ok(XattrId { reference, count })
+                })
+                .context("while reading xattr id table")?;
+            fs.xattrs = Some((kv_start, ids));
+        }
+        Ok(fs)
+    }
+
+    /// Lookup tables are stored in consecutive metadata blocks, whose
+    /// positions are listed at `index`
+    fn read_table<T>(
+        &self,
+        index: u64,
+        count: u32,
+        mut parse: impl FnMut(&mut MetadataReader) -> Result<T>,
+    ) -> Result<Vec<T>> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let mut first = [0; 8];
+        self.file
+            .read_exact_at(&mut first, index)
+            .context("while reading table index")?;
+        let mut r = MetadataReader::new(self, u64::from_le_bytes(first), 0);
+        (0..count).map(|_| parse(&mut r)).collect()
+    }
+
+    fn decompress(&self, data: &[u8], max: usize) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(max);
+        match self.sb.compressor {
+            Compressor::Gzip => {
+                ZlibDecoder::new(data)
+                    .take(max as u64 + 1)
+                    .read_to_end(&mut out)
+                    .context("while decompressing gzip")?;
+            }
+            Compressor::Xz => {
+                XzDecoder::new(data)
+                    .take(max as u64 + 1)
+                    .read_to_end(&mut out)
+                    .context("while decompressing xz")?;
+            }
+            Compressor::Lz4 => {
+                out = lz4::decompress(data, max + 1).context("while decompressing lz4")?;
+            }
+            Compressor::Zstd => {
+                out = zstd::bulk::decompress(data, max).context("while decompressing zstd")?;
+            }
+        }
+        ensure!(
+            out.len() <= max,
+            "block decompressed to more than {max} bytes"
+        );
+        Ok(out)
+    }
+
+    /// Read (and decompress) the metadata block at `pos`
+    fn metadata_block(&self, pos: u64) -> Result<Rc<MetadataBlock>> {
+        if let Some(block) = self.metadata.borrow().get(&pos) {
+            return Ok(block.clone());
+        }
+        let mut header = [0; 2];
+        self.file
+            .read_exact_at(&mut header, pos)
+            .with_context(|| format!("while reading metadata block header at {pos}"))?;
+        let header = u16::from_le_bytes(header);
+        let len = (header & !METADATA_UNCOMPRESSED) as usize;
+        ensure!(
+            len <= METADATA_BLOCK_SIZE,
+            "metadata block at {pos} is too big"
+        );
+        let mut data = vec![0; len];
+        self.file
+            .read_exact_at(&mut data, pos + 2)
+            .with_context(|| format!("while reading metadata block at {pos}"))?;
+        if header & METADATA_UNCOMPRESSED == 0 {
+            data = self
+                .decompress(&data, METADATA_BLOCK_SIZE)
+                .with_context(|| format!("while decompressing metadata block at {pos}"))?;
+        }
+        let block = Rc::new(MetadataBlock {
+            data,
+            next: pos + 2 + len as u64,
+        });
+        self.metadata.borrow_mut().insert(pos, block.clone());
+        Ok(block)
+    }
+
+    fn inode(&self, reference: u64) -> Result<Inode> {
+        let mut r = MetadataReader::reference(self, self.sb.inode_table_start, reference);
+        let ty = u16(&mut r)?;
+        let perm = u32::from(u16(&mut r)?) & 0o7777;
+        let uid = self.id(u16(&mut r)?)?;
+        let gid = self.id(u16(&mut r)?)?;
+        let mtime = TimeSpec::new(u32(&mut r)?.into(), 0);
+        let ino = u32(&mut r)?.into();
+        let mut nlink = 1;
+        let mut xattr = NONE;
+        let mut contents = Contents::None;
+        let kind = match ty {
+            // basic directory
+            1 => {
+                let block = u32(&mut r)?;
+                nlink = u32(&mut r)?;
+                let size = u16(&mut r)?.into();
+                let offset = u16(&mut r)?;
+                let _parent = u32(&mut r)?;
+                contents = Contents::Dir {
+                    listing: (u64::from(block) << 16) | u64::from(offset),
+                    size,
+                };
+                Kind::Dir
+            }
+            // extended directory
+            8 => {
+                nlink = u32(&mut r)?;
+                let size = u32(&mut r)?;
+                let block = u32(&mut r)?;
+                let _parent = u32(&mut r)?;
+                let _index_count = u16(&mut r)?;
+                let offset = u16(&mut r)?;
+                xattr = u32(&mut r)?;
+                contents = Contents::Dir {
+                    listing: (u64::from(block) << 16) | u64::from(offset),
+                    size,
+                };
+                Kind::Dir
+            }
+            // basic and extended file
+            2 | 9 => {
+                let (blocks_start, fragment_index, fragment_offset, size) = if ty == 2 {
+                    (
+                        u32(&mut r)?.into(),
+                        u32(&mut r)?,
+                        u32(&mut r)?,
+                        u32(&mut r)?.into(),
+                    )
+                } else {
+                    let blocks_start = u64(&mut r)?;
+                    let size = u64(&mut r)?;
+                    let _sparse = u64(&mut r)?;
+                    nlink = u32(&mut r)?;
+                    let fragment_index = u32(&mut r)?;
+                    let fragment_offset = u32(&mut r)?;
+                    xattr = u32(&mut r)?;
+                    (blocks_start, fragment_index, fragment_offset, size)
+                };
+                let block_size = u64::from(self.sb.block_size);
+                let fragment =
+                    (fragment_index != NONE).then_some((fragment_index, fragment_offset));
+                // the tail end of the file is in a fragment, if it has one
+                let num_blocks = if fragment.is_some() {
+                    size / block_size
+                } else {
+                    size.div_ceil(block_size)
+                };
+                let blocks = (0..num_blocks)
+                    .map(|_| u32(&mut r))
+                    .collect::<Result<_>>()?;
+                contents = Contents::File {
+                    blocks_start,
+                    blocks,
+                    fragment,
+                    size,
+                };
+                Kind::File
+            }
+            // basic and extended symlink
+            3 | 10 => {
+                nlink = u32(&mut r)?;
+                let len = u32(&mut r)? as usize;
+                ensure!(len <= PATH_MAX, "symlink target is too long");
+                let mut target = vec![0; len];
+                r.read_exact(&mut target)?;
+                if ty == 10 {
+                    xattr = u32(&mut r)?;
+                }
+                Kind::Symlink(OsString::from_vec(target))
+            }
+            // basic and extended block and char devices
+            4 | 5 | 11 | 12 => {
+                nlink = u32(&mut r)?;
+                let dev = decode_dev(u32(&mut r)?);
+                if ty > 8 {
+                    xattr = u32(&mut r)?;
+                }
+                match ty {
+                    4 | 11 => Kind::Device(SFlag::S_IFBLK, dev),
+                    _ => Kind::Device(SFlag::S_IFCHR, dev),
+                }
+            }
+            // basic and extended fifos and sockets
+            6 | 7 | 13 | 14 => {
+                nlink = u32(&mut r)?;
+                if ty > 8 {
+                    xattr = u32(&mut r)?;
+                }
+                match ty {
+                    6 | 13 => Kind::Fifo,
+                    _ => Kind::Socket,
+                }
+            }
+            _ => bail!("unknown inode type {ty}"),
+        };
+        Ok(Inode {
+            meta: Metadata {
+                ino,
+                kind,
+                perm,
+                uid,
+                gid,
+                mtime,
+                nlink,
+                xattrs: self.xattrs(xattr).context("while reading xattrs")?,
+            },
+            contents,
+        })
+    }
+
+    fn id(&self, index: u16) -> Result<u32> {
+        self.ids
+            .get(usize::from(index))
+            .copied()
+            .with_context(|| format!("id {index} is not in the id table"))
+    }
+
+    fn xattrs(&self, index: u32) -> Result<Vec<(OsString, Vec<u8>)>> {
+        if index == NONE {
+            return Ok(Vec::new());
+        }
+        let (kv_start, ids) = self
+            .xattrs
+            .as_ref()
+            .context("inode has xattrs, but there is no xattr table")?;
+        let id = ids
+            .get(index as usize)
+            .with_context(|| format!("xattr id {index} is not in the xattr id table"))?;
+        let mut r = MetadataReader::reference(self, *kv_start, id.reference);
+        let mut xattrs = Vec::new();
+        for _ in 0..id.count {
+            let ty = u16(&mut r)?;
+            let name_len = u16(&mut r)?;
+            let prefix: &[u8] = match ty & 0xff {
+                0 => b"user.",
+                1 => b"trusted.",
+                2 => b"security.",
+                other => bail!("unknown xattr prefix {other}"),
+            };
+            let mut name = prefix.to_vec();
+            let start = name.len();
+            name.resize(start + usize::from(name_len), 0);
+            r.read_exact(&mut name[start..])?;
+            // out-of-line values are stored once and referred to by every
+            // inode that has the same one
+            let value = if ty & 0x100 != 0 {
+                let _len = u32(&mut r)?;
+                let reference = u64(&mut r)?;
+                read_xattr_value(&mut MetadataReader::reference(self, *kv_start, reference))?
+            } else {
+                read_xattr_value(&mut r)?
+            };
+            xattrs.push((OsString::from_vec(name), value));
+        }
+        Ok(xattrs)
+    }
+
+    /// Read (and decompress) a data or fragment block, with its on-disk
+    /// `size` as it is stored in inodes and the fragment table
+    fn data_block(&self, pos: u64, size: u32) -> Result<Vec<u8>> {
+        let on_disk = size & !DATA_UNCOMPRESSED;
+        ensure!(
+            on_disk <= self.sb.block_size,
+            "data block at {pos} is too big"
+        );
+        let mut data = vec![0; on_disk as usize];
+        self.file
+            .read_exact_at(&mut data, pos)
+            .with_context(|| format!("while reading data block at {pos}"))?;
+        if size & DATA_UNCOMPRESSED == 0 {
+            data = self
+                .decompress(&data, self.sb.block_size as usize)
+                .with_context(|| format!("while decompressing data block at {pos}"))?;
+        }
+        Ok(data)
+    }
+
+    fn fragment(&self, index: u32) -> Result<Rc<Vec<u8>>> {
+        if let Some((cached, block)) = &*self.fragment_cache.borrow() {
+            if *cached == index {
+                return Ok(block.clone());
+            }
+        }
+        let fragment = self
+            .fragments
+            .get(index as usize)
+            .with_context(|| format!("fragment {index} is not in the fragment table"))?;
+        let block = Rc::new(self.data_block(fragment.start, fragment.size)?);
+        *self.fragment_cache.borrow_mut() = Some((index, block.clone()));
+        Ok(block)
+    }
+}
+
+impl Image for Squashfs {
+    type Inode = Inode;
+
+    fn root(&self) -> Result<Inode> {
+        self.inode(self.sb.root_inode)
+            .context("while reading root inode")
+    }
+
+    fn read_dir(&self, dir: &Inode) -> Result<Vec<(Vec<u8>, Inode)>> {
+        let Contents::Dir { listing, size } = dir.contents else {
+            bail!("not a directory");
+        };
+        // the size includes the implicit '.' and '..' entries
+        let size = size.checked_sub(3).context("directory size is too small")?;
+        let mut r = MetadataReader::reference(self, self.sb.directory_table_start, listing);
+        let mut entries = Vec::new();
+        let mut read = 0;
+        while read < size {
+            let count = u32(&mut r)? + 1;
+            let start = u32(&mut r)?;
+            let _inode_number = u32(&mut r)?;
+            read += 12;
+            ensure!(count <= 256, "directory header has {count} entries");
+            for _ in 0..count {
+                let offset = u16(&mut r)?;
+                let _inode_offset = u16(&mut r)?;
+                let _ty = u16(&mut r)?;
+                let name_len = usize::from(u16(&mut r)?) + 1;
+                let mut name = vec![0; name_len];
+                r.read_exact(&mut name)?;
+                read += 8 + name_len as u32;
+                let inode = self
+                    .inode((u64::from(start) << 16) | u64::from(offset))
+                    .with_context(|| {
+                        format!("while reading inode of {}", String::from_utf8_lossy(&name))
+                    })?;
+                entries.push((name, inode));
+            }
+        }
+        ensure!(read == size, "directory listing overran its size");
+        Ok(entries)
+    }
+
+    fn write_contents(&self, file: &Inode, dst: &File) -> Result<()> {
+        let Contents::File {
+            blocks_start,
+            blocks,
+            fragment,
+            size,
+        } = &file.contents
+        else {
+            bail!("not a regular file");
+        };
+        let block_size = u64::from(self.sb.block_size);
+        let mut pos = *blocks_start;
+        let mut offset = 0;
+        for block in blocks {
+            let len = std::cmp::min(block_size, size - offset);
+            // sparse blocks are not stored at all
+            if *block != 0 {
+                let data = self.data_block(pos, *block)?;
+                ensure!(
+                    data.len() as u64 == len,
+                    "data block at {pos} is {} bytes, not {len}",
+                    data.len()
+                );
+                dst.write_all_at(&data, offset)
+                    .context("while writing data")?;
+                pos += u64::from(block & !DATA_UNCOMPRESSED);
+            }
+            offset += len;
+        }
+        if let Some((index, start)) = fragment {
+            let block = self.fragment(*index)?;
+            let start = *start as usize;
+            let tail = block
+                .get(start..start + (size - offset) as usize)
+                .context("fragment is shorter than the end of the file")?;
+            dst.write_all_at(tail, offset)
+                .context("while writing data")?;
+        }
+        dst.set_len(*size).context("while setting file size")?;
+        Ok(())
+    }
+}
+
+/// Sequential reader of metadata, which may span any number of blocks
+struct MetadataReader<'a> {
+    fs: &'a Squashfs,
+    block: Option<Rc<MetadataBlock>>,
+    /// Position of the next block to read (or the current one if it has not
+    /// been read yet)
+    next: u64,
+    offset: usize,
+}
+
+impl<'a> MetadataReader<'a> {
+    fn new(fs: &'a Squashfs, pos: u64, offset: usize) -> Self {
+        Self {
+            fs,
+            block: None,
+            next: pos,
+            offset,
+        }
+    }
+
+    /// Start reading from a reference to a block (relative to the start of
+    /// `table`) and an offset within the decompressed block
+    fn reference(fs: &'a Squashfs, table: u64, reference: u64) -> Self {
+        Self::new(fs, table + (reference >> 16), (reference & 0xffff) as usize)
+    }
+}
+
+impl Read for MetadataReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(block) = &self.block {
+                if self.offset < block.data.len() {
+                    let n = std::cmp::min(buf.len(), block.data.len() - self.offset);
+                    buf[..n].copy_from_slice(&block.data[self.offset..self.offset + n]);
+                    self.offset += n;
+                    return Ok(n);
+                }
+                self.offset -= block.data.len();
+            }
+            let block = self
+                .fs
+                .metadata_block(self.next)
+                .map_err(std::io::Error::other)?;
+            if block.data.is_empty() {
+                return Ok(0);
+            }
+            self.next = block.next;
+            self.block = Some(block);
+        }
+    }
+}
+
+fn read_xattr_value(r: &mut impl Read) -> Result<Vec<u8>> {
+    let len = u32(r)? as usize;
+    ensure!(len <= XATTR_SIZE_MAX, "xattr value is too long");
+    let mut value = vec![0; len];
+    r.read_exact(&mut value)?;
+    Ok(value)
+}
+
+fn u16(r: &mut impl Read) -> Result<u16> {
+    let mut buf = [0; 2];
+    r.read_exact(&mut buf).context("unexpected end of data")?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn u32(r: &mut impl Read) -> Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf).context("unexpected end of data")?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn u64(r: &mut impl Read) -> Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf).context("unexpected end of data")?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::ZlibEncoder;
+
+    use super::super::extract_squashfs;
+    use super::super::tests::*;
+    use super::*;
+
+    const BLOCK_SIZE: usize = 4096;
+    const OWNED: (u16, u16) = (1, 2);
+    const ROOT: (u16, u16) = (0, 0);
+
+    /// Wrap `data` in a single uncompressed metadata block
+    fn metadata(data: &[u8]) -> Vec<u8> {
+        let mut block = (data.len() as u16 | METADATA_UNCOMPRESSED)
+            .to_le_bytes()
+            .to_vec();
+        block.extend_from_slice(data);
+        block
+    }
+
+    /// Fields that every inode starts with, where `ids` are indices into the
+    /// id table
+    fn inode(ty: u16, perm: u16, ids: (u16, u16), mtime: u32, ino: u32) -> Vec<u8> {
+        [
+            &ty.to_le_bytes()[..],
+            &perm.to_le_bytes(),
+            &ids.0.to_le_bytes(),
+            &ids.1.to_le_bytes(),
+            &mtime.to_le_bytes(),
+            &ino.to_le_bytes(),
+        ]
+        .concat()
+    }
+
+    fn dir_entry(inode_ref: usize, ty: u16, name: &str) -> Vec<u8> {
+        [
+            &(inode_ref as u16).to_le_bytes()[..],
+            &0u16.to_le_bytes(),
+            &ty.to_le_bytes(),
+            &(name.len() as u16 - 1).to_le_bytes(),
+            name.as_bytes(),
+        ]
+        .concat()
+    }
+
+    /// Directory listing with a single header (all the inodes are in the
+    /// first metadata block)
+    fn dir_listing(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut listing = [
+            (entries.len() as u32 - 1).to_le_bytes(),
+            0u32.to_le_bytes(),
+            1u32.to_le_bytes(),
+        ]
+        .concat();
+        listing.extend(entries.concat());
+        listing
+    }
+
+    const GZIP: u16 = 1;
+    const LZ4: u16 = 5;
+
+    /// Build an image of the tree that [check_tree] expects, with its data
+    /// compressed with `compressor`
+    fn build(compressor: u16) -> Vec<u8> {
+        let contents = file_contents();
+        let mut image = vec![0; SUPERBLOCK_LEN];
+
+        // the first block of /file is compressed, the second one is a hole
+        // and the tail is in an uncompressed fragment
+        let blocks_start = image.len() as u64;
+        let compressed = match compressor {
+            GZIP => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(&contents[..BLOCK_SIZE])
+                    .expect("failed to compress");
+                encoder.finish().expect("failed to compress")
+            }
+            LZ4 => lz4_compress(&contents[..BLOCK_SIZE]),
+            _ => unreachable!("unknown compressor {compressor}"),
+        };
+        image.extend(&compressed);
+        let fragment_start = image.len() as u64;
+        image.extend(&contents[2 * BLOCK_SIZE..]);
+
+        let mut inodes = Vec::new();
+        let file = inodes.len();
+        inodes.extend(inode(9, 0o4755, OWNED, 300, 2));
+        inodes.extend(blocks_start.to_le_bytes());
+        inodes.extend((contents.len() as u64).to_le_bytes());
+        inodes.extend((BLOCK_SIZE as u64).to_le_bytes());
+        // nlink, fragment index and offset, xattr index
+        inodes.extend([2u32, 0, 0, 0].map(u32::to_le_bytes).concat());
+        inodes.extend((compressed.len() as u32).to_le_bytes());
+        inodes.extend(0u32.to_le_bytes());
+        let symlink = inodes.len();
+        inodes.extend(inode(3, 0o777, OWNED, 400, 3));
+        inodes.extend([1u32, 4].map(u32::to_le_bytes).concat());
+        inodes.extend(b"file");
+        let fifo = inodes.len();
+        inodes.extend(inode(6, 0o600, ROOT, 500, 4));
+        inodes.extend(1u32.to_le_bytes());
+        let null = inodes.len();
+        inodes.extend(inode(5, 0o666, ROOT, 500, 5));
+        inodes.extend([1u32, 0x103].map(u32::to_le_bytes).concat());
+
+        let subdir_listing = dir_listing(&[dir_entry(file, 2, "hardlink")]);
+        let subdir = inodes.len();
+        inodes.extend(inode(1, 0o700, OWNED, 200, 6));
+        inodes.extend([0u32, 2].map(u32::to_le_bytes).concat());
+        inodes.extend((subdir_listing.len() as u16 + 3).to_le_bytes());
+        inodes.extend(0u16.to_le_bytes());
+        inodes.extend(1u32.to_le_bytes());
+        let root_listing = dir_listing(&[
+            dir_entry(file, 2, "file"),
+            dir_entry(fifo, 6, "fifo"),
+            dir_entry(null, 5, "null"),
+            dir_entry(subdir, 1, "subdir"),
+            dir_entry(symlink, 3, "symlink"),
+        ]);
+        let root = inodes.len();
+        inodes.extend(inode(1, 0o755, ROOT, 100, 1));
+        inodes.extend([0u32, 3].map(u32::to_le_bytes).concat());
+        inodes.extend((root_listing.len() as u16 + 3).to_le_bytes());
+        inodes.extend((subdir_listing.len() as u16).to_le_bytes());
+        inodes.extend(7u32.to_le_bytes());
+
+        let inode_table_start = image.len() as u64;
+        image.extend(metadata(&inodes));
+        let directory_table_start = image.len() as u64;
+        image.extend(metadata(&[subdir_listing, root_listing].concat()));
+
+        let mut table = |entries: Vec<u8>| {
+            let block = image.len() as u64;
+            image.extend(metadata(&entries));
+            let index = image.len() as u64;
+            image.extend(block.to_le_bytes());
+            index
+        };
+        let fragment_table_start = table(
+            [
+                &fragment_start.to_le_bytes()[..],
+                &((contents.len() - 2 * BLOCK_SIZE) as u32 | DATA_UNCOMPRESSED).to_le_bytes(),
+                &0u32.to_le_bytes(),
+            ]
+            .concat(),
+        );
+        let id_table_start = table([0u32, 42, 43].map(u32::to_le_bytes).concat());
+
+        // the capability is stored out-of-line
+        let mut kv = (CAPABILITY.len() as u32).to_le_bytes().to_vec();
+        kv.extend(CAPABILITY);
+        let xattrs = kv.len() as u64;
+        kv.extend([0u16, 3].map(u16::to_le_bytes).concat());
+        kv.extend(b"foo");
+        kv.extend(3u32.to_le_bytes());
+        kv.extend(b"bar");
+        kv.extend([0x102u16, 10].map(u16::to_le_bytes).concat());
+        kv.extend(b"capability");
+        kv.extend(8u32.to_le_bytes());
+        kv.extend(0u64.to_le_bytes());
+        let kv_start = image.len() as u64;
+        image.extend(metadata(&kv));
+        let ids = image.len() as u64;
+        image.extend(metadata(
+            &[
+                &xattrs.to_le_bytes()[..],
+                &2u32.to_le_bytes(),
+                &0u32.to_le_bytes(),
+            ]
+            .concat(),
+        ));
+        let xattr_id_table_start = image.len() as u64;
+        image.extend(kv_start.to_le_bytes());
+        image.extend([1u32, 0].map(u32::to_le_bytes).concat());
+        image.extend(ids.to_le_bytes());
+
+        let bytes_used = image.len() as u64;
+        let superblock = [
+            &[MAGIC, 6, 0, BLOCK_SIZE as u32, 1]
+                .map(u32::to_le_bytes)
+                .concat()[..],
+            // compressor, block_log, flags, id count, version
+            &[compressor, 12, 0, 3, 4, 0].map(u16::to_le_bytes).concat(),
+            &[
+                root as u64,
+                bytes_used,
+                id_table_start,
+                xattr_id_table_start,
+                inode_table_start,
+                directory_table_start,
+                fragment_table_start,
+                u64::MAX,
+            ]
+            .map(u64::to_le_bytes)
+            .concat(),
+        ]
+        .concat();
+        image[..SUPERBLOCK_LEN].copy_from_slice(&superblock);
+        image
+    }
+
+    #[test]
+    #[ignore = "must run as root"]
+    fn extract() {
+        let (_tmp, root) = extract_image(&build(GZIP), extract_squashfs);
+        check_tree(&root);
+    }
+
+    #[test]
+    #[ignore = "must run as root"]
+    fn extract_lz4() {
+        let (_tmp, root) = extract_image(&build(LZ4), extract_squashfs);
+        check_tree(&root);
+    }
+
+    #[test]
+    fn unsupported_compressor() {
+        let mut image = build(GZIP);
+        // lzo
+        image[20] = 3;
+        let tmp = tempfile::NamedTempFile::new().expect("failed to create tempfile");
+        std::fs::write(tmp.path(), &image).expect("failed to write image");
+        let err = Squashfs::open(File::open(tmp.path()).expect("failed to open image"))
+            .err()
+            .expect("lzo should be rejected");
+        assert!(err.to_string().contains("lzo"), "{err}");
+    }
+}
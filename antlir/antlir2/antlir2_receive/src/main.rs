@@ -7,11 +7,15 @@
 
 use std::fs::File;
 use std::io::BufReader;
+use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
+use std::process::Stdio;
 
 use antlir2_btrfs::Subvolume;
 use antlir2_cas_dir::CasDir;
+use antlir2_chunk_store::ChunkStore;
+use antlir2_chunk_store::Index;
 use antlir2_working_volume::WorkingVolume;
 use anyhow::anyhow;
 use anyhow::Context;
@@ -20,9 +24,16 @@ use clap::ValueEnum;
 use tracing::trace;
 use tracing::warn;
 use tracing_subscriber::prelude::*;
+use verify::Verifier;
+use verify::VerifyArgs;
 
 #[cfg(facebook)]
 mod caf;
+mod filesystem;
+mod oci;
+mod sendstream;
+mod untar;
+mod verify;
 
 #[derive(Parser, Debug)]
 /// Receive a pre-built image package into the local working volume.
@@ -44,6 +55,27 @@ pub(crate) struct Receive {
     #[clap(long, default_value = "btrfs")]
     /// path to 'btrfs' command
     btrfs: PathBuf,
+    #[clap(long)]
+    /// Local chunk store to reassemble a chunk_store index from. Defaults to
+    /// the store that was packaged alongside the index.
+    chunk_store: Option<PathBuf>,
+    #[clap(long, default_value_t)]
+    /// Platform (os/arch[/variant]) of the manifest to receive out of a
+    /// multi-platform OCI image index. Defaults to the host platform.
+    oci_platform: oci::Platform,
+    #[clap(long)]
+    /// Apply sendstreams in userspace instead of with 'btrfs receive'. This
+    /// is implied when the working dir is not on btrfs, in which case the
+    /// layer is received into a plain directory.
+    userspace: bool,
+    #[clap(long)]
+    /// Previously received layer that an incremental sendstream applies to.
+    /// Incremental sendstreams are always applied in userspace.
+    parent: Option<PathBuf>,
+    #[clap(flatten)]
+    /// Checks of the source (or the reassembled stream for chunk_store) that
+    /// must pass for the layer to be kept
+    verify: VerifyArgs,
 }
 
 #[derive(Debug, Copy, Clone, ValueEnum)]
@@ -52,10 +84,32 @@ pub enum Format {
     #[clap(name = "cas_dir")]
     CasDir,
     Tar,
+    /// Either a packaged chunk store directory, or just the index file (with
+    /// the chunks in --chunk-store)
+    #[clap(name = "chunk_store")]
+    ChunkStore,
+    /// An OCI image layout directory
+    Oci,
+    /// A squashfs image, extracted without mounting it
+    Squashfs,
+    /// An erofs image, extracted without mounting it
+    Erofs,
     #[cfg(facebook)]
     Caf,
 }
 
+impl Format {
+    /// Formats that are received from a single file (or, for chunk_store, a
+    /// single reassembled stream) that can be checked with --verify, as
+    /// opposed to a whole directory
+    fn is_verifiable(&self) -> bool {
+        matches!(
+            self,
+            Self::Sendstream | Self::Tar | Self::ChunkStore | Self::Squashfs | Self::Erofs
+        )
+    }
+}
+
 #[derive(Parser, Debug)]
 struct SetupArgs {
     #[clap(long)]
@@ -106,6 +160,14 @@ impl Receive {
         trace!("setting up WorkingVolume");
         let working_volume = WorkingVolume::ensure(self.setup.working_dir.clone())?;
 
+        if !self.verify.is_empty() && !self.format.is_verifiable() {
+            return Err(anyhow!(
+                "{:?} sources are directories and cannot be verified, only single-file formats can",
+                self.format
+            )
+            .into());
+        }
+
         let rootless = if self.rootless {
             antlir2_rootless::unshare_new_userns().context("while setting up userns")?;
             antlir2_isolate::unshare_and_privatize_mount_ns()
@@ -119,26 +181,129 @@ impl Receive {
 
         let root = rootless.map(|r| r.escalate()).transpose()?;
 
+        let on_btrfs = antlir2_btrfs::ensure_path_is_on_btrfs(&self.setup.working_dir).is_ok();
+
+        if let Err(e) = self.receive_into(&dst, on_btrfs) {
+            // never leave a partially received (and possibly untrusted)
+            // layer lying around
+            if let Err(cleanup) = delete(&dst, on_btrfs) {
+                warn!(
+                    "couldn't delete partial layer '{}': {cleanup:?}",
+                    dst.display()
+                );
+            }
+            return Err(e);
+        }
+
+        if self.output.exists() {
+            trace!("removing existing output {}", self.output.display());
+            // Don't fail if the old subvol couldn't be deleted, just print
+            // a warning. We really don't want to fail someone's build if
+            // the only thing that went wrong is not being able to delete
+            // the last version of it.
+            match Subvolume::open(&self.output) {
+                Ok(old_subvol) => {
+                    if let Err(e) = old_subvol.delete() {
+                        warn!(
+                            "couldn't delete old subvol '{}': {e:?}",
+                            self.output.display()
+                        );
+                    }
+                }
+                Err(_) if !on_btrfs => {
+                    if let Err(e) =
+                        std::fs::read_link(&self.output).and_then(std::fs::remove_dir_all)
+                    {
+                        warn!("couldn't delete old dir '{}': {e:?}", self.output.display());
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "couldn't open old subvol '{}': {e:?}",
+                        self.output.display()
+                    );
+                }
+            }
+        }
+        drop(root);
+
+        let _ = std::fs::remove_file(&self.output);
+        std::os::unix::fs::symlink(&dst, &self.output).context("while making symlink")?;
+
+        Ok(())
+    }
+
+    /// Materialize the layer at `dst`
+    fn receive_into(&self, dst: &Path, on_btrfs: bool) -> Result<()> {
         match self.format {
+            Format::Sendstream if self.userspace || self.parent.is_some() || !on_btrfs => {
+                let mut source = self.open_source()?;
+                match &self.parent {
+                    Some(parent) if on_btrfs => {
+                        trace!("snapshotting parent {}", parent.display());
+                        Subvolume::open(parent)
+                            .context("while opening parent subvol")?
+                            .snapshot(dst, Default::default())
+                            .context("while snapshotting parent")?;
+                    }
+                    Some(parent) => {
+                        sendstream::copy_parent(parent, dst).context("while copying parent")?;
+                    }
+                    None if on_btrfs => {
+                        Subvolume::create(dst).context("while creating subvol")?;
+                    }
+                    None => {
+                        std::fs::create_dir(dst).context("while creating dir")?;
+                    }
+                }
+                sendstream::receive(&mut source, dst, self.parent.as_deref())
+                    .context("while receiving sendstream")?;
+                source.finish().context("while verifying source")?;
+            }
             Format::Sendstream => {
                 // make sure that working_dir is btrfs before we try to invoke
                 // 'btrfs' so that we can fail with a nicely categorized error
                 antlir2_btrfs::ensure_path_is_on_btrfs(&self.setup.working_dir)?;
 
                 let recv_tmp = tempfile::tempdir_in(&self.setup.working_dir)?;
-                let mut cmd = Command::new(self.btrfs);
-                cmd.arg("--quiet")
-                    .arg("receive")
-                    .arg(recv_tmp.path())
-                    .arg("-f")
-                    .arg(&self.source);
+                let mut cmd = Command::new(&self.btrfs);
+                cmd.arg("--quiet").arg("receive").arg(recv_tmp.path());
+                // the source has to go through the verifier on its way to
+                // 'btrfs receive'
+                let source = if self.verify.is_empty() {
+                    cmd.arg("-f").arg(&self.source);
+                    None
+                } else {
+                    cmd.stdin(Stdio::piped());
+                    Some(self.open_source()?)
+                };
                 if self.rootless {
                     cmd.arg("--force-decompress");
                 }
                 trace!("receiving sendstream: {cmd:?}");
-                let res = cmd.spawn()?.wait()?;
-                if !res.success() {
-                    return Err(anyhow!("btrfs-receive failed").into());
+                let mut child = cmd.spawn()?;
+                // stdin is closed at the end of the match so that
+                // btrfs-receive sees the end of the stream
+                let copied = match (source, child.stdin.take()) {
+                    (Some(mut source), Some(mut stdin)) => std::io::copy(&mut source, &mut stdin)
+                        .context("while streaming source to btrfs-receive")
+                        .map(|_| Some(source)),
+                    _ => Ok(None),
+                };
+                let res = child.wait()?;
+                let checked = if res.success() {
+                    copied.and_then(|source| match source {
+                        Some(source) => source.finish().context("while verifying source"),
+                        None => Ok(()),
+                    })
+                } else {
+                    Err(anyhow!("btrfs-receive failed"))
+                };
+                if let Err(e) = checked {
+                    // the received subvolume is readonly, so it would
+                    // otherwise be left behind in the working dir
+                    delete_received(recv_tmp.path());
+                    return Err(e.into());
                 }
                 let entries: Vec<_> = std::fs::read_dir(&recv_tmp)
                     .context("while reading tmp dir")?
@@ -164,66 +329,137 @@ impl Receive {
                     subvol.path().display(),
                     dst.display()
                 );
-                std::fs::rename(subvol.path(), &dst).context("while renaming subvol")?;
+                std::fs::rename(subvol.path(), dst).context("while renaming subvol")?;
             }
             Format::CasDir => {
-                let subvol = Subvolume::create(&dst).context("while creating subvol")?;
-                let cas_dir = CasDir::open(self.source).context("while opening CasDir")?;
+                let subvol = Subvolume::create(dst).context("while creating subvol")?;
+                let cas_dir = CasDir::open(&self.source).context("while opening CasDir")?;
                 cas_dir
                     .hydrate_into(subvol.path())
                     .context("while materializing CasDir")?;
             }
             Format::Tar => {
-                let subvol = Subvolume::create(&dst).context("while creating subvol")?;
-                let mut archive =
-                    tar::Archive::new(BufReader::new(File::open(&self.source).with_context(
-                        || format!("while opening source file {}", self.source.display()),
-                    )?));
-                archive
-                    .unpack(subvol.path())
+                let mut source = self.open_source()?;
+                let subvol = Subvolume::create(dst).context("while creating subvol")?;
+                untar::unpack(untar::decompress(&mut source)?, subvol.path())
                     .context("while unpacking tar")?;
+                source.finish().context("while verifying source")?;
+            }
+            Format::ChunkStore => {
+                let index_path = if self.source.is_dir() {
+                    self.source.join(antlir2_chunk_store::INDEX_FILENAME)
+                } else {
+                    self.source.clone()
+                };
+                let store_path = match &self.chunk_store {
+                    Some(store) => store.clone(),
+                    None => index_path
+                        .parent()
+                        .context("index has no parent dir")?
+                        .join(antlir2_chunk_store::STORE_DIRNAME),
+                };
+                let index = Index::read(&index_path)?;
+                let store = ChunkStore::open(store_path)?;
+                let missing = index.missing(&store)?;
+                if !missing.is_empty() {
+                    return Err(anyhow!(
+                        "chunk store {} is missing {} chunks needed by {} (first is {})",
+                        store.path().display(),
+                        missing.len(),
+                        index_path.display(),
+                        missing[0],
+                    )
+                    .into());
+                }
+                let mut source = Verifier::new(BufReader::new(index.reader(store)), &self.verify)?;
+                let subvol = Subvolume::create(dst).context("while creating subvol")?;
+                // the tar stream is a complete and faithful copy of the layer
+                untar::unpack(&mut source, subvol.path())
+                    .context("while unpacking reassembled tar")?;
+                source
+                    .finish()
+                    .context("while verifying reassembled stream")?;
+            }
+            Format::Oci => {
+                let subvol = Subvolume::create(dst).context("while creating subvol")?;
+                oci::receive(&self.source, &self.oci_platform, subvol.path())
+                    .context("while receiving oci image")?;
+            }
+            Format::Squashfs => {
+                let subvol = Subvolume::create(dst).context("while creating subvol")?;
+                filesystem::extract_squashfs(self.open_image()?, subvol.path())
+                    .context("while extracting squashfs")?;
+            }
+            Format::Erofs => {
+                let subvol = Subvolume::create(dst).context("while creating subvol")?;
+                filesystem::extract_erofs(self.open_image()?, subvol.path())
+                    .context("while extracting erofs")?;
             }
             #[cfg(facebook)]
             Format::Caf => {
-                caf::recv_caf(&self.source, &dst).context("while receiving caf")?;
+                caf::recv_caf(&self.source, dst).context("while receiving caf")?;
             }
         };
-        let mut subvol = Subvolume::open(&dst).context("while opening subvol")?;
-
-        subvol
-            .set_readonly(true)
-            .context("while making subvol ro")?;
+        // only a userspace-received sendstream can end up as a plain directory
+        if on_btrfs {
+            let mut subvol = Subvolume::open(dst).context("while opening subvol")?;
+            subvol
+                .set_readonly(true)
+                .context("while making subvol ro")?;
+        }
+        Ok(())
+    }
 
-        if self.output.exists() {
-            trace!("removing existing output {}", self.output.display());
-            // Don't fail if the old subvol couldn't be deleted, just print
-            // a warning. We really don't want to fail someone's build if
-            // the only thing that went wrong is not being able to delete
-            // the last version of it.
-            match Subvolume::open(&self.output) {
-                Ok(old_subvol) => {
-                    if let Err(e) = old_subvol.delete() {
-                        warn!(
-                            "couldn't delete old subvol '{}': {e:?}",
-                            self.output.display()
-                        );
-                    }
-                }
-                Err(e) => {
-                    warn!(
-                        "couldn't open old subvol '{}': {e:?}",
-                        self.output.display()
-                    );
-                }
-            }
+    /// Filesystem images are read out of order, so they are verified in full
+    /// before anything is extracted from them
+    fn open_image(&self) -> Result<File> {
+        if !self.verify.is_empty() {
+            let mut source = self.open_source()?;
+            std::io::copy(&mut source, &mut std::io::sink()).context("while reading source")?;
+            source.finish().context("while verifying source")?;
         }
-        drop(root);
+        Ok(File::open(&self.source)
+            .with_context(|| format!("while opening source file {}", self.source.display()))?)
+    }
 
-        let _ = std::fs::remove_file(&self.output);
-        std::os::unix::fs::symlink(subvol.path(), &self.output).context("while making symlink")?;
+    fn open_source(&self) -> Result<Verifier<BufReader<File>>> {
+        let f = File::open(&self.source)
+            .with_context(|| format!("while opening source file {}", self.source.display()))?;
+        Ok(Verifier::new(BufReader::new(f), &self.verify)?)
+    }
+}
 
-        Ok(())
+/// Delete any (possibly partial) subvolumes that 'btrfs receive' left behind
+fn delete_received(recv_tmp: &Path) {
+    let entries = match std::fs::read_dir(recv_tmp) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("couldn't read '{}': {e:?}", recv_tmp.display());
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if let Err(e) = delete(&path, true) {
+            warn!(
+                "couldn't delete received subvol '{}': {e:?}",
+                path.display()
+            );
+        }
+    }
+}
+
+/// Delete a layer, whether it's a subvolume or a plain directory
+fn delete(path: &Path, on_btrfs: bool) -> anyhow::Result<()> {
+    if std::fs::symlink_metadata(path).is_err() {
+        return Ok(());
+    }
+    if on_btrfs {
+        if let Ok(subvol) = Subvolume::open(path) {
+            return subvol.delete().map_err(|(_, e)| e.into());
+        }
     }
+    std::fs::remove_dir_all(path).with_context(|| format!("while deleting {}", path.display()))
 }
 
 fn main() -> Result<()> {
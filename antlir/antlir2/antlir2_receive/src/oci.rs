@@ -0,0 +1,681 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Receive an OCI image layout by applying each layer of the selected
+//! manifest in order, the same way an overlayfs-based container runtime
+//! would see the final rootfs.
+
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
+use flate2::read::GzDecoder;
+use oci_spec::image::Arch;
+use oci_spec::image::Descriptor;
+use oci_spec::image::ImageIndex;
+use oci_spec::image::ImageManifest;
+use oci_spec::image::MediaType;
+use oci_spec::image::Os;
+use sha2::Digest;
+use sha2::Sha256;
+use tracing::trace;
+
+use crate::untar;
+use crate::untar::normalize;
+use crate::untar::resolve_in_root;
+use crate::untar::Unpacker;
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// docker-produced layouts still use the docker media type for gzipped layers
+const DOCKER_LAYER_GZIP: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+
+/// Platform to select out of a (possibly multi-platform) image index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Platform {
+    os: Os,
+    arch: Arch,
+    variant: Option<String>,
+}
+
+impl Default for Platform {
+    /// The platform of the host
+    fn default() -> Self {
+        Self {
+            os: Os::default(),
+            arch: Arch::default(),
+            variant: None,
+        }
+    }
+}
+
+impl FromStr for Platform {
+    type Err = anyhow::Error;
+
+    /// Parse the usual `os/arch[/variant]` form
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split('/');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(os), Some(arch), variant, None) if !os.is_empty() && !arch.is_empty() => {
+                Ok(Self {
+                    os: os.into(),
+                    arch: arch.into(),
+                    variant: variant.map(str::to_owned),
+                })
+            }
+            _ => bail!("'{s}' is not a platform of the form os/arch[/variant]"),
+        }
+    }
+}
+
+impl Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.arch)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{variant}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Platform {
+    /// Descriptors without a platform are assumed to be usable anywhere,
+    /// which is how single-platform layouts are usually written
+    fn matches(&self, descriptor: &Descriptor) -> bool {
+        match descriptor.platform() {
+            None => true,
+            Some(p) => {
+                p.os() == &self.os
+                    && p.architecture() == &self.arch
+                    && match &self.variant {
+                        Some(variant) => p.variant().as_ref() == Some(variant),
+                        None => true,
+                    }
+            }
+        }
+    }
+}
+
+struct Layout {
+    root: PathBuf,
+}
+
+impl Layout {
+    fn blob(&self, descriptor: &Descriptor) -> Result<PathBuf> {
+        let digest = descriptor.digest();
+        let hex = digest
+            .strip_prefix("sha256:")
+            .with_context(|| format!("unsupported digest '{digest}'"))?;
+        ensure!(
+            !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()),
+            "malformed digest '{digest}'"
+        );
+        Ok(self.root.join("blobs/sha256").join(hex))
+    }
+
+    fn open_blob(&self, descriptor: &Descriptor) -> Result<BlobReader<File>> {
+        let file = File::open(self.blob(descriptor)?)
+            .with_context(|| format!("while opening blob {}", descriptor.digest()))?;
+        Ok(BlobReader::new(file))
+    }
+
+    /// Read a (small) json blob, after checking it against its descriptor
+    fn read_blob(&self, descriptor: &Descriptor) -> Result<Vec<u8>> {
+        let mut blob = self.open_blob(descriptor)?;
+        let mut contents = Vec::new();
+        blob.read_to_end(&mut contents)
+            .with_context(|| format!("while reading blob {}", descriptor.digest()))?;
+        blob.finish(descriptor)?;
+        Ok(contents)
+    }
+
+    /// Find the image manifest for the given platform, descending into nested
+    /// indexes if necessary
+    fn manifest(&self, index: &ImageIndex, platform: &Platform) -> Result<Option<ImageManifest>> {
+        for descriptor in index.manifests() {
+            if !platform.matches(descriptor) {
+                continue;
+            }
+            match descriptor.media_type() {
+                MediaType::ImageManifest => {
+                    return ImageManifest::from_reader(self.read_blob(descriptor)?.as_slice())
+                        .with_context(|| {
+                            format!("while reading image manifest {}", descriptor.digest())
+                        })
+                        .map(Some);
+                }
+                MediaType::ImageIndex => {
+                    let nested = ImageIndex::from_reader(self.read_blob(descriptor)?.as_slice())
+                        .with_context(|| {
+                            format!("while reading nested index {}", descriptor.digest())
+                        })?;
+                    if let Some(manifest) = self.manifest(&nested, platform)? {
+                        return Ok(Some(manifest));
+                    }
+                }
+                other => trace!("skipping {} with media type {other}", descriptor.digest()),
+            }
+        }
+        Ok(None)
+    }
+
+    fn layer_reader<'a>(
+        &self,
+        layer: &Descriptor,
+        blob: &'a mut BlobReader<File>,
+    ) -> Result<Box<dyn Read + 'a>> {
+        let blob = BufReader::new(blob);
+        Ok(match layer.media_type() {
+            MediaType::ImageLayer | MediaType::ImageLayerNonDistributable => Box::new(blob),
+            MediaType::ImageLayerGzip | MediaType::ImageLayerNonDistributableGzip => {
+                Box::new(GzDecoder::new(blob))
+            }
+            MediaType::Other(media_type) if media_type == DOCKER_LAYER_GZIP => {
+                Box::new(GzDecoder::new(blob))
+            }
+            MediaType::ImageLayerZstd | MediaType::ImageLayerNonDistributableZstd => Box::new(
+                zstd::Decoder::with_buffer(blob).context("while setting up zstd decompression")?,
+            ),
+            other => bail!("unsupported layer media type '{other}'"),
+        })
+    }
+}
+
+/// Unpack the image for `platform` from the OCI layout directory `layout` into
+/// `dst`, which should be an empty directory.
+pub(crate) fn receive(layout: &Path, platform: &Platform, dst: &Path) -> Result<()> {
+    let layout = Layout {
+        root: layout.to_owned(),
+    };
+    let index =
+        ImageIndex::from_file(layout.root.join("index.json")).context("while reading oci index")?;
+    let manifest = layout
+        .manifest(&index, platform)?
+        .with_context(|| format!("no image manifest for platform {platform}"))?;
+    for layer in manifest.layers() {
+        trace!("applying layer {}", layer.digest());
+        let mut blob = layout.open_blob(layer)?;
+        apply_layer(layout.layer_reader(layer, &mut blob)?, dst)
+            .with_context(|| format!("while applying layer {}", layer.digest()))?;
+        blob.finish(layer)?;
+    }
+    Ok(())
+}
+
+/// [Read] wrapper that checks a blob against the digest and size in its
+/// descriptor as it is read. Like [crate::verify::Verifier], a layer has
+/// already been applied by the time a mismatch is found, so the whole receive
+/// must be thrown away if [BlobReader::finish] fails.
+struct BlobReader<R> {
+    inner: R,
+    sha256: Sha256,
+    len: u64,
+}
+
+impl<R: Read> BlobReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            sha256: Sha256::new(),
+            len: 0,
+        }
+    }
+
+    /// Consume anything that wasn't needed (like padding after the end of a
+    /// tar) and check the result
+    fn finish(mut self, descriptor: &Descriptor) -> Result<()> {
+        std::io::copy(&mut self, &mut std::io::sink())
+            .with_context(|| format!("while reading the rest of blob {}", descriptor.digest()))?;
+        ensure!(
+            i64::try_from(self.len).ok() == Some(descriptor.size()),
+            "blob {} is {} bytes, but its descriptor says {}",
+            descriptor.digest(),
+            self.len,
+            descriptor.size()
+        );
+        let actual = format!("sha256:{}", hex::encode(self.sha256.finalize()));
+        ensure!(
+            &actual == descriptor.digest(),
+            "digest mismatch: expected {}, got {actual}",
+            descriptor.digest()
+        );
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for BlobReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.sha256.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+/// Apply a single layer tarball on top of whatever is already in `dst`.
+///
+/// Whiteouts only ever hide things from lower layers, so everything unpacked
+/// by this layer is tracked and left alone, regardless of whether the
+/// whiteout comes before or after it in the archive.
+fn apply_layer<R: Read>(reader: R, dst: &Path) -> Result<()> {
+    let mut archive = untar::archive(reader);
+    let mut unpacker = Unpacker::new(dst);
+    let mut this_layer = HashSet::new();
+    for entry in archive.entries().context("while reading layer entries")? {
+        let mut entry = entry.context("while reading layer entry")?;
+        let path = normalize(&entry.path().context("while reading entry path")?)?;
+        let name = match path.file_name() {
+            Some(name) => name.to_owned(),
+            // the root directory itself
+            None => continue,
+        };
+        let parent = path.parent().unwrap_or(Path::new(""));
+
+        if name == OPAQUE_WHITEOUT {
+            trace!("opaque whiteout of /{}", parent.display());
+            let dir = resolve_in_root(dst, &path)?
+                .parent()
+                .context("opaque whiteout has no parent")?
+                .to_owned();
+            remove_lower(&dir, parent, &this_layer).with_context(|| {
+                format!("while applying opaque whiteout of /{}", parent.display())
+            })?;
+            continue;
+        }
+        if let Some(hidden) = name.to_str().and_then(|n| n.strip_prefix(WHITEOUT_PREFIX)) {
+            let hidden = parent.join(hidden);
+            if this_layer.contains(&hidden) {
+                continue;
+            }
+            trace!("whiteout of /{}", hidden.display());
+            let target = resolve_in_root(dst, &hidden)?;
+            remove(&target)
+                .with_context(|| format!("while applying whiteout of /{}", hidden.display()))?;
+            continue;
+        }
+
+        let target = resolve_in_root(dst, &path)?;
+        // tar happily replaces files and symlinks, but not something of a
+        // different type (eg a directory replacing a symlink to one)
+        if let Ok(meta) = std::fs::symlink_metadata(&target) {
+            if meta.is_dir() != entry.header().entry_type().is_dir() {
+                remove(&target).with_context(|| {
+                    format!("while replacing lower layer's /{}", path.display())
+                })?;
+            }
+        }
+        unpacker.unpack(&mut entry, &path)?;
+        // parent directories are implicitly created by this layer too
+        for ancestor in path.ancestors() {
+            if !this_layer.insert(ancestor.to_owned()) {
+                break;
+            }
+        }
+    }
+    // deleting whiteouts bumps the mtime of the parent directory too, so
+    // this must come after everything else in the layer
+    unpacker.finish()
+}
+
+/// Remove whatever is at `path` (if anything), without following symlinks
+fn remove(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => std::fs::remove_dir_all(path)
+            .with_context(|| format!("while removing dir {}", path.display())),
+        Ok(_) => {
+            std::fs::remove_file(path).with_context(|| format!("while removing {}", path.display()))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("while statting {}", path.display())),
+    }
+}
+
+/// Remove everything under `dir` that was not put there by the current layer
+fn remove_lower(dir: &Path, relpath: &Path, this_layer: &HashSet<PathBuf>) -> Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("while reading {}", dir.display())),
+    };
+    for entry in entries {
+        let entry = entry.with_context(|| format!("while reading {}", dir.display()))?;
+        let child = relpath.join(entry.file_name());
+        if this_layer.contains(&child) {
+            // a directory from this layer may still have lower contents
+            // underneath it if it was merged with one from a lower layer
+            if entry.file_type()?.is_dir() {
+                remove_lower(&entry.path(), &child, this_layer)?;
+            }
+        } else {
+            remove(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use oci_spec::image::DescriptorBuilder;
+    use oci_spec::image::ImageIndexBuilder;
+    use oci_spec::image::ImageManifestBuilder;
+
+    use super::*;
+
+    enum Entry<'a> {
+        Dir(&'a str),
+        File(&'a str, &'a str),
+        Symlink(&'a str, &'a str),
+    }
+
+    fn layer(entries: &[Entry]) -> Vec<u8> {
+        // ownership is preserved, so make everything owned by whoever is
+        // running the test
+        let me = tempfile::tempfile()
+            .and_then(|f| f.metadata())
+            .expect("failed to stat tempfile");
+        let mut builder = tar::Builder::new(Vec::new());
+        for entry in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_uid(me.uid().into());
+            header.set_gid(me.gid().into());
+            header.set_mtime(1234);
+            match entry {
+                Entry::Dir(path) => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_mode(0o755);
+                    header.set_size(0);
+                    builder.append_data(&mut header, path, std::io::empty())
+                }
+                Entry::File(path, contents) => {
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_mode(0o644);
+                    header.set_size(contents.len() as u64);
+                    builder.append_data(&mut header, path, contents.as_bytes())
+                }
+                Entry::Symlink(path, target) => {
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_mode(0o777);
+                    header.set_size(0);
+                    builder.append_link(&mut header, path, target)
+                }
+            }
+            .expect("failed to append entry");
+        }
+        builder.into_inner().expect("failed to finish layer")
+    }
+
+    fn tree(root: &Path) -> Vec<String> {
+        let mut paths = Vec::new();
+        let mut stack = vec![root.to_owned()];
+        while let Some(dir) = stack.pop() {
+            for entry in std::fs::read_dir(&dir).expect("failed to read dir") {
+                let entry = entry.expect("failed to read entry");
+                let rel = entry
+                    .path()
+                    .strip_prefix(root)
+                    .expect("not under root")
+                    .to_str()
+                    .expect("non-utf8 path")
+                    .to_owned();
+                if entry.file_type().expect("no file type").is_dir() {
+                    stack.push(entry.path());
+                    paths.push(rel + "/");
+                } else {
+                    paths.push(rel);
+                }
+            }
+        }
+        paths.sort();
+        paths
+    }
+
+    /// Write `contents` into the layout as a blob and describe it
+    fn add_blob(root: &Path, media_type: MediaType, contents: &[u8]) -> Descriptor {
+        let digest = hex::encode(Sha256::digest(contents));
+        let blobs = root.join("blobs/sha256");
+        std::fs::create_dir_all(&blobs).expect("failed to create blobs dir");
+        std::fs::write(blobs.join(&digest), contents).expect("failed to write blob");
+        DescriptorBuilder::default()
+            .media_type(media_type)
+            .digest(format!("sha256:{digest}"))
+            .size(contents.len() as i64)
+            .build()
+            .expect("failed to build descriptor")
+    }
+
+    /// Layout with a single (gzipped) layer, behind a nested index.
+    /// `layer_descriptor` may tamper with the layer's descriptor.
+    fn image_layout(root: &Path, layer_descriptor: impl FnOnce(Descriptor) -> Descriptor) {
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut gz, &layer(&[Entry::File("hello", "world")]))
+            .expect("failed to gzip");
+        let layer = add_blob(
+            root,
+            MediaType::ImageLayerGzip,
+            &gz.finish().expect("failed to gzip"),
+        );
+        let config = add_blob(root, MediaType::ImageConfig, b"{}");
+        let manifest = ImageManifestBuilder::default()
+            .schema_version(2u32)
+            .config(config)
+            .layers(vec![layer_descriptor(layer)])
+            .build()
+            .expect("failed to build manifest");
+        let manifest = add_blob(
+            root,
+            MediaType::ImageManifest,
+            manifest
+                .to_string()
+                .expect("failed to serialize manifest")
+                .as_bytes(),
+        );
+        let nested = ImageIndexBuilder::default()
+            .schema_version(2u32)
+            .manifests(vec![manifest])
+            .build()
+            .expect("failed to build index");
+        let nested = add_blob(
+            root,
+            MediaType::ImageIndex,
+            nested
+                .to_string()
+                .expect("failed to serialize index")
+                .as_bytes(),
+        );
+        ImageIndexBuilder::default()
+            .schema_version(2u32)
+            .manifests(vec![nested])
+            .build()
+            .expect("failed to build index")
+            .to_file(root.join("index.json"))
+            .expect("failed to write index");
+    }
+
+    /// Path of the blob for the first descriptor in the (nested) index
+    fn first_blob(layout: &Path, index: &Path) -> PathBuf {
+        let index = ImageIndex::from_file(index).expect("failed to read index");
+        Layout {
+            root: layout.to_owned(),
+        }
+        .blob(&index.manifests()[0])
+        .expect("failed to find blob")
+    }
+
+    fn receive_layout(layout: &Path) -> Result<()> {
+        let dst = tempfile::tempdir().expect("failed to create tempdir");
+        receive(layout, &Platform::default(), dst.path())?;
+        assert_eq!(
+            std::fs::read_to_string(dst.path().join("hello")).expect("failed to read"),
+            "world"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn nested_index() {
+        let layout = tempfile::tempdir().expect("failed to create tempdir");
+        image_layout(layout.path(), |layer| layer);
+        receive_layout(layout.path()).expect("failed to receive");
+    }
+
+    #[test]
+    fn blob_mismatch() {
+        let layout = tempfile::tempdir().expect("failed to create tempdir");
+        image_layout(layout.path(), |mut layer| {
+            layer.set_size(layer.size() + 1);
+            layer
+        });
+        let err = receive_layout(layout.path()).expect_err("layer size is wrong");
+        assert!(
+            format!("{err:#}").contains("but its descriptor says"),
+            "unexpected error: {err:#}"
+        );
+
+        let layout = tempfile::tempdir().expect("failed to create tempdir");
+        image_layout(layout.path(), |layer| layer);
+        let nested = first_blob(layout.path(), &layout.path().join("index.json"));
+        let manifest = ImageManifest::from_file(first_blob(layout.path(), &nested))
+            .expect("failed to read manifest");
+        let blob = Layout {
+            root: layout.path().to_owned(),
+        }
+        .blob(&manifest.layers()[0])
+        .expect("failed to find layer");
+        // still a valid layer of the same size, just with different contents
+        let mut contents = std::fs::read(&blob).expect("failed to read layer");
+        // the gzip mtime field, which doesn't change what is unpacked
+        contents[4] ^= 0xff;
+        std::fs::write(&blob, contents).expect("failed to write layer");
+        let err = receive_layout(layout.path()).expect_err("layer digest is wrong");
+        assert!(
+            format!("{err:#}").contains("digest mismatch"),
+            "unexpected error: {err:#}"
+        );
+    }
+
+    #[test]
+    fn corrupt_nested_manifest() {
+        let layout = tempfile::tempdir().expect("failed to create tempdir");
+        image_layout(layout.path(), |layer| layer);
+        let nested = first_blob(layout.path(), &layout.path().join("index.json"));
+        let manifest = first_blob(layout.path(), &nested);
+        std::fs::write(manifest, b"not json").expect("failed to corrupt manifest");
+        let err = receive_layout(layout.path()).expect_err("manifest is corrupt");
+        assert!(
+            !format!("{err:#}").contains("no image manifest"),
+            "error was swallowed: {err:#}"
+        );
+    }
+
+    #[test]
+    fn platform() {
+        let p: Platform = "linux/arm64/v8".parse().expect("failed to parse");
+        assert_eq!(p.os, Os::Linux);
+        assert_eq!(p.arch, Arch::ARM64);
+        assert_eq!(p.variant.as_deref(), Some("v8"));
+        assert_eq!(p.to_string(), "linux/arm64/v8");
+        assert_eq!(
+            "linux/amd64".parse::<Platform>().expect("failed to parse"),
+            Platform {
+                os: Os::Linux,
+                arch: Arch::Amd64,
+                variant: None,
+            }
+        );
+        assert!("linux".parse::<Platform>().is_err());
+        assert!("linux/amd64/v2/extra".parse::<Platform>().is_err());
+    }
+
+    #[test]
+    fn whiteouts() {
+        let dst = tempfile::tempdir().expect("failed to create tempdir");
+        apply_layer(
+            &layer(&[
+                Entry::Dir("etc/"),
+                Entry::File("etc/keep", "keep"),
+                Entry::File("etc/remove", "remove"),
+                Entry::Dir("opaque/"),
+                Entry::File("opaque/old", "old"),
+                Entry::Dir("opaque/sub/"),
+                Entry::File("opaque/sub/old", "old"),
+                Entry::Dir("replaced/"),
+                Entry::File("replaced/child", "child"),
+            ])[..],
+            dst.path(),
+        )
+        .expect("failed to apply first layer");
+        apply_layer(
+            &layer(&[
+                Entry::Dir("etc/"),
+                Entry::File("etc/.wh.remove", ""),
+                Entry::File("etc/.wh.missing", ""),
+                // added in the same layer as the opaque marker, so it stays
+                Entry::File("opaque/sub/new", "new"),
+                Entry::File("opaque/.wh..wh..opq", ""),
+                // a directory turned into a file
+                Entry::File("replaced", "now a file"),
+                // whiteout after the thing it would hide in the same layer
+                Entry::File("same-layer", "stays"),
+                Entry::File(".wh.same-layer", ""),
+            ])[..],
+            dst.path(),
+        )
+        .expect("failed to apply second layer");
+        assert_eq!(
+            tree(dst.path()),
+            [
+                "etc/",
+                "etc/keep",
+                "opaque/",
+                "opaque/sub/",
+                "opaque/sub/new",
+                "replaced",
+                "same-layer",
+            ]
+        );
+        assert_eq!(
+            std::fs::read_to_string(dst.path().join("replaced")).expect("failed to read"),
+            "now a file"
+        );
+        let meta = std::fs::metadata(dst.path().join("etc")).expect("failed to stat");
+        assert_eq!(meta.mtime(), 1234, "dir mtime was not restored");
+    }
+
+    #[test]
+    fn whiteout_through_symlink() {
+        let dst = tempfile::tempdir().expect("failed to create tempdir");
+        let outside = tempfile::tempdir().expect("failed to create tempdir");
+        std::fs::write(outside.path().join("precious"), "precious").expect("failed to write");
+        apply_layer(
+            &layer(&[Entry::Symlink(
+                "escape",
+                outside.path().to_str().expect("non-utf8 tmpdir"),
+            )])[..],
+            dst.path(),
+        )
+        .expect("failed to apply first layer");
+        apply_layer(
+            &layer(&[Entry::File("escape/.wh.precious", "")])[..],
+            dst.path(),
+        )
+        .expect_err("whiteout through a symlink should fail");
+        assert!(outside.path().join("precious").exists());
+    }
+}
@@ -0,0 +1,652 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Userspace implementation of `btrfs receive` that applies a sendstream to
+//! an ordinary directory tree, so that sendstreams can be received onto
+//! filesystems other than btrfs.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
+use nix::sys::stat::SFlag;
+use nix::sys::stat::UtimensatFlags;
+use nix::sys::time::TimeSpec;
+use sendstream_parser::wire::ParserControl;
+use sendstream_parser::Command;
+use tracing::trace;
+use uuid::Uuid;
+
+const SENDSTREAM_MAGIC: &[u8] = b"btrfs-stream\0";
+
+/// Apply every sendstream in `source` to `dst`.
+///
+/// The first stream may be a full stream (in which case `dst` should be
+/// empty), or an incremental stream (in which case `dst` must already contain
+/// the parent). Clone sources are looked up in `dst` when they come from the
+/// subvolume being received (or one received earlier into `dst` by the same
+/// source), and otherwise in `parent`, which is the unmodified parent of the
+/// first incremental stream.
+pub(crate) fn receive<R: Read + Send + Unpin>(
+    mut source: R,
+    dst: &Path,
+    parent: Option<&Path>,
+) -> Result<()> {
+    let header = check_version(&mut source)?;
+    let mut receiver = Receiver {
+        root: dst.to_owned(),
+        parent: parent.map(Path::to_owned),
+        clone_sources: HashMap::new(),
+        error: None,
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("while building tokio runtime")?;
+    let num_cmds = runtime.block_on(async {
+        let reader = BlockingReader(std::io::Cursor::new(header).chain(source));
+        sendstream_parser::wire::parse(tokio::io::BufReader::new(reader), |cmd| {
+            match receiver.apply(cmd) {
+                Ok(()) => ParserControl::KeepGoing,
+                Err(e) => {
+                    receiver.error = Some(e.context(format!("while applying {cmd:?}")));
+                    ParserControl::Enough
+                }
+            }
+        })
+        .await
+        .context("while parsing sendstream")
+    })?;
+    if let Some(e) = receiver.error {
+        return Err(e);
+    }
+    trace!("applied {num_cmds} sendstream commands");
+    Ok(())
+}
+
+/// Prepare `dst` for an incremental sendstream by copying the whole `parent`
+/// layer into it (reflinking where possible).
+///
+/// `parent` is usually the symlink to the layer in buck-out, so it is copied
+/// by its contents, never as a link, since the incremental stream would
+/// otherwise be applied to the parent itself.
+pub(crate) fn copy_parent(parent: &Path, dst: &Path) -> Result<()> {
+    trace!("copying parent {} to {}", parent.display(), dst.display());
+    std::fs::create_dir(dst).with_context(|| format!("while creating {}", dst.display()))?;
+    let res = std::process::Command::new("cp")
+        .arg("--archive")
+        .arg("--reflink=auto")
+        .arg("--no-target-directory")
+        .arg(parent.join("."))
+        .arg(dst)
+        .status()
+        .context("while running cp")?;
+    ensure!(res.success(), "failed to copy {}", parent.display());
+    Ok(())
+}
+
+/// sendstream_parser only understands the commands from v1 of the protocol,
+/// so refuse v2 streams up front rather than failing halfway through.
+/// Returns the header that was consumed from `source`.
+fn check_version<R: Read>(source: &mut R) -> Result<[u8; SENDSTREAM_MAGIC.len() + 4]> {
+    let mut header = [0; SENDSTREAM_MAGIC.len() + 4];
+    source
+        .read_exact(&mut header)
+        .context("while reading sendstream header")?;
+    let (magic, version) = header.split_at(SENDSTREAM_MAGIC.len());
+    ensure!(magic == SENDSTREAM_MAGIC, "source is not a sendstream");
+    let version = u32::from_le_bytes(version.try_into().context("malformed header")?);
+    ensure!(
+        version == 1,
+        "only v1 sendstreams can be received in userspace, source is v{version}"
+    );
+    Ok(header)
+}
+
+/// The parser wants an [tokio::io::AsyncRead], but the source may be any
+/// [Read] (for example one that is verifying the source as it goes). Blocking
+/// in here is fine, since the parser is the only thing running on the runtime.
+struct BlockingReader<R>(R);
+
+impl<R: Read + Unpin> tokio::io::AsyncRead for BlockingReader<R> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let n = self.0.read(buf.initialize_unfilled())?;
+        buf.advance(n);
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+struct Receiver {
+    root: PathBuf,
+    parent: Option<PathBuf>,
+    /// Directories that contain the contents of each subvolume that a clone
+    /// may refer to
+    clone_sources: HashMap<Uuid, PathBuf>,
+    /// The parser callback can't fail, so the first error is stashed here and
+    /// parsing is stopped
+    error: Option<anyhow::Error>,
+}
+
+impl Receiver {
+    /// Resolve a path from the sendstream under `root`, refusing anything that
+    /// would escape it (either lexically or through a symlink)
+    fn resolve(root: &Path, path: &Path) -> Result<PathBuf> {
+        let mut resolved = root.to_owned();
+        let mut components = path.components().peekable();
+        while let Some(component) = components.next() {
+            match component {
+                Component::Normal(c) => resolved.push(c),
+                Component::CurDir => continue,
+                _ => bail!("illegal path in sendstream: {}", path.display()),
+            }
+            // the final component itself is allowed to be a symlink, since
+            // commands never follow it
+            if components.peek().is_some() {
+                match std::fs::symlink_metadata(&resolved) {
+                    Ok(meta) => ensure!(
+                        !meta.file_type().is_symlink(),
+                        "{} traverses symlink {}",
+                        path.display(),
+                        resolved.display()
+                    ),
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => {
+                        return Err(e)
+                            .with_context(|| format!("while statting {}", resolved.display()));
+                    }
+                }
+            }
+        }
+        Ok(resolved)
+    }
+
+    fn path(&self, path: &Path) -> Result<PathBuf> {
+        Self::resolve(&self.root, path)
+    }
+
+    fn open_rw(&self, path: &Path) -> Result<File> {
+        let path = self.path(path)?;
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(nix::libc::O_NOFOLLOW)
+            .open(&path)
+            .with_context(|| format!("while opening {}", path.display()))
+    }
+
+    fn apply(&mut self, cmd: &Command<'_>) -> Result<()> {
+        match cmd {
+            Command::Subvol(s) => {
+                trace!("receiving full sendstream for {}", s.uuid());
+                self.clone_sources.insert(s.uuid(), self.root.clone());
+            }
+            Command::Snapshot(s) => {
+                trace!(
+                    "receiving incremental sendstream for {} (parent {})",
+                    s.uuid(),
+                    s.clone_uuid()
+                );
+                // if the parent is not known already (from an earlier stream
+                // in this same source), it must be what was passed as the parent
+                if !self.clone_sources.contains_key(&s.clone_uuid()) {
+                    let parent = self.parent.take().with_context(|| {
+                        format!("incremental sendstream requires parent {}", s.clone_uuid())
+                    })?;
+                    self.clone_sources.insert(s.clone_uuid(), parent);
+                }
+                self.clone_sources.insert(s.uuid(), self.root.clone());
+            }
+            Command::End => {}
+            Command::Mkdir(m) => {
+                let path = self.path(m.path().as_path())?;
+                std::fs::create_dir(&path)
+                    .with_context(|| format!("while creating dir {}", path.display()))?;
+            }
+            Command::Mkfile(m) => {
+                let path = self.path(m.path().as_path())?;
+                File::create_new(&path)
+                    .with_context(|| format!("while creating file {}", path.display()))?;
+            }
+            Command::Mkfifo(m) => self.mknod(m.path().as_path(), m.mode(), m.rdev())?,
+            Command::Mknod(m) => self.mknod(m.path().as_path(), m.mode(), m.rdev())?,
+            Command::Mksock(m) => self.mknod(m.path().as_path(), m.mode(), m.rdev())?,
+            Command::Symlink(s) => {
+                let path = self.path(s.link_name())?;
+                std::os::unix::fs::symlink(s.target().as_path(), &path)
+                    .with_context(|| format!("while creating symlink {}", path.display()))?;
+            }
+            Command::Link(l) => {
+                let target = self.path(l.target().as_path())?;
+                let path = self.path(l.link_name())?;
+                std::fs::hard_link(&target, &path).with_context(|| {
+                    format!(
+                        "while hardlinking {} -> {}",
+                        path.display(),
+                        target.display()
+                    )
+                })?;
+            }
+            Command::Rename(r) => {
+                let from = self.path(r.from())?;
+                let to = self.path(r.to())?;
+                std::fs::rename(&from, &to).with_context(|| {
+                    format!("while renaming {} -> {}", from.display(), to.display())
+                })?;
+            }
+            Command::Unlink(u) => {
+                let path = self.path(u.path())?;
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("while unlinking {}", path.display()))?;
+            }
+            Command::Rmdir(r) => {
+                let path = self.path(r.path())?;
+                std::fs::remove_dir(&path)
+                    .with_context(|| format!("while removing dir {}", path.display()))?;
+            }
+            Command::Write(w) => {
+                self.open_rw(w.path())?
+                    .write_all_at(w.data().as_slice(), w.offset().as_u64())
+                    .with_context(|| format!("while writing to {}", w.path().display()))?;
+            }
+            Command::Clone(c) => {
+                let src_root = self.clone_sources.get(&c.uuid()).with_context(|| {
+                    format!("clone source subvolume {} is not available", c.uuid())
+                })?;
+                let src_path = Self::resolve(src_root, c.src_path())?;
+                let mut src = OpenOptions::new()
+                    .read(true)
+                    .custom_flags(nix::libc::O_NOFOLLOW)
+                    .open(&src_path)
+                    .with_context(|| format!("while opening {}", src_path.display()))?;
+                let mut dst = self.open_rw(c.dst_path())?;
+                src.seek(SeekFrom::Start(c.src_offset().as_u64()))?;
+                dst.seek(SeekFrom::Start(c.dst_offset().as_u64()))?;
+                // std uses copy_file_range under the hood, which will reflink
+                // when the underlying filesystem supports it
+                let copied = std::io::copy(&mut src.take(c.len().as_u64()), &mut dst)
+                    .with_context(|| {
+                        format!(
+                            "while cloning {} into {}",
+                            src_path.display(),
+                            c.dst_path().display()
+                        )
+                    })?;
+                ensure!(
+                    copied == c.len().as_u64(),
+                    "clone source {} is too short",
+                    src_path.display()
+                );
+            }
+            Command::Truncate(t) => {
+                self.open_rw(t.path())?
+                    .set_len(t.size())
+                    .with_context(|| format!("while truncating {}", t.path().display()))?;
+            }
+            Command::Chmod(c) => {
+                let path = self.path(c.path())?;
+                // chmod(2) always follows symlinks, and 'btrfs send' never
+                // chmods one (their mode is meaningless anyway)
+                let meta = std::fs::symlink_metadata(&path)
+                    .with_context(|| format!("while statting {}", path.display()))?;
+                ensure!(
+                    !meta.file_type().is_symlink(),
+                    "refusing to chmod symlink {}",
+                    path.display()
+                );
+                std::fs::set_permissions(&path, c.mode().permissions())
+                    .with_context(|| format!("while chmodding {}", path.display()))?;
+            }
+            Command::Chown(c) => {
+                let path = self.path(c.path())?;
+                std::os::unix::fs::lchown(&path, Some(c.uid().as_raw()), Some(c.gid().as_raw()))
+                    .with_context(|| format!("while chowning {}", path.display()))?;
+            }
+            Command::SetXattr(s) => {
+                let path = self.path(s.path())?;
+                xattr::set(&path, OsStr::from_bytes(s.name()), s.data().as_slice()).with_context(
+                    || {
+                        format!(
+                            "while setting xattr {} on {}",
+                            String::from_utf8_lossy(s.name()),
+                            path.display()
+                        )
+                    },
+                )?;
+            }
+            Command::RemoveXattr(r) => {
+                let path = self.path(r.path())?;
+                xattr::remove(&path, OsStr::from_bytes(r.name())).with_context(|| {
+                    format!(
+                        "while removing xattr {} from {}",
+                        String::from_utf8_lossy(r.name()),
+                        path.display()
+                    )
+                })?;
+            }
+            Command::Utimes(u) => {
+                let path = self.path(u.path())?;
+                nix::sys::stat::utimensat(
+                    None,
+                    &path,
+                    &timespec(*u.atime())?,
+                    &timespec(*u.mtime())?,
+                    UtimensatFlags::NoFollowSymlink,
+                )
+                .with_context(|| format!("while setting times on {}", path.display()))?;
+            }
+            Command::UpdateExtent(_) => {
+                return Err(anyhow!(
+                    "sendstream was created with --no-data and cannot be received"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn mknod(
+        &self,
+        path: &Path,
+        mode: sendstream_parser::Mode,
+        rdev: sendstream_parser::Rdev,
+    ) -> Result<()> {
+        let path = self.path(path)?;
+        let kind = mode.file_type();
+        ensure!(
+            [
+                SFlag::S_IFIFO,
+                SFlag::S_IFCHR,
+                SFlag::S_IFBLK,
+                SFlag::S_IFSOCK
+            ]
+            .contains(&kind),
+            "cannot mknod {} with type {kind:?}",
+            path.display()
+        );
+        nix::sys::stat::mknod(&path, kind, mode.mode(), rdev.as_u64())
+            .with_context(|| format!("while creating special file {}", path.display()))
+    }
+}
+
+fn timespec(time: SystemTime) -> Result<TimeSpec> {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(TimeSpec::from)
+        .context("timestamp is before the epoch")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    static DEMO: &[u8] = include_bytes!("testdata/demo.sendstream");
+
+    /// The demo is a full sendstream followed by an incremental one
+    fn split_demo() -> (&'static [u8], &'static [u8]) {
+        let second = DEMO
+            .windows(SENDSTREAM_MAGIC.len())
+            .skip(1)
+            .position(|w| w == SENDSTREAM_MAGIC)
+            .expect("no second sendstream")
+            + 1;
+        DEMO.split_at(second)
+    }
+
+    /// The demo is entirely root-owned and includes a device node
+    fn check_full(root: &Path) {
+        let msg = root.join("hello/msg");
+        assert_eq!(
+            std::fs::metadata(root.join("hello/msg-hard"))
+                .expect("failed to stat")
+                .ino(),
+            std::fs::metadata(&msg).expect("failed to stat").ino(),
+        );
+        assert_eq!(
+            std::fs::read_link(root.join("hello/msg-sym")).expect("failed to readlink"),
+            Path::new("hello/msg")
+        );
+        let meta = std::fs::metadata(&msg).expect("failed to stat");
+        assert_eq!(meta.mode() & 0o7777, 0o400);
+        assert_eq!(meta.mtime(), 1671045523);
+        assert_eq!(
+            std::fs::read(root.join("hello/lorem")).expect("failed to read"),
+            std::fs::read(root.join("hello/lorem-reflinked")).expect("failed to read"),
+        );
+        assert_eq!(
+            std::fs::metadata(root.join("huge-empty-file"))
+                .expect("failed to stat")
+                .len(),
+            100 * 1024 * 1024 * 1024
+        );
+        let null = std::fs::metadata(root.join("null")).expect("failed to stat");
+        assert!(null.file_type().is_char_device());
+        assert_eq!(null.rdev(), nix::sys::stat::makedev(1, 3));
+        assert!(std::fs::symlink_metadata(root.join("myfifo"))
+            .expect("failed to stat")
+            .file_type()
+            .is_fifo());
+        assert!(std::fs::symlink_metadata(root.join("socket-node.sock"))
+            .expect("failed to stat")
+            .file_type()
+            .is_socket());
+    }
+
+    #[test]
+    #[ignore = "must run as root"]
+    fn full_and_incremental() {
+        let tmp = tempfile::tempdir().expect("failed to create tempdir");
+        let dst = tmp.path().join("dst");
+        std::fs::create_dir(&dst).expect("failed to create dst");
+        receive(DEMO, &dst, None).expect("failed to receive");
+        check_full(&dst);
+        // changes from the incremental stream
+        assert_eq!(
+            std::fs::read_to_string(dst.join("hello/msg")).expect("failed to read"),
+            "Goodbye!\n"
+        );
+        assert!(xattr::get(dst.join("hello/msg"), "user.antlir.demo")
+            .expect("failed to get xattr")
+            .is_none());
+        assert!(!dst.join("to-be-deleted").exists());
+        assert!(!dst.join("dir-to-be-deleted").exists());
+    }
+
+    #[test]
+    #[ignore = "must run as root"]
+    fn incremental_from_parent() {
+        let (full, incremental) = split_demo();
+        let tmp = tempfile::tempdir().expect("failed to create tempdir");
+
+        let parent = tmp.path().join("parent");
+        std::fs::create_dir(&parent).expect("failed to create parent");
+        receive(full, &parent, None).expect("failed to receive full");
+        check_full(&parent);
+        assert_eq!(
+            xattr::get(parent.join("hello/msg"), "user.antlir.demo")
+                .expect("failed to get xattr")
+                .as_deref(),
+            Some(br#"{"hello": "world"}"#.as_slice())
+        );
+
+        let child = tmp.path().join("child");
+        std::fs::create_dir(&child).expect("failed to create child");
+        receive(incremental, &child, None).expect_err("incremental stream should require a parent");
+        std::fs::remove_dir(&child).expect("failed to remove child");
+
+        // just like the buck-out output of the parent layer
+        let parent_link = tmp.path().join("parent-link");
+        std::os::unix::fs::symlink(&parent, &parent_link).expect("failed to symlink");
+        copy_parent(&parent_link, &child).expect("failed to copy parent");
+        assert!(std::fs::symlink_metadata(&child)
+            .expect("failed to stat")
+            .is_dir());
+        receive(incremental, &child, Some(&parent_link)).expect("failed to receive incremental");
+        assert_eq!(
+            std::fs::read_to_string(child.join("hello/msg")).expect("failed to read"),
+            "Goodbye!\n"
+        );
+        assert!(!child.join("to-be-deleted").exists());
+        // the parent is left untouched
+        assert_eq!(
+            std::fs::read_to_string(parent.join("hello/msg")).expect("failed to read"),
+            "Hello world!\n"
+        );
+        assert!(parent.join("to-be-deleted").exists());
+    }
+
+    /// Encode a single command with the given attributes
+    fn command(ty: u16, attrs: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (attr, value) in attrs {
+            data.extend_from_slice(&attr.to_le_bytes());
+            data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            data.extend_from_slice(value);
+        }
+        let mut cmd = Vec::new();
+        cmd.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        cmd.extend_from_slice(&ty.to_le_bytes());
+        // the crc is not checked by the parser
+        cmd.extend_from_slice(&0u32.to_le_bytes());
+        cmd.extend_from_slice(&data);
+        cmd
+    }
+
+    #[test]
+    fn never_follows_final_symlink() {
+        let tmp = tempfile::tempdir().expect("failed to create tempdir");
+        let outside = tmp.path().join("outside");
+        std::fs::write(&outside, "secret").expect("failed to write");
+        std::fs::set_permissions(&outside, std::fs::Permissions::from_mode(0o600))
+            .expect("failed to chmod");
+        let outside_mtime = std::fs::metadata(&outside).expect("failed to stat").mtime();
+        let dst = tmp.path().join("dst");
+        std::fs::create_dir(&dst).expect("failed to create dst");
+
+        // the order of these matches the lists in send.h
+        const SUBVOL: u16 = 1;
+        const SYMLINK: u16 = 8;
+        const CHMOD: u16 = 18;
+        const UTIMES: u16 = 20;
+        const END: u16 = 21;
+        const UUID: u16 = 1;
+        const CTRANSID: u16 = 2;
+        const INO: u16 = 3;
+        const MODE: u16 = 5;
+        const CTIME: u16 = 9;
+        const MTIME: u16 = 10;
+        const ATIME: u16 = 11;
+        const PATH: u16 = 15;
+        const LINK: u16 = 17;
+
+        let time = [0; 12];
+        let stream_with = |cmds: &[Vec<u8>]| {
+            let mut stream = SENDSTREAM_MAGIC.to_vec();
+            stream.extend_from_slice(&1u32.to_le_bytes());
+            stream.extend(command(
+                SUBVOL,
+                &[(PATH, b"evil"), (UUID, &[0; 16]), (CTRANSID, &[0; 8])],
+            ));
+            stream.extend(command(
+                SYMLINK,
+                &[
+                    (PATH, b"link"),
+                    (INO, &1u64.to_le_bytes()),
+                    (LINK, outside.as_os_str().as_bytes()),
+                ],
+            ));
+            for cmd in cmds {
+                stream.extend_from_slice(cmd);
+            }
+            stream.extend(command(END, &[]));
+            stream
+        };
+
+        let stream = stream_with(&[command(
+            UTIMES,
+            &[
+                (PATH, b"link"),
+                (ATIME, &time),
+                (MTIME, &time),
+                (CTIME, &time),
+            ],
+        )]);
+        receive(stream.as_slice(), &dst, None).expect("failed to receive");
+        assert_eq!(
+            std::fs::symlink_metadata(dst.join("link"))
+                .expect("failed to stat")
+                .mtime(),
+            0
+        );
+
+        std::fs::remove_file(dst.join("link")).expect("failed to remove link");
+        let stream = stream_with(&[command(
+            CHMOD,
+            &[(PATH, b"link"), (MODE, &0o777u64.to_le_bytes())],
+        )]);
+        let err = receive(stream.as_slice(), &dst, None).expect_err("chmod should be refused");
+        assert!(
+            format!("{err:#}").contains("refusing to chmod symlink"),
+            "{err:#}"
+        );
+
+        let meta = std::fs::metadata(&outside).expect("failed to stat");
+        assert_eq!(meta.mode() & 0o7777, 0o600);
+        assert_eq!(meta.mtime(), outside_mtime);
+    }
+
+    #[test]
+    fn refuses_v2() {
+        let tmp = tempfile::tempdir().expect("failed to create tempdir");
+        let mut v2 = DEMO.to_vec();
+        v2[SENDSTREAM_MAGIC.len()] = 2;
+        let err = receive(v2.as_slice(), tmp.path(), None).expect_err("v2 should be refused");
+        assert!(format!("{err:#}").contains("is v2"), "{err:#}");
+    }
+
+    #[test]
+    fn resolve() {
+        let tmp = tempfile::tempdir().expect("failed to create tempdir");
+        let root = tmp.path();
+        std::fs::create_dir(root.join("dir")).expect("failed to create dir");
+        std::os::unix::fs::symlink("/etc", root.join("escape")).expect("failed to symlink");
+        assert_eq!(
+            Receiver::resolve(root, Path::new("dir/file")).expect("failed to resolve"),
+            root.join("dir/file")
+        );
+        // the final component is never followed, so it's fine to be a symlink
+        assert_eq!(
+            Receiver::resolve(root, Path::new("escape")).expect("failed to resolve"),
+            root.join("escape")
+        );
+        assert!(Receiver::resolve(root, Path::new("escape/passwd")).is_err());
+        assert!(Receiver::resolve(root, Path::new("../outside")).is_err());
+        assert!(Receiver::resolve(root, Path::new("/etc/passwd")).is_err());
+    }
+}
@@ -0,0 +1,623 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Faithfully unpack tar archives (whole images, or OCI layers) into a layer.
+//!
+//! `tar::Archive::unpack` is close, but it turns device nodes into empty
+//! files, quietly skips entries that would escape the destination instead of
+//! failing and drops xattrs and ownership unless explicitly asked.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
+use bzip2::read::BzDecoder;
+use flate2::read::MultiGzDecoder;
+use nix::errno::Errno;
+use nix::sys::stat::fchmodat;
+use nix::sys::stat::makedev;
+use nix::sys::stat::mknod;
+use nix::sys::stat::utimensat;
+use nix::sys::stat::FchmodatFlags;
+use nix::sys::stat::Mode;
+use nix::sys::stat::SFlag;
+use nix::sys::stat::UtimensatFlags;
+use nix::sys::time::TimeSpec;
+use nix::unistd::fchownat;
+use nix::unistd::FchownatFlags;
+use nix::unistd::Gid;
+use nix::unistd::Uid;
+use tar::EntryType;
+use tracing::trace;
+use tracing::warn;
+use xz2::read::XzDecoder;
+
+const XATTR_PAX_PREFIX: &[u8] = b"SCHILY.xattr.";
+
+/// Wrap a (possibly compressed) tar archive in the right decoder, detecting
+/// the compression from the magic bytes at the start of the stream
+pub(crate) fn decompress<'a, R: Read + 'a>(reader: R) -> Result<Box<dyn Read + 'a>> {
+    let mut reader = BufReader::new(reader);
+    let magic = reader.fill_buf().context("while reading archive header")?;
+    Ok(if magic.starts_with(&[0x1f, 0x8b]) {
+        trace!("archive is gzip compressed");
+        Box::new(MultiGzDecoder::new(reader))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        trace!("archive is zstd compressed");
+        Box::new(zstd::Decoder::with_buffer(reader).context("while setting up zstd decompression")?)
+    } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        trace!("archive is xz compressed");
+        Box::new(XzDecoder::new_multi_decoder(reader))
+    } else if magic.starts_with(b"BZh") {
+        trace!("archive is bzip2 compressed");
+        Box::new(BzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    })
+}
+
+/// Set up an archive to keep all the metadata that is needed for a faithful
+/// copy of the original filesystem
+pub(crate) fn archive<R: Read>(reader: R) -> tar::Archive<R> {
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(true);
+    archive.set_preserve_mtime(true);
+    // xattrs are applied by [Unpacker] after ownership is set, since chown
+    // clears file capabilities
+    archive.set_unpack_xattrs(false);
+    archive.set_overwrite(true);
+    archive
+}
+
+/// Unpack an entire archive into `dst`
+pub(crate) fn unpack<R: Read>(reader: R, dst: &Path) -> Result<()> {
+    let mut archive = archive(reader);
+    let mut unpacker = Unpacker::new(dst);
+    for entry in archive.entries().context("while reading archive entries")? {
+        let mut entry = entry.context("while reading archive entry")?;
+        let path = normalize(&entry.path().context("while reading entry path")?)?;
+        unpacker.unpack(&mut entry, &path)?;
+    }
+    unpacker.finish()
+}
+
+pub(crate) struct Unpacker<'a> {
+    dst: &'a Path,
+    /// Unpacking children bumps the mtime of the parent directory, so they
+    /// are all set at the very end
+    dir_mtimes: Vec<(PathBuf, SystemTime)>,
+}
+
+impl<'a> Unpacker<'a> {
+    pub(crate) fn new(dst: &'a Path) -> Self {
+        Self {
+            dst,
+            dir_mtimes: Vec::new(),
+        }
+    }
+
+    /// Unpack a single entry to `path` (which must already have been
+    /// [normalize]d) under the destination
+    pub(crate) fn unpack<R: Read>(&mut self, entry: &mut tar::Entry<R>, path: &Path) -> Result<()> {
+        let xattrs = xattrs(entry)
+            .with_context(|| format!("while reading xattrs of /{}", path.display()))?;
+        if path.as_os_str().is_empty() {
+            return self.unpack_root(entry.header(), xattrs);
+        }
+        let target = resolve_in_root(self.dst, path)?;
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        match entry_type {
+            EntryType::Link => {
+                let link_name = entry
+                    .link_name()
+                    .context("while reading hardlink target")?
+                    .with_context(|| format!("hardlink /{} has no target", path.display()))?;
+                let link_name = normalize(&link_name)?;
+                let src = resolve_in_root(self.dst, &link_name)?;
+                if std::fs::symlink_metadata(&target).is_ok() {
+                    std::fs::remove_file(&target)
+                        .with_context(|| format!("while replacing {}", target.display()))?;
+                }
+                std::fs::hard_link(&src, &target).with_context(|| {
+                    format!(
+                        "while hardlinking /{} -> /{}",
+                        path.display(),
+                        link_name.display()
+                    )
+                })?;
+                // a hardlink shares all its metadata with the original
+                return Ok(());
+            }
+            EntryType::Char | EntryType::Block | EntryType::Fifo => {
+                match self.mknod(header, &target) {
+                    Ok(()) => {}
+                    // an unprivileged user namespace can only create
+                    // whiteout (0:0) devices, but everything else in the
+                    // layer is still worth having
+                    Err(Errno::EPERM) if entry_type != EntryType::Fifo => {
+                        warn!(
+                            "skipping device node /{} that cannot be created without real root",
+                            path.display()
+                        );
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        return Err(e)
+                            .with_context(|| format!("while creating /{}", path.display()));
+                    }
+                }
+            }
+            _ => {
+                if entry_type.is_dir() {
+                    let mtime = header.mtime().context("while reading dir mtime")?;
+                    self.dir_mtimes.push((
+                        target.clone(),
+                        SystemTime::UNIX_EPOCH + Duration::from_secs(mtime),
+                    ));
+                }
+                ensure!(
+                    entry
+                        .unpack_in(self.dst)
+                        .with_context(|| format!("while unpacking /{}", path.display()))?,
+                    "refusing to unpack /{} outside of the destination",
+                    path.display()
+                );
+            }
+        }
+        for (name, value) in xattrs {
+            xattr::set(&target, &name, &value).with_context(|| {
+                format!(
+                    "while setting xattr {} on /{}",
+                    name.to_string_lossy(),
+                    path.display()
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// The destination already exists, so the root entry (`./`) only carries
+    /// metadata that has to be applied to it
+    fn unpack_root(
+        &mut self,
+        header: &tar::Header,
+        xattrs: Vec<(OsString, Vec<u8>)>,
+    ) -> Result<()> {
+        ensure!(
+            header.entry_type().is_dir(),
+            "root entry must be a directory, not {:?}",
+            header.entry_type()
+        );
+        let uid = header.uid().context("while reading root uid")?;
+        let gid = header.gid().context("while reading root gid")?;
+        fchownat(
+            None,
+            self.dst,
+            Some(Uid::from_raw(
+                uid.try_into().context("root uid is too big")?,
+            )),
+            Some(Gid::from_raw(
+                gid.try_into().context("root gid is too big")?,
+            )),
+            FchownatFlags::FollowSymlink,
+        )
+        .context("while setting ownership of /")?;
+        // chown clears setuid/setgid bits, so the mode comes after it
+        let mode = header.mode().context("while reading root mode")?;
+        fchmodat(
+            None,
+            self.dst,
+            Mode::from_bits_truncate(mode),
+            FchmodatFlags::FollowSymlink,
+        )
+        .context("while setting mode of /")?;
+        for (name, value) in xattrs {
+            xattr::set(self.dst, &name, &value)
+                .with_context(|| format!("while setting xattr {} on /", name.to_string_lossy()))?;
+        }
+        let mtime = header.mtime().context("while reading root mtime")?;
+        self.dir_mtimes.push((
+            self.dst.to_owned(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(mtime),
+        ));
+        Ok(())
+    }
+
+    /// tar would create an empty regular file for these
+    fn mknod(&self, header: &tar::Header, target: &Path) -> nix::Result<()> {
+        let kind = match header.entry_type() {
+            EntryType::Char => SFlag::S_IFCHR,
+            EntryType::Block => SFlag::S_IFBLK,
+            _ => SFlag::S_IFIFO,
+        };
+        let mode = Mode::from_bits_truncate(header.mode().map_err(io_errno)?);
+        let dev = if kind == SFlag::S_IFIFO {
+            0
+        } else {
+            makedev(
+                header.device_major().map_err(io_errno)?.unwrap_or(0).into(),
+                header.device_minor().map_err(io_errno)?.unwrap_or(0).into(),
+            )
+        };
+        match nix::unistd::unlink(target) {
+            Ok(()) | Err(Errno::ENOENT) => {}
+            Err(e) => return Err(e),
+        }
+        mknod(target, kind, mode, dev)?;
+        fchownat(
+            None,
+            target,
+            Some(Uid::from_raw(
+                header
+                    .uid()
+                    .map_err(io_errno)?
+                    .try_into()
+                    .map_err(|_| Errno::EOVERFLOW)?,
+            )),
+            Some(Gid::from_raw(
+                header
+                    .gid()
+                    .map_err(io_errno)?
+                    .try_into()
+                    .map_err(|_| Errno::EOVERFLOW)?,
+            )),
+            FchownatFlags::NoFollowSymlink,
+        )?;
+        // mknod is subject to the umask
+        fchmodat(None, target, mode, FchmodatFlags::FollowSymlink)?;
+        let mtime = TimeSpec::from(Duration::from_secs(header.mtime().map_err(io_errno)?));
+        utimensat(
+            None,
+            target,
+            &mtime,
+            &mtime,
+            UtimensatFlags::NoFollowSymlink,
+        )
+    }
+
+    pub(crate) fn finish(self) -> Result<()> {
+        for (path, mtime) in self.dir_mtimes {
+            // it may have been replaced since it was unpacked
+            if std::fs::symlink_metadata(&path).is_ok_and(|m| m.is_dir()) {
+                File::open(&path)
+                    .and_then(|f| f.set_modified(mtime))
+                    .with_context(|| format!("while setting mtime of {}", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Malformed headers are the only io errors that mknod can hit
+fn io_errno(e: std::io::Error) -> Errno {
+    e.raw_os_error().map_or(Errno::EINVAL, Errno::from_i32)
+}
+
+/// xattrs (including file capabilities) are stored as `SCHILY.xattr.*` pax
+/// extended headers
+fn xattrs<R: Read>(entry: &mut tar::Entry<R>) -> Result<Vec<(OsString, Vec<u8>)>> {
+    let mut xattrs = Vec::new();
+    if let Some(extensions) = entry.pax_extensions()? {
+        for ext in extensions {
+            let ext = ext?;
+            if let Some(name) = ext.key_bytes().strip_prefix(XATTR_PAX_PREFIX) {
+                xattrs.push((
+                    std::ffi::OsStr::from_bytes(name).to_owned(),
+                    ext.value_bytes().to_vec(),
+                ));
+            }
+        }
+    }
+    Ok(xattrs)
+}
+
+/// Strip leading `/` and `./` from archive paths, and refuse anything that
+/// tries to leave the root
+pub(crate) fn normalize(path: &Path) -> Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => normalized.push(c),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                bail!("archive entry {} escapes the destination", path.display())
+            }
+        }
+    }
+    Ok(normalized)
+}
+
+/// Join `relpath` onto `root`, refusing to go through a symlink in any of the
+/// directories along the way. Those would be resolved against the host instead
+/// of the image (and archives never legitimately contain entries underneath a
+/// symlink anyway).
+pub(crate) fn resolve_in_root(root: &Path, relpath: &Path) -> Result<PathBuf> {
+    let mut path = root.to_owned();
+    if let Some(parent) = relpath.parent() {
+        for component in parent.components() {
+            path.push(component);
+            match std::fs::symlink_metadata(&path) {
+                Ok(meta) => ensure!(
+                    !meta.file_type().is_symlink(),
+                    "archive entry /{} escapes the destination through symlink /{}",
+                    relpath.display(),
+                    path.strip_prefix(root).unwrap_or(&path).display(),
+                ),
+                // nothing underneath can exist either
+                Err(e) if e.kind() == ErrorKind::NotFound => break,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("while statting {}", path.display()));
+                }
+            }
+        }
+    }
+    Ok(root.join(relpath))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+
+    /// Ownership is preserved, so default to whoever is running the test
+    fn header(entry_type: EntryType, mode: u32) -> tar::Header {
+        let me = tempfile::tempfile()
+            .and_then(|f| f.metadata())
+            .expect("failed to stat tempfile");
+        let mut header = tar::Header::new_gnu();
+        header.set_uid(me.uid().into());
+        header.set_gid(me.gid().into());
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_mtime(1234);
+        header.set_size(0);
+        header
+    }
+
+    fn simple_archive() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut h = header(EntryType::Directory, 0o755);
+        builder
+            .append_data(&mut h, "etc/", std::io::empty())
+            .expect("failed to append");
+        let mut h = header(EntryType::Regular, 0o644);
+        h.set_size(5);
+        builder
+            .append_data(&mut h, "etc/hello", &b"world"[..])
+            .expect("failed to append");
+        builder.into_inner().expect("failed to finish archive")
+    }
+
+    #[test]
+    fn compression() {
+        let tar = simple_archive();
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&tar).expect("failed to gzip");
+        let mut bz = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::Default);
+        bz.write_all(&tar).expect("failed to bzip2");
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(&tar).expect("failed to xz");
+        for (name, data) in [
+            ("plain", tar.clone()),
+            ("gzip", gz.finish().expect("failed to gzip")),
+            (
+                "zstd",
+                zstd::encode_all(&tar[..], 0).expect("failed to zstd"),
+            ),
+            ("xz", xz.finish().expect("failed to xz")),
+            ("bzip2", bz.finish().expect("failed to bzip2")),
+        ] {
+            let tmp = tempfile::tempdir().expect("failed to create tempdir");
+            let dst = tmp.path();
+            unpack(decompress(data.as_slice()).expect("failed to open"), dst)
+                .unwrap_or_else(|e| panic!("failed to unpack {name}: {e:#}"));
+            assert_eq!(
+                std::fs::read_to_string(dst.join("etc/hello")).expect("failed to read"),
+                "world",
+                "{name}"
+            );
+            assert_eq!(
+                std::fs::metadata(dst.join("etc"))
+                    .expect("failed to stat")
+                    .mtime(),
+                1234,
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    #[ignore = "must run as root"]
+    fn metadata() {
+        // cap_net_bind_service=ep
+        let cap: &[u8] = &[
+            0x01, 0x00, 0x00, 0x02, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let mut builder = tar::Builder::new(Vec::new());
+        builder
+            .append_pax_extensions([
+                ("SCHILY.xattr.user.greeting", &b"hello"[..]),
+                ("SCHILY.xattr.security.capability", cap),
+            ])
+            .expect("failed to append pax");
+        let mut h = header(EntryType::Regular, 0o755);
+        h.set_uid(1234);
+        h.set_gid(5678);
+        h.set_size(5);
+        builder
+            .append_data(&mut h, "ping", &b"hello"[..])
+            .expect("failed to append");
+        let mut h = header(EntryType::Link, 0o755);
+        builder
+            .append_link(&mut h, "ping-hard", "ping")
+            .expect("failed to append");
+        let mut h = header(EntryType::Char, 0o600);
+        h.set_uid(42);
+        h.set_device_major(1).expect("failed to set major");
+        h.set_device_minor(3).expect("failed to set minor");
+        builder
+            .append_data(&mut h, "null", std::io::empty())
+            .expect("failed to append");
+        let mut h = header(EntryType::Fifo, 0o640);
+        builder
+            .append_data(&mut h, "fifo", std::io::empty())
+            .expect("failed to append");
+        let archive = builder.into_inner().expect("failed to finish archive");
+
+        let dst = tempfile::tempdir().expect("failed to create tempdir");
+        unpack(&archive[..], dst.path()).expect("failed to unpack");
+
+        let ping = std::fs::metadata(dst.path().join("ping")).expect("failed to stat");
+        assert_eq!((ping.uid(), ping.gid()), (1234, 5678));
+        assert_eq!(ping.mode() & 0o7777, 0o755);
+        assert_eq!(ping.nlink(), 2);
+        assert_eq!(
+            ping.ino(),
+            std::fs::metadata(dst.path().join("ping-hard"))
+                .expect("failed to stat")
+                .ino()
+        );
+        assert_eq!(
+            xattr::get(dst.path().join("ping"), "user.greeting").expect("failed to get xattr"),
+            Some(b"hello".to_vec())
+        );
+        assert_eq!(
+            xattr::get(dst.path().join("ping"), "security.capability")
+                .expect("failed to get xattr"),
+            Some(cap.to_vec()),
+            "file capability did not survive chown"
+        );
+
+        let null = std::fs::symlink_metadata(dst.path().join("null")).expect("failed to stat");
+        assert!(null.file_type().is_char_device());
+        assert_eq!(null.rdev(), nix::sys::stat::makedev(1, 3));
+        assert_eq!(null.uid(), 42);
+        assert_eq!(null.mode() & 0o7777, 0o600);
+        assert_eq!(null.mtime(), 1234);
+
+        let fifo = std::fs::symlink_metadata(dst.path().join("fifo")).expect("failed to stat");
+        assert!(fifo.file_type().is_fifo());
+        assert_eq!(fifo.mode() & 0o7777, 0o640);
+    }
+
+    #[test]
+    #[ignore = "must run as root"]
+    fn root_metadata() {
+        let mut builder = tar::Builder::new(Vec::new());
+        builder
+            .append_pax_extensions([("SCHILY.xattr.user.greeting", &b"hello"[..])])
+            .expect("failed to append pax");
+        let mut h = header(EntryType::Directory, 0o1750);
+        h.set_uid(1234);
+        h.set_gid(5678);
+        h.set_mtime(4321);
+        builder
+            .append_data(&mut h, "./", std::io::empty())
+            .expect("failed to append");
+        let mut h = header(EntryType::Regular, 0o644);
+        builder
+            .append_data(&mut h, "child", std::io::empty())
+            .expect("failed to append");
+        let archive = builder.into_inner().expect("failed to finish archive");
+
+        let tmp = tempfile::tempdir().expect("failed to create tempdir");
+        let dst = tmp.path().join("root");
+        std::fs::create_dir(&dst).expect("failed to create dst");
+        unpack(&archive[..], &dst).expect("failed to unpack");
+
+        let root = std::fs::metadata(&dst).expect("failed to stat");
+        assert_eq!((root.uid(), root.gid()), (1234, 5678));
+        assert_eq!(root.mode() & 0o7777, 0o1750);
+        // not bumped by unpacking the child
+        assert_eq!(root.mtime(), 4321);
+        assert_eq!(
+            xattr::get(&dst, "user.greeting").expect("failed to get xattr"),
+            Some(b"hello".to_vec())
+        );
+        assert!(dst.join("child").exists());
+    }
+
+    #[test]
+    fn parent_dir_escape() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut h = header(EntryType::Regular, 0o644);
+        // set_path refuses '..', but a malicious archive can contain anything
+        let name = b"../escaped";
+        h.as_old_mut().name[..name.len()].copy_from_slice(name);
+        h.set_cksum();
+        builder
+            .append(&h, std::io::empty())
+            .expect("failed to append");
+        let archive = builder.into_inner().expect("failed to finish archive");
+
+        let tmp = tempfile::tempdir().expect("failed to create tempdir");
+        let dst = tmp.path().join("dst");
+        std::fs::create_dir(&dst).expect("failed to create dst");
+        let err = unpack(&archive[..], &dst).expect_err("'..' should be rejected");
+        assert!(
+            format!("{err:#}").contains("escapes the destination"),
+            "unclear error: {err:#}"
+        );
+        assert!(!tmp.path().join("escaped").exists());
+    }
+
+    #[test]
+    fn symlink_escape() {
+        let outside = tempfile::tempdir().expect("failed to create tempdir");
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut h = header(EntryType::Symlink, 0o777);
+        builder
+            .append_link(&mut h, "escape", outside.path())
+            .expect("failed to append");
+        let mut h = header(EntryType::Regular, 0o644);
+        h.set_size(4);
+        builder
+            .append_data(&mut h, "escape/evil", &b"evil"[..])
+            .expect("failed to append");
+        let archive = builder.into_inner().expect("failed to finish archive");
+
+        let dst = tempfile::tempdir().expect("failed to create tempdir");
+        let err = unpack(&archive[..], dst.path()).expect_err("symlink escape should be rejected");
+        assert!(
+            format!("{err:#}").contains("escapes the destination through symlink /escape"),
+            "unclear error: {err:#}"
+        );
+        assert!(!outside.path().join("evil").exists());
+    }
+
+    #[test]
+    fn normalize_paths() {
+        assert_eq!(
+            normalize(Path::new("./usr/bin/")).expect("failed to normalize"),
+            Path::new("usr/bin")
+        );
+        assert_eq!(
+            normalize(Path::new("/etc/passwd")).expect("failed to normalize"),
+            Path::new("etc/passwd")
+        );
+        assert!(normalize(Path::new("usr/../../etc")).is_err());
+    }
+}
@@ -0,0 +1,425 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Integrity checks of the source that is being received.
+//!
+//! Everything is checked on the fly while the source is being read, so that
+//! huge images never need to be read twice (or held in memory). The flip side
+//! is that the layer is already (at least partially) materialized by the time
+//! a mismatch is detected, so callers must throw it away if [Verifier::finish]
+//! fails.
+
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use clap::Parser;
+use openssl::hash::Hasher;
+use openssl::hash::MessageDigest;
+use openssl::pkey::Id;
+use openssl::pkey::PKey;
+use openssl::pkey::Public;
+use sha2::Digest;
+use sha2::Sha256;
+use tracing::trace;
+
+/// Both public keys and signatures start with this to identify ed25519
+const MINISIGN_ALG: &[u8; 2] = b"Ed";
+/// Signature of the BLAKE2b-512 hash of the data, rather than the data itself
+const MINISIGN_ALG_PREHASHED: &[u8; 2] = b"ED";
+const MINISIGN_KEY_ID_LEN: usize = 8;
+const TRUSTED_COMMENT_PREFIX: &str = "trusted comment: ";
+
+#[derive(Parser, Debug, Default)]
+pub(crate) struct VerifyArgs {
+    #[clap(long)]
+    /// Hex-encoded sha256 that the source must match
+    expected_sha256: Option<String>,
+    #[clap(long, requires = "minisign_public_key")]
+    /// Detached minisign signature of the source
+    minisign_signature: Option<PathBuf>,
+    #[clap(long, requires = "minisign_signature")]
+    /// minisign public key (either a file or just the base64-encoded key)
+    /// that is trusted to sign the source. May be given more than once.
+    minisign_public_key: Vec<String>,
+}
+
+impl VerifyArgs {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.expected_sha256.is_none() && self.minisign_signature.is_none()
+    }
+}
+
+/// [Read] wrapper that checks the data read through it against the expected
+/// digest and/or signature.
+pub(crate) struct Verifier<R> {
+    inner: R,
+    sha256: Option<(Sha256, Vec<u8>)>,
+    minisign: Option<(Hasher, Signature, PKey<Public>)>,
+}
+
+impl<R: Read> Verifier<R> {
+    /// Everything that can be checked without reading any data (the
+    /// signature is well-formed and from a trusted key) fails here, before
+    /// anything is received
+    pub(crate) fn new(inner: R, args: &VerifyArgs) -> Result<Self> {
+        let sha256 = args
+            .expected_sha256
+            .as_deref()
+            .map(|expected| {
+                let expected = hex::decode(expected)
+                    .with_context(|| format!("expected sha256 '{expected}' is not hex"))?;
+                ensure!(
+                    expected.len() == Sha256::output_size(),
+                    "expected sha256 is {} bytes, not {}",
+                    expected.len(),
+                    Sha256::output_size()
+                );
+                Ok((Sha256::new(), expected))
+            })
+            .transpose()?;
+        let minisign = args
+            .minisign_signature
+            .as_deref()
+            .map(|path| {
+                let signature = Signature::read(path)?;
+                let mut trusted = Vec::new();
+                for key in &args.minisign_public_key {
+                    trusted.push(PublicKey::load(key)?);
+                }
+                let key = trusted
+                    .into_iter()
+                    .find(|k| k.key_id == signature.key_id)
+                    .with_context(|| {
+                        format!(
+                            "{} was signed by untrusted key {}",
+                            path.display(),
+                            key_id_hex(&signature.key_id)
+                        )
+                    })?;
+                // the global signature covers the trusted comment, so check
+                // it right away
+                let mut global = signature.signature.to_vec();
+                global.extend_from_slice(signature.trusted_comment.as_bytes());
+                ensure!(
+                    ed25519_verify(&key.key, &signature.global_signature, &global)?,
+                    "trusted comment signature in {} is invalid",
+                    path.display()
+                );
+                let hasher = Hasher::new(blake2b512()?)?;
+                Ok((hasher, signature, key.key))
+            })
+            .transpose()?;
+        Ok(Self {
+            inner,
+            sha256,
+            minisign,
+        })
+    }
+
+    /// Consume any data that the receiver didn't need (for example padding
+    /// after the end of a tar archive) and check the result
+    pub(crate) fn finish(mut self) -> Result<()> {
+        std::io::copy(&mut self, &mut std::io::sink())
+            .context("while reading the rest of the source")?;
+        if let Some((hasher, expected)) = self.sha256 {
+            let actual = hasher.finalize();
+            ensure!(
+                actual.as_slice() == expected,
+                "sha256 mismatch: expected {}, got {}",
+                hex::encode(expected),
+                hex::encode(actual)
+            );
+            trace!("sha256 matched");
+        }
+        if let Some((mut hasher, signature, key)) = self.minisign {
+            let hash = hasher.finish()?;
+            ensure!(
+                ed25519_verify(&key, &signature.signature, &hash)?,
+                "minisign signature from key {} does not match",
+                key_id_hex(&signature.key_id)
+            );
+            trace!(
+                "minisign signature verified, trusted comment: {}",
+                signature.trusted_comment
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Verifier<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some((hasher, _)) = &mut self.sha256 {
+            hasher.update(&buf[..n]);
+        }
+        if let Some((hasher, _, _)) = &mut self.minisign {
+            hasher.update(&buf[..n])?;
+        }
+        Ok(n)
+    }
+}
+
+struct PublicKey {
+    key_id: [u8; MINISIGN_KEY_ID_LEN],
+    key: PKey<Public>,
+}
+
+impl PublicKey {
+    /// Public keys are either a file in the format written by `minisign -G`
+    /// (an untrusted comment followed by the base64 key) or just the base64
+    /// key, as it would be given to `minisign -P`
+    fn load(key: &str) -> Result<Self> {
+        let encoded = if Path::new(key).exists() {
+            let contents = std::fs::read_to_string(key)
+                .with_context(|| format!("while reading public key {key}"))?;
+            contents
+                .lines()
+                .find(|l| !l.is_empty() && !l.starts_with("untrusted comment:"))
+                .with_context(|| format!("{key} does not contain a public key"))?
+                .to_owned()
+        } else {
+            key.to_owned()
+        };
+        let decoded = STANDARD
+            .decode(encoded.trim())
+            .with_context(|| format!("public key {key} is not valid base64"))?;
+        let (alg, rest) = decoded.split_at(MINISIGN_ALG.len().min(decoded.len()));
+        ensure!(
+            alg == MINISIGN_ALG && rest.len() == MINISIGN_KEY_ID_LEN + 32,
+            "{key} is not a minisign ed25519 public key"
+        );
+        let (key_id, key) = rest.split_at(MINISIGN_KEY_ID_LEN);
+        Ok(Self {
+            key_id: key_id.try_into().context("malformed key id")?,
+            key: PKey::public_key_from_raw_bytes(key, Id::ED25519)
+                .context("while loading ed25519 public key")?,
+        })
+    }
+}
+
+struct Signature {
+    key_id: [u8; MINISIGN_KEY_ID_LEN],
+    signature: [u8; 64],
+    trusted_comment: String,
+    global_signature: [u8; 64],
+}
+
+impl Signature {
+    fn read(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("while reading signature {}", path.display()))?;
+        let mut lines = contents.lines();
+        let mut next_line = |what: &str| {
+            lines
+                .next()
+                .with_context(|| format!("{} is missing the {what}", path.display()))
+        };
+        next_line("untrusted comment")?;
+        let signature = STANDARD
+            .decode(next_line("signature")?.trim())
+            .with_context(|| format!("signature in {} is not valid base64", path.display()))?;
+        let trusted_comment = next_line("trusted comment")?
+            .strip_prefix(TRUSTED_COMMENT_PREFIX)
+            .with_context(|| format!("{} has a malformed trusted comment", path.display()))?
+            .to_owned();
+        let global_signature = STANDARD
+            .decode(next_line("global signature")?.trim())
+            .with_context(|| {
+                format!("global signature in {} is not valid base64", path.display())
+            })?;
+
+        ensure!(
+            signature.len() == 2 + MINISIGN_KEY_ID_LEN + 64,
+            "{} is not a minisign signature",
+            path.display()
+        );
+        let (alg, rest) = signature.split_at(2);
+        let (key_id, signature) = rest.split_at(MINISIGN_KEY_ID_LEN);
+        if alg == MINISIGN_ALG {
+            // the whole source would have to be buffered to check these
+            bail!(
+                "{} is a legacy (non-prehashed) minisign signature, which cannot be verified while streaming. Re-sign it with a current version of minisign.",
+                path.display()
+            );
+        }
+        ensure!(
+            alg == MINISIGN_ALG_PREHASHED,
+            "{} uses an unknown signature algorithm",
+            path.display()
+        );
+        Ok(Self {
+            key_id: key_id.try_into().context("malformed key id")?,
+            signature: signature.try_into().context("malformed signature")?,
+            trusted_comment,
+            global_signature: global_signature
+                .as_slice()
+                .try_into()
+                .with_context(|| format!("{} has a malformed global signature", path.display()))?,
+        })
+    }
+}
+
+fn blake2b512() -> Result<MessageDigest> {
+    MessageDigest::from_name("BLAKE2b512").context("openssl does not support BLAKE2b-512")
+}
+
+fn ed25519_verify(key: &PKey<Public>, signature: &[u8], data: &[u8]) -> Result<bool> {
+    openssl::sign::Verifier::new_without_digest(key)
+        .and_then(|mut v| v.verify_oneshot(signature, data))
+        .context("while verifying ed25519 signature")
+}
+
+/// minisign displays key ids as little-endian u64s
+fn key_id_hex(key_id: &[u8; MINISIGN_KEY_ID_LEN]) -> String {
+    format!("{:016X}", u64::from_le_bytes(*key_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::pkey::Private;
+    use openssl::sign::Signer;
+
+    use super::*;
+
+    const DATA: &[u8] = b"hello world\n";
+    const KEY_ID: [u8; MINISIGN_KEY_ID_LEN] = *b"antlir2!";
+
+    fn sign(key: &PKey<Private>, data: &[u8]) -> Vec<u8> {
+        Signer::new_without_digest(key)
+            .and_then(|mut s| s.sign_oneshot_to_vec(data))
+            .expect("failed to sign")
+    }
+
+    /// Write out a keypair and signature in the same format as minisign
+    fn setup(dir: &Path, data: &[u8]) -> (PathBuf, String) {
+        let key = PKey::generate_ed25519().expect("failed to generate key");
+        let mut public = MINISIGN_ALG.to_vec();
+        public.extend_from_slice(&KEY_ID);
+        public.extend(key.raw_public_key().expect("failed to get public key"));
+        let pubkey_path = dir.join("key.pub");
+        std::fs::write(
+            &pubkey_path,
+            format!(
+                "untrusted comment: minisign public key\n{}\n",
+                STANDARD.encode(&public)
+            ),
+        )
+        .expect("failed to write public key");
+
+        let mut hasher = Hasher::new(blake2b512().expect("no blake2b")).expect("no hasher");
+        hasher.update(data).expect("failed to hash");
+        let signature = sign(&key, &hasher.finish().expect("failed to hash"));
+        let trusted_comment = "timestamp:1234\tfile:test";
+        let mut global = signature.clone();
+        global.extend_from_slice(trusted_comment.as_bytes());
+        let global = sign(&key, &global);
+        let mut encoded = MINISIGN_ALG_PREHASHED.to_vec();
+        encoded.extend_from_slice(&KEY_ID);
+        encoded.extend(signature);
+        let sig_path = dir.join("data.minisig");
+        std::fs::write(
+            &sig_path,
+            format!(
+                "untrusted comment: signature\n{}\n{TRUSTED_COMMENT_PREFIX}{trusted_comment}\n{}\n",
+                STANDARD.encode(&encoded),
+                STANDARD.encode(&global)
+            ),
+        )
+        .expect("failed to write signature");
+        (sig_path, STANDARD.encode(&public))
+    }
+
+    fn verify(data: &[u8], args: &VerifyArgs) -> Result<()> {
+        let mut verifier = Verifier::new(data, args)?;
+        // only partially consume it, like a tar reader would
+        let mut buf = [0; 4];
+        verifier.read_exact(&mut buf)?;
+        verifier.finish()
+    }
+
+    #[test]
+    fn sha256() {
+        let good = VerifyArgs {
+            expected_sha256: Some(
+                "a948904f2f0f479b8f8197694b30184b0d2ed1c1cd2a1ec0fb85d299a192a447".into(),
+            ),
+            ..Default::default()
+        };
+        verify(DATA, &good).expect("sha256 should match");
+        let err = verify(b"goodbye world\n", &good).expect_err("sha256 should not match");
+        assert!(
+            err.to_string().starts_with("sha256 mismatch"),
+            "unexpected error: {err:#}"
+        );
+        let bad = VerifyArgs {
+            expected_sha256: Some("a9489".into()),
+            ..Default::default()
+        };
+        verify(DATA, &bad).expect_err("malformed digest");
+    }
+
+    #[test]
+    fn minisign() {
+        let tmp = tempfile::tempdir().expect("failed to create tempdir");
+        let (sig, pubkey) = setup(tmp.path(), DATA);
+        let args = VerifyArgs {
+            minisign_signature: Some(sig.clone()),
+            minisign_public_key: vec![tmp.path().join("key.pub").to_str().unwrap().into()],
+            ..Default::default()
+        };
+        verify(DATA, &args).expect("signature should be valid");
+        // the bare base64 key works just as well
+        let args = VerifyArgs {
+            minisign_signature: Some(sig.clone()),
+            minisign_public_key: vec![pubkey],
+            ..Default::default()
+        };
+        verify(DATA, &args).expect("signature should be valid");
+        let err = verify(b"hello world!\n", &args).expect_err("data was tampered with");
+        assert!(
+            err.to_string().contains("does not match"),
+            "unexpected error: {err:#}"
+        );
+    }
+
+    #[test]
+    fn minisign_untrusted() {
+        let tmp = tempfile::tempdir().expect("failed to create tempdir");
+        let (sig, _) = setup(tmp.path(), DATA);
+        // a different key with the same id
+        let other = tempfile::tempdir().expect("failed to create tempdir");
+        let (_, other_key) = setup(other.path(), DATA);
+        let args = VerifyArgs {
+            minisign_signature: Some(sig.clone()),
+            minisign_public_key: vec![other_key],
+            ..Default::default()
+        };
+        verify(DATA, &args).expect_err("signed by a different key");
+
+        let mut untrusted = MINISIGN_ALG.to_vec();
+        untrusted.extend_from_slice(b"someone!");
+        untrusted.extend_from_slice(&[0; 32]);
+        let args = VerifyArgs {
+            minisign_signature: Some(sig),
+            minisign_public_key: vec![STANDARD.encode(untrusted)],
+            ..Default::default()
+        };
+        let err = verify(DATA, &args).expect_err("signed by an unknown key");
+        assert!(
+            err.to_string().contains("untrusted key"),
+            "unexpected error: {err:#}"
+        );
+    }
+}
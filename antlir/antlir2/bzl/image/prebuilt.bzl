@@ -9,7 +9,7 @@ load("//antlir/antlir2/antlir2_overlayfs:overlayfs.bzl", "get_antlir2_use_overla
 load("//antlir/antlir2/antlir2_rootless:cfg.bzl", "rootless_cfg")
 load("//antlir/antlir2/antlir2_rootless:package.bzl", "get_antlir2_rootless")
 load("//antlir/antlir2/bzl:build_phase.bzl", "BuildPhase")
-load("//antlir/antlir2/bzl:platform.bzl", "rule_with_default_target_platform")
+load("//antlir/antlir2/bzl:platform.bzl", "arch_select", "rule_with_default_target_platform")
 load("//antlir/antlir2/bzl:types.bzl", "BuildApplianceInfo", "FlavorInfo", "LayerContents", "LayerInfo")
 load("//antlir/bzl:build_defs.bzl", "internal_external")
 load(":facts.bzl", "facts")
@@ -45,41 +45,17 @@ def _impl(ctx: AnalysisContext) -> list[Provider]:
         # antlir2-receive treats them the same
         format = "sendstream"
 
-    if format == "tar":
-        if ctx.attrs.src.basename.endswith("gz"):
-            src = ctx.actions.declare_output("uncompressed")
-            ctx.actions.run(
-                cmd_args(
-                    "bash",
-                    "-e",
-                    "-c",
-                    cmd_args(
-                        "zcat",
-                        ctx.attrs.src,
-                        cmd_args(src.as_output(), format = "> {}"),
-                        delimiter = " ",
-                    ),
-                ),
-                category = "decompress",
-                # we're going to need it to be locally available to extract it
-                # into an image, but it *can* be run remotely
-                prefer_local = True,
-            )
-        if ctx.attrs.src.basename.endswith("zst"):
-            src = ctx.actions.declare_output("uncompressed")
-            ctx.actions.run(
-                cmd_args(
-                    "zstd",
-                    "-d",
-                    ctx.attrs.src,
-                    "-o",
-                    src.as_output(),
-                ),
-                category = "decompress",
-                # we're going to need it to be locally available to extract it
-                # into an image, but it *can* be run remotely
-                prefer_local = True,
-            )
+    if ctx.attrs.parent_layer and format != "sendstream":
+        fail("parent_layer is only supported for incremental sendstreams")
+    verified = ctx.attrs.sha256 or ctx.attrs.minisign_signature
+    if verified and format not in ("chunk_store", "erofs", "sendstream", "squashfs", "tar"):
+        fail("only single-file formats can be verified, not {}".format(format))
+    if verified and src != ctx.attrs.src:
+        # the digest and signature are of the compressed file, which
+        # antlir2-receive never sees
+        fail("compressed sendstreams cannot be verified")
+    if ctx.attrs.minisign_signature and not ctx.attrs.minisign_public_keys:
+        fail("minisign_signature requires minisign_public_keys")
 
     subvol_symlink = ctx.actions.declare_output("subvol_symlink")
     ctx.actions.run(
@@ -91,6 +67,11 @@ def _impl(ctx: AnalysisContext) -> list[Provider]:
             cmd_args(format, format = "--format={}"),
             cmd_args(ctx.attrs._btrfs[RunInfo], format = "--btrfs={}") if format == "sendstream" and ctx.attrs._btrfs else cmd_args(),
             cmd_args(src, format = "--source={}"),
+            cmd_args(ctx.attrs.parent_layer[LayerInfo].subvol_symlink, format = "--parent={}") if ctx.attrs.parent_layer else cmd_args(),
+            cmd_args(ctx.attrs._oci_platform, format = "--oci-platform={}") if format == "oci" else cmd_args(),
+            cmd_args(ctx.attrs.sha256, format = "--expected-sha256={}") if ctx.attrs.sha256 else cmd_args(),
+            cmd_args(ctx.attrs.minisign_signature, format = "--minisign-signature={}") if ctx.attrs.minisign_signature else cmd_args(),
+            [cmd_args(key, format = "--minisign-public-key={}") for key in ctx.attrs.minisign_public_keys],
             cmd_args(subvol_symlink.as_output(), format = "--output={}"),
             cmd_args("--rootless") if ctx.attrs._rootless else cmd_args(),
             cmd_args("--working-format=overlayfs") if ctx.attrs._overlayfs else cmd_args(),
@@ -145,11 +126,33 @@ _prebuilt = rule(
         "antlir2": attrs.exec_dep(default = "antlir//antlir/antlir2/antlir2:antlir2"),
         "antlir2_receive": attrs.default_only(attrs.exec_dep(default = "antlir//antlir/antlir2/antlir2_receive:antlir2-receive")),
         "flavor": attrs.option(attrs.dep(providers = [FlavorInfo]), default = None),
-        "format": attrs.enum(["cas_dir", "sendstream.v2", "sendstream", "sendstream.zst", "tar", "caf"]),
+        "format": attrs.enum(["cas_dir", "chunk_store", "erofs", "oci", "sendstream.v2", "sendstream", "sendstream.zst", "squashfs", "tar", "caf"]),
         "labels": attrs.list(attrs.string(), default = []),
+        "minisign_public_keys": attrs.list(
+            attrs.string(),
+            default = [],
+            doc = "base64 minisign public keys that are trusted to sign src",
+        ),
+        "minisign_signature": attrs.option(
+            attrs.source(),
+            default = None,
+            doc = "detached minisign signature of src",
+        ),
+        "parent_layer": attrs.option(
+            attrs.dep(providers = [LayerInfo]),
+            default = None,
+            doc = "layer that an incremental sendstream was generated against",
+        ),
+        "sha256": attrs.option(
+            attrs.string(),
+            default = None,
+            doc = "expected sha256 of src (or the reassembled stream for chunk_store)",
+        ),
         "src": attrs.source(doc = "source file of the image"),
         "_btrfs": attrs.option(attrs.exec_dep(), default = None),
         "_new_facts_db": attrs.exec_dep(default = "antlir//antlir/antlir2/antlir2_facts:new-facts-db"),
+        # pick the manifest for the target platform out of a multi-arch index
+        "_oci_platform": attrs.default_only(attrs.string(default = arch_select(aarch64 = "linux/arm64", x86_64 = "linux/amd64"))),
         "_overlayfs": attrs.bool(default = False),
         "_rootless": attrs.default_only(attrs.bool(default = select({
             "DEFAULT": False,
@@ -6,55 +6,78 @@
 load("//antlir/antlir2/antlir2_rootless:cfg.bzl", "rootless_cfg")
 load("//antlir/antlir2/bzl:types.bzl", "LayerInfo")
 load("//antlir/antlir2/bzl/package:cfg.bzl", "cfg_attrs", "package_cfg")
+load("//antlir/buck2/bzl:ensure_single_output.bzl", "ensure_single_output")
 load(":gpt.bzl", "GptPartitionSource")
 load(":macro.bzl", "package_macro")
-load(":sendstream.bzl", "sendstream_v2_anon")
+load(":manifest.bzl", "declare_manifest", "manifest_args", "manifest_attrs", "manifest_sub_targets")
+load(":sendstream.bzl", "SendstreamInfo", "sendstream_v2_anon")
 
-def _impl(ctx: AnalysisContext) -> list[Provider]:
-    package = ctx.actions.declare_output("image.btrfs")
+def _impl(ctx: AnalysisContext) -> Promise:
+    paths = list(ctx.attrs.subvols.keys())
 
-    spec = ctx.actions.write_json(
-        "spec.json",
-        {"btrfs": {
-            "btrfs_packager_path": ctx.attrs.btrfs_packager[RunInfo],
-            "spec": {
-                "compression_level": ctx.attrs.compression_level,
-                "default_subvol": ctx.attrs.default_subvol,
-                "free_mb": ctx.attrs.free_mb,
-                "label": ctx.attrs.label,
-                "seed_device": ctx.attrs.seed_device,
-                "subvols": {
-                    path: {
-                        "sendstream": ctx.actions.anon_target(sendstream_v2_anon, {
-                            "antlir2_packager": ctx.attrs.antlir2_packager,
-                            "compression_level": ctx.attrs.compression_level,
-                            "layer": subvol["layer"],
-                            "name": str(subvol["layer"].label.raw_target()),
-                            "_rootless": ctx.attrs._rootless,
-                        }).artifact("sendstream"),
-                        "writable": subvol.get("writable"),
-                    }
-                    for path, subvol in ctx.attrs.subvols.items()
+    def with_anon(anon_sendstreams) -> list[Provider]:
+        sendstreams = {path: anon_sendstreams[i] for i, path in enumerate(paths)}
+        package = ctx.actions.declare_output("image.btrfs")
+        manifest = declare_manifest(ctx, "image.btrfs")
+
+        spec = ctx.actions.write_json(
+            "spec.json",
+            {"btrfs": {
+                "btrfs_packager_path": ctx.attrs.btrfs_packager[RunInfo],
+                # the image holds exactly what is in each subvol's sendstream,
+                # so its manifest is just theirs under each subvol path
+                "subvol_manifests": {
+                    path: ensure_single_output(sendstream[DefaultInfo].sub_targets["manifest"])
+                    for path, sendstream in sendstreams.items()
+                } if manifest else {},
+                "spec": {
+                    "compression_level": ctx.attrs.compression_level,
+                    "default_subvol": ctx.attrs.default_subvol,
+                    "free_mb": ctx.attrs.free_mb,
+                    "label": ctx.attrs.label,
+                    "seed_device": ctx.attrs.seed_device,
+                    "subvols": {
+                        path: {
+                            "sendstream": sendstreams[path][SendstreamInfo].sendstream,
+                            "writable": subvol.get("writable"),
+                        }
+                        for path, subvol in ctx.attrs.subvols.items()
+                    },
                 },
-            },
-        }},
-        with_inputs = True,
-    )
+            }},
+            with_inputs = True,
+        )
 
-    ctx.actions.run(
-        cmd_args(
-            ctx.attrs.antlir2_packager[RunInfo],
-            cmd_args(spec, format = "--spec={}"),
-            cmd_args(package.as_output(), format = "--out={}"),
-        ),
-        local_only = True,  # needs root
-        category = "antlir2_package",
-        identifier = "btrfs",
-    )
-    return [
-        DefaultInfo(package),
-        GptPartitionSource(src = package),
-    ]
+        ctx.actions.run(
+            cmd_args(
+                ctx.attrs.antlir2_packager[RunInfo],
+                cmd_args(spec, format = "--spec={}"),
+                cmd_args(package.as_output(), format = "--out={}"),
+                manifest_args(manifest),
+            ),
+            local_only = True,  # needs root
+            category = "antlir2_package",
+            identifier = "btrfs",
+        )
+        return [
+            DefaultInfo(package, sub_targets = manifest_sub_targets(manifest)),
+            GptPartitionSource(src = package, manifest = manifest),
+        ]
+
+    return ctx.actions.anon_targets([
+        (
+            sendstream_v2_anon,
+            {
+                "antlir2_packager": ctx.attrs.antlir2_packager,
+                "compression_level": ctx.attrs.compression_level,
+                "layer": ctx.attrs.subvols[path]["layer"],
+                "manifest": ctx.attrs.manifest,
+                "name": str(ctx.attrs.subvols[path]["layer"].label.raw_target()),
+                "_rootless": ctx.attrs._rootless,
+            },
+        )
+        for path in paths
+    ]).promise.map(with_anon)
 
 _btrfs = rule(
     impl = _impl,
@@ -86,7 +109,7 @@ _btrfs = rule(
             default = None,
         ),
         "_rootless": rootless_cfg.is_rootless_attr,
-    } | cfg_attrs(),
+    } | cfg_attrs() | manifest_attrs,
     cfg = package_cfg,
 )
 
@@ -12,15 +12,18 @@ load(":btrfs.bzl", "btrfs")
 load(":cfg.bzl", "layer_attrs", "package_cfg")
 load(":gpt.bzl", "GptPartitionSource", "gpt")
 load(":macro.bzl", "package_macro")
+load(":manifest.bzl", "declare_manifest", "manifest_args", "manifest_attrs", "manifest_sub_targets")
+load(":reproducible.bzl", "source_date_epoch_attrs", "source_date_epoch_env")
 load(":sendstream.bzl", "sendstream_v2")
 load(":stamp_buildinfo.bzl", "stamp_buildinfo_rule")
+load(":verity.bzl", "verity")
 
 # Attrs that are required by all packages
 common_attrs = {
     "build_appliance": attrs.option(attrs.exec_dep(providers = [BuildApplianceInfo]), default = None),
     "labels": attrs.list(attrs.string(), default = []),
     "out": attrs.option(attrs.string(doc = "Output filename"), default = None),
-} | layer_attrs
+} | layer_attrs | source_date_epoch_attrs | manifest_attrs
 
 # Attrs that will only ever be used as default_only
 default_attrs = {
@@ -35,6 +38,19 @@ default_attrs = {
     )),
 } | {k: attrs.default_only(v) for k, v in attrs_selected_by_cfg().items()}
 
+def _dep_value(val):
+    if type(val) == "dependency":
+        if RunInfo in val:
+            return val[RunInfo]
+        else:
+            return ensure_single_output(val)
+    return val
+
+def _spec_value(val):
+    if type(val) == "list":
+        return [_dep_value(v) for v in val]
+    return _dep_value(val)
+
 def _generic_impl_with_layer(
         layer: [Dependency, ProviderCollection],
         *,
@@ -53,17 +69,12 @@ def _generic_impl_with_layer(
         output_name += "." + force_extension
 
     package = ctx.actions.declare_output(output_name, dir = is_dir)
+    manifest = declare_manifest(ctx, output_name)
     spec_opts = {}
     if uses_build_appliance:
         spec_opts["build_appliance"] = build_appliance[BuildApplianceInfo].dir
     for key in rule_attr_keys:
-        val = getattr(ctx.attrs, key)
-        if type(val) == "dependency":
-            if RunInfo in val:
-                val = val[RunInfo]
-            else:
-                val = ensure_single_output(val)
-        spec_opts[key] = val
+        spec_opts[key] = _spec_value(getattr(ctx.attrs, key))
 
     spec = ctx.actions.write_json(
         "spec.json",
@@ -72,22 +83,24 @@ def _generic_impl_with_layer(
     )
     ctx.actions.run(
         cmd_args(
-            cmd_args("sudo", "--preserve-env=TMPDIR") if (sudo and not ctx.attrs._rootless) else cmd_args(),
+            cmd_args("sudo", "--preserve-env=TMPDIR,SOURCE_DATE_EPOCH") if (sudo and not ctx.attrs._rootless) else cmd_args(),
             ctx.attrs._antlir2_packager[RunInfo],
             cmd_args(spec, format = "--spec={}"),
             cmd_args(layer[LayerInfo].contents.subvol_symlink, format = "--layer={}"),
             "--dir" if is_dir else cmd_args(),
             cmd_args(package.as_output(), format = "--out={}"),
+            manifest_args(manifest),
             "--rootless" if ctx.attrs._rootless else cmd_args(),
         ),
         local_only = True,
         category = "antlir2_package",
         identifier = format,
+        env = source_date_epoch_env(ctx),
     )
 
-    providers = [DefaultInfo(package)]
+    providers = [DefaultInfo(package, sub_targets = manifest_sub_targets(manifest))]
     if can_be_partition:
-        providers.append(GptPartitionSource(src = package))
+        providers.append(GptPartitionSource(src = package, manifest = manifest))
     return providers
 
 def _generic_impl(
@@ -175,108 +188,6 @@ def _new_package_rule(
         ),
     )
 
-def _compressed_impl(
-        ctx: AnalysisContext,
-        uncompressed: typing.Callable,
-        rule_attr_keys: list[str],
-        compressor: str) -> list[Provider]:
-    src = ctx.actions.anon_target(
-        uncompressed,
-        {key: getattr(ctx.attrs, key) for key in default_attrs.keys()} |
-        {
-            "layer": ctx.attrs.layer,
-            "name": str(ctx.label.raw_target()),
-            "out": "uncompressed",
-        } | {key: getattr(ctx.attrs, key) for key in rule_attr_keys} | (
-            {"dot_meta": ctx.attrs.dot_meta} if ctx.attrs.dot_meta != None else {}
-        ),
-    ).artifact("package")
-    package = ctx.actions.declare_output(ctx.label.name)
-
-    if compressor == "gzip":
-        compress_cmd = cmd_args(
-            "compressor=\"$(which pigz || which gzip)\"",
-            cmd_args(
-                "$compressor",
-                cmd_args(str(ctx.attrs.compression_level), format = "-{}"),
-                src,
-                cmd_args(package.as_output(), format = "--stdout > {}"),
-                delimiter = " \\\n",
-            ),
-            delimiter = " \n",
-        )
-    elif compressor == "zstd":
-        compress_cmd = cmd_args(
-            "zstd",
-            "--compress",
-            cmd_args(str(ctx.attrs.compression_level), format = "-{}"),
-            "-T0",  # we like threads
-            src,
-            cmd_args(package.as_output(), format = "--stdout > {}"),
-            delimiter = " \\\n",
-        )
-    else:
-        fail("unknown compressor '{}'".format(compressor))
-
-    script = ctx.actions.write(
-        "compress.sh",
-        cmd_args(
-            "#!/bin/sh",
-            compress_cmd,
-            delimiter = "\n",
-        ),
-        is_executable = True,
-    )
-    ctx.actions.run(
-        cmd_args(
-            "/bin/sh",
-            script,
-            hidden = [package.as_output(), src],
-        ),
-        category = "compress",
-        identifier = compressor,
-    )
-    return [DefaultInfo(package, sub_targets = {
-        "uncompressed": [DefaultInfo(src)],
-    })]
-
-def _new_compressed_package_rule(
-        compressor: str,
-        uncompressed: typing.Callable,
-        default_compression_level: int,
-        rule_attrs: dict[str, Attr] = {}):
-    return rule(
-        impl = partial(
-            _compressed_impl,
-            uncompressed = uncompressed,
-            rule_attr_keys = list(rule_attrs.keys()),
-            compressor = compressor,
-        ),
-        attrs = default_attrs | common_attrs | rule_attrs | {
-            "compression_level": attrs.int(default = default_compression_level),
-            "dot_meta": attrs.option(attrs.bool(), default = None),
-        },
-        cfg = package_cfg,
-    )
-
-_cpio, _cpio_anon = _new_package_rule(
-    format = "cpio",
-    sudo = True,
-    uses_build_appliance = True,
-)
-
-_cpio_gz = _new_compressed_package_rule(
-    default_compression_level = 3,
-    uncompressed = _cpio_anon,
-    compressor = "gzip",
-)
-
-_cpio_zst = _new_compressed_package_rule(
-    default_compression_level = 15,
-    uncompressed = _cpio_anon,
-    compressor = "zstd",
-)
-
 _rpm, _rpm_anon = _new_package_rule(
     rule_attrs = {
         "arch": attrs.enum(
@@ -338,7 +249,6 @@ _vfat, _vfat_anon = _new_package_rule(
     format = "vfat",
     sudo = True,
     can_be_partition = True,
-    uses_build_appliance = True,
 )
 
 _squashfs, squashfs_anon = _new_package_rule(
@@ -353,23 +263,69 @@ _squashfs, squashfs_anon = _new_package_rule(
     uses_build_appliance = True,
 )
 
-_tar, tar_anon = _new_package_rule(
+def _tar_rule_attrs(*, compression: str | None = None, default_compression_level: int | None = None) -> dict[str, Attr]:
+    return {
+        "compression": attrs.default_only(attrs.string(default = compression)) if compression else attrs.enum(
+            ["none", "gzip", "zstd", "xz"],
+            default = "none",
+        ),
+        "compression_level": attrs.option(attrs.int(), default = default_compression_level),
+        "compression_threads": attrs.option(
+            attrs.int(),
+            default = None,
+            doc = "number of compression threads (zstd and xz only), defaults to all cpus",
+        ),
+    }
+
+_tar, _tar_anon = _new_package_rule(
     format = "tar",
+    rule_attrs = _tar_rule_attrs(),
     sudo = True,
-    uses_build_appliance = True,
     force_extension = "tar",
 )
 
-_tar_gz = _new_compressed_package_rule(
-    default_compression_level = 3,
-    uncompressed = tar_anon,
-    compressor = "gzip",
+_tar_gz, _tar_gz_anon = _new_package_rule(
+    format = "tar",
+    rule_attrs = _tar_rule_attrs(compression = "gzip", default_compression_level = 3),
+    sudo = True,
+)
+
+tar_zst_rule, _tar_zst_anon = _new_package_rule(
+    format = "tar",
+    rule_attrs = _tar_rule_attrs(compression = "zstd", default_compression_level = 15),
+    sudo = True,
+)
+
+def _cpio_rule_attrs(*, compression: str | None = None, default_compression_level: int | None = None) -> dict[str, Attr]:
+    return _tar_rule_attrs(
+        compression = compression,
+        default_compression_level = default_compression_level,
+    ) | {
+        "early_microcode": attrs.dict(
+            attrs.enum(["GenuineIntel", "AuthenticAMD"]),
+            attrs.list(attrs.string()),
+            default = {},
+            doc = "microcode files (or directories of them) in the layer to " +
+                  "prepend as an uncompressed early microcode archive, by cpu vendor",
+        ),
+    }
+
+_cpio, _cpio_anon = _new_package_rule(
+    format = "cpio",
+    rule_attrs = _cpio_rule_attrs(),
+    sudo = True,
+)
+
+_cpio_gz, _cpio_gz_anon = _new_package_rule(
+    format = "cpio",
+    rule_attrs = _cpio_rule_attrs(compression = "gzip", default_compression_level = 3),
+    sudo = True,
 )
 
-tar_zst_rule = _new_compressed_package_rule(
-    default_compression_level = 15,
-    uncompressed = tar_anon,
-    compressor = "zstd",
+_cpio_zst, _cpio_zst_anon = _new_package_rule(
+    format = "cpio",
+    rule_attrs = _cpio_rule_attrs(compression = "zstd", default_compression_level = 15),
+    sudo = True,
 )
 
 _ext3, _ext3_anon = _new_package_rule(
@@ -391,12 +347,80 @@ _ext3, _ext3_anon = _new_package_rule(
     uses_build_appliance = True,
 )
 
+_ext4, _ext4_anon = _new_package_rule(
+    format = "ext4",
+    rule_attrs = {
+        "block_size": attrs.int(default = 4096),
+        "features": attrs.list(
+            attrs.string(),
+            default = [],
+            doc = "features to enable (or disable when prefixed with '^') on top of a fixed base set (the mke2fs.conf defaults are not used)",
+        ),
+        "free_mb": attrs.int(
+            default = 0,
+            doc = "include at least this much free space in the image",
+        ),
+        "inode_size": attrs.int(default = 256),
+        "inodes": attrs.option(
+            attrs.int(),
+            default = None,
+            doc = "total number of inodes (default is enough for the layer and free_mb)",
+        ),
+        "label": attrs.option(attrs.string(), default = None),
+        "reserved_blocks": attrs.int(
+            default = 0,
+            doc = "number of blocks that only root can allocate",
+        ),
+        "size_mb": attrs.option(
+            attrs.int(),
+            default = None,
+            doc = "absolute size of the image (default is the minimal size for the layer)",
+        ),
+        "uuid": attrs.option(
+            attrs.string(),
+            default = None,
+            doc = "filesystem uuid (default is derived from the layer contents)",
+        ),
+    },
+    can_be_partition = True,
+    sudo = True,
+    uses_build_appliance = True,
+)
+
+_chunk_store, _chunk_store_anon = _new_package_rule(
+    format = "chunk_store",
+    rule_attrs = {
+        "avg_chunk_size": attrs.int(
+            default = 65536,
+            doc = "target average chunk size (power of two), chunks range from 1/4 to 4x this",
+        ),
+        "compression_level": attrs.int(default = 3, doc = "zstd level for each chunk"),
+    },
+    is_dir = True,
+    sudo = True,
+)
+
 _unprivileged_dir, _unprivileged_dir_anon = _new_package_rule(
     format = "unprivileged_dir",
     is_dir = True,
     sudo = True,
 )
 
+_composefs, _composefs_anon = _new_package_rule(
+    format = "composefs",
+    rule_attrs = {
+        "label": attrs.option(attrs.string(), default = None),
+        "object_digest": attrs.enum(
+            ["sha256", "fsverity"],
+            default = "fsverity",
+            doc = "how objects are named (fsverity digests are also recorded in the image for overlayfs to enforce)",
+        ),
+    },
+    is_dir = True,
+    sudo = True,
+    uses_build_appliance = True,
+)
+
 _erofs, _erofs_anon = _new_package_rule(
     format = "erofs",
     sudo = True,
@@ -408,20 +432,129 @@ _erofs, _erofs_anon = _new_package_rule(
     can_be_partition = True,
 )
 
+_iso, _iso_anon = _new_package_rule(
+    format = "iso",
+    rule_attrs = {
+        "bios_boot_image": attrs.option(
+            attrs.string(),
+            default = None,
+            doc = "path in the layer of a no-emulation El Torito boot image (like isolinux.bin)",
+        ),
+        "bios_boot_info_table": attrs.bool(default = True),
+        "bios_boot_load_size": attrs.int(default = 4, doc = "number of 512-byte sectors to load"),
+        "boot_catalog": attrs.string(default = "boot.catalog"),
+        "efi_boot_image": attrs.option(
+            attrs.dep(),
+            default = None,
+            doc = "FAT image (like a package.vfat) for the UEFI El Torito boot entry",
+        ),
+        "volume_label": attrs.option(attrs.string(), default = None),
+    },
+    force_extension = "iso",
+    sudo = True,
+    uses_build_appliance = True,
+)
+
+_uki, _uki_anon = _new_package_rule(
+    format = "uki",
+    rule_attrs = {
+        "cmdline": attrs.list(attrs.string(), default = [], doc = "Kernel command line arguments"),
+        "initrds": attrs.list(
+            attrs.one_of(attrs.dep(), attrs.string()),
+            default = [],
+            doc = "initrds (like package.cpio_zst outputs) to concatenate into the .initrd section",
+        ),
+        "kernel": attrs.one_of(attrs.dep(), attrs.string()),
+        "os_release": attrs.option(
+            attrs.one_of(attrs.dep(), attrs.string()),
+            default = None,
+            doc = "Defaults to the os-release file in the layer",
+        ),
+        "stub": attrs.one_of(
+            attrs.dep(),
+            attrs.string(),
+            default = arch_select(
+                aarch64 = "/usr/lib/systemd/boot/efi/linuxaa64.efi.stub",
+                x86_64 = "/usr/lib/systemd/boot/efi/linuxx64.efi.stub",
+            ),
+        ),
+        "uname": attrs.option(
+            attrs.string(),
+            default = None,
+            doc = "Kernel release, read from the kernel image (x86_64 only) if unset",
+        ),
+    },
+    dot_meta = False,
+    force_extension = "efi",
+    sudo = True,
+)
+
+_deb, _deb_anon = _new_package_rule(
+    format = "deb",
+    rule_attrs = {
+        "arch": attrs.string(
+            default = arch_select(aarch64 = "arm64", x86_64 = "amd64"),
+            doc = "Debian architecture name (like 'amd64' or 'all')",
+        ),
+        "breaks": attrs.list(attrs.string(), default = []),
+        "compression": attrs.enum(["none", "gzip", "zstd", "xz"], default = "xz"),
+        "compression_level": attrs.option(attrs.int(), default = None),
+        "compression_threads": attrs.option(
+            attrs.int(),
+            default = None,
+            doc = "number of compression threads (zstd and xz only), defaults to all cpus",
+        ),
+        "conffiles": attrs.list(
+            attrs.string(),
+            default = [],
+            doc = "absolute paths of files that dpkg should preserve if they were modified locally",
+        ),
+        "conflicts": attrs.list(attrs.string(), default = []),
+        "deb_name": attrs.string(),
+        "depends": attrs.list(attrs.string(), default = []),
+        "description": attrs.option(attrs.string(), default = None),
+        "homepage": attrs.option(attrs.string(), default = None),
+        "maintainer": attrs.string(),
+        "post_install_script": attrs.option(attrs.string(), default = None),
+        "post_uninstall_script": attrs.option(attrs.string(), default = None),
+        "pre_depends": attrs.list(attrs.string(), default = []),
+        "pre_install_script": attrs.option(attrs.string(), default = None),
+        "pre_uninstall_script": attrs.option(attrs.string(), default = None),
+        "priority": attrs.option(attrs.string(), default = None),
+        "provides": attrs.list(attrs.string(), default = []),
+        "recommends": attrs.list(attrs.string(), default = []),
+        "replaces": attrs.list(attrs.string(), default = []),
+        "section": attrs.option(attrs.string(), default = None),
+        "suggests": attrs.list(attrs.string(), default = []),
+        "summary": attrs.option(attrs.string(), default = None),
+        "version": attrs.string(),
+    },
+    dot_meta = False,
+    force_extension = "deb",
+    sudo = True,
+)
+
 package = struct(
     btrfs = btrfs,
+    chunk_store = package_macro(_chunk_store),
+    composefs = package_macro(_composefs),
     cpio = package_macro(_cpio),
     cpio_gz = package_macro(_cpio_gz),
     cpio_zst = package_macro(_cpio_zst),
+    deb = package_macro(_deb),
     erofs = package_macro(_erofs),
     ext3 = package_macro(_ext3),
+    ext4 = package_macro(_ext4),
     gpt = gpt,
+    iso = package_macro(_iso),
     rpm = package_macro(_rpm, always_rootless = True),
     sendstream_v2 = sendstream_v2,
     squashfs = package_macro(_squashfs),
     tar = package_macro(_tar),
     tar_gz = package_macro(_tar_gz),
     tar_zst = package_macro(tar_zst_rule),
+    uki = package_macro(_uki),
     unprivileged_dir = package_macro(_unprivileged_dir),
+    verity = verity,
     vfat = package_macro(_vfat),
 )
@@ -3,25 +3,30 @@
 # This source code is licensed under the MIT license found in the
 # LICENSE file in the root directory of this source tree.
 
-load("//antlir/antlir2/bzl:types.bzl", "BuildApplianceInfo", "LayerInfo")
 load("//antlir/buck2/bzl:ensure_single_output.bzl", "ensure_single_output")
 load(":cfg.bzl", "layer_attrs", "package_cfg")
 load(":defs.bzl", "common_attrs", "default_attrs")
 load(":macro.bzl", "package_macro")
 load(":oci.bzl", "oci_attrs", "oci_rule")
+load(":reproducible.bzl", "source_date_epoch_env")
 
 def _impl(ctx: AnalysisContext) -> Promise:
-    build_appliance = ctx.attrs.build_appliance or ctx.attrs.layer[LayerInfo].build_appliance
-
     def with_anon(oci) -> list[Provider]:
         out = ctx.actions.declare_output(ctx.label.name)
+        # the archive holds exactly the same root filesystem as the oci image
+        # (which is built with a manifest if this is), so just reuse that
+        sub_targets = {}
+        if "manifest" in oci[DefaultInfo].sub_targets:
+            sub_targets["manifest"] = oci[DefaultInfo].sub_targets["manifest"]
 
         oci = ensure_single_output(oci)
         spec = ctx.actions.write_json(
             "spec.json",
             {"docker_archive": {
-                "build_appliance": build_appliance[BuildApplianceInfo].dir,
+                "layer_compression": ctx.attrs.archive_layer_compression,
                 "oci": oci,
+                "repo_tags": ctx.attrs.repo_tags,
+                "target_arch": ctx.attrs._target_arch,
             }},
             with_inputs = True,
         )
@@ -34,9 +39,10 @@ def _impl(ctx: AnalysisContext) -> Promise:
             ),
             category = "antlir2_package",
             identifier = "docker_archive",
+            env = source_date_epoch_env(ctx),
         )
         return [
-            DefaultInfo(out, sub_targets = {"oci": [DefaultInfo(oci)]}),
+            DefaultInfo(out, sub_targets = {"oci": [DefaultInfo(oci)]} | sub_targets),
         ]
 
     all_attrs = {
@@ -49,9 +55,22 @@ def _impl(ctx: AnalysisContext) -> Promise:
         {"name": str(ctx.attrs.layer.label.raw_target())} | all_attrs,
     ).promise.map(with_anon)
 
+_docker_archive_attrs = {
+    "archive_layer_compression": attrs.enum(
+        ["uncompressed", "gzip"],
+        default = "uncompressed",
+        doc = "Compression of layers in the archive (docker load does not support zstd)",
+    ),
+    "repo_tags": attrs.list(
+        attrs.string(),
+        default = [],
+        doc = "Tags to load the image as (':latest' is added to any without a tag)",
+    ),
+}
+
 _docker_archive = rule(
     impl = _impl,
-    attrs = oci_attrs | layer_attrs | default_attrs | common_attrs,
+    attrs = _docker_archive_attrs | oci_attrs | layer_attrs | default_attrs | common_attrs,
     cfg = package_cfg,
 )
 
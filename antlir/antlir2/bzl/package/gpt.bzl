@@ -3,31 +3,122 @@
 # This source code is licensed under the MIT license found in the
 # LICENSE file in the root directory of this source tree.
 
-load("//antlir/antlir2/bzl:platform.bzl", "rule_with_default_target_platform")
+load("//antlir/antlir2/bzl:platform.bzl", "arch_select", "rule_with_default_target_platform")
 load("//antlir/antlir2/bzl:types.bzl", "LayerInfo")
+load(":manifest.bzl", "declare_manifest", "manifest_args", "manifest_attrs", "manifest_sub_targets")
 
-GptPartitionSource = provider(fields = ["src"])
+GptPartitionSource = provider(fields = {
+    "src": provider_field(Artifact),
+    # manifest of the package, if it was built with one
+    "manifest": provider_field(Artifact | None, default = None),
+    # set for images produced by package.verity, so that the partition uuid can
+    # be derived from the root hash
+    "verity": provider_field(dict[str, typing.Any] | None, default = None),
+})
 
-PartitionType = enum("linux", "esp")
+# Architectures in the Discoverable Partitions Specification, named the same
+# way as in `systemd-id128 show`
+_DPS_ARCHES = [
+    "alpha",
+    "arc",
+    "arm",
+    "arm64",
+    "ia64",
+    "loongarch64",
+    "mips",
+    "mips64",
+    "mips-le",
+    "mips64-le",
+    "parisc",
+    "ppc",
+    "ppc64",
+    "ppc64-le",
+    "riscv32",
+    "riscv64",
+    "s390",
+    "s390x",
+    "tilegx",
+    "x86",
+    "x86-64",
+]
+
+# root and usr partitions (and their verity partitions) have a different type
+# for every architecture. The bare names (like "root") are resolved to the
+# architecture of the target platform.
+_DPS_ARCH_KINDS = [
+    "root-{}",
+    "root-{}-verity",
+    "root-{}-verity-sig",
+    "usr-{}",
+    "usr-{}-verity",
+    "usr-{}-verity-sig",
+]
+
+PartitionType = enum(*(
+    [
+        "bios-boot",
+        "esp",
+        "home",
+        "linux",
+        "srv",
+        "swap",
+        "tmp",
+        "user-home",
+        "var",
+        "xbootldr",
+    ] +
+    [kind.replace("-{}", "") for kind in _DPS_ARCH_KINDS] +
+    [kind.format(arch) for arch in _DPS_ARCHES for kind in _DPS_ARCH_KINDS]
+))
+
+PartitionAttribute = enum(
+    "legacy-bios-bootable",
+    "grow-fs",
+    "read-only",
+    "no-auto",
+)
 
 def Partition(
         src: str,
         type: PartitionType = PartitionType("linux"),
         label: str | None = None,
-        alignment: int | None = None):
-    return (src, type.value, label, alignment, "_internal_came_from_package_fn")
+        alignment: int | None = None,
+        guid: str | None = None,
+        attributes: list[PartitionAttribute] = [],
+        hybrid_mbr: bool = False):
+    return (
+        src,
+        type.value,
+        label,
+        alignment,
+        guid,
+        [a.value for a in attributes],
+        hybrid_mbr,
+        "_internal_came_from_package_fn",
+    )
+
+def _resolve_type(type: str, arch: str) -> str:
+    for kind in _DPS_ARCH_KINDS:
+        if type == kind.replace("-{}", ""):
+            return kind.format(arch)
+    return type
 
 def _impl(ctx: AnalysisContext) -> list[Provider]:
     partitions = []
-    for src, type, label, alignment, _token in ctx.attrs.partitions:
+    for src, type, label, alignment, guid, attributes, hybrid_mbr, _token in ctx.attrs.partitions:
         if alignment == 0 or (alignment != None and alignment % ctx.attrs.block_size != 0):
             fail("alignment must be a multiple of block size")
 
         partitions.append({
             "alignment": alignment,
+            "attributes": attributes,
+            "guid": guid,
+            "hybrid_mbr": hybrid_mbr,
+            "manifest": src[GptPartitionSource].manifest,
             "name": label,
             "src": src[GptPartitionSource].src,
-            "type": type,
+            "type": _resolve_type(type, ctx.attrs._dps_arch),
+            "verity": src[GptPartitionSource].verity,
         })
 
     if not partitions:
@@ -43,17 +134,20 @@ def _impl(ctx: AnalysisContext) -> list[Provider]:
             "gpt": {
                 "block_size": str(ctx.attrs.block_size),
                 "disk_guid": ctx.attrs.disk_guid,
+                "mbr": ctx.attrs.mbr,
                 "partitions": partitions,
             },
         },
         with_inputs = True,
     )
     out = ctx.actions.declare_output("package.gpt")
+    manifest = declare_manifest(ctx, "package.gpt")
     ctx.actions.run(
         cmd_args(
             ctx.attrs._antlir2_packager[RunInfo],
             cmd_args(spec, format = "--spec={}"),
             cmd_args(out.as_output(), format = "--out={}"),
+            manifest_args(manifest),
         ),
         category = "antlir2_package",
         identifier = "gpt",
@@ -62,7 +156,7 @@ def _impl(ctx: AnalysisContext) -> list[Provider]:
         out,
         sub_targets = {
             "spec.json": [DefaultInfo(spec_json)],
-        },
+        } | manifest_sub_targets(manifest),
     )]
 
 _gpt = rule(
@@ -72,17 +166,26 @@ _gpt = rule(
         "build_appliance": attrs.option(attrs.exec_dep(providers = [LayerInfo]), default = None),
         "disk_guid": attrs.option(attrs.string(), default = None),
         "labels": attrs.list(attrs.string(), default = []),
+        "mbr": attrs.enum(
+            ["none", "protective", "hybrid"],
+            default = "protective",
+            doc = "hybrid mirrors partitions created with hybrid_mbr=True in the MBR",
+        ),
         "partitions": attrs.list(
             attrs.tuple(
                 attrs.dep(providers = [GptPartitionSource]),
                 attrs.enum(PartitionType.values(), default = "linux"),
                 attrs.option(attrs.string()),
                 attrs.option(attrs.int()),
+                attrs.option(attrs.string()),
+                attrs.list(attrs.enum(PartitionAttribute.values())),
+                attrs.bool(),
                 attrs.enum(["_internal_came_from_package_fn"]),
             ),
         ),
         "_antlir2_packager": attrs.default_only(attrs.exec_dep(default = "antlir//antlir/antlir2/antlir2_packager:antlir2-packager")),
-    },
+        "_dps_arch": attrs.default_only(attrs.string(default = arch_select(aarch64 = "arm64", x86_64 = "x86-64"))),
+    } | manifest_attrs,
 )
 
 gpt = rule_with_default_target_platform(_gpt)
@@ -0,0 +1,32 @@
+# Copyright (c) Meta Platforms, Inc. and affiliates.
+#
+# This source code is licensed under the MIT license found in the
+# LICENSE file in the root directory of this source tree.
+
+manifest_attrs = {
+    "manifest": attrs.bool(
+        default = False,
+        doc = "Also write a json listing of every file that was packaged " +
+              "(with its metadata and sha256), available as the [manifest] " +
+              "subtarget",
+    ),
+}
+
+def declare_manifest(ctx: AnalysisContext, output_name: str) -> Artifact | None:
+    """
+    Declare the manifest sidecar for a package named `output_name`, if the
+    user asked for one
+    """
+    if not ctx.attrs.manifest:
+        return None
+    return ctx.actions.declare_output(output_name + ".manifest.json")
+
+def manifest_args(manifest: Artifact | None) -> cmd_args:
+    if not manifest:
+        return cmd_args()
+    return cmd_args(manifest.as_output(), format = "--manifest={}")
+
+def manifest_sub_targets(manifest: Artifact | None) -> dict[str, list[Provider]]:
+    if not manifest:
+        return {}
+    return {"manifest": [DefaultInfo(manifest)]}
@@ -7,16 +7,26 @@ load("//antlir/antlir2/bzl:types.bzl", "LayerInfo")
 load(":cfg.bzl", "layer_attrs", "package_cfg")
 load(":defs.bzl", "common_attrs", "default_attrs")
 load(":macro.bzl", "package_macro")
+load(":manifest.bzl", "declare_manifest", "manifest_args", "manifest_sub_targets")
+load(":reproducible.bzl", "source_date_epoch_attrs", "source_date_epoch_env")
 
 OciLayer = record(
     tar = Artifact,
-    tar_zst = Artifact,
+    # recorded in the image config history
+    label = str,
 )
 
 OciLayersInfo = provider(fields = [
     "layers",  # [(BuildPhase, Artifact)]
 ])
 
+# A single-architecture image that can be combined with others into a
+# multi-arch index
+OciManifestInfo = provider(fields = {
+    "manifest": typing.Any,  # json-able dict of the image manifest spec
+    "ref": str,
+})
+
 def _oci_layers_impl(ctx: AnalysisContext) -> list[Provider]:
     layer = ctx.attrs.layer[LayerInfo]
 
@@ -32,7 +42,7 @@ def _oci_layers_impl(ctx: AnalysisContext) -> list[Provider]:
             parent = parent[1]  # parent phase info doesn't matter, throw it away
         ctx.actions.run(
             cmd_args(
-                "sudo" if not ctx.attrs._rootless else cmd_args(),
+                cmd_args("sudo", "--preserve-env=SOURCE_DATE_EPOCH") if not ctx.attrs._rootless else cmd_args(),
                 ctx.attrs._make_oci_layer[RunInfo],
                 "--rootless" if ctx.attrs._rootless else cmd_args(),
                 cmd_args(parent.subvol_symlink, format = "--parent={}") if parent else cmd_args(),
@@ -42,28 +52,12 @@ def _oci_layers_impl(ctx: AnalysisContext) -> list[Provider]:
             local_only = True,  # comparing local subvols
             category = "oci_layer",
             identifier = child_phase.value,
+            env = source_date_epoch_env(ctx),
         )
 
-        # the uncompressed tar is needed for hashing, but then we want to put a
-        # compressed tar in the actual archive
-        # need a compressed tar to actually put in the archive, but the
-        tar_zst = ctx.actions.declare_output(child_phase.value, "layer.tar.zst")
-        ctx.actions.run(
-            cmd_args(
-                "zstd",
-                "--compress",
-                "-15",
-                "-T0",  # we like threads
-                tar,
-                "-o",
-                tar_zst.as_output(),
-            ),
-            category = "oci_layer_compress",
-            identifier = child_phase.value,
-        )
         oci_layers.append((child_phase, OciLayer(
             tar = tar,
-            tar_zst = tar_zst,
+            label = "{} ({})".format(layer.label.raw_target(), child_phase.value),
         )))
 
     return [
@@ -81,10 +75,41 @@ _oci_layers = anon_rule(
             ),
         ),
         "_rootless": attrs.bool(),
-    },
+    } | source_date_epoch_attrs,
     artifact_promise_mappings = {},
 )
 
+def _build_oci(
+        ctx: AnalysisContext,
+        *,
+        ref: str,
+        manifests: list[typing.Any],
+        package_manifest: Artifact | None = None) -> Artifact:
+    out = ctx.actions.declare_output(ctx.label.name, dir = True)
+    spec = ctx.actions.write_json(
+        "spec.json",
+        {"oci": {
+            "manifests": manifests,
+            "ref": ref,
+        }},
+        with_inputs = True,
+    )
+    ctx.actions.run(
+        cmd_args(
+            ctx.attrs._antlir2_packager[RunInfo],
+            "--dir",
+            cmd_args(out.as_output(), format = "--out={}"),
+            cmd_args(spec, format = "--spec={}"),
+            manifest_args(package_manifest),
+        ),
+        category = "antlir2_package",
+        identifier = "oci",
+        env = source_date_epoch_env(ctx),
+        # reading from a layer subvol can only be done locally
+        local_only = any([m["env_defaults_from_layer"] for m in manifests]),
+    )
+    return out
+
 def _impl(ctx: AnalysisContext) -> Promise:
     layers = [ctx.attrs.layer]
     for _ in range(0, 1000):
@@ -102,37 +127,40 @@ def _impl(ctx: AnalysisContext) -> Promise:
                 deltas.append(layer)
                 multi_layer_subtargets[phase.value] = [DefaultInfo(sub_targets = {
                     "tar": [DefaultInfo(layer.tar)],
-                    "tar.zst": [DefaultInfo(layer.tar_zst)],
                 })]
             sub_targets_layers[str(i)] = [DefaultInfo(sub_targets = multi_layer_subtargets)]
 
-        out = ctx.actions.declare_output(ctx.label.name, dir = True)
-        spec = ctx.actions.write_json(
-            "spec.json",
-            {"oci": {
-                "deltas": deltas,
-                "entrypoint": ctx.attrs.entrypoint,
-                "ref": ctx.attrs.ref,
-                "target_arch": ctx.attrs._target_arch,
-            }},
-            with_inputs = True,
-        )
-        ctx.actions.run(
-            cmd_args(
-                ctx.attrs._antlir2_packager[RunInfo],
-                "--dir",
-                cmd_args(out.as_output(), format = "--out={}"),
-                cmd_args(spec, format = "--spec={}"),
-            ),
-            category = "antlir2_package",
-            identifier = "oci",
+        manifest = {
+            "cmd": ctx.attrs.cmd,
+            "deltas": deltas,
+            "entrypoint": ctx.attrs.entrypoint,
+            "env": ctx.attrs.env,
+            "env_defaults_from_layer": ctx.attrs.layer[LayerInfo].contents.subvol_symlink if ctx.attrs.env_from_etc_environment else None,
+            "exposed_ports": ctx.attrs.exposed_ports,
+            "labels": ctx.attrs.config_labels,
+            "layer_compression": ctx.attrs.layer_compression,
+            "stop_signal": ctx.attrs.stop_signal,
+            "target_arch": ctx.attrs._target_arch,
+            "user": ctx.attrs.user,
+            "volumes": ctx.attrs.volumes,
+            "working_dir": ctx.attrs.working_dir,
+        }
+        package_manifest = declare_manifest(ctx, ctx.label.name)
+        out = _build_oci(
+            ctx,
+            ref = ctx.attrs.ref,
+            manifests = [manifest],
+            package_manifest = package_manifest,
         )
         return [
             DefaultInfo(
                 out,
-                sub_targets = {"layers": [DefaultInfo(sub_targets = sub_targets_layers)]},
+                sub_targets = {
+                    "layers": [DefaultInfo(sub_targets = sub_targets_layers)],
+                } | manifest_sub_targets(package_manifest),
             ),
             RunInfo(cmd_args(out)),
+            OciManifestInfo(manifest = manifest, ref = ctx.attrs.ref),
         ]
 
     return ctx.actions.anon_targets([
@@ -141,6 +169,7 @@ def _impl(ctx: AnalysisContext) -> Promise:
             {
                 "layer": layer,
                 "name": layer[LayerInfo].label,
+                "source_date_epoch": ctx.attrs.source_date_epoch,
                 "_make_oci_layer": ctx.attrs._make_oci_layer,
                 "_rootless": ctx.attrs._rootless,
             },
@@ -149,11 +178,38 @@ def _impl(ctx: AnalysisContext) -> Promise:
     ]).promise.map(_with_anon)
 
 oci_attrs = {
+    "cmd": attrs.option(
+        attrs.list(attrs.string()),
+        default = None,
+        doc = "Default arguments to the entrypoint",
+    ),
+    "config_labels": attrs.dict(
+        attrs.string(),
+        attrs.string(),
+        default = {},
+        doc = "Labels in the image config (not to be confused with buck labels)",
+    ),
     "entrypoint": attrs.list(attrs.string(), doc = "Command to run as the main process"),
+    "env": attrs.dict(attrs.string(), attrs.string(), default = {}, doc = "Environment variables"),
+    "env_from_etc_environment": attrs.bool(
+        default = False,
+        doc = "Use variables in the layer's /etc/environment (like PATH) as defaults for 'env'",
+    ),
+    "exposed_ports": attrs.list(attrs.string(), default = [], doc = "Ports to expose, as port[/proto]"),
+    "layer_compression": attrs.enum(
+        ["uncompressed", "gzip", "zstd", "zstd_chunked", "estargz"],
+        default = "zstd",
+        doc = "Compression for layer blobs. Some older registries and runtimes only support gzip. " +
+              "zstd_chunked and estargz are seekable formats that allow lazy pulling",
+    ),
     "ref": attrs.string(
         default = native.read_config("build_info", "revision", "local"),
         doc = "Ref name for OCI image",
     ),
+    "stop_signal": attrs.option(attrs.string(), default = None, doc = "Signal to stop the container"),
+    "user": attrs.option(attrs.string(), default = None, doc = "User (and optionally group) to run as"),
+    "volumes": attrs.list(attrs.string(), default = [], doc = "Directories that should be volumes"),
+    "working_dir": attrs.option(attrs.string(), default = None, doc = "Working directory of the entrypoint"),
     "_make_oci_layer": attrs.default_only(
         attrs.exec_dep(
             default = "antlir//antlir/antlir2/antlir2_packager/make_oci_layer:make-oci-layer",
@@ -167,4 +223,63 @@ oci_rule = rule(
     cfg = package_cfg,
 )
 
-oci = package_macro(oci_rule)
+_oci = package_macro(oci_rule)
+
+def _multi_arch_impl(ctx: AnalysisContext) -> list[Provider]:
+    refs = {image[OciManifestInfo].ref: None for image in ctx.attrs.images}
+    if len(refs) != 1:
+        fail("all images in a multi-arch index must have the same ref, got {}".format(list(refs)))
+    out = _build_oci(
+        ctx,
+        ref = list(refs)[0],
+        manifests = [image[OciManifestInfo].manifest for image in ctx.attrs.images],
+    )
+    return [
+        DefaultInfo(out, sub_targets = {
+            image[OciManifestInfo].manifest["target_arch"]: image.providers
+            for image in ctx.attrs.images
+        }),
+        RunInfo(cmd_args(out)),
+    ]
+
+_oci_multi_arch = rule(
+    impl = _multi_arch_impl,
+    attrs = {
+        "images": attrs.list(attrs.dep(providers = [OciManifestInfo])),
+        "labels": attrs.list(attrs.string(), default = []),
+        "_antlir2_packager": default_attrs["_antlir2_packager"],
+    } | source_date_epoch_attrs,
+)
+
+def oci(
+        *,
+        name: str,
+        target_arches: list[str] | None = None,
+        **kwargs):
+    """
+    Build an OCI image layout from an image layer.
+
+    If `target_arches` is given, `layer` is built once for each architecture
+    and all the images are combined into a single multi-arch index (with one
+    manifest per architecture). Each single-arch image is also available as a
+    subtarget named for its architecture (eg `:name[x86_64]`).
+    """
+    if not target_arches:
+        return _oci(name = name, **kwargs)
+
+    visibility = kwargs.pop("visibility", None)
+    labels = kwargs.get("labels", [])
+    for arch in target_arches:
+        _oci(
+            name = "{}--{}".format(name, arch),
+            target_arch = arch,
+            visibility = [":" + name],
+            **kwargs
+        )
+    _oci_multi_arch(
+        name = name,
+        images = [":{}--{}".format(name, arch) for arch in target_arches],
+        labels = labels,
+        source_date_epoch = kwargs.get("source_date_epoch"),
+        visibility = visibility,
+    )
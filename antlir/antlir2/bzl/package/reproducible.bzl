@@ -0,0 +1,22 @@
+# Copyright (c) Meta Platforms, Inc. and affiliates.
+#
+# This source code is licensed under the MIT license found in the
+# LICENSE file in the root directory of this source tree.
+
+source_date_epoch_attrs = {
+    "source_date_epoch": attrs.option(
+        attrs.int(),
+        default = None,
+        doc = "No timestamp in the package will be later than this (in " +
+              "seconds since the unix epoch). Defaults to February 4 2004",
+    ),
+}
+
+def source_date_epoch_env(ctx: AnalysisContext) -> dict[str, str]:
+    """
+    Environment for the packager that has SOURCE_DATE_EPOCH set if the user
+    chose one (otherwise the packager uses its own default)
+    """
+    if ctx.attrs.source_date_epoch == None:
+        return {}
+    return {"SOURCE_DATE_EPOCH": str(ctx.attrs.source_date_epoch)}
@@ -8,6 +8,8 @@ load("//antlir/antlir2/antlir2_rootless:cfg.bzl", "rootless_cfg")
 load("//antlir/antlir2/bzl:types.bzl", "LayerInfo")
 load("//antlir/antlir2/bzl/package:cfg.bzl", "layer_attrs", "package_cfg")
 load(":macro.bzl", "package_macro")
+load(":manifest.bzl", "declare_manifest", "manifest_args", "manifest_attrs", "manifest_sub_targets")
+load(":reproducible.bzl", "source_date_epoch_attrs", "source_date_epoch_env")
 
 SendstreamInfo = provider(fields = [
     "sendstream",  # 'artifact' that is the btrfs sendstream
@@ -30,6 +32,7 @@ def _find_incremental_parent(*, layer: LayerInfo, parent_label: Label) -> Depend
 
 def _impl(ctx: AnalysisContext) -> list[Provider]:
     sendstream = ctx.actions.declare_output("image.sendstream")
+    manifest = declare_manifest(ctx, "image.sendstream")
 
     userspace = ctx.attrs._rootless
 
@@ -68,14 +71,18 @@ def _impl(ctx: AnalysisContext) -> list[Provider]:
             fail("failed to get subvol_symlink from incremental_parent, cannot proceed: {}".format(
                 ctx.attrs.incremental_parent[SendstreamInfo],
             ))
+        incremental_parent_label = str(ctx.attrs.incremental_parent[SendstreamInfo].layer[LayerInfo].label.raw_target())
     else:
         incremental_parent = None
+        incremental_parent_label = None
 
     spec = ctx.actions.write_json(
         "spec.json",
         {"sendstream": {
             "compression_level": ctx.attrs.compression_level,
             "incremental_parent": incremental_parent,
+            "incremental_parent_label": incremental_parent_label,
+            "label": str(ctx.attrs.layer[LayerInfo].label.raw_target()),
             "subvol_symlink": subvol_symlink.as_output() if not userspace else None,
             "userspace": userspace,
             "volume_name": ctx.attrs.volume_name,
@@ -90,16 +97,17 @@ def _impl(ctx: AnalysisContext) -> list[Provider]:
             cmd_args(spec, format = "--spec={}"),
             cmd_args(ctx.attrs.layer[LayerInfo].contents.subvol_symlink, format = "--layer={}"),
             cmd_args(sendstream.as_output(), format = "--out={}"),
+            manifest_args(manifest),
         ),
         local_only = True,  # needs root and local subvol
         # the old output is used to clean up the local subvolume
         no_outputs_cleanup = not userspace,
         category = "antlir2_package",
         identifier = "sendstream",
-        env = {"RUST_LOG": "trace"},
+        env = {"RUST_LOG": "trace"} | source_date_epoch_env(ctx),
     )
     return [
-        DefaultInfo(sendstream),
+        DefaultInfo(sendstream, sub_targets = manifest_sub_targets(manifest)),
         SendstreamInfo(
             sendstream = sendstream,
             subvol_symlink = subvol_symlink,
@@ -120,7 +128,7 @@ _attrs = {
     "labels": attrs.list(attrs.string(), default = []),
     "volume_name": attrs.string(default = "volume"),
     "_rootless": rootless_cfg.is_rootless_attr,
-} | layer_attrs | rootless_cfg.attrs
+} | layer_attrs | rootless_cfg.attrs | source_date_epoch_attrs | manifest_attrs
 
 _sendstream_v2 = rule(
     impl = _impl,
@@ -0,0 +1,99 @@
+# Copyright (c) Meta Platforms, Inc. and affiliates.
+#
+# This source code is licensed under the MIT license found in the
+# LICENSE file in the root directory of this source tree.
+
+load("//antlir/antlir2/bzl:platform.bzl", "rule_with_default_target_platform")
+load(":gpt.bzl", "GptPartitionSource")
+load(":manifest.bzl", "declare_manifest", "manifest_args", "manifest_attrs", "manifest_sub_targets")
+
+def _impl(ctx: AnalysisContext) -> list[Provider]:
+    out = ctx.actions.declare_output(ctx.label.name, dir = True)
+    manifest = declare_manifest(ctx, ctx.label.name)
+    spec = ctx.actions.write_json(
+        "spec.json",
+        {"verity": {
+            "data_block_size": ctx.attrs.data_block_size,
+            "hash_block_size": ctx.attrs.hash_block_size,
+            "hash_tree": ctx.attrs.hash_tree,
+            "manifest": ctx.attrs.src[GptPartitionSource].manifest,
+            "salt": ctx.attrs.salt,
+            "src": ctx.attrs.src[GptPartitionSource].src,
+            "uuid": ctx.attrs.uuid,
+        }},
+        with_inputs = True,
+    )
+    ctx.actions.run(
+        cmd_args(
+            ctx.attrs._antlir2_packager[RunInfo],
+            "--dir",
+            cmd_args(out.as_output(), format = "--out={}"),
+            cmd_args(spec, format = "--spec={}"),
+            manifest_args(manifest),
+        ),
+        category = "antlir2_package",
+        identifier = "verity",
+    )
+
+    image = out.project("image")
+    params = out.project("verity.json")
+    data_partition = GptPartitionSource(
+        src = image,
+        manifest = manifest,
+        verity = {"params": params, "role": "data"},
+    )
+    sub_targets = {
+        "image": [DefaultInfo(image), data_partition],
+        "verity.json": [DefaultInfo(params)],
+    } | manifest_sub_targets(manifest)
+    if ctx.attrs.hash_tree == "sidecar":
+        hash_tree = out.project("hashtree")
+        sub_targets["hashtree"] = [
+            DefaultInfo(hash_tree),
+            GptPartitionSource(
+                src = hash_tree,
+                verity = {"params": params, "role": "hash"},
+            ),
+        ]
+
+    return [
+        DefaultInfo(out, sub_targets = sub_targets),
+        data_partition,
+    ]
+
+_verity = rule(
+    impl = _impl,
+    attrs = {
+        "data_block_size": attrs.int(default = 4096),
+        "hash_block_size": attrs.int(default = 4096),
+        "hash_tree": attrs.enum(
+            ["appended", "sidecar"],
+            default = "appended",
+            doc = "Append the hash tree to the image, or write it to a separate file (available as the [hashtree] subtarget)",
+        ),
+        "labels": attrs.list(attrs.string(), default = []),
+        "salt": attrs.option(
+            attrs.string(),
+            default = None,
+            doc = "Hex-encoded salt. Defaults to a digest of the image for reproducibility",
+        ),
+        "src": attrs.dep(
+            providers = [GptPartitionSource],
+            doc = "Read-only filesystem package (like package.erofs or package.squashfs)",
+        ),
+        "uuid": attrs.option(attrs.string(), default = None, doc = "Verity superblock uuid"),
+        "_antlir2_packager": attrs.default_only(attrs.exec_dep(default = "antlir//antlir/antlir2/antlir2_packager:antlir2-packager")),
+    } | manifest_attrs,
+    doc = """
+    Compute a dm-verity hash tree over a read-only filesystem image.
+
+    The output directory contains the (block-aligned) `image`, a `hashtree`
+    file if `hash_tree = "sidecar"` and `verity.json` with the root hash, salt
+    and other parameters needed by `veritysetup`.
+    When used as a partition in package.gpt, the data ([image]) and hash
+    ([hashtree]) partitions get uuids derived from the root hash, as described
+    by the Discoverable Partitions Specification.
+    """,
+)
+
+verity = rule_with_default_target_platform(_verity)
@@ -3,39 +3,50 @@
 # This source code is licensed under the MIT license found in the
 # LICENSE file in the root directory of this source tree.
 
+load("//antlir/buck2/bzl:ensure_single_output.bzl", "ensure_single_output")
 load(":cfg.bzl", "layer_attrs", "package_cfg")
 load(":defs.bzl", "common_attrs", "default_attrs", "squashfs_anon")
 load(":macro.bzl", "package_macro")
+load(":reproducible.bzl", "source_date_epoch_env")
 
-def _impl(ctx: AnalysisContext) -> list[Provider]:
-    out = ctx.actions.declare_output(ctx.label.name)
-    squash = ctx.actions.anon_target(squashfs_anon, {
+def _impl(ctx: AnalysisContext) -> Promise:
+    def with_anon(squash) -> list[Provider]:
+        out = ctx.actions.declare_output(ctx.label.name)
+        sub_targets = {}
+        if "manifest" in squash[DefaultInfo].sub_targets:
+            # the xar is just a wrapper around the squashfs of the layer
+            sub_targets["manifest"] = squash[DefaultInfo].sub_targets["manifest"]
+
+        squash = ensure_single_output(squash)
+        spec = ctx.actions.write_json(
+            "spec.json",
+            {"xar": {
+                "executable": ctx.attrs.executable,
+                "squashfs": squash,
+                "target_name": ctx.label.name,
+            }},
+            with_inputs = True,
+        )
+        ctx.actions.run(
+            cmd_args(
+                ctx.attrs._antlir2_packager[RunInfo],
+                cmd_args(out.as_output(), format = "--out={}"),
+                cmd_args(spec, format = "--spec={}"),
+            ),
+            category = "antlir2_package",
+            identifier = "xar",
+            env = source_date_epoch_env(ctx),
+            local_only = True,  # requires local subvol
+        )
+        return [
+            DefaultInfo(out, sub_targets = {"squashfs": [DefaultInfo(squash)]} | sub_targets),
+            RunInfo(cmd_args(out)),
+        ]
+
+    return ctx.actions.anon_target(squashfs_anon, {
         k: getattr(ctx.attrs, k)
         for k in list(layer_attrs) + list(common_attrs) + list(default_attrs)
-    }).artifact("package")
-    spec = ctx.actions.write_json(
-        "spec.json",
-        {"xar": {
-            "executable": ctx.attrs.executable,
-            "squashfs": squash,
-            "target_name": ctx.label.name,
-        }},
-        with_inputs = True,
-    )
-    ctx.actions.run(
-        cmd_args(
-            ctx.attrs._antlir2_packager[RunInfo],
-            cmd_args(out.as_output(), format = "--out={}"),
-            cmd_args(spec, format = "--spec={}"),
-        ),
-        category = "antlir2_package",
-        identifier = "xar",
-        local_only = True,  # requires local subvol
-    )
-    return [
-        DefaultInfo(out, sub_targets = {"squashfs": [DefaultInfo(squash)]}),
-        RunInfo(cmd_args(out)),
-    ]
+    }).promise.map(with_anon)
 
 _xar = rule(
     impl = _impl,
@@ -0,0 +1,123 @@
+# Copyright (c) Meta Platforms, Inc. and affiliates.
+#
+# This source code is licensed under the MIT license found in the
+# LICENSE file in the root directory of this source tree.
+
+import hashlib
+import os
+import struct
+import subprocess
+from contextlib import contextmanager
+from pathlib import Path
+from typing import Iterator, Tuple
+from unittest import TestCase
+
+FSVERITY = Path(os.environ["FSVERITY"])
+SHA256 = Path(os.environ["SHA256"])
+
+BLOCK_SIZE = 4096
+
+
+def fsverity_digest(data: bytes) -> bytes:
+    if data:
+        hashes = [
+            hashlib.sha256(data[i : i + BLOCK_SIZE].ljust(BLOCK_SIZE, b"\0")).digest()
+            for i in range(0, len(data), BLOCK_SIZE)
+        ]
+        while len(hashes) > 1:
+            level = b"".join(hashes)
+            hashes = [
+                hashlib.sha256(
+                    level[i : i + BLOCK_SIZE].ljust(BLOCK_SIZE, b"\0")
+                ).digest()
+                for i in range(0, len(level), BLOCK_SIZE)
+            ]
+        root = hashes[0]
+    else:
+        root = b"\0" * 32
+    descriptor = struct.pack("<BBBBIQ", 1, 1, 12, 0, 0, len(data))
+    descriptor += root.ljust(64, b"\0") + b"\0" * 176
+    return hashlib.sha256(descriptor).digest()
+
+
+@contextmanager
+def mounted(package: Path) -> Iterator[Path]:
+    subprocess.run(
+        ["erofsfuse", package / "image.erofs", "/mnt"],
+        check=True,
+    )
+    try:
+        yield Path("/mnt")
+    finally:
+        subprocess.run(["umount", "/mnt"], check=True)
+
+
+def redirected_files(root: Path) -> Iterator[Tuple[Path, str, bytes]]:
+    for dirpath, _, filenames in os.walk(root):
+        for name in filenames:
+            path = Path(dirpath) / name
+            if path.is_symlink() or not path.is_file():
+                continue
+            if path.stat().st_size == 0:
+                continue
+            redirect = os.getxattr(path, "trusted.overlay.redirect").decode()
+            metacopy = os.getxattr(path, "trusted.overlay.metacopy")
+            yield path, redirect, metacopy
+
+
+class TestComposefs(TestCase):
+    def test_sha256(self) -> None:
+        with mounted(SHA256) as root:
+            files = list(redirected_files(root))
+            self.assertTrue(files)
+            for path, redirect, metacopy in files:
+                with self.subTest(path):
+                    self.assertEqual(metacopy, b"")
+                    obj = SHA256 / "objects" / redirect.lstrip("/")
+                    data = obj.read_bytes()
+                    self.assertEqual(len(data), path.stat().st_size)
+                    digest = hashlib.sha256(data).hexdigest()
+                    self.assertEqual(redirect, f"/{digest[:2]}/{digest[2:]}")
+
+    def test_fsverity(self) -> None:
+        with mounted(FSVERITY) as root:
+            files = list(redirected_files(root))
+            self.assertTrue(files)
+            for path, redirect, metacopy in files:
+                with self.subTest(path):
+                    obj = FSVERITY / "objects" / redirect.lstrip("/")
+                    digest = fsverity_digest(obj.read_bytes())
+                    self.assertEqual(
+                        redirect, f"/{digest.hex()[:2]}/{digest.hex()[2:]}"
+                    )
+                    # struct ovl_metacopy: version, len, flags, sha256 algo
+                    self.assertEqual(metacopy, bytes([0, 36, 0, 1]) + digest)
+
+    def test_objects_are_deduplicated(self) -> None:
+        for package, digest in [
+            (SHA256, lambda data: hashlib.sha256(data).digest()),
+            (FSVERITY, fsverity_digest),
+        ]:
+            with self.subTest(package.name), mounted(package) as root:
+                a = os.getxattr(root / "duplicates/a", "trusted.overlay.redirect")
+                b = os.getxattr(root / "duplicates/b", "trusted.overlay.redirect")
+                self.assertEqual(a, b)
+                # the image only has metadata, the contents are in the object
+                contents = b"same contents\n"
+                expected = digest(contents)
+                # exactly one object has these contents
+                objects = [
+                    obj
+                    for obj in (package / "objects").glob("*/*")
+                    if obj.stat().st_size == len(contents)
+                    and digest(obj.read_bytes()) == expected
+                ]
+                self.assertEqual(
+                    [package / "objects" / a.decode().lstrip("/")], objects
+                )
+        # identical files share one object no matter how objects are named
+        self.assertEqual(
+            len(list((SHA256 / "objects").glob("*/*"))),
+            len(list((FSVERITY / "objects").glob("*/*"))),
+        )
+
@@ -0,0 +1,72 @@
+# Copyright (c) Meta Platforms, Inc. and affiliates.
+#
+# This source code is licensed under the MIT license found in the
+# LICENSE file in the root directory of this source tree.
+
+import os
+import subprocess
+from pathlib import Path
+from typing import Dict, Tuple
+from unittest import TestCase
+
+INITRD = Path(os.environ["INITRD"])
+
+
+def parse_newc(data: bytes) -> Tuple[Dict[str, Tuple[int, bytes]], int]:
+    """
+    Parse a single newc archive at the start of `data`, returning the entries
+    (name -> (mode, contents)) and the length of the archive (including the
+    padding after the trailer)
+    """
+    entries = {}
+    offset = 0
+    while True:
+        assert data[offset : offset + 6] == b"070701", data[offset : offset + 6]
+        fields = [
+            int(data[offset + 6 + i * 8 : offset + 14 + i * 8], 16) for i in range(13)
+        ]
+        mode, filesize, namesize = fields[1], fields[6], fields[11]
+        name_start = offset + 110
+        name = data[name_start : name_start + namesize - 1].decode()
+        data_start = (name_start + namesize + 3) & ~3
+        contents = data[data_start : data_start + filesize]
+        offset = (data_start + filesize + 3) & ~3
+        if name == "TRAILER!!!":
+            return entries, (offset + 511) & ~511
+        entries[name] = (mode, contents)
+
+
+class TestEarlyMicrocode(TestCase):
+    def setUp(self) -> None:
+        super().setUp()
+        self.data = INITRD.read_bytes()
+        self.early, self.early_len = parse_newc(self.data)
+
+    def test_early_archive(self) -> None:
+        self.assertEqual(
+            {
+                "kernel": (0o40755, b""),
+                "kernel/x86": (0o40755, b""),
+                "kernel/x86/microcode": (0o40755, b""),
+                "kernel/x86/microcode/AuthenticAMD.bin": (0o100644, b"amd\n"),
+                "kernel/x86/microcode/GenuineIntel.bin": (
+                    0o100644,
+                    b"intel part 1\nintel part 2\n",
+                ),
+            },
+            self.early,
+        )
+
+    def test_main_archive(self) -> None:
+        rest = self.data[self.early_len :]
+        # zstd magic
+        self.assertEqual(rest[:4], b"\x28\xb5\x2f\xfd")
+        decompressed = subprocess.run(
+            ["zstd", "-d", "--stdout"],
+            input=rest,
+            capture_output=True,
+            check=True,
+        ).stdout
+        main, _ = parse_newc(decompressed)
+        self.assertEqual(main["init"], (0o100755, b"#!/bin/sh\necho hello\n"))
+        self.assertIn("usr/lib/firmware/intel-ucode/06-01-01", main)
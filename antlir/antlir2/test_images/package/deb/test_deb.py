@@ -0,0 +1,96 @@
+# Copyright (c) Meta Platforms, Inc. and affiliates.
+#
+# This source code is licensed under the MIT license found in the
+# LICENSE file in the root directory of this source tree.
+
+import hashlib
+import io
+import os
+import subprocess
+import tarfile
+from pathlib import Path
+from typing import Dict
+from unittest import TestCase
+
+DEB: Path = Path(os.environ["DEB"])
+
+
+def ar_members(data: bytes) -> Dict[str, bytes]:
+    assert data.startswith(b"!<arch>\n"), "not an ar archive"
+    members = {}
+    pos = 8
+    while pos < len(data):
+        header = data[pos : pos + 60]
+        assert header[58:60] == b"`\n", "bad ar header"
+        name = header[0:16].decode().rstrip()
+        size = int(header[48:58].decode())
+        pos += 60
+        members[name] = data[pos : pos + size]
+        pos += size + (size % 2)
+    return members
+
+
+def open_tar(name: str, data: bytes) -> tarfile.TarFile:
+    # python's tarfile does not understand zstd
+    if name.endswith(".zst"):
+        data = subprocess.run(
+            ["zstd", "-d", "-c"], input=data, check=True, capture_output=True
+        ).stdout
+    return tarfile.open(fileobj=io.BytesIO(data))
+
+
+class Test(TestCase):
+    def setUp(self) -> None:
+        self.members = ar_members(DEB.read_bytes())
+        names = list(self.members)
+        self.assertEqual("debian-binary", names[0])
+        self.assertTrue(names[1].startswith("control.tar"), names)
+        self.assertTrue(names[2].startswith("data.tar"), names)
+        self.control = open_tar(names[1], self.members[names[1]])
+        self.data = open_tar(names[2], self.members[names[2]])
+
+    def control_file(self, name: str) -> str:
+        f = self.control.extractfile(name)
+        assert f is not None
+        return f.read().decode()
+
+    def test_debian_binary(self) -> None:
+        self.assertEqual(b"2.0\n", self.members["debian-binary"])
+
+    def test_control(self) -> None:
+        control = self.control_file("control")
+        self.assertIn("Package: antlir2-test\n", control)
+        self.assertIn("Version: 1.2.3-1\n", control)
+        self.assertIn("Architecture: all\n", control)
+        self.assertIn("Depends: libc6 (>= 2.34)\n", control)
+        self.assertIn(
+            "Description: antlir2 test package\n"
+            " First paragraph.\n"
+            " .\n"
+            " Second paragraph.\n",
+            control,
+        )
+
+    def test_conffiles(self) -> None:
+        self.assertEqual("/etc/antlir2-test.conf\n", self.control_file("conffiles"))
+
+    def test_postinst(self) -> None:
+        self.assertEqual(
+            "#!/bin/sh\nset -e\necho installed\n", self.control_file("postinst")
+        )
+        self.assertEqual(0o755, self.control.getmember("postinst").mode)
+
+    def test_md5sums(self) -> None:
+        sums = {}
+        for line in self.control_file("md5sums").splitlines():
+            digest, path = line.split("  ", 1)
+            sums[path] = digest
+        f = self.data.extractfile("usr/bin/antlir2-test")
+        assert f is not None
+        self.assertEqual(hashlib.md5(f.read()).hexdigest(), sums["usr/bin/antlir2-test"])
+
+    def test_data(self) -> None:
+        member = self.data.getmember("usr/bin/antlir2-test")
+        self.assertEqual(0o755, member.mode)
+        self.assertEqual("root", member.uname)
+        self.assertEqual("root", member.gname)
@@ -3,14 +3,17 @@
 # This source code is licensed under the MIT license found in the
 # LICENSE file in the root directory of this source tree.
 
+import json
 import os
 import re
 import subprocess
+import tarfile
 from pathlib import Path
 from subprocess import CalledProcessError
 from unittest import TestCase
 
 DOCKER_ARCHIVE_PATH: Path = Path(os.environ["DOCKER_ARCHIVE"])
+DOCKER_ARCHIVE_TAGGED_PATH: Path = Path(os.environ["DOCKER_ARCHIVE_TAGGED"])
 
 
 class Test(TestCase):
@@ -54,3 +57,24 @@ class Test(TestCase):
             capture_output=True,
         )
         self.assertEqual("Entrypoint!\n555 0 0\n", proc.stdout)
+
+    def test_tagged_archive(self) -> None:
+        with tarfile.open(DOCKER_ARCHIVE_TAGGED_PATH) as tar:
+            manifest = json.load(tar.extractfile("manifest.json"))
+        self.assertEqual(len(manifest), 1)
+        self.assertEqual(
+            manifest[0]["RepoTags"],
+            ["antlir2-test:latest", "localhost/antlir2-test:tagged"],
+        )
+        for layer in manifest[0]["Layers"]:
+            self.assertTrue(layer.endswith(".tar.gz"), layer)
+
+    def test_podman_load_tagged(self) -> None:
+        subprocess.run(
+            ["podman", "load", "--input", DOCKER_ARCHIVE_TAGGED_PATH],
+            check=True,
+            capture_output=True,
+        )
+        for tag in ["localhost/antlir2-test:latest", "localhost/antlir2-test:tagged"]:
+            with self.subTest(tag):
+                subprocess.run(["podman", "image", "exists", tag], check=True)
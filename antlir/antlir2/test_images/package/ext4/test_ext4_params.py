@@ -0,0 +1,67 @@
+# Copyright (c) Meta Platforms, Inc. and affiliates.
+#
+# This source code is licensed under the MIT license found in the
+# LICENSE file in the root directory of this source tree.
+
+import os
+import subprocess
+from pathlib import Path
+from typing import Dict
+from unittest import TestCase
+
+DEFAULT = Path(os.environ["DEFAULT"])
+FREE_MB = Path(os.environ["FREE_MB"])
+SIZE_MB = Path(os.environ["SIZE_MB"])
+TUNED = Path(os.environ["TUNED"])
+
+LARGE_FILE_SIZE = 256 * 1024 * 1024
+
+
+def superblock(path: Path) -> Dict[str, str]:
+    out = subprocess.run(
+        ["dumpe2fs", "-h", path], check=True, capture_output=True, text=True
+    ).stdout
+    fields = {}
+    for line in out.splitlines():
+        key, sep, value = line.partition(":")
+        if sep:
+            fields[key.strip()] = value.strip()
+    return fields
+
+
+class TestExt4Params(TestCase):
+    def test_default_is_minimally_sized(self) -> None:
+        sb = superblock(DEFAULT)
+        size = int(sb["Block count"]) * int(sb["Block size"])
+        self.assertGreater(size, LARGE_FILE_SIZE)
+        # only a little bit more than what's needed for the contents
+        self.assertLess(size, LARGE_FILE_SIZE * 1.15)
+        self.assertLess(int(sb["Free blocks"]) * int(sb["Block size"]), size * 0.05)
+        self.assertEqual(DEFAULT.stat().st_size, size)
+
+    def test_free_mb(self) -> None:
+        sb = superblock(FREE_MB)
+        self.assertGreaterEqual(
+            int(sb["Free blocks"]) * int(sb["Block size"]), 256 * 1024 * 1024
+        )
+
+    def test_size_mb(self) -> None:
+        self.assertEqual(SIZE_MB.stat().st_size, 1024 * 1024 * 1024)
+
+    def test_tuned(self) -> None:
+        sb = superblock(TUNED)
+        features = set(sb["Filesystem features"].split())
+        self.assertLessEqual({"metadata_csum", "64bit", "orphan_file"}, features)
+        self.assertNotIn("resize_inode", features)
+        self.assertEqual(sb["Inode size"], "512")
+        self.assertGreaterEqual(int(sb["Inode count"]), 100000)
+        self.assertEqual(sb["Reserved block count"], "1024")
+        self.assertEqual(sb["Filesystem UUID"], "a2d3b2c4-4bd0-4dd4-9d1f-2a6a7fd0e7a1")
+        self.assertEqual(sb["Filesystem volume name"], "root")
+
+    def test_deterministic_uuid(self) -> None:
+        # the same layer always gets the same uuid
+        self.assertEqual(
+            superblock(DEFAULT)["Filesystem UUID"],
+            superblock(SIZE_MB)["Filesystem UUID"],
+        )
@@ -0,0 +1,65 @@
+# Copyright (c) Meta Platforms, Inc. and affiliates.
+#
+# This source code is licensed under the MIT license found in the
+# LICENSE file in the root directory of this source tree.
+
+import json
+import os
+import platform
+import struct
+import subprocess
+from pathlib import Path
+from unittest import TestCase
+
+GPT = Path(os.environ["GPT"])
+
+ROOT_TYPES = {
+    "aarch64": "B921B045-1DF0-41C3-AF44-4C6F280D3FAE",
+    "x86_64": "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709",
+}
+
+
+class TestDiscoverablePartitions(TestCase):
+    def setUp(self) -> None:
+        super().setUp()
+        self.partitions = json.loads(
+            subprocess.run(
+                ["sfdisk", "--json", GPT], check=True, capture_output=True, text=True
+            ).stdout
+        )["partitiontable"]["partitions"]
+
+    def test_types(self) -> None:
+        self.assertEqual(
+            [p["type"] for p in self.partitions],
+            [
+                "C12A7328-F81F-11D2-BA4B-00A0C93EC93B",
+                ROOT_TYPES[platform.machine()],
+            ],
+        )
+
+    def test_guid(self) -> None:
+        self.assertEqual(
+            self.partitions[1]["uuid"], "01234567-89AB-CDEF-0123-456789ABCDEF"
+        )
+
+    def test_attributes(self) -> None:
+        self.assertEqual(self.partitions[0]["attrs"], "LegacyBIOSBootable")
+        self.assertEqual(self.partitions[1]["attrs"], "GUID:59,60")
+
+    def test_hybrid_mbr(self) -> None:
+        with GPT.open("rb") as f:
+            lba0 = f.read(512)
+        self.assertEqual(lba0[510:], b"\x55\xaa")
+        records = [lba0[446 + i * 16 : 446 + (i + 1) * 16] for i in range(4)]
+        esp = self.partitions[0]
+        # the gpt itself
+        self.assertEqual(records[0][4], 0xEE)
+        self.assertEqual(struct.unpack("<II", records[0][8:]), (1, esp["start"] - 1))
+        # the esp, marked active
+        self.assertEqual(records[1][0], 0x80)
+        self.assertEqual(records[1][4], 0xEF)
+        self.assertEqual(
+            struct.unpack("<II", records[1][8:]), (esp["start"], esp["size"])
+        )
+        self.assertEqual(records[2], b"\0" * 16)
+        self.assertEqual(records[3], b"\0" * 16)
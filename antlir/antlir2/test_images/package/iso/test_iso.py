@@ -0,0 +1,48 @@
+# Copyright (c) Meta Platforms, Inc. and affiliates.
+#
+# This source code is licensed under the MIT license found in the
+# LICENSE file in the root directory of this source tree.
+
+import os
+import subprocess
+import tempfile
+from pathlib import Path
+from unittest import TestCase
+
+ISO: Path = Path(os.environ["ISO"])
+
+
+class Test(TestCase):
+    def xorriso(self, *args: str) -> str:
+        return subprocess.run(
+            ["xorriso", "-indev", ISO, *args],
+            check=True,
+            capture_output=True,
+            text=True,
+        ).stdout
+
+    def test_el_torito(self) -> None:
+        report = self.xorriso("-report_el_torito", "plain")
+        self.assertIn("El Torito catalog  : ", report)
+        self.assertRegex(report, r"El Torito boot img :\s+1\s+BIOS")
+        self.assertRegex(report, r"El Torito boot img :\s+2\s+UEFI")
+
+    def test_volume_label(self) -> None:
+        self.assertIn("Volume id    : 'ANTLIR2'", self.xorriso("-pvd_info"))
+
+    def test_rock_ridge(self) -> None:
+        with tempfile.TemporaryDirectory() as tmp:
+            dst = Path(tmp) / "extracted"
+            self.xorriso(
+                "-osirrox",
+                "on",
+                "-extract",
+                "/a-very-long-file-name-that-does-not-fit-in-iso9660.txt",
+                str(dst),
+            )
+            self.assertEqual("hello", dst.read_text())
+            self.assertEqual(0o755, dst.stat().st_mode & 0o777)
+
+    def test_joliet(self) -> None:
+        listing = self.xorriso("-rr", "off", "-joliet", "on", "-ls", "/")
+        self.assertIn("a-very-long-file-name-that-does-not-fit-in-iso9660.txt", listing)
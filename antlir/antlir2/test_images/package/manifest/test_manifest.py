@@ -0,0 +1,65 @@
+# Copyright (c) Meta Platforms, Inc. and affiliates.
+#
+# This source code is licensed under the MIT license found in the
+# LICENSE file in the root directory of this source tree.
+
+import hashlib
+import json
+import os
+from pathlib import Path
+from typing import Any, Dict
+from unittest import TestCase
+
+CPIO_MANIFEST = Path(os.environ["CPIO_MANIFEST"])
+SQUASHFS_MANIFEST = Path(os.environ["SQUASHFS_MANIFEST"])
+
+
+def load(path: Path) -> Dict[str, Dict[str, Any]]:
+    with path.open() as f:
+        return {e["path"]: e for e in json.load(f)["entries"]}
+
+
+class TestManifest(TestCase):
+    def setUp(self) -> None:
+        self.manifest = load(SQUASHFS_MANIFEST)
+
+    def test_same_layer_same_manifest(self) -> None:
+        # .meta records the package target, which is the only thing that
+        # differs between these
+        def without_meta(m):
+            return {k: v for k, v in m.items() if not k.startswith("/.meta")}
+
+        self.assertEqual(
+            without_meta(self.manifest), without_meta(load(CPIO_MANIFEST))
+        )
+
+    def test_file(self) -> None:
+        entry = self.manifest["/default-dir/executable"]
+        self.assertEqual(entry["type"], "file")
+        self.assertEqual(entry["mode"], "0555")
+        self.assertEqual(entry["uid"], 0)
+        self.assertEqual(entry["gid"], 0)
+        contents = b"#!/bin/bash\necho hello"
+        self.assertEqual(entry["size"], len(contents))
+        self.assertEqual(entry["sha256"], hashlib.sha256(contents).hexdigest())
+
+    def test_owner(self) -> None:
+        entry = self.manifest["/i-am-owned-by-nonstandard"]
+        self.assertEqual((entry["uid"], entry["gid"]), (42, 43))
+
+    def test_xattrs(self) -> None:
+        self.assertEqual(
+            self.manifest["/i-have-xattrs"]["xattrs"],
+            {"user.baz": b"qux".hex(), "user.foo": b"bar".hex()},
+        )
+
+    def test_symlink(self) -> None:
+        entry = self.manifest["/relative-dir-symlink"]
+        self.assertEqual(entry["type"], "symlink")
+        self.assertEqual(entry["symlink_target"], "default-dir")
+        self.assertNotIn("sha256", entry)
+
+    def test_directory(self) -> None:
+        entry = self.manifest["/default-dir"]
+        self.assertEqual(entry["type"], "directory")
+        self.assertNotIn("size", entry)
@@ -3,6 +3,7 @@
 # This source code is licensed under the MIT license found in the
 # LICENSE file in the root directory of this source tree.
 
+import json
 import os
 import re
 import subprocess
@@ -54,3 +55,18 @@ class Test(TestCase):
             capture_output=True,
         )
         self.assertEqual("Entrypoint!\n555 0 0\n", proc.stdout)
+
+    def test_config(self) -> None:
+        def blob(digest: str):
+            algo, digest = digest.split(":")
+            with open(OCI_PATH / "blobs" / algo / digest) as f:
+                return json.load(f)
+
+        with open(OCI_PATH / "index.json") as f:
+            index = json.load(f)
+        manifest = blob(index["manifests"][0]["digest"])
+        config = blob(manifest["config"]["digest"])
+        self.assertIn("ANTLIR2_TEST=1", config["config"]["Env"])
+        self.assertEqual("/tmp", config["config"]["WorkingDir"])
+        self.assertEqual(len(manifest["layers"]), len(config["history"]))
+        self.assertEqual(len(config["rootfs"]["diff_ids"]), len(config["history"]))
@@ -0,0 +1,50 @@
+# Copyright (c) Meta Platforms, Inc. and affiliates.
+#
+# This source code is licensed under the MIT license found in the
+# LICENSE file in the root directory of this source tree.
+
+import json
+import os
+from pathlib import Path
+from unittest import TestCase
+
+OCI_PATH: Path = Path(os.environ["OCI"])
+
+
+class Test(TestCase):
+    def blob(self, digest: str):
+        algo, digest = digest.split(":")
+        with open(OCI_PATH / "blobs" / algo / digest) as f:
+            return json.load(f)
+
+    def image_index(self):
+        with open(OCI_PATH / "index.json") as f:
+            index = json.load(f)
+        # a single nested index holds all the per-arch manifests
+        self.assertEqual(1, len(index["manifests"]))
+        return index["manifests"][0]
+
+    def test_index(self) -> None:
+        desc = self.image_index()
+        self.assertEqual("application/vnd.oci.image.index.v1+json", desc["mediaType"])
+        self.assertIn("org.opencontainers.image.ref.name", desc["annotations"])
+        nested = self.blob(desc["digest"])
+        platforms = [m["platform"] for m in nested["manifests"]]
+        self.assertEqual(
+            [
+                {"architecture": "amd64", "os": "linux"},
+                {"architecture": "arm64", "os": "linux", "variant": "v8"},
+            ],
+            platforms,
+        )
+        # the ref only names the whole image, not each platform's manifest
+        for m in nested["manifests"]:
+            self.assertNotIn("annotations", m)
+
+    def test_config_matches_platform(self) -> None:
+        nested = self.blob(self.image_index()["digest"])
+        for desc in nested["manifests"]:
+            manifest = self.blob(desc["digest"])
+            config = self.blob(manifest["config"]["digest"])
+            self.assertEqual(desc["platform"]["architecture"], config["architecture"])
+            self.assertEqual(desc["platform"].get("variant"), config.get("variant"))
@@ -0,0 +1,38 @@
+# Copyright (c) Meta Platforms, Inc. and affiliates.
+#
+# This source code is licensed under the MIT license found in the
+# LICENSE file in the root directory of this source tree.
+
+import os
+import platform
+from pathlib import Path
+from unittest import TestCase
+
+RECEIVED = Path("/received")
+
+
+class TestReceive(TestCase):
+    def test_whiteout(self) -> None:
+        self.assertEqual((RECEIVED / "whiteout/keep").read_text(), "keep\n")
+        self.assertFalse((RECEIVED / "whiteout/remove").exists())
+        # whiteout markers are never unpacked themselves
+        self.assertEqual(os.listdir(RECEIVED / "whiteout"), ["keep"])
+
+    def test_lower_layers(self) -> None:
+        self.assertTrue((RECEIVED / "usr/bin/bash").exists())
+        self.assertTrue((RECEIVED / "usr/bin/ls").exists())
+
+    def test_ownership(self) -> None:
+        st = (RECEIVED / "entrypoint.sh").stat()
+        self.assertEqual((st.st_uid, st.st_gid), (0, 0))
+
+    def test_target_platform(self) -> None:
+        # the manifest matching the target platform was picked out of the
+        # multi-arch index, so the binaries are for this machine
+        with (RECEIVED / "usr/bin/bash").open("rb") as f:
+            header = f.read(20)
+        self.assertEqual(header[:4], b"\x7fELF")
+        e_machine = int.from_bytes(header[18:20], "little")
+        self.assertEqual(
+            e_machine, {"x86_64": 0x3E, "aarch64": 0xB7}[platform.machine()]
+        )
@@ -0,0 +1,21 @@
+# Copyright (c) Meta Platforms, Inc. and affiliates.
+#
+# This source code is licensed under the MIT license found in the
+# LICENSE file in the root directory of this source tree.
+
+import os
+import tarfile
+from pathlib import Path
+from unittest import TestCase
+
+TAR: Path = Path(os.environ["TAR"])
+
+
+class TestSourceDateEpoch(TestCase):
+    def test_mtimes_clamped(self) -> None:
+        with tarfile.open(TAR) as tar:
+            mtimes = {m.name: m.mtime for m in tar.getmembers()}
+        self.assertTrue(mtimes)
+        # everything in the layer was created at build time, long after the
+        # epoch that the package was built with
+        self.assertEqual({1000}, set(mtimes.values()), mtimes)
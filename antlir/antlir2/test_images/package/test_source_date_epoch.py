@@ -0,0 +1,44 @@
+# Copyright (c) Meta Platforms, Inc. and affiliates.
+#
+# This source code is licensed under the MIT license found in the
+# LICENSE file in the root directory of this source tree.
+
+import os
+import subprocess
+import tempfile
+from pathlib import Path
+from unittest import TestCase
+
+FORMAT: str = os.environ["FORMAT"]
+PACKAGE: Path = Path(os.environ["PACKAGE"])
+REBUILT: Path = Path(os.environ["REBUILT"])
+
+
+def extract(package: Path, dst: Path) -> None:
+    if FORMAT == "squashfs":
+        cmd = ["unsquashfs", "-no-progress", "-d", str(dst), str(package)]
+    elif FORMAT == "erofs":
+        cmd = ["fsck.erofs", f"--extract={dst}", str(package)]
+    else:
+        raise NotImplementedError(FORMAT)
+    subprocess.run(cmd, check=True)
+
+
+class TestSourceDateEpoch(TestCase):
+    def test_reproducible(self) -> None:
+        self.assertEqual(PACKAGE.read_bytes(), REBUILT.read_bytes())
+
+    def test_mtimes_clamped(self) -> None:
+        with tempfile.TemporaryDirectory() as tmp:
+            root = Path(tmp) / "root"
+            extract(PACKAGE, root)
+            mtimes = {
+                str(path.relative_to(root)): path.lstat().st_mtime
+                for path in [root / "old", root / "old/file", root / "new"]
+            }
+        # the old entries predate the epoch that the package was built with,
+        # but the new one was created long after it
+        self.assertEqual(
+            {"old": 500, "old/file": 500, "new": 1000},
+            mtimes,
+        )
@@ -0,0 +1,41 @@
+# Copyright (c) Meta Platforms, Inc. and affiliates.
+#
+# This source code is licensed under the MIT license found in the
+# LICENSE file in the root directory of this source tree.
+
+import os
+import subprocess
+import tempfile
+from pathlib import Path
+from unittest import TestCase
+
+UKI: Path = Path(os.environ["UKI"])
+
+
+class Test(TestCase):
+    def section(self, name: str) -> bytes:
+        with tempfile.NamedTemporaryFile() as f:
+            subprocess.run(
+                ["objcopy", "--dump-section", f"{name}={f.name}", UKI, "/dev/null"],
+                check=True,
+            )
+            # the raw data is padded to the file alignment
+            return Path(f.name).read_bytes().rstrip(b"\0")
+
+    def test_sections(self) -> None:
+        headers = subprocess.run(
+            ["objdump", "--section-headers", UKI],
+            check=True,
+            capture_output=True,
+            text=True,
+        ).stdout
+        for section in [".osrel", ".cmdline", ".uname", ".initrd", ".linux"]:
+            self.assertIn(section, headers)
+
+    def test_contents(self) -> None:
+        self.assertEqual(b"console=ttyS0 quiet", self.section(".cmdline"))
+        self.assertEqual(b"6.0.0-antlir", self.section(".uname"))
+        self.assertEqual(b"not really a kernel", self.section(".linux"))
+        self.assertIn(b"ID=", self.section(".osrel"))
+        # newc cpio magic
+        self.assertTrue(self.section(".initrd").startswith(b"070701"))
@@ -0,0 +1,61 @@
+# Copyright (c) Meta Platforms, Inc. and affiliates.
+#
+# This source code is licensed under the MIT license found in the
+# LICENSE file in the root directory of this source tree.
+
+import json
+import os
+import subprocess
+from pathlib import Path
+from unittest import TestCase
+
+APPENDED: Path = Path(os.environ["APPENDED"])
+SIDECAR: Path = Path(os.environ["SIDECAR"])
+GPT: Path = Path(os.environ["GPT"])
+
+
+class Test(TestCase):
+    def params(self, path: Path):
+        with open(path / "verity.json") as f:
+            return json.load(f)
+
+    def verify(self, data: Path, hash_tree: Path, params) -> None:
+        subprocess.run(
+            [
+                "veritysetup",
+                "verify",
+                data,
+                hash_tree,
+                params["root_hash"],
+            ]
+            + params["veritysetup_args"],
+            check=True,
+        )
+
+    def test_appended(self) -> None:
+        params = self.params(APPENDED)
+        self.assertEqual("appended", params["hash_tree"])
+        self.verify(APPENDED / "image", APPENDED / "image", params)
+
+    def test_sidecar(self) -> None:
+        params = self.params(SIDECAR)
+        self.assertEqual("sidecar", params["hash_tree"])
+        self.verify(SIDECAR / "image", SIDECAR / "hashtree", params)
+
+    def test_same_root_hash(self) -> None:
+        self.assertEqual(
+            self.params(APPENDED)["root_hash"], self.params(SIDECAR)["root_hash"]
+        )
+
+    def test_gpt_partition_uuids(self) -> None:
+        root_hash = self.params(SIDECAR)["root_hash"]
+        sfdisk = json.loads(
+            subprocess.run(
+                ["sfdisk", "--json", GPT], check=True, capture_output=True, text=True
+            ).stdout
+        )
+        uuids = [
+            p["uuid"].lower().replace("-", "")
+            for p in sfdisk["partitiontable"]["partitions"]
+        ]
+        self.assertEqual([root_hash[:32], root_hash[32:]], uuids)
@@ -0,0 +1,53 @@
+# Copyright (c) Meta Platforms, Inc. and affiliates.
+#
+# This source code is licensed under the MIT license found in the
+# LICENSE file in the root directory of this source tree.
+
+import os
+import subprocess
+import tempfile
+from pathlib import Path
+from unittest import TestCase
+
+FAT_SIZE = int(os.environ["FAT_SIZE"])
+VFAT = Path(os.environ["VFAT"])
+
+
+class TestVfat(TestCase):
+    def test_fsck(self) -> None:
+        # -n never modifies the image, and fails if there is anything to fix
+        subprocess.run(["fsck.fat", "-n", "-v", VFAT], check=True)
+
+    def test_fat_type(self) -> None:
+        with VFAT.open("rb") as f:
+            boot_sector = f.read(512)
+        offset = 82 if FAT_SIZE == 32 else 54
+        self.assertEqual(
+            boot_sector[offset : offset + 8], f"FAT{FAT_SIZE}".ljust(8).encode()
+        )
+
+    def test_label(self) -> None:
+        label = subprocess.run(
+            ["fatlabel", VFAT], check=True, capture_output=True, text=True
+        ).stdout
+        self.assertEqual(label.strip(), "ESP")
+
+    def test_contents(self) -> None:
+        with tempfile.TemporaryDirectory() as out:
+            subprocess.run(
+                ["mcopy", "-s", "-n", "-i", VFAT, "::/EFI", "::/loader", out],
+                check=True,
+                env={**os.environ, "MTOOLS_SKIP_CHECK": "1"},
+            )
+            out = Path(out)
+            self.assertEqual(
+                (out / "EFI/BOOT/BOOTX64.EFI").read_text(),
+                "not really an efi binary\n",
+            )
+            self.assertEqual((out / "loader/loader.conf").read_text(), "timeout 3\n")
+            # symlinks are followed, since vfat can't represent them
+            self.assertEqual((out / "loader/default.conf").read_text(), "timeout 3\n")
+            self.assertEqual(
+                (out / "loader/entries/A Long Entry Name.conf").read_text(),
+                "title antlir2\n",
+            )